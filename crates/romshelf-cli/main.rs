@@ -10,24 +10,28 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use romshelf_core::dat;
 use romshelf_core::db;
+use romshelf_core::hash;
+use romshelf_core::profile::ProfileRegistry;
+use romshelf_core::report;
 use romshelf_core::scan::{self, ScanProgress};
+use romshelf_core::services::audit::{self, SetStatus};
 use romshelf_core::services::dat_importer::{DatImportOptions, DatImportOutcome, DatImporter};
-use romshelf_core::services::progress::{DatImportEvent, ProgressSink, ScanEvent};
+use romshelf_core::services::diagnostics::{self, NearMiss};
+use romshelf_core::services::history::{self, DatSnapshot, SnapshotEvent};
+use romshelf_core::services::merge::{self, MergeMode};
+use romshelf_core::services::path_template::PathTemplate;
+use romshelf_core::services::progress::{
+    DatImportEvent, ProgressSink, RebuildEvent, ScanEvent, TzipEvent,
+};
+use romshelf_core::services::rebuild::{
+    self, ArchiveFormat, RebuildMode, RebuildOptions, Rebuilder,
+};
+use romshelf_core::services::tzip::{self, TzipOptions, TzipOutcome};
+use romshelf_core::services::verifier::{self, Scope};
 use romshelf_core::tosec;
-use romshelf_core::verify;
-
-/// A matched file ready for organisation
-/// (source_path, filename, rom_name, dat_name, set_name, category)
-type MatchedFile = (
-    PathBuf,
-    String,
-    String,
-    String,
-    Option<String>,
-    Option<String>,
-);
+use romshelf_core::verify::NamePolicy;
+use romshelf_core::volume;
 
 #[derive(Parser)]
 #[command(name = "romshelf")]
@@ -45,6 +49,14 @@ struct Cli {
     #[arg(long, global = true)]
     progress_log: Option<PathBuf>,
 
+    /// Database file to use instead of a profile's
+    #[arg(long, global = true, conflicts_with = "profile")]
+    db: Option<PathBuf>,
+
+    /// Library profile to use (defaults to the active profile)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -74,6 +86,34 @@ enum Commands {
         /// Show detailed issues
         #[arg(long)]
         issues: bool,
+
+        /// Recompute all matches from scratch before reporting
+        #[arg(long)]
+        rematch: bool,
+
+        /// Report completeness per set instead of per ROM
+        #[arg(long)]
+        sets: bool,
+
+        /// Check set archives: naming, misplaced ROMs and unneeded files
+        #[arg(long, conflicts_with = "sets")]
+        containers: bool,
+
+        /// Print a full report as json, csv, markdown or html instead
+        #[arg(long, conflicts_with_all = ["issues", "sets", "containers"])]
+        format: Option<String>,
+
+        /// Only this DAT (ID or name, partial match)
+        #[arg(long, conflicts_with = "containers")]
+        dat: Option<String>,
+
+        /// Only DATs in this category and below (e.g. TOSEC/Commodore)
+        #[arg(long, conflicts_with_all = ["dat", "containers"])]
+        category: Option<String>,
+
+        /// Only files at or under this path
+        #[arg(long, conflicts_with_all = ["sets", "containers"])]
+        path: Option<PathBuf>,
     },
     /// Organise ROMs into a structured directory
     Organise {
@@ -93,14 +133,52 @@ enum Commands {
         #[arg(long)]
         loose: bool,
 
-        /// Create one ZIP per DAT instead of per set
+        /// Create one archive per DAT instead of per set
         #[arg(long)]
         zip_per_dat: bool,
 
+        /// Archive format: zip (TorrentZip), 7z (solid LZMA2) or zip-zstd.
+        /// DATs with their own output format keep it
+        #[arg(long, default_value = "zip", conflicts_with = "loose")]
+        format: String,
+
+        /// Output path template, e.g. "{system}/{title} ({region})".
+        /// Variables: dat, category, system, set, description, parent, title,
+        /// region, tags, first_letter
+        #[arg(long)]
+        template: Option<String>,
+
+        /// Only show where each set would go, without reading any file
+        #[arg(long)]
+        preview: bool,
+
+        /// Bring existing archives up to date: keep correct entries, add
+        /// missing ROMs and move wrong or unneeded entries to the backup
+        #[arg(long, conflicts_with = "loose")]
+        merge: bool,
+
+        /// Where --merge moves dropped entries (default: <target>/.romshelf-backup)
+        #[arg(long, requires = "merge")]
+        backup: Option<PathBuf>,
+
         /// Only rename misnamed files in-place (don't reorganise)
         #[arg(long)]
         rename_only: bool,
     },
+    /// Rewrite zip and 7z archives in place as TorrentZip
+    Tzip {
+        /// Archives, or directories to search for archives
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Number of worker threads (default: all cores)
+        #[arg(long, short = 't')]
+        threads: Option<usize>,
+
+        /// Only list the archives that would be rewritten
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Show collection statistics
     Stats,
     /// Show collection health report
@@ -111,6 +189,114 @@ enum Commands {
         #[arg(long)]
         details: bool,
     },
+    /// Show how completeness changed over time (snapshots after scans, verifies and imports)
+    History {
+        /// Only this DAT (ID or name, partial match)
+        #[arg(long)]
+        dat: Option<String>,
+
+        /// Only snapshots from this date on (e.g. 2024-05-01)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Show at most this many of the latest snapshots
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Print the snapshots as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Search sets, ROMs and files by name
+    Search {
+        /// Words to search for (each matches as a prefix)
+        #[arg(required = true)]
+        query: Vec<String>,
+
+        /// Maximum number of results
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+    /// Registered scan roots (collection top-level directories)
+    Roots {
+        #[command(subcommand)]
+        command: RootsCommands,
+    },
+    /// Volumes holding scan roots (external drives, NAS shares)
+    Volumes {
+        #[command(subcommand)]
+        command: VolumesCommands,
+    },
+    /// Manage library profiles (separate databases)
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum RootsCommands {
+    /// List scan roots and how many files each holds
+    List,
+    /// Point a root (or every root below a directory) at a new location
+    Relocate {
+        /// Current root path, or a parent of several roots
+        old: PathBuf,
+
+        /// New location
+        new: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum VolumesCommands {
+    /// List volumes and roots with their online state and last-seen time
+    Status,
+    /// Write a volume marker file so a drive is recognised wherever it is mounted
+    Mark {
+        /// Top-level directory of the drive
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// List profiles and their databases
+    List,
+    /// Register a new profile
+    Add {
+        /// Profile name (letters, digits, '-' and '_')
+        name: String,
+
+        /// Database file (defaults to ~/.romshelf/profiles/<name>.db)
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+    /// Forget a profile (its database file is kept)
+    Remove {
+        /// Profile name
+        name: String,
+    },
+    /// Make a profile the default for later commands
+    Use {
+        /// Profile name
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Check database integrity and report orphaned rows
+    Check {
+        /// Remove orphaned rows (or clear dangling references)
+        #[arg(long)]
+        repair: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -148,6 +334,66 @@ enum DatCommands {
         /// DAT ID or name (partial match)
         dat: String,
     },
+    /// Show or set how a MAME DAT's sets are stored (split, merged, non-merged)
+    MergeMode {
+        /// DAT ID or name (partial match)
+        dat: String,
+
+        /// split, merged, non-merged, or none to audit sets on their own
+        mode: Option<String>,
+    },
+    /// Enable a disabled DAT and match files against it again
+    Enable {
+        /// DAT ID or name (partial match)
+        dat: String,
+    },
+    /// Disable a DAT: keep its entries but drop it from matching and verification
+    Disable {
+        /// DAT ID or name (partial match)
+        dat: String,
+    },
+    /// Show or set a DAT's priority; the highest wins when a file matches several DATs
+    Priority {
+        /// DAT ID or name (partial match)
+        dat: String,
+
+        /// New priority (default 0; higher wins)
+        #[arg(allow_hyphen_values = true)]
+        priority: Option<i64>,
+    },
+    /// Show or set the archive format organise writes a DAT's sets in
+    OutputFormat {
+        /// DAT ID or name (partial match)
+        dat: String,
+
+        /// zip, 7z, zip-zstd, or default to follow organise --format
+        format: Option<String>,
+    },
+    /// Show or change how filenames are checked against a DAT's ROM names
+    NamePolicy {
+        /// DAT ID or name (partial match)
+        dat: String,
+
+        /// insensitive or sensitive
+        #[arg(long)]
+        case: Option<String>,
+
+        /// nfc (normalise before comparing) or exact
+        #[arg(long)]
+        unicode: Option<String>,
+
+        /// compare or ignore
+        #[arg(long)]
+        extension: Option<String>,
+
+        /// file-name (compare base names) or full-path (path inside the archive)
+        #[arg(long)]
+        archive_path: Option<String>,
+
+        /// Go back to the default policy
+        #[arg(long, conflicts_with_all = ["case", "unicode", "extension", "archive_path"])]
+        reset: bool,
+    },
     /// Remove a DAT and all its entries
     Remove {
         /// DAT ID or name (partial match)
@@ -166,9 +412,16 @@ enum DatCommands {
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    // Get database path
-    let db_path = get_db_path()?;
-    let mut conn = db::init_db(&db_path)?;
+    let mut profiles = ProfileRegistry::load()?;
+    if let Commands::Profile { command } = &cli.command {
+        return cmd_profile(&mut profiles, command);
+    }
+
+    let db_path = match &cli.db {
+        Some(path) => path.clone(),
+        None => profiles.resolve(cli.profile.as_deref())?,
+    };
+    let library = db::Library::open(&db_path)?;
 
     let verbose = cli.verbose;
     let progress_sink = CliProgressSink::new(cli.progress_json, cli.progress_log.clone());
@@ -176,23 +429,54 @@ fn main() -> Result<()> {
     match cli.command {
         Commands::Dat { command } => match command {
             DatCommands::Import { path, category } => cmd_dat_import(
-                &mut conn,
+                &mut library.writer(),
                 path.as_path(),
                 category.as_deref(),
                 progress_sink.clone(),
             ),
             DatCommands::ImportDir { path, prefix } => cmd_dat_import_dir(
-                &mut conn,
+                &mut library.writer(),
                 &path,
                 prefix.as_deref(),
                 verbose,
                 progress_sink.clone(),
             ),
             DatCommands::List { category, search } => {
-                cmd_dat_list(&conn, category.as_deref(), search.as_deref())
+                cmd_dat_list(&*library.reader()?, category.as_deref(), search.as_deref())
+            }
+            DatCommands::Info { dat } => cmd_dat_info(&*library.reader()?, &dat),
+            DatCommands::MergeMode { dat, mode } => {
+                cmd_dat_merge_mode(&library.writer(), &dat, mode.as_deref())
+            }
+            DatCommands::Enable { dat } => cmd_dat_enable(&library.writer(), &dat, true),
+            DatCommands::Disable { dat } => cmd_dat_enable(&library.writer(), &dat, false),
+            DatCommands::Priority { dat, priority } => {
+                cmd_dat_priority(&library.writer(), &dat, priority)
+            }
+            DatCommands::OutputFormat { dat, format } => {
+                cmd_dat_output_format(&library.writer(), &dat, format.as_deref())
+            }
+            DatCommands::NamePolicy {
+                dat,
+                case,
+                unicode,
+                extension,
+                archive_path,
+                reset,
+            } => cmd_dat_name_policy(
+                &library.writer(),
+                &dat,
+                NamePolicyChanges {
+                    case,
+                    unicode,
+                    extension,
+                    archive_path,
+                    reset,
+                },
+            ),
+            DatCommands::Remove { dat, yes, dry_run } => {
+                cmd_dat_remove(&library.writer(), &dat, yes, dry_run)
             }
-            DatCommands::Info { dat } => cmd_dat_info(&conn, &dat),
-            DatCommands::Remove { dat, yes, dry_run } => cmd_dat_remove(&conn, &dat, yes, dry_run),
         },
         Commands::Scan {
             path,
@@ -200,10 +484,10 @@ fn main() -> Result<()> {
             prune,
         } => {
             if prune {
-                cmd_prune(&conn, verbose)
+                cmd_prune(&library.writer(), verbose)
             } else if let Some(path) = path {
                 cmd_scan(
-                    &conn,
+                    &library.writer(),
                     &path,
                     threads,
                     verbose,
@@ -215,41 +499,97 @@ fn main() -> Result<()> {
                 std::process::exit(1);
             }
         }
-        Commands::Verify { issues } => cmd_verify(&conn, issues),
+        Commands::Verify {
+            issues,
+            rematch,
+            sets,
+            containers,
+            format,
+            dat,
+            category,
+            path,
+        } => {
+            let format = format.map(|f| f.parse::<report::Format>()).transpose()?;
+            let conn = library.writer();
+            let Some(scope) =
+                verify_scope(&conn, dat.as_deref(), category.as_deref(), path.as_deref())?
+            else {
+                return Ok(());
+            };
+            cmd_verify(&conn, issues, rematch, sets, containers, format, &scope)
+        }
         Commands::Organise {
             target,
             dry_run,
             copy,
             loose,
             zip_per_dat,
+            format,
+            template,
+            preview,
+            merge,
+            backup,
             rename_only,
         } => {
             if rename_only {
-                cmd_rename_in_place(&conn, dry_run)
+                cmd_rename_in_place(&library.writer(), dry_run)
             } else {
                 cmd_organise(
-                    &conn,
+                    &library.writer(),
                     target.as_ref().unwrap(),
                     dry_run,
                     copy,
-                    loose,
-                    zip_per_dat,
+                    OrganiseLayout {
+                        loose,
+                        zip_per_dat,
+                        format,
+                        template,
+                    },
+                    OrganiseMerge { merge, backup },
+                    preview,
                 )
             }
         }
-        Commands::Stats => cmd_stats(&conn),
-        Commands::Health => cmd_health(&conn),
-        Commands::Duplicates { details } => cmd_duplicates(&conn, details),
+        Commands::Tzip {
+            paths,
+            threads,
+            dry_run,
+        } => cmd_tzip(&library.writer(), &paths, threads, dry_run),
+        Commands::Stats => cmd_stats(&*library.reader()?),
+        Commands::Health => cmd_health(&*library.reader()?),
+        Commands::Duplicates { details } => cmd_duplicates(&*library.reader()?, details),
+        Commands::History {
+            dat,
+            since,
+            limit,
+            json,
+        } => cmd_history(
+            &*library.reader()?,
+            dat.as_deref(),
+            since.as_deref(),
+            limit,
+            json,
+        ),
+        Commands::Search { query, limit } => {
+            cmd_search(&*library.reader()?, &query.join(" "), limit)
+        }
+        Commands::Db { command } => match command {
+            DbCommands::Check { repair } => cmd_db_check(&mut library.writer(), repair),
+        },
+        Commands::Roots { command } => match command {
+            RootsCommands::List => cmd_roots_list(&*library.reader()?),
+            RootsCommands::Relocate { old, new } => {
+                cmd_roots_relocate(&library.writer(), &old, &new)
+            }
+        },
+        Commands::Volumes { command } => match command {
+            VolumesCommands::Status => cmd_volumes_status(&library.writer()),
+            VolumesCommands::Mark { path } => cmd_volumes_mark(&library.writer(), &path),
+        },
+        Commands::Profile { .. } => unreachable!("handled before opening a library"),
     }
 }
 
-fn get_db_path() -> Result<PathBuf> {
-    let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Cannot find home directory"))?;
-    let config_dir = home.join(".romshelf");
-    std::fs::create_dir_all(&config_dir)?;
-    Ok(config_dir.join("romshelf.db"))
-}
-
 /// Import result for tracking duplicates
 enum ImportResult {
     Imported {
@@ -285,6 +625,7 @@ fn cmd_dat_import(
             duration,
             entries_per_sec,
         } => {
+            record_snapshot(conn, SnapshotEvent::Import)?;
             println!("Imported: {}", name);
             if let Some(v) = version {
                 println!("  Version: {}", v);
@@ -433,6 +774,10 @@ fn cmd_dat_import_dir(
     if !progress_sink.is_json() {
         eprintln!(); // New line after progress
     }
+    // One snapshot for the whole batch, not one per DAT
+    if imported > 0 {
+        record_snapshot(conn, SnapshotEvent::Import)?;
+    }
     println!("\nImport complete:");
     println!("  Imported:   {:>6}", imported);
     println!("  Duplicates: {:>6}", duplicates);
@@ -443,6 +788,14 @@ fn cmd_dat_import_dir(
     Ok(())
 }
 
+/// Record a history snapshot in a transaction of its own
+fn record_snapshot(conn: &rusqlite::Connection, event: SnapshotEvent) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    history::record_snapshot(&tx, event)?;
+    tx.commit()?;
+    Ok(())
+}
+
 fn import_single_dat(
     conn: &mut rusqlite::Connection,
     path: &Path,
@@ -483,7 +836,16 @@ fn import_single_dat(
     Ok(mapped)
 }
 
-type DatListRow = (i64, String, Option<String>, Option<String>, i64, String);
+type DatListRow = (
+    i64,
+    String,
+    Option<String>,
+    Option<String>,
+    i64,
+    String,
+    bool,
+    i64,
+);
 
 fn cmd_dat_list(
     conn: &rusqlite::Connection,
@@ -492,7 +854,8 @@ fn cmd_dat_list(
 ) -> Result<()> {
     // Build query with optional filters
     let mut sql = String::from(
-        "SELECT d.id, d.name, d.category, dv.version, dv.entry_count, dv.loaded_at
+        "SELECT d.id, d.name, d.category, dv.version, dv.entry_count, dv.loaded_at,
+                d.enabled, d.priority
          FROM dats d
          JOIN dat_versions dv ON d.id = dv.dat_id
          WHERE 1=1",
@@ -515,64 +878,40 @@ fn cmd_dat_list(
 
     let mut stmt = conn.prepare(&sql)?;
 
+    let map_row = |row: &rusqlite::Row| -> rusqlite::Result<DatListRow> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+        ))
+    };
     let rows: Vec<DatListRow> = match (category_filter, search_filter) {
         (Some(cat), Some(search)) => stmt
-            .query_map([cat, search], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                ))
-            })?
+            .query_map([cat, search], map_row)?
             .filter_map(|r| r.ok())
             .collect(),
         (Some(cat), None) => stmt
-            .query_map([cat], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                ))
-            })?
+            .query_map([cat], map_row)?
             .filter_map(|r| r.ok())
             .collect(),
         (None, Some(search)) => stmt
-            .query_map([search], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                ))
-            })?
+            .query_map([search], map_row)?
             .filter_map(|r| r.ok())
             .collect(),
         (None, None) => stmt
-            .query_map([], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                ))
-            })?
+            .query_map([], map_row)?
             .filter_map(|r| r.ok())
             .collect(),
     };
 
     let count = rows.len();
 
-    for (id, name, category, version, entry_count, loaded_at) in rows {
+    for (id, name, category, version, entry_count, loaded_at, enabled, priority) in rows {
         println!("[{}] {}", id, name);
         if let Some(cat) = category {
             println!("    Category: {}", cat);
@@ -582,6 +921,12 @@ fn cmd_dat_list(
         }
         println!("    Entries: {}", entry_count);
         println!("    Loaded: {}", loaded_at);
+        if !enabled {
+            println!("    Status: disabled");
+        }
+        if priority != 0 {
+            println!("    Priority: {}", priority);
+        }
         println!();
     }
 
@@ -598,42 +943,47 @@ fn cmd_dat_list(
     Ok(())
 }
 
-/// Show detailed information about a DAT
-fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
+/// Find a DAT by ID or partial name, explaining on stdout when there is no single match
+fn resolve_dat(conn: &rusqlite::Connection, dat_ref: &str) -> Result<Option<i64>> {
     // Try to find by ID first, then by name
     let dat_id: Option<i64> = dat_ref.parse().ok().and_then(|id: i64| {
         conn.query_row("SELECT id FROM dats WHERE id = ?1", [id], |row| row.get(0))
             .ok()
     });
+    if dat_id.is_some() {
+        return Ok(dat_id);
+    }
 
-    let dat_id = match dat_id {
-        Some(id) => id,
-        None => {
-            // Search by name (case-insensitive substring match)
-            let matches: Vec<(i64, String)> = conn
-                .prepare("SELECT id, name FROM dats WHERE name LIKE '%' || ?1 || '%'")?
-                .query_map([dat_ref], |row| Ok((row.get(0)?, row.get(1)?)))?
-                .filter_map(|r| r.ok())
-                .collect();
+    // Search by name (case-insensitive substring match)
+    let matches: Vec<(i64, String)> = conn
+        .prepare("SELECT id, name FROM dats WHERE name LIKE '%' || ?1 || '%'")?
+        .query_map([dat_ref], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
 
-            match matches.len() {
-                0 => {
-                    println!("No DAT found matching '{}'", dat_ref);
-                    return Ok(());
-                }
-                1 => matches[0].0,
-                _ => {
-                    println!(
-                        "Multiple DATs match '{}'. Please be more specific:",
-                        dat_ref
-                    );
-                    for (id, name) in &matches {
-                        println!("  [{}] {}", id, name);
-                    }
-                    return Ok(());
-                }
+    match matches.len() {
+        0 => {
+            println!("No DAT found matching '{}'", dat_ref);
+            Ok(None)
+        }
+        1 => Ok(Some(matches[0].0)),
+        _ => {
+            println!(
+                "Multiple DATs match '{}'. Please be more specific:",
+                dat_ref
+            );
+            for (id, name) in &matches {
+                println!("  [{}] {}", id, name);
             }
+            Ok(None)
         }
+    }
+}
+
+/// Show detailed information about a DAT
+fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
     };
 
     // Get DAT details
@@ -658,6 +1008,11 @@ fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
             ))
         },
     )?;
+    let (enabled, priority): (bool, i64) = conn.query_row(
+        "SELECT enabled, priority FROM dats WHERE id = ?1",
+        [dat_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
 
     // Get version details
     let (version_id, version, loaded_at, entry_count): (i64, Option<String>, String, i64) = conn
@@ -676,8 +1031,8 @@ fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
 
     // Get match count (how many entries have matching files)
     let matched_count: i64 = conn.query_row(
-        "SELECT COUNT(DISTINCT de.id) FROM dat_entries de
-         JOIN files f ON (f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size))
+        "SELECT COUNT(DISTINCT m.dat_entry_id) FROM matches m
+         JOIN dat_entries de ON de.id = m.dat_entry_id
          WHERE de.dat_version_id = ?1",
         [version_id],
         |row| row.get(0),
@@ -694,6 +1049,18 @@ fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
         println!("  Category:   {}", cat);
     }
     println!("  Format:     {}", format);
+    if !enabled {
+        println!("  Status:     disabled");
+    }
+    if priority != 0 {
+        println!("  Priority:   {}", priority);
+    }
+    if let Some(mode) = merge::merge_mode(conn, dat_id)? {
+        println!("  Merge mode: {}", mode);
+    }
+    if let Some(output) = rebuild::output_format(conn, dat_id)? {
+        println!("  Output:     {}", output);
+    }
     println!("  File:       {}", file_path);
     if let Some(size) = file_size {
         println!("  File size:  {}", format_bytes(size));
@@ -727,77 +1094,183 @@ fn cmd_dat_info(conn: &rusqlite::Connection, dat_ref: &str) -> Result<()> {
     Ok(())
 }
 
-/// Remove a DAT and all its entries
-fn cmd_dat_remove(
+/// Show or change a DAT's merge mode
+fn cmd_dat_merge_mode(
     conn: &rusqlite::Connection,
     dat_ref: &str,
-    skip_confirm: bool,
-    dry_run: bool,
+    mode: Option<&str>,
 ) -> Result<()> {
-    // Try to find by ID first, then by name
-    let dat_id: Option<i64> = dat_ref.parse().ok().and_then(|id: i64| {
-        conn.query_row("SELECT id FROM dats WHERE id = ?1", [id], |row| row.get(0))
-            .ok()
-    });
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
+    };
+    let name: String = conn.query_row("SELECT name FROM dats WHERE id = ?1", [dat_id], |row| {
+        row.get(0)
+    })?;
+
+    if let Some(mode) = mode {
+        let mode = match mode {
+            "none" => None,
+            other => Some(other.parse::<MergeMode>()?),
+        };
+        merge::set_merge_mode(conn, dat_id, mode)?;
+    }
 
-    let dat_id = match dat_id {
-        Some(id) => id,
-        None => {
-            // Search by name (case-insensitive substring match)
-            let matches: Vec<(i64, String)> = conn
-                .prepare("SELECT id, name FROM dats WHERE name LIKE '%' || ?1 || '%'")?
-                .query_map([dat_ref], |row| Ok((row.get(0)?, row.get(1)?)))?
-                .filter_map(|r| r.ok())
-                .collect();
+    match merge::merge_mode(conn, dat_id)? {
+        Some(mode) => println!("{}: {}", name, mode),
+        None => println!("{}: none (sets audited on their own)", name),
+    }
+    Ok(())
+}
 
-            match matches.len() {
-                0 => {
-                    println!("No DAT found matching '{}'", dat_ref);
-                    return Ok(());
-                }
-                1 => matches[0].0,
-                _ => {
-                    println!(
-                        "Multiple DATs match '{}'. Please be more specific:",
-                        dat_ref
-                    );
-                    for (id, name) in &matches {
-                        println!("  [{}] {}", id, name);
-                    }
-                    return Ok(());
-                }
-            }
-        }
+/// Enable or disable a DAT
+fn cmd_dat_enable(conn: &rusqlite::Connection, dat_ref: &str, enabled: bool) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
     };
+    let name: String = conn.query_row("SELECT name FROM dats WHERE id = ?1", [dat_id], |row| {
+        row.get(0)
+    })?;
+
+    verifier::set_dat_enabled(conn, dat_id, enabled)?;
+    if enabled {
+        let matched: i64 = conn.query_row(
+            "SELECT COUNT(DISTINCT m.file_id) FROM matches m
+             JOIN dat_entries e ON e.id = m.dat_entry_id
+             JOIN dat_versions v ON v.id = e.dat_version_id
+             WHERE v.dat_id = ?1",
+            [dat_id],
+            |row| row.get(0),
+        )?;
+        println!("Enabled {} ({} files matched)", name, matched);
+    } else {
+        println!("Disabled {}", name);
+    }
+    Ok(())
+}
 
-    // Get DAT details for confirmation
-    let (name, entry_count): (String, i64) = conn.query_row(
-        "SELECT d.name, dv.entry_count FROM dats d
-         JOIN dat_versions dv ON d.id = dv.dat_id
-         WHERE d.id = ?1",
+/// Show or set a DAT's priority
+fn cmd_dat_priority(
+    conn: &rusqlite::Connection,
+    dat_ref: &str,
+    priority: Option<i64>,
+) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
+    };
+    if let Some(priority) = priority {
+        verifier::set_dat_priority(conn, dat_id, priority)?;
+    }
+    let (name, priority): (String, i64) = conn.query_row(
+        "SELECT name, priority FROM dats WHERE id = ?1",
         [dat_id],
         |row| Ok((row.get(0)?, row.get(1)?)),
     )?;
+    println!("{}: priority {}", name, priority);
+    Ok(())
+}
 
-    // Get version ID for counts/deletion
-    let version_id: i64 = conn.query_row(
-        "SELECT id FROM dat_versions WHERE dat_id = ?1",
-        [dat_id],
-        |row| row.get(0),
-    )?;
+/// Show or set the archive format of a DAT's rebuilt sets
+fn cmd_dat_output_format(
+    conn: &rusqlite::Connection,
+    dat_ref: &str,
+    format: Option<&str>,
+) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
+    };
+    let name: String = conn.query_row("SELECT name FROM dats WHERE id = ?1", [dat_id], |row| {
+        row.get(0)
+    })?;
+
+    if let Some(format) = format {
+        let format = match format {
+            "default" => None,
+            other => Some(other.parse::<ArchiveFormat>()?),
+        };
+        rebuild::set_output_format(conn, dat_id, format)?;
+    }
 
-    // Get counts for display
-    let set_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM sets WHERE dat_version_id = ?1",
-        [version_id],
-        |row| row.get(0),
-    )?;
+    match rebuild::output_format(conn, dat_id)? {
+        Some(format) => println!("{}: {}", name, format),
+        None => println!("{}: default (organise --format)", name),
+    }
+    Ok(())
+}
 
-    let match_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM matches WHERE dat_entry_id IN (SELECT id FROM dat_entries WHERE dat_version_id = ?1)",
-        [version_id],
-        |row| row.get(0),
-    )?;
+/// Options of `dat name-policy`; unset fields keep their current value
+struct NamePolicyChanges {
+    case: Option<String>,
+    unicode: Option<String>,
+    extension: Option<String>,
+    archive_path: Option<String>,
+    reset: bool,
+}
+
+/// Show or change a DAT's name policy
+fn cmd_dat_name_policy(
+    conn: &rusqlite::Connection,
+    dat_ref: &str,
+    changes: NamePolicyChanges,
+) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
+    };
+    let name: String = conn.query_row("SELECT name FROM dats WHERE id = ?1", [dat_id], |row| {
+        row.get(0)
+    })?;
+
+    let mut policy = if changes.reset {
+        NamePolicy::default()
+    } else {
+        verifier::name_policy(conn, dat_id)?
+    };
+    if let Some(case) = &changes.case {
+        policy.case = parse_policy_option("case", case)?;
+    }
+    if let Some(unicode) = &changes.unicode {
+        policy.unicode = parse_policy_option("unicode", unicode)?;
+    }
+    if let Some(extension) = &changes.extension {
+        policy.extension = parse_policy_option("extension", extension)?;
+    }
+    if let Some(archive_path) = &changes.archive_path {
+        policy.archive_path = parse_policy_option("archive-path", archive_path)?;
+    }
+    if policy != verifier::name_policy(conn, dat_id)? {
+        verifier::set_name_policy(conn, dat_id, &policy)?;
+    }
+
+    println!("{}:", name);
+    if let serde_json::Value::Object(fields) = serde_json::to_value(policy)? {
+        for (field, value) in fields {
+            println!("  {:<14} {}", field, value.as_str().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// Parse a name policy option value (`sensitive`, `full-path`, ...)
+fn parse_policy_option<T: serde::de::DeserializeOwned>(option: &str, value: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(value.to_string()))
+        .map_err(|_| anyhow!("Invalid --{} value: {}", option, value))
+}
+
+/// Remove a DAT and all its entries
+fn cmd_dat_remove(
+    conn: &rusqlite::Connection,
+    dat_ref: &str,
+    skip_confirm: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+        return Ok(());
+    };
+
+    // Get DAT details for confirmation
+    let name: String = conn.query_row("SELECT name FROM dats WHERE id = ?1", [dat_id], |row| {
+        row.get(0)
+    })?;
+    let counts = db::dat_removal_counts(conn, dat_id)?;
 
     // Dry run - just show what would be removed
     if dry_run {
@@ -805,10 +1278,10 @@ fn cmd_dat_remove(
         println!("  [{}] {}", dat_id, name);
         println!();
         println!("Would delete:");
-        println!("  Entries:    {:>6}", entry_count);
-        println!("  Sets:       {:>6}", set_count);
-        if match_count > 0 {
-            println!("  Matches:    {:>6}", match_count);
+        println!("  Entries:    {:>6}", counts.entries);
+        println!("  Sets:       {:>6}", counts.sets);
+        if counts.matches > 0 {
+            println!("  Matches:    {:>6}", counts.matches);
         }
         return Ok(());
     }
@@ -816,7 +1289,7 @@ fn cmd_dat_remove(
     // Confirm deletion unless -y flag was passed
     if !skip_confirm {
         println!("About to remove:");
-        println!("  [{}] {} ({} entries)", dat_id, name, entry_count);
+        println!("  [{}] {} ({} entries)", dat_id, name, counts.entries);
         println!();
         eprint!("Are you sure? [y/N] ");
 
@@ -832,29 +1305,14 @@ fn cmd_dat_remove(
         }
     }
 
-    // Delete in order: matches -> dat_entries -> sets -> dat_versions -> dats
-    // Note: matches reference dat_entries, so delete them first
-    let matches_deleted: usize = conn.execute(
-        "DELETE FROM matches WHERE dat_entry_id IN (SELECT id FROM dat_entries WHERE dat_version_id = ?1)",
-        [version_id],
-    )?;
-
-    let entries_deleted: usize = conn.execute(
-        "DELETE FROM dat_entries WHERE dat_version_id = ?1",
-        [version_id],
-    )?;
-
-    let sets_deleted: usize =
-        conn.execute("DELETE FROM sets WHERE dat_version_id = ?1", [version_id])?;
-
-    conn.execute("DELETE FROM dat_versions WHERE id = ?1", [version_id])?;
-    conn.execute("DELETE FROM dats WHERE id = ?1", [dat_id])?;
+    // Versions, sets, entries and matches cascade from the DAT row
+    let removed = db::remove_dat(conn, dat_id)?;
 
     println!("Removed: {}", name);
-    println!("  Entries deleted: {}", entries_deleted);
-    println!("  Sets deleted:    {}", sets_deleted);
-    if matches_deleted > 0 {
-        println!("  Matches deleted: {}", matches_deleted);
+    println!("  Entries deleted: {}", removed.entries);
+    println!("  Sets deleted:    {}", removed.sets);
+    if removed.matches > 0 {
+        println!("  Matches deleted: {}", removed.matches);
     }
 
     Ok(())
@@ -868,6 +1326,7 @@ fn cmd_scan(
     json_progress: bool,
     progress_sink: CliProgressSink,
 ) -> Result<()> {
+    db::volumes::refresh(conn)?;
     let thread_count = threads.unwrap_or_else(num_cpus::get).max(1);
     let cancel_flag = Arc::new(AtomicBool::new(false));
     if !json_progress {
@@ -882,10 +1341,24 @@ fn cmd_scan(
         });
     }
 
+    if !path.is_dir() {
+        return Err(anyhow!("Not a directory: {}", path.display()));
+    }
+
+    // Paths are stored relative to the scan root that covers this directory
+    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let root = db::roots::register_root(conn, &canonical_path)?;
+    db::volumes::attach_root(conn, &root)?;
+    let root_path = PathBuf::from(&root.path);
+
     // Load existing files from database for incremental scan
     let existing_files: Arc<std::collections::HashMap<String, (i64, Option<i64>)>> = Arc::new({
-        let mut stmt = conn.prepare("SELECT path, size, mtime FROM files")?;
-        stmt.query_map([], |row| {
+        let mut stmt = conn.prepare(
+            "SELECT p.path, f.size, f.mtime FROM files f
+             JOIN file_paths p ON p.file_id = f.id
+             WHERE f.root_id = ?1",
+        )?;
+        stmt.query_map([root.id], |row| {
             Ok((
                 row.get::<_, String>(0)?,
                 (row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?),
@@ -914,15 +1387,14 @@ fn cmd_scan(
         eprintln!("  Discovering directories and files...");
     }
 
-    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
     let checkpoint_source = canonical_path.to_string_lossy().to_string();
-    if let Some(cp) = db::get_checkpoint(conn, "scan", &checkpoint_source)? {
-        if !json_progress {
-            eprintln!(
-                "Previous scan interrupted (last file: {}). Resuming...",
-                cp.last_token
-            );
-        }
+    if let Some(cp) = db::get_checkpoint(conn, "scan", &checkpoint_source)?
+        && !json_progress
+    {
+        eprintln!(
+            "Previous scan interrupted (last file: {}). Resuming...",
+            cp.last_token
+        );
     }
     db::upsert_checkpoint(conn, "scan", &checkpoint_source, "starting")?;
 
@@ -1098,21 +1570,20 @@ fn cmd_scan(
     // Stream results to the database instead of buffering everything in memory
     let (output_tx, output_rx) = unbounded::<scan::ScanOutput>();
     let skip_map = Arc::clone(&existing_files);
-    let skip_root = path.to_path_buf();
-    let skip_predicate: Arc<dyn Fn(&Path, u64, Option<i64>) -> bool + Send + Sync> =
-        Arc::new(move |file_path, size, mtime| {
-            if !file_path.starts_with(&skip_root) {
-                return false;
-            }
-            let key = file_path.to_string_lossy().to_string();
-            if let Some(&(existing_size, existing_mtime)) = skip_map.get(&key) {
-                existing_size == size as i64 && existing_mtime == mtime
-            } else {
-                false
-            }
-        });
+    let skip_root = canonical_path.clone();
+    let skip_predicate: Arc<scan::SkipPredicate> = Arc::new(move |file_path, size, mtime| {
+        if !file_path.starts_with(&skip_root) {
+            return false;
+        }
+        let key = file_path.to_string_lossy().to_string();
+        if let Some(&(existing_size, existing_mtime)) = skip_map.get(&key) {
+            existing_size == size as i64 && existing_mtime == mtime
+        } else {
+            false
+        }
+    });
 
-    let scan_path = path.to_path_buf();
+    let scan_path = canonical_path.clone();
     let progress_for_scan = Arc::clone(&progress);
     let cancel_for_scan = cancel_flag.clone();
     let scan_options = scan::ScanOptions {
@@ -1136,13 +1607,14 @@ fn cmd_scan(
     // Store scanned files in database
     let now = chrono::Utc::now().to_rfc3339();
     let mut stmt = conn.prepare(
-        "INSERT OR REPLACE INTO files (path, filename, size, mtime, crc32, md5, sha1, scanned_at, directory_id)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        "INSERT OR REPLACE INTO files (root_id, path, filename, size, mtime, crc32, md5, sha1, scanned_at, directory_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
     )?;
 
     // Cache for directory IDs to avoid repeated lookups
     let mut dir_cache: std::collections::HashMap<String, i64> = std::collections::HashMap::new();
 
+    let mut changed_files: Vec<i64> = Vec::new();
     let mut new_files = 0;
     let mut updated_files = 0;
     let mut unchanged_files = 0;
@@ -1166,31 +1638,35 @@ fn cmd_scan(
                     new_files += 1;
                 }
 
+                let relative = db::roots::relative_path(&root_path, &file.path)
+                    .ok_or_else(|| anyhow!("{} is outside scan root {}", path_str, root.path))?;
                 let dir_path = file
                     .path
                     .parent()
-                    .map(|p| p.to_string_lossy().to_string())
+                    .and_then(|p| db::roots::relative_path(&root_path, p))
                     .unwrap_or_default();
 
                 let dir_id = if let Some(&id) = dir_cache.get(&dir_path) {
                     id
                 } else {
-                    let id = db::get_or_create_directory(conn, &dir_path)?;
+                    let id = db::get_or_create_directory(conn, root.id, &dir_path)?;
                     dir_cache.insert(dir_path.clone(), id);
                     id
                 };
 
                 stmt.execute(rusqlite::params![
-                    path_str,
+                    root.id,
+                    relative,
                     file.filename,
                     file.size as i64,
                     file.mtime,
-                    file.crc32,
-                    file.md5,
-                    file.sha1,
+                    hash::crc32_to_db(&file.crc32),
+                    hash::md5_to_db(&file.md5),
+                    hash::sha1_to_db(&file.sha1),
                     now,
                     dir_id
                 ])?;
+                changed_files.push(conn.last_insert_rowid());
             }
             scan::ScanOutput::Skipped { path } => {
                 let path_str = path.to_string_lossy().to_string();
@@ -1218,13 +1694,14 @@ fn cmd_scan(
 
     // Handle missing files (files in DB that were under this scan path but weren't found)
     // Only remove files that are within the scanned directory - don't touch files from other paths
-    let scan_path_str = path.to_string_lossy().to_string();
     let mut missing_files = 0;
     for existing_path in existing_files.keys() {
         // Only consider files that are under the scanned directory
-        if existing_path.starts_with(&scan_path_str) && !seen_paths.contains(existing_path) {
+        if Path::new(existing_path).starts_with(&canonical_path)
+            && !seen_paths.contains(existing_path)
+        {
             // File was in the scanned directory but no longer exists - remove from database
-            conn.execute("DELETE FROM files WHERE path = ?1", [existing_path])?;
+            db::remove_file_by_path(conn, existing_path)?;
             missing_files += 1;
         }
     }
@@ -1306,12 +1783,22 @@ fn cmd_scan(
         );
     }
 
+    // New and changed files (replaced rows lost their matches) need matching
+    if !changed_files.is_empty() {
+        eprint!("  Matching against DATs...");
+        let tx = conn.unchecked_transaction()?;
+        let recorded = verifier::match_files(&tx, &changed_files)?;
+        tx.commit()?;
+        eprintln!(" done ({} matches)", recorded);
+    }
+
     // Recompute directory statistics (rollup from files to directories)
     if !dir_cache.is_empty() {
         eprint!("  Computing directory statistics...");
         db::recompute_directory_stats(conn)?;
         eprintln!(" done ({} directories)", dir_cache.len());
     }
+    record_snapshot(conn, SnapshotEvent::Scan)?;
 
     Ok(())
 }
@@ -1319,10 +1806,17 @@ fn cmd_scan(
 /// Remove database entries for files that no longer exist on disk
 fn cmd_prune(conn: &rusqlite::Connection, verbose: bool) -> Result<()> {
     eprintln!("Checking for stale database entries...");
+    db::volumes::refresh(conn)?;
 
-    // Load all file paths from database
+    // Load file paths on reachable volumes; files on unplugged drives are kept
+    let offline = db::volumes::offline_summary(conn)?.files;
     let paths: Vec<(i64, String)> = {
-        let mut stmt = conn.prepare("SELECT id, path FROM files")?;
+        let mut stmt = conn.prepare(
+            "SELECT p.file_id, p.path FROM file_paths p
+             JOIN files f ON f.id = p.file_id
+             JOIN scan_roots r ON r.id = f.root_id
+             WHERE r.online = 1",
+        )?;
         stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
             .filter_map(|r| r.ok())
             .collect()
@@ -1330,7 +1824,14 @@ fn cmd_prune(conn: &rusqlite::Connection, verbose: bool) -> Result<()> {
 
     let total = paths.len();
     if total == 0 {
-        println!("No files in database.");
+        if offline > 0 {
+            println!(
+                "All {} files are on offline volumes; nothing to check.",
+                offline
+            );
+        } else {
+            println!("No files in database.");
+        }
         return Ok(());
     }
 
@@ -1359,7 +1860,7 @@ fn cmd_prune(conn: &rusqlite::Connection, verbose: bool) -> Result<()> {
             if verbose {
                 eprintln!("\r  Pruning: {}", path_str);
             }
-            conn.execute("DELETE FROM files WHERE id = ?1", [id])?;
+            db::remove_file(conn, *id)?;
             pruned += 1;
         }
     }
@@ -1370,121 +1871,150 @@ fn cmd_prune(conn: &rusqlite::Connection, verbose: bool) -> Result<()> {
     println!("  Checked:    {:>6}", total);
     println!("  Kept:       {:>6}", kept);
     println!("  Pruned:     {:>6}", pruned);
+    if offline > 0 {
+        println!("  Offline:    {:>6} (volume not mounted, kept)", offline);
+    }
 
     Ok(())
 }
 
-fn cmd_verify(conn: &rusqlite::Connection, show_issues: bool) -> Result<()> {
-    // Load files from database
-    let mut file_stmt =
-        conn.prepare("SELECT path, filename, size, mtime, crc32, md5, sha1 FROM files")?;
-    let files: Vec<scan::ScannedFile> = file_stmt
-        .query_map([], |row| {
-            Ok(scan::ScannedFile {
-                path: PathBuf::from(row.get::<_, String>(0)?),
-                filename: row.get(1)?,
-                size: row.get::<_, i64>(2)? as u64,
-                mtime: row.get(3)?,
-                crc32: row.get(4)?,
-                md5: row.get(5)?,
-                sha1: row.get(6)?,
-            })
-        })?
-        .filter_map(|r| r.ok())
-        .collect();
+/// Build the scope of `verify` from its options; `None` (after a message)
+/// when the DAT or category doesn't exist
+fn verify_scope(
+    conn: &rusqlite::Connection,
+    dat: Option<&str>,
+    category: Option<&str>,
+    path: Option<&Path>,
+) -> Result<Option<Scope>> {
+    let mut scope = Scope::default();
+    if let Some(dat_ref) = dat {
+        let Some(dat_id) = resolve_dat(conn, dat_ref)? else {
+            return Ok(None);
+        };
+        scope = Scope::dat(dat_id);
+    }
+    if let Some(category) = category {
+        scope = Scope::category(conn, category)?;
+        if scope.dat_ids.as_ref().is_some_and(|ids| ids.is_empty()) {
+            println!("No DATs in category '{}'", category);
+            return Ok(None);
+        }
+    }
+    if let Some(path) = path {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        scope = scope.with_path(path.to_string_lossy());
+    }
+    Ok(Some(scope))
+}
 
-    // Load DAT entries from database, grouped by DAT
-    let mut entry_stmt = conn.prepare(
-        "SELECT de.name, de.size, de.crc32, de.md5, de.sha1, d.name as dat_name
-         FROM dat_entries de
-         JOIN dat_versions dv ON de.dat_version_id = dv.id
-         JOIN dats d ON dv.dat_id = d.id",
-    )?;
-    let all_entries: Vec<(dat::DatEntry, String)> = entry_stmt
-        .query_map([], |row| {
-            Ok((
-                dat::DatEntry {
-                    name: row.get(0)?,
-                    size: row.get::<_, i64>(1)? as u64,
-                    crc32: row.get(2)?,
-                    md5: row.get(3)?,
-                    sha1: row.get(4)?,
-                },
-                row.get::<_, String>(5)?,
-            ))
-        })?
-        .filter_map(|r| r.ok())
-        .collect();
+fn cmd_verify(
+    conn: &rusqlite::Connection,
+    show_issues: bool,
+    rematch: bool,
+    sets: bool,
+    containers: bool,
+    format: Option<report::Format>,
+    scope: &Scope,
+) -> Result<()> {
+    db::volumes::refresh(conn)?;
+    if rematch {
+        if scope.is_everything() {
+            eprint!("Re-matching files against all DATs...");
+        } else {
+            eprint!("Re-matching files in scope...");
+        }
+        let tx = conn.unchecked_transaction()?;
+        let recorded = verifier::rematch(&tx, scope)?;
+        tx.commit()?;
+        eprintln!(" done ({} matches)", recorded);
+    }
+
+    // Reports are written even when empty, so scripts always get parseable output
+    if let Some(format) = format {
+        let report = report::build(conn, scope)?;
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        report::write(&report, format, &mut out)?;
+        out.flush()?;
+        return Ok(());
+    }
 
-    if all_entries.is_empty() {
+    let dat_count: i64 = conn.query_row("SELECT COUNT(*) FROM dats", [], |row| row.get(0))?;
+    if dat_count == 0 {
         println!("No DATs loaded. Use `romshelf dat import <path>` first.");
         return Ok(());
     }
 
-    if files.is_empty() {
+    let file_count: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+    if file_count == 0 {
         println!("No files scanned. Use `romshelf scan <path>` first.");
         return Ok(());
     }
+    record_snapshot(conn, SnapshotEvent::Verify)?;
 
-    // Group entries by DAT name
-    let mut entries_by_dat: std::collections::HashMap<String, Vec<dat::DatEntry>> =
-        std::collections::HashMap::new();
-    for (entry, dat_name) in all_entries {
-        entries_by_dat.entry(dat_name).or_default().push(entry);
+    if sets {
+        return print_set_audit(conn, show_issues, scope);
+    }
+    if containers {
+        return print_container_audit(conn, scope);
     }
 
-    // Track all misnamed and unmatched for detailed output
-    let mut all_misnamed = Vec::new();
-    let mut all_unmatched = files.clone();
-
-    // Verify per DAT
-    for (dat_name, entries) in &entries_by_dat {
-        let result = verify::verify(&files, entries);
-
-        let total = entries.len();
-        let verified_count = result.verified.len();
-        let misnamed_count = result.misnamed.len();
-        let missing_count = result.missing.len();
-
-        // Remove matched files from unmatched list
-        for m in &result.verified {
-            all_unmatched.retain(|f| f.path != m.file.path);
-        }
-        for m in &result.misnamed {
-            all_unmatched.retain(|f| f.path != m.file.path);
-            all_misnamed.push(m.clone());
-        }
-
-        let verified_pct = if total > 0 {
-            (verified_count as f32 / total as f32) * 100.0
+    for dat in verifier::summarise_in(conn, scope)? {
+        let verified_pct = if dat.total > 0 {
+            (dat.verified as f32 / dat.total as f32) * 100.0
         } else {
             0.0
         };
 
-        println!("{}", dat_name);
-        println!("  Verified:   {:>6} ({:.1}%)", verified_count, verified_pct);
-        println!("  Misnamed:   {:>6}", misnamed_count);
-        println!("  Missing:    {:>6}", missing_count);
+        println!("{}", dat.name);
+        println!("  Verified:   {:>6} ({:.1}%)", dat.verified, verified_pct);
+        println!("  Misnamed:   {:>6}", dat.misnamed);
+        println!("  Missing:    {:>6}", dat.missing);
+        if dat.offline > 0 {
+            println!(
+                "  Offline:    {:>6} (have, volume not mounted)",
+                dat.offline
+            );
+        }
         println!();
     }
 
     // Summary of unmatched files (not in any DAT)
-    if !all_unmatched.is_empty() {
-        println!("Unmatched files (not in any DAT): {}", all_unmatched.len());
+    let unmatched = if scope.includes_unmatched() {
+        verifier::unmatched_files_in(conn, scope)?
+    } else {
+        Vec::new()
+    };
+    if !unmatched.is_empty() {
+        println!("Unmatched files (not in any DAT): {}", unmatched.len());
     }
 
     if show_issues {
-        if !all_misnamed.is_empty() {
+        let misnamed = verifier::misnamed_files_in(conn, scope)?;
+        if !misnamed.is_empty() {
             println!("\nMISNAMED:");
-            for m in &all_misnamed {
-                println!("  {} -> {}", m.file.filename, m.entry.name);
+            for file in &misnamed {
+                println!("  {} -> {}", file.filename, file.expected);
             }
         }
 
-        if !all_unmatched.is_empty() {
+        if !unmatched.is_empty() {
             println!("\nUNMATCHED:");
-            for f in &all_unmatched {
-                println!("  {} (no DAT match)", f.filename);
+            let near_misses: std::collections::HashMap<i64, NearMiss> =
+                diagnostics::diagnose_unmatched(conn)?
+                    .into_iter()
+                    .map(|near_miss| (near_miss.file_id, near_miss))
+                    .collect();
+            for file in &unmatched {
+                match near_misses.get(&file.file_id) {
+                    Some(near_miss) => println!(
+                        "  {} (likely {} in {}: {})",
+                        file.filename,
+                        near_miss.entry_name,
+                        near_miss.dat_name,
+                        near_miss.describe()
+                    ),
+                    None => println!("  {} (no DAT match)", file.filename),
+                }
             }
         }
     }
@@ -1492,436 +2022,374 @@ fn cmd_verify(conn: &rusqlite::Connection, show_issues: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_organise(
-    conn: &rusqlite::Connection,
-    target: &Path,
-    dry_run: bool,
-    copy: bool,
-    loose: bool,
-    zip_per_dat: bool,
-) -> Result<()> {
-    // Load all matched files with their DAT and set info
-    // Include category for directory structure
-    let mut stmt = conn.prepare(
-        "SELECT f.path, f.filename, de.name as rom_name, d.name as dat_name, s.name as set_name, d.category
-         FROM files f
-         JOIN dat_entries de ON f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         JOIN dat_versions dv ON de.dat_version_id = dv.id
-         JOIN dats d ON dv.dat_id = d.id
-         LEFT JOIN sets s ON de.set_id = s.id",
-    )?;
-
-    let matches: Vec<MatchedFile> = stmt
-        .query_map([], |row| {
-            Ok((
-                PathBuf::from(row.get::<_, String>(0)?),
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, Option<String>>(4)?,
-                row.get::<_, Option<String>>(5)?,
-            ))
-        })?
-        .filter_map(|r| r.ok())
-        .collect();
-
-    if matches.is_empty() {
-        println!("No matched files to organise. Run `romshelf scan` and `romshelf verify` first.");
-        return Ok(());
-    }
-
-    let mode_desc = if loose {
-        "as loose files"
-    } else if zip_per_dat {
-        "into ZIP per DAT"
-    } else {
-        "into TorrentZIP per set"
-    };
-
-    println!(
-        "{}",
-        if dry_run {
-            format!("Dry run - showing what would be organised {}:", mode_desc)
-        } else {
-            format!("Organising files {}...", mode_desc)
-        }
-    );
-
-    if loose {
-        organise_loose(&matches, target, dry_run, copy)
-    } else if zip_per_dat {
-        organise_zip_per_dat(&matches, target, dry_run, copy)
-    } else {
-        organise_zip_per_set(&matches, target, dry_run, copy)
-    }
-}
-
-/// Organise files as loose files
-fn organise_loose(matches: &[MatchedFile], target: &Path, dry_run: bool, copy: bool) -> Result<()> {
-    let mut organised = 0;
-    let mut skipped = 0;
-    let mut errors = 0;
-    let mut seen_archives: std::collections::HashSet<PathBuf> = std::collections::HashSet::new();
-
-    for (source_path, _filename, rom_name, _dat_name, set_name, category) in matches {
-        // Handle archive paths (archive.zip#entry.rom)
-        let (actual_source, target_filename) =
-            if let Some(hash_pos) = source_path.to_string_lossy().find('#') {
-                // File is inside an archive - organise the archive itself
-                let archive_path_str = &source_path.to_string_lossy()[..hash_pos];
-                let archive_path = PathBuf::from(archive_path_str);
-
-                // Skip if we've already processed this archive
-                if seen_archives.contains(&archive_path) {
-                    continue;
-                }
-                seen_archives.insert(archive_path.clone());
-
-                // Use the archive filename, keeping the extension
-                let archive_filename = archive_path
-                    .file_name()
-                    .map(|s| s.to_string_lossy().to_string())
-                    .unwrap_or_else(|| "unknown.zip".to_string());
+/// Per-DAT set counts, incomplete sets and the ROMs they lack
+fn print_set_audit(conn: &rusqlite::Connection, show_missing: bool, scope: &Scope) -> Result<()> {
+    let sets = audit::audit_sets_in(conn, scope)?;
 
-                (archive_path, archive_filename)
-            } else {
-                // Loose file - use the ROM name from the DAT
-                (source_path.clone(), rom_name.clone())
-            };
-
-        // Create target path: target/category/[set_name/]filename
-        // Category is the directory path like "CPC/Games/[DSK]"
-        let base_dir = if let Some(cat) = category {
-            target.join(cat)
+    for dat in audit::summarise_sets(&sets) {
+        let complete_pct = if dat.total > 0 {
+            (dat.complete as f32 / dat.total as f32) * 100.0
         } else {
-            target.to_path_buf()
-        };
-        let target_dir = if let Some(set) = set_name {
-            base_dir.join(sanitise_path(set))
-        } else {
-            base_dir
+            0.0
         };
-        let target_path = target_dir.join(&target_filename);
-
-        // Check if source exists
-        if !actual_source.exists() {
-            if dry_run {
-                println!("  [MISSING] {}", actual_source.display());
-            }
-            skipped += 1;
-            continue;
-        }
 
-        // Check if target already exists
-        if target_path.exists() {
-            if dry_run {
-                println!("  [EXISTS] {}", target_path.display());
-            }
-            skipped += 1;
-            continue;
-        }
-
-        if dry_run {
-            println!(
-                "  {} {} -> {}",
-                if copy { "[COPY]" } else { "[MOVE]" },
-                actual_source.display(),
-                target_path.display()
-            );
-            organised += 1;
-        } else {
-            if let Err(e) = std::fs::create_dir_all(&target_dir) {
-                eprintln!("  Error creating {}: {}", target_dir.display(), e);
-                errors += 1;
-                continue;
-            }
-
-            let result = if copy {
-                std::fs::copy(&actual_source, &target_path).map(|_| ())
-            } else {
-                std::fs::rename(&actual_source, &target_path)
-            };
-
-            match result {
-                Ok(()) => organised += 1,
-                Err(e) => {
-                    eprintln!("  Error: {}", e);
-                    errors += 1;
+        println!("{}", dat.name);
+        println!("  Complete:   {:>6} ({:.1}%)", dat.complete, complete_pct);
+        println!("  Partial:    {:>6}", dat.partial);
+        println!("  Missing:    {:>6}", dat.missing);
+
+        let dat_sets = sets.iter().filter(|set| set.dat_id == dat.dat_id);
+        for set in dat_sets {
+            match set.status {
+                SetStatus::Complete => {}
+                SetStatus::Partial => {
+                    println!("    {} ({}/{} ROMs)", set.name, set.have, set.total);
+                    for rom in &set.missing_roms {
+                        println!("      missing: {}", rom);
+                    }
                 }
+                SetStatus::Missing if show_missing => {
+                    println!("    {} (missing)", set.name);
+                }
+                SetStatus::Missing => {}
             }
         }
+        println!();
     }
 
-    print_organise_summary(organised, skipped, errors, dry_run, copy);
     Ok(())
 }
 
-/// Organise files into ZIP archives, one per set
-fn organise_zip_per_set(
-    matches: &[MatchedFile],
-    target: &Path,
-    dry_run: bool,
-    copy: bool,
-) -> Result<()> {
-    // Group files by (category, set_name)
-    // category is the path like "CPC/Games/[DSK]"
-    let mut sets: std::collections::HashMap<(String, String), Vec<(PathBuf, String)>> =
-        std::collections::HashMap::new();
+/// Wrongly named set archives, ROMs in the wrong archive and unneeded extras
+fn print_container_audit(conn: &rusqlite::Connection, scope: &Scope) -> Result<()> {
+    let audit = audit::audit_containers_in(conn, scope)?;
 
-    for (source_path, _filename, rom_name, _dat_name, set_name, category) in matches {
-        let cat = category.clone().unwrap_or_default();
-        let set = set_name.clone().unwrap_or_else(|| "unknown".to_string());
-        let set_key = (cat, set);
-        sets.entry(set_key)
-            .or_default()
-            .push((source_path.clone(), rom_name.clone()));
+    println!("Misnamed archives: {}", audit.misnamed_archives.len());
+    for archive in &audit.misnamed_archives {
+        println!("  {} -> {}", archive.archive, archive.expected);
     }
 
-    let mut archives_created = 0;
-    let mut files_packed = 0;
-    let mut errors = 0;
-
-    for ((category, set_name), files) in &sets {
-        // Use category path for directory structure
-        let target_dir = target.join(category);
-        let archive_name = format!("{}.zip", sanitise_path(set_name));
-        let archive_path = target_dir.join(&archive_name);
-
-        if dry_run {
-            println!("  {} ({} files)", archive_path.display(), files.len());
-            archives_created += 1;
-            files_packed += files.len();
-        } else {
-            if archive_path.exists() {
-                continue;
-            }
-
-            if let Err(e) = std::fs::create_dir_all(&target_dir) {
-                eprintln!("  Error creating {}: {}", target_dir.display(), e);
-                errors += 1;
-                continue;
-            }
-
-            match create_archive_from_matches(&archive_path, files, copy) {
-                Ok(count) => {
-                    println!("  {} ({} files)", archive_path.display(), count);
-                    archives_created += 1;
-                    files_packed += count;
-                }
-                Err(e) => {
-                    eprintln!("  [ERROR] {}: {}", archive_path.display(), e);
-                    errors += 1;
-                }
-            }
-        }
+    println!(
+        "\nROMs in the wrong set archive: {}",
+        audit.misplaced_roms.len()
+    );
+    for rom in &audit.misplaced_roms {
+        println!(
+            "  {}#{} (archive is {}, belongs to {})",
+            rom.archive,
+            rom.entry,
+            rom.archive_set,
+            rom.belongs_to.join(", ")
+        );
     }
 
-    println!();
-    println!("{}:", if dry_run { "Would create" } else { "Created" });
-    println!("  Archives: {:>6}", archives_created);
-    println!("  Files:    {:>6}", files_packed);
-    if errors > 0 {
-        println!("  Errors:   {:>6}", errors);
+    println!(
+        "\nUnneeded files in set archives: {}",
+        audit.unneeded_files.len()
+    );
+    for file in &audit.unneeded_files {
+        println!("  {}#{}", file.archive, file.entry);
     }
 
-    Ok(())
+    Ok(())
+}
+
+/// Options of `organise` choosing the target's layout
+struct OrganiseLayout {
+    loose: bool,
+    zip_per_dat: bool,
+    format: String,
+    template: Option<String>,
+}
+
+/// Options of `organise` for archives already in the target
+struct OrganiseMerge {
+    merge: bool,
+    backup: Option<PathBuf>,
 }
 
-/// Organise files into ZIP archives, one per DAT
-fn organise_zip_per_dat(
-    matches: &[MatchedFile],
+fn cmd_organise(
+    conn: &rusqlite::Connection,
     target: &Path,
     dry_run: bool,
     copy: bool,
+    layout: OrganiseLayout,
+    existing: OrganiseMerge,
+    preview: bool,
 ) -> Result<()> {
-    // Group files by (category, dat_name)
-    // category is the path like "CPC/Games/[DSK]"
-    type DatFileEntry = (PathBuf, String, Option<String>);
-    let mut dats: std::collections::HashMap<(String, String), Vec<DatFileEntry>> =
-        std::collections::HashMap::new();
-
-    for (source_path, _filename, rom_name, dat_name, set_name, category) in matches {
-        let cat = category.clone().unwrap_or_default();
-        let dat_key = (cat, dat_name.clone());
-        dats.entry(dat_key).or_default().push((
-            source_path.clone(),
-            rom_name.clone(),
-            set_name.clone(),
-        ));
+    let format: ArchiveFormat = layout.format.parse()?;
+    let template = layout
+        .template
+        .as_deref()
+        .map(|t| t.parse::<PathTemplate>())
+        .transpose()?;
+    let sources = rebuild::load_sources(conn)?;
+    if sources.is_empty() {
+        println!("No matched files to organise. Run `romshelf scan` and `romshelf verify` first.");
+        return Ok(());
     }
 
-    let mut archives_created = 0;
-    let mut files_packed = 0;
-    let mut errors = 0;
+    let mode = if layout.loose {
+        RebuildMode::Loose
+    } else if layout.zip_per_dat {
+        RebuildMode::ZipPerDat
+    } else {
+        RebuildMode::ZipPerSet
+    };
+    let format_desc = match format {
+        ArchiveFormat::Zip => "TorrentZIP",
+        ArchiveFormat::SevenZip => "7z",
+        ArchiveFormat::ZipZstd => "zstd ZIP",
+    };
+    let mode_desc = match mode {
+        RebuildMode::Loose => "as loose files".to_string(),
+        RebuildMode::ZipPerDat => format!("into {} per DAT", format_desc),
+        RebuildMode::ZipPerSet => format!("into {} per set", format_desc),
+    };
+    let options = RebuildOptions {
+        target: target.to_path_buf(),
+        mode,
+        dry_run,
+        copy,
+        format,
+        template,
+        merge: existing.merge,
+        backup: existing.backup,
+    };
 
-    for ((category, dat_name), files) in &dats {
-        // Use category path for directory structure
-        let target_dir = target.join(category);
-        let archive_name = format!("{}.zip", sanitise_path(dat_name));
-        let archive_path = target_dir.join(&archive_name);
+    if preview {
+        return print_organise_preview(&Rebuilder::new(options, ()), &sources, target);
+    }
 
+    println!(
+        "{}",
         if dry_run {
-            println!("  {} ({} files)", archive_path.display(), files.len());
-            archives_created += 1;
-            files_packed += files.len();
+            format!("Dry run - showing what would be organised {}:", mode_desc)
         } else {
-            if archive_path.exists() {
-                continue;
-            }
-
-            if let Err(e) = std::fs::create_dir_all(&target_dir) {
-                eprintln!("  Error creating {}: {}", target_dir.display(), e);
-                errors += 1;
-                continue;
-            }
-
-            // For per-DAT archives, include set name in the path inside the archive
-            let files_with_paths: Vec<(PathBuf, String)> = files
-                .iter()
-                .map(|(path, rom_name, set_name)| {
-                    let inner_path = if let Some(set) = set_name {
-                        format!("{}/{}", sanitise_path(set), rom_name)
-                    } else {
-                        rom_name.clone()
-                    };
-                    (path.clone(), inner_path)
-                })
-                .collect();
+            format!("Organising files {}...", mode_desc)
+        }
+    );
 
-            match create_archive_from_matches(&archive_path, &files_with_paths, copy) {
-                Ok(count) => {
-                    println!("  {} ({} files)", archive_path.display(), count);
-                    archives_created += 1;
-                    files_packed += count;
-                }
-                Err(e) => {
-                    eprintln!("  [ERROR] {}: {}", archive_path.display(), e);
-                    errors += 1;
-                }
-            }
+    let rebuilder = Rebuilder::new(options, move |event| match event {
+        RebuildEvent::FilePlaced {
+            source,
+            target,
+            copied,
+        } if dry_run => println!(
+            "  {} {} -> {}",
+            if copied { "[COPY]" } else { "[MOVE]" },
+            source.display(),
+            target.display()
+        ),
+        RebuildEvent::SourceMissing { path } if dry_run => {
+            println!("  [MISSING] {}", path.display())
+        }
+        RebuildEvent::TargetExists { path } if dry_run => {
+            println!("  [EXISTS] {}", path.display())
+        }
+        RebuildEvent::ArchiveWritten { path, files } => {
+            println!("  {} ({} files)", path.display(), files)
+        }
+        RebuildEvent::ArchiveMerged {
+            path,
+            kept,
+            added,
+            dropped,
+        } => println!(
+            "  [MERGE] {} ({} kept, {} added, {} dropped)",
+            path.display(),
+            kept,
+            added,
+            dropped
+        ),
+        RebuildEvent::SourceRewritten { path, removed } => println!(
+            "  [REWRITE] {} ({} members moved out)",
+            path.display(),
+            removed
+        ),
+        RebuildEvent::Failed { path, error } => {
+            eprintln!("  [ERROR] {}: {}", path.display(), error)
         }
+        _ => {}
+    });
+    let rebuilder = if existing.merge {
+        rebuilder.with_set_roms(rebuild::load_set_roms(conn)?)
+    } else {
+        rebuilder
+    };
+    let result = rebuilder.rebuild(&sources)?;
+    if !result.removed.is_empty() || !result.rewritten.is_empty() {
+        let tx = conn.unchecked_transaction()?;
+        rebuild::record_moves(&tx, &result)?;
+        tx.commit()?;
     }
 
     println!();
-    println!("{}:", if dry_run { "Would create" } else { "Created" });
-    println!("  Archives: {:>6}", archives_created);
-    println!("  Files:    {:>6}", files_packed);
-    if errors > 0 {
-        println!("  Errors:   {:>6}", errors);
+    if mode == RebuildMode::Loose {
+        println!(
+            "{}:",
+            if dry_run {
+                "Would organise"
+            } else {
+                "Organised"
+            }
+        );
+        println!(
+            "  {}: {:>6}",
+            if copy { "Copied" } else { "Moved" },
+            result.placed
+        );
+    } else {
+        println!("{}:", if dry_run { "Would create" } else { "Created" });
+        println!("  Archives: {:>6}", result.archives);
+        println!("  Files:    {:>6}", result.placed);
+        if result.merged > 0 {
+            println!("  Merged:   {:>6}", result.merged);
+        }
+        if !result.removed.is_empty() {
+            println!("  Removed:  {:>6}", result.removed.len());
+        }
+    }
+    if result.skipped > 0 {
+        println!("  Skipped:  {:>6}", result.skipped);
+    }
+    if result.errors > 0 {
+        println!("  Errors:   {:>6}", result.errors);
     }
-
     Ok(())
 }
 
-/// Create a ZIP archive from matched files (TorrentZIP compliant)
-fn create_archive_from_matches(
-    archive_path: &PathBuf,
-    files: &[(PathBuf, String)],
-    _copy: bool,
-) -> Result<usize> {
-    use std::io::Write;
-
-    let file = std::fs::File::create(archive_path)?;
-    let mut zip = zip::ZipWriter::new(file);
-
-    // TorrentZIP settings: deflate level 9, no extra fields
-    let options = zip::write::SimpleFileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .compression_level(Some(9));
-
-    // TorrentZIP requires alphabetically sorted entries
-    let mut sorted_files: Vec<_> = files.to_vec();
-    sorted_files.sort_by(|a, b| a.1.to_lowercase().cmp(&b.1.to_lowercase()));
-
-    let mut count = 0;
-    for (source_path, inner_name) in &sorted_files {
-        // Handle archive paths - need to extract the file
-        let content = if let Some(hash_pos) = source_path.to_string_lossy().find('#') {
-            let archive_path_str = &source_path.to_string_lossy()[..hash_pos];
-            let entry_name = &source_path.to_string_lossy()[hash_pos + 1..];
-            extract_file_from_archive(&PathBuf::from(archive_path_str), entry_name)?
+/// Print where `organise` would put each set, flagging archives sets would share
+fn print_organise_preview(
+    rebuilder: &Rebuilder,
+    sources: &[rebuild::RebuildSource],
+    target: &Path,
+) -> Result<()> {
+    let planned = rebuilder.preview(sources)?;
+    println!("Preview - {} would hold:", target.display());
+    let mut conflicts = 0;
+    for entry in &planned {
+        let path = entry.path.strip_prefix(target).unwrap_or(&entry.path);
+        if entry.conflict {
+            conflicts += 1;
+            println!("  [CONFLICT] {} ({} files)", path.display(), entry.files);
+            for set in &entry.sets {
+                println!("      {}", set);
+            }
         } else {
-            std::fs::read(source_path)?
-        };
-
-        zip.start_file(inner_name, options)?;
-        zip.write_all(&content)?;
-        count += 1;
+            println!(
+                "  {} ({} files, {})",
+                path.display(),
+                entry.files,
+                entry.sets.join(", ")
+            );
+        }
     }
-
-    zip.finish()?;
-    Ok(count)
+    println!();
+    println!("Targets:   {:>6}", planned.len());
+    if conflicts > 0 {
+        println!("Conflicts: {:>6}", conflicts);
+    }
+    Ok(())
 }
 
-/// Extract a single file from an archive
-fn extract_file_from_archive(archive_path: &PathBuf, entry_name: &str) -> Result<Vec<u8>> {
-    let ext = archive_path
-        .extension()
-        .map(|s| s.to_ascii_lowercase().to_string_lossy().to_string())
-        .unwrap_or_default();
-
-    if ext == "zip" {
-        let file = std::fs::File::open(archive_path)?;
-        let mut archive = zip::ZipArchive::new(std::io::BufReader::new(file))?;
-        let mut entry = archive.by_name(entry_name)?;
-        let mut content = Vec::new();
-        std::io::Read::read_to_end(&mut entry, &mut content)?;
-        Ok(content)
-    } else if ext == "7z" {
-        // Extract to temp and read
-        let temp_dir = tempfile::tempdir()?;
-        sevenz_rust::decompress_file(archive_path, temp_dir.path())?;
-        let extracted_path = temp_dir.path().join(entry_name);
-        Ok(std::fs::read(extracted_path)?)
+/// Rewrite archives as TorrentZip and point their members' rows at the results
+fn cmd_tzip(
+    conn: &rusqlite::Connection,
+    paths: &[PathBuf],
+    threads: Option<usize>,
+    dry_run: bool,
+) -> Result<()> {
+    let thread_count = threads.unwrap_or_else(num_cpus::get).max(1);
+    if dry_run {
+        println!("Dry run - showing archives that are not TorrentZip yet:");
     } else {
-        Err(anyhow::anyhow!("Unknown archive format"))
+        println!(
+            "Converting archives to TorrentZip with {} threads...",
+            thread_count
+        );
     }
-}
 
-fn print_organise_summary(
-    organised: usize,
-    skipped: usize,
-    errors: usize,
-    dry_run: bool,
-    copy: bool,
-) {
+    let options = TzipOptions {
+        threads: thread_count,
+        dry_run,
+    };
+    let archives = tzip::convert_paths(paths, &options, &move |event| match event {
+        TzipEvent::WouldConvert { path } => println!("  [TZIP] {}", path.display()),
+        TzipEvent::ArchiveConverted {
+            path,
+            new_path,
+            entries,
+        } => {
+            if new_path == path {
+                println!("  [TZIP] {} ({} entries)", path.display(), entries)
+            } else {
+                println!(
+                    "  [TZIP] {} -> {} ({} entries)",
+                    path.display(),
+                    new_path.display(),
+                    entries
+                )
+            }
+        }
+        TzipEvent::ArchiveFailed { path, error } => {
+            eprintln!("  [ERROR] {}: {}", path.display(), error)
+        }
+        TzipEvent::DiscoveryFailed { path, error } => {
+            eprintln!("  Warning: {}: {}", path.display(), error)
+        }
+        _ => {}
+    })?;
+
+    let mut updated = 0;
+    if !dry_run {
+        let tx = conn.unchecked_transaction()?;
+        for archive in &archives {
+            if let TzipOutcome::Converted { new_path, .. } = &archive.outcome {
+                updated += tzip::record_conversion(&tx, &archive.path, new_path)?;
+            }
+        }
+        tx.commit()?;
+    }
+
+    let count = |f: fn(&TzipOutcome) -> bool| archives.iter().filter(|a| f(&a.outcome)).count();
     println!();
     println!(
         "{}:",
         if dry_run {
-            "Would organise"
+            "Would convert"
         } else {
-            "Organised"
+            "Converted"
         }
     );
     println!(
-        "  {}: {:>6}",
-        if copy { "Copied" } else { "Moved" },
-        organised
+        "  Archives:         {:>6}",
+        count(|o| matches!(o, TzipOutcome::Converted { .. } | TzipOutcome::WouldConvert))
     );
-    if skipped > 0 {
-        println!("  Skipped:  {:>6}", skipped);
+    println!(
+        "  Already TorrentZip: {:>4}",
+        count(|o| matches!(o, TzipOutcome::AlreadyTorrentzipped))
+    );
+    let failed = count(|o| matches!(o, TzipOutcome::Failed { .. }));
+    if failed > 0 {
+        println!("  Errors:           {:>6}", failed);
     }
-    if errors > 0 {
-        println!("  Errors:   {:>6}", errors);
+    if updated > 0 {
+        println!("  Files updated:    {:>6}", updated);
     }
-}
-
-/// Sanitise a string for use as a directory/file name
-fn sanitise_path(name: &str) -> String {
-    name.chars()
-        .map(|c| match c {
-            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
-            _ => c,
-        })
-        .collect()
+    Ok(())
 }
 
 fn cmd_stats(conn: &rusqlite::Connection) -> Result<()> {
     // Get DAT counts
-    let dat_count: i64 = conn.query_row("SELECT COUNT(*) FROM dats", [], |row| row.get(0))?;
+    let dat_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM dats WHERE enabled = 1", [], |row| {
+            row.get(0)
+        })?;
     let entry_count: i64 =
-        conn.query_row("SELECT COUNT(*) FROM dat_entries", [], |row| row.get(0))?;
+        conn.query_row("SELECT COUNT(*) FROM enabled_dat_entries", [], |row| {
+            row.get(0)
+        })?;
     let file_count: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
 
     println!("Collection Summary");
@@ -1945,11 +2413,12 @@ fn cmd_stats(conn: &rusqlite::Connection) -> Result<()> {
             d.name,
             d.category,
             COUNT(DISTINCT de.id) as total_entries,
-            COUNT(DISTINCT CASE WHEN f.id IS NOT NULL THEN de.id END) as matched_entries
+            COUNT(DISTINCT m.dat_entry_id) as matched_entries
          FROM dats d
          JOIN dat_versions dv ON d.id = dv.dat_id
          JOIN dat_entries de ON dv.id = de.dat_version_id
-         LEFT JOIN files f ON (f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size))
+         LEFT JOIN matches m ON m.dat_entry_id = de.id
+         WHERE d.enabled = 1
          GROUP BY d.id, d.name, d.category
          ORDER BY d.category, d.name",
     )?;
@@ -1968,10 +2437,7 @@ fn cmd_stats(conn: &rusqlite::Connection) -> Result<()> {
     // Count unmatched files (files not in any DAT)
     let unmatched_files: i64 = conn.query_row(
         "SELECT COUNT(*) FROM files f
-         WHERE NOT EXISTS (
-             SELECT 1 FROM dat_entries de
-             WHERE f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         )",
+         WHERE NOT EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id)",
         [],
         |row| row.get(0),
     )?;
@@ -1990,6 +2456,13 @@ fn cmd_stats(conn: &rusqlite::Connection) -> Result<()> {
     );
     println!("  Missing:          {:>8}", total_entries - total_matched);
     println!("  Unmatched files:  {:>8}", unmatched_files);
+    let offline = db::volumes::offline_summary(conn)?;
+    if offline.files > 0 {
+        println!(
+            "  Have (offline):   {:>8}  ({} files on unmounted volumes)",
+            offline.entries, offline.files
+        );
+    }
     println!();
 
     // Check if we have any categories
@@ -2059,9 +2532,14 @@ fn cmd_health(conn: &rusqlite::Connection) -> Result<()> {
     println!("========================\n");
 
     // Basic counts
-    let dat_count: i64 = conn.query_row("SELECT COUNT(*) FROM dats", [], |row| row.get(0))?;
+    let dat_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM dats WHERE enabled = 1", [], |row| {
+            row.get(0)
+        })?;
     let entry_count: i64 =
-        conn.query_row("SELECT COUNT(*) FROM dat_entries", [], |row| row.get(0))?;
+        conn.query_row("SELECT COUNT(*) FROM enabled_dat_entries", [], |row| {
+            row.get(0)
+        })?;
     let file_count: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
 
     if dat_count == 0 {
@@ -2078,19 +2556,17 @@ fn cmd_health(conn: &rusqlite::Connection) -> Result<()> {
 
     // Verified files (match by hash AND correct name)
     let verified_count: i64 = conn.query_row(
-        "SELECT COUNT(DISTINCT f.id) FROM files f
-         JOIN dat_entries de ON f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         WHERE LOWER(f.filename) = LOWER(de.name)",
+        "SELECT COUNT(DISTINCT file_id) FROM matches WHERE name_correct = 1",
         [],
         |row| row.get(0),
     )?;
 
     // Misnamed files (match by hash but wrong name)
     let misnamed_count: i64 = conn.query_row(
-        "SELECT COUNT(DISTINCT f.id) FROM files f
-         JOIN dat_entries de ON f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         WHERE LOWER(f.filename) != LOWER(de.name)
-         AND f.path NOT LIKE '%#%'",
+        "SELECT COUNT(*) FROM files f
+         WHERE f.path NOT LIKE '%#%'
+           AND EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id)
+           AND NOT EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id AND m.name_correct = 1)",
         [],
         |row| row.get(0),
     )?;
@@ -2098,21 +2574,15 @@ fn cmd_health(conn: &rusqlite::Connection) -> Result<()> {
     // Unmatched files (no DAT match)
     let unmatched_count: i64 = conn.query_row(
         "SELECT COUNT(*) FROM files f
-         WHERE NOT EXISTS (
-             SELECT 1 FROM dat_entries de
-             WHERE f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         )",
+         WHERE NOT EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id)",
         [],
         |row| row.get(0),
     )?;
 
     // Missing entries (DAT entries with no matching file)
     let missing_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM dat_entries de
-         WHERE NOT EXISTS (
-             SELECT 1 FROM files f
-             WHERE f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
-         )",
+        "SELECT COUNT(*) FROM enabled_dat_entries de
+         WHERE NOT EXISTS (SELECT 1 FROM matches m WHERE m.dat_entry_id = de.id)",
         [],
         |row| row.get(0),
     )?;
@@ -2147,10 +2617,10 @@ fn cmd_health(conn: &rusqlite::Connection) -> Result<()> {
     // DATs with zero matches
     let empty_dats: i64 = conn.query_row(
         "SELECT COUNT(*) FROM dats d
-         WHERE NOT EXISTS (
+         WHERE d.enabled = 1 AND NOT EXISTS (
              SELECT 1 FROM dat_versions dv
              JOIN dat_entries de ON dv.id = de.dat_version_id
-             JOIN files f ON f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size)
+             JOIN matches m ON m.dat_entry_id = de.id
              WHERE dv.dat_id = d.id
          )",
         [],
@@ -2179,6 +2649,13 @@ fn cmd_health(conn: &rusqlite::Connection) -> Result<()> {
         "  Missing:          {:>8} ({:.1}%)",
         missing_count, missing_pct
     );
+    let offline = db::volumes::offline_summary(conn)?;
+    if offline.files > 0 {
+        println!(
+            "  Have (offline):   {:>8}  <- Mount the volume to check them",
+            offline.entries
+        );
+    }
     println!();
 
     // Issues section
@@ -2451,12 +2928,13 @@ fn cmd_duplicates(conn: &rusqlite::Connection, show_details: bool) -> Result<()>
     let mut stmt = conn.prepare(
         "SELECT sha1, COUNT(*) as count, SUM(size) as total_size
          FROM files
+         WHERE sha1 IS NOT NULL
          GROUP BY sha1
          HAVING COUNT(*) > 1
          ORDER BY total_size DESC",
     )?;
 
-    let duplicates: Vec<(String, i64, i64)> = stmt
+    let duplicates: Vec<(Vec<u8>, i64, i64)> = stmt
         .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
         .filter_map(|r| r.ok())
         .collect();
@@ -2491,8 +2969,11 @@ fn cmd_duplicates(conn: &rusqlite::Connection, show_details: bool) -> Result<()>
 
     if show_details {
         // Show each duplicate group with file paths
-        let mut path_stmt =
-            conn.prepare("SELECT path, size FROM files WHERE sha1 = ?1 ORDER BY path")?;
+        let mut path_stmt = conn.prepare(
+            "SELECT p.path, f.size FROM files f
+                 JOIN file_paths p ON p.file_id = f.id
+                 WHERE f.sha1 = ?1 ORDER BY p.path",
+        )?;
 
         for (sha1, count, _) in &duplicates {
             let paths: Vec<(String, i64)> = path_stmt
@@ -2503,7 +2984,7 @@ fn cmd_duplicates(conn: &rusqlite::Connection, show_details: bool) -> Result<()>
             if let Some((_, size)) = paths.first() {
                 println!(
                     "[{}] {} copies, {} each:",
-                    &sha1[..8],
+                    &hash::bytes_to_hex(sha1)[..8],
                     count,
                     format_bytes(*size)
                 );
@@ -2518,8 +2999,11 @@ fn cmd_duplicates(conn: &rusqlite::Connection, show_details: bool) -> Result<()>
         println!("Top duplicates by wasted space (use --details for full list):");
         println!();
 
-        let mut path_stmt =
-            conn.prepare("SELECT path FROM files WHERE sha1 = ?1 ORDER BY path LIMIT 1")?;
+        let mut path_stmt = conn.prepare(
+            "SELECT p.path FROM files f
+                 JOIN file_paths p ON p.file_id = f.id
+                 WHERE f.sha1 = ?1 ORDER BY p.path LIMIT 1",
+        )?;
 
         for (sha1, count, total_size) in duplicates.iter().take(10) {
             let size_per_file = total_size / count;
@@ -2552,6 +3036,334 @@ fn cmd_duplicates(conn: &rusqlite::Connection, show_details: bool) -> Result<()>
     Ok(())
 }
 
+/// Show collection history, with changes between snapshots
+fn cmd_history(
+    conn: &rusqlite::Connection,
+    dat_ref: Option<&str>,
+    since: Option<&str>,
+    limit: usize,
+    json: bool,
+) -> Result<()> {
+    let dat_id = match dat_ref {
+        Some(dat_ref) => match resolve_dat(conn, dat_ref)? {
+            Some(id) => Some(id),
+            None => return Ok(()),
+        },
+        None => None,
+    };
+    let snapshots = history::history(conn, dat_id, since)?;
+    let shown = &snapshots[snapshots.len().saturating_sub(limit)..];
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(shown)?);
+        return Ok(());
+    }
+    if shown.is_empty() {
+        println!("No history yet. Snapshots are taken after each scan, verify and DAT import.");
+        return Ok(());
+    }
+
+    // Each DAT line shows the change since the previous snapshot of that DAT
+    let mut previous: std::collections::HashMap<i64, &DatSnapshot> =
+        std::collections::HashMap::new();
+    let first_shown = snapshots.len() - shown.len();
+    for (idx, snapshot) in snapshots.iter().enumerate() {
+        let show = idx >= first_shown;
+        if show {
+            println!(
+                "{}  {:<7} {:>8} files  {:>10}",
+                snapshot
+                    .taken_at
+                    .get(..16)
+                    .unwrap_or(&snapshot.taken_at)
+                    .replace('T', " "),
+                snapshot.event.as_str(),
+                snapshot.file_count,
+                format_bytes(snapshot.total_bytes)
+            );
+        }
+        for dat in &snapshot.dats {
+            let before = previous.insert(dat.dat_id, dat);
+            if show && before != Some(dat) {
+                let (have_delta, sets_delta) = match before {
+                    Some(before) => (
+                        signed(dat.have - before.have),
+                        signed(dat.sets_complete - before.sets_complete),
+                    ),
+                    None => ("new".to_string(), "new".to_string()),
+                };
+                println!(
+                    "    {:<40} have {}/{} ({})  sets {}/{} ({})",
+                    dat.name,
+                    dat.have,
+                    dat.total,
+                    have_delta,
+                    dat.sets_complete,
+                    dat.sets_total,
+                    sets_delta
+                );
+            }
+        }
+    }
+
+    // Overall change across the snapshots shown
+    let first = &shown[0];
+    let last = &shown[shown.len() - 1];
+    if shown.len() > 1 {
+        println!(
+            "
+Change since {}:",
+            first.taken_at.get(..10).unwrap_or(&first.taken_at)
+        );
+        for dat in &last.dats {
+            let Some(start) = first.dats.iter().find(|d| d.dat_id == dat.dat_id) else {
+                println!("  {:<40} imported since", dat.name);
+                continue;
+            };
+            println!(
+                "  {:<40} have {}  complete sets {}",
+                dat.name,
+                signed(dat.have - start.have),
+                signed(dat.sets_complete - start.sets_complete)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A change with its sign, e.g. "+3", "-1", "0"
+fn signed(delta: i64) -> String {
+    if delta > 0 {
+        format!("+{}", delta)
+    } else {
+        delta.to_string()
+    }
+}
+
+/// Check database integrity and optionally repair orphaned rows
+fn cmd_search(conn: &rusqlite::Connection, query: &str, limit: usize) -> Result<()> {
+    let hits = db::search::search(conn, query, limit)?;
+    if hits.is_empty() {
+        println!("No results for \"{}\"", query);
+        return Ok(());
+    }
+
+    for hit in &hits {
+        let status = match hit.status {
+            db::search::SearchStatus::Have => "have",
+            db::search::SearchStatus::Partial => "partial",
+            db::search::SearchStatus::Missing => "missing",
+            db::search::SearchStatus::Unmatched => "unmatched",
+        };
+        let kind = match hit.kind {
+            db::search::SearchHitKind::Set => "set",
+            db::search::SearchHitKind::Rom => "rom",
+            db::search::SearchHitKind::File => "file",
+        };
+        println!("[{:<4}] {:<9} {}", kind, status, hit.name);
+        if let Some(description) = &hit.description
+            && description != &hit.name
+        {
+            println!("                 {}", description);
+        }
+        let mut context = Vec::new();
+        if let Some(dat) = &hit.dat_name {
+            context.push(dat.clone());
+        }
+        if let Some(set) = &hit.set_name
+            && set != &hit.name
+        {
+            context.push(set.clone());
+        }
+        if !context.is_empty() {
+            println!("                 {}", context.join(" / "));
+        }
+        if let Some(path) = &hit.path {
+            println!("                 {}", path);
+        }
+    }
+    println!();
+    println!("{} result(s)", hits.len());
+    Ok(())
+}
+
+fn cmd_db_check(conn: &mut rusqlite::Connection, repair: bool) -> Result<()> {
+    let report = db::integrity::check(conn)?;
+
+    println!("Database Integrity");
+    println!("==================");
+    if report.structure.iter().all(|m| m == "ok") {
+        println!("  Structure:        ok");
+    } else {
+        println!("  Structure:        {} problem(s)", report.structure.len());
+        for message in &report.structure {
+            println!("    {}", message);
+        }
+    }
+
+    if report.orphans.is_empty() {
+        println!("  Orphaned rows:    {:>8}", 0);
+        return Ok(());
+    }
+
+    println!("  Orphaned rows:    {:>8}", report.orphan_count());
+    for orphan in &report.orphans {
+        println!(
+            "    {}.{} -> missing {}: {}",
+            orphan.table, orphan.column, orphan.parent, orphan.count
+        );
+    }
+
+    if repair {
+        let summary = db::integrity::repair(conn)?;
+        println!();
+        println!("Repaired:");
+        println!("  Rows deleted:     {:>8}", summary.deleted);
+        println!("  References reset: {:>8}", summary.nulled);
+    } else {
+        println!();
+        println!("Run `romshelf db check --repair` to clean up orphaned rows.");
+    }
+
+    Ok(())
+}
+
+fn cmd_roots_list(conn: &rusqlite::Connection) -> Result<()> {
+    let roots = db::roots::list_roots(conn)?;
+    if roots.is_empty() {
+        println!("No scan roots registered. Run `romshelf scan <path>` to add one.");
+        return Ok(());
+    }
+
+    println!("{:>4}  {:>8}  Path", "ID", "Files");
+    println!("{}", "-".repeat(60));
+    for root in &roots {
+        println!("{:>4}  {:>8}  {}", root.id, root.file_count, root.path);
+    }
+    Ok(())
+}
+
+fn cmd_roots_relocate(conn: &rusqlite::Connection, old: &Path, new: &Path) -> Result<()> {
+    if !new.exists() {
+        eprintln!(
+            "Warning: {} does not exist; files will show as missing until it does",
+            new.display()
+        );
+    }
+
+    let moved = db::roots::relocate_roots(conn, old, new)?;
+    for root in &moved {
+        println!(
+            "  {} -> {} ({} files)",
+            root.old_path, root.new_path, root.file_count
+        );
+    }
+    println!("Relocated {} root(s)", moved.len());
+    Ok(())
+}
+
+fn cmd_volumes_status(conn: &rusqlite::Connection) -> Result<()> {
+    db::volumes::refresh(conn)?;
+    let volumes = db::volumes::list_volumes(conn)?;
+    let roots = db::volumes::list_root_status(conn)?;
+    if roots.is_empty() {
+        println!("No scan roots registered. Run `romshelf scan <path>` to add one.");
+        return Ok(());
+    }
+
+    let last_seen = |at: &Option<String>| {
+        at.as_deref()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string())
+    };
+
+    if !volumes.is_empty() {
+        println!("Volumes");
+        println!("-------");
+        for volume in &volumes {
+            println!(
+                "  {:<20} {:<8} {:>8} files  last seen {}  ({})",
+                truncate_string(&volume.label, 20),
+                if volume.online { "online" } else { "offline" },
+                volume.file_count,
+                last_seen(&volume.last_seen_at),
+                volume.last_mount.as_deref().unwrap_or("?")
+            );
+        }
+        println!();
+    }
+
+    println!("Roots");
+    println!("-----");
+    for root in &roots {
+        let volume = root
+            .volume_id
+            .and_then(|id| volumes.iter().find(|v| v.id == id))
+            .map(|v| v.label.as_str())
+            .unwrap_or("-");
+        println!(
+            "  {:<8} {:>8} files  last seen {}  [{}] {}",
+            if root.online { "online" } else { "offline" },
+            root.file_count,
+            last_seen(&root.last_seen_at),
+            volume,
+            root.path
+        );
+    }
+    Ok(())
+}
+
+fn cmd_volumes_mark(conn: &rusqlite::Connection, path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return Err(anyhow!("Not a directory: {}", path.display()));
+    }
+    let id = db::volumes::mark_volume(conn, path)?;
+    println!(
+        "Marked {} as volume {} ({})",
+        path.display(),
+        &id[..8],
+        volume::MARKER_FILE
+    );
+    Ok(())
+}
+
+fn cmd_profile(profiles: &mut ProfileRegistry, command: &ProfileCommands) -> Result<()> {
+    match command {
+        ProfileCommands::List => {
+            for profile in profiles.list() {
+                let marker = if profile.active { "*" } else { " " };
+                println!(
+                    "{} {:<20} {}",
+                    marker,
+                    profile.name,
+                    profile.db_path.display()
+                );
+            }
+        }
+        ProfileCommands::Add { name, path } => {
+            let db_path = profiles.add(name, path.as_deref())?;
+            profiles.save()?;
+            println!("Added profile '{}' ({})", name, db_path.display());
+        }
+        ProfileCommands::Remove { name } => {
+            let profile = profiles.remove(name)?;
+            profiles.save()?;
+            println!(
+                "Removed profile '{}' (database kept at {})",
+                name,
+                profile.db_path.display()
+            );
+        }
+        ProfileCommands::Use { name } => {
+            profiles.set_active(name)?;
+            profiles.save()?;
+            println!("Now using profile '{}'", name);
+        }
+    }
+    Ok(())
+}
+
 /// Format bytes as human-readable string
 fn format_bytes(bytes: i64) -> String {
     const KB: i64 = 1024;
@@ -2590,18 +3402,10 @@ fn format_bytes_short(bytes: i64) -> String {
 fn cmd_rename_in_place(conn: &rusqlite::Connection, dry_run: bool) -> Result<()> {
     // Find misnamed files: files that match a DAT entry by hash but have wrong filename
     // Only consider loose files (not inside archives - those have # in path)
-    let mut stmt = conn.prepare(
-        "SELECT DISTINCT f.path, f.filename, de.name as correct_name
-         FROM files f
-         JOIN dat_entries de ON (f.sha1 = de.sha1 OR (f.crc32 = de.crc32 AND f.size = de.size))
-         WHERE f.path NOT LIKE '%#%'
-           AND LOWER(f.filename) != LOWER(de.name)
-         ORDER BY f.path",
-    )?;
-
-    let misnamed: Vec<(String, String, String)> = stmt
-        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
-        .filter_map(|r| r.ok())
+    let misnamed: Vec<(i64, String, String, String)> = verifier::misnamed_files(conn)?
+        .into_iter()
+        .filter(|f| !f.path.contains('#'))
+        .map(|f| (f.file_id, f.path, f.filename, f.expected))
         .collect();
 
     if misnamed.is_empty() {
@@ -2623,7 +3427,7 @@ fn cmd_rename_in_place(conn: &rusqlite::Connection, dry_run: bool) -> Result<()>
     let mut skipped = 0;
     let mut errors = 0;
 
-    for (path_str, current_name, correct_name) in &misnamed {
+    for (file_id, path_str, current_name, correct_name) in &misnamed {
         let path = PathBuf::from(path_str);
 
         // Build the new path (same directory, new filename)
@@ -2663,11 +3467,7 @@ fn cmd_rename_in_place(conn: &rusqlite::Connection, dry_run: bool) -> Result<()>
                     renamed += 1;
 
                     // Update the database with the new path
-                    let new_path_str = new_path.to_string_lossy().to_string();
-                    conn.execute(
-                        "UPDATE files SET path = ?1, filename = ?2 WHERE path = ?3",
-                        rusqlite::params![new_path_str, correct_name, path_str],
-                    )?;
+                    db::rename_file(conn, *file_id, correct_name)?;
                 }
                 Err(e) => {
                     eprintln!("  [ERROR] {} -> {}: {}", current_name, correct_name, e);
@@ -0,0 +1,71 @@
+//! Matching throughput against synthetic DATs of increasing size
+//!
+//! Run with `cargo bench -p romshelf-core --bench verify`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use romshelf_core::dat::DatEntry;
+use romshelf_core::scan::ScannedFile;
+use romshelf_core::verify::{self, MatchIndex};
+
+/// Entries with distinct hashes; every tenth one carries only a CRC32
+fn entries(count: usize) -> Vec<DatEntry> {
+    (0..count)
+        .map(|i| DatEntry {
+            name: format!("rom{i}.bin"),
+            size: 1024 + (i % 64) as u64,
+            crc32: Some(format!("{:08x}", i as u32)),
+            md5: None,
+            sha1: (i % 10 != 0).then(|| format!("{:040x}", i)),
+            merge: None,
+        })
+        .collect()
+}
+
+/// One file per second entry, plus the same number that match nothing
+fn files(entries: &[DatEntry]) -> Vec<ScannedFile> {
+    let matching = entries.iter().step_by(2).map(|entry| ScannedFile {
+        path: entry.name.clone().into(),
+        filename: entry.name.clone(),
+        size: entry.size,
+        mtime: None,
+        crc32: entry.crc32.clone().unwrap_or_default(),
+        md5: String::new(),
+        sha1: entry.sha1.clone().unwrap_or_default(),
+    });
+    let junk = (0..entries.len() / 2).map(|i| ScannedFile {
+        path: format!("junk{i}").into(),
+        filename: format!("junk{i}"),
+        size: 1,
+        mtime: None,
+        crc32: "ffffffff".to_string(),
+        md5: String::new(),
+        sha1: format!("{:040x}", u64::MAX - i as u64),
+    });
+    matching.chain(junk).collect()
+}
+
+fn bench_verify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify");
+    group.sample_size(10);
+
+    for count in [10_000, 100_000, 1_000_000] {
+        let entries = entries(count);
+        let files = files(&entries);
+        group.throughput(Throughput::Elements(files.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("index", count), &entries, |b, entries| {
+            b.iter(|| MatchIndex::new(entries))
+        });
+        let index = MatchIndex::new(&entries);
+        group.bench_with_input(BenchmarkId::new("match", count), &files, |b, files| {
+            b.iter(|| verify::match_files(files, &index))
+        });
+        group.bench_with_input(BenchmarkId::new("verify", count), &files, |b, files| {
+            b.iter(|| verify::verify(files, &entries))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_verify);
+criterion_main!(benches);
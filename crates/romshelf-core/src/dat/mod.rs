@@ -1,5 +1,6 @@
 //! DAT parsing module - streaming parser with visitor support (TOSEC, No-Intro, MAME, etc.)
 
+use crate::hash;
 use anyhow::{Context, Result, anyhow};
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
@@ -38,6 +39,8 @@ pub struct DatEntry {
     pub crc32: Option<String>,
     pub md5: Option<String>,
     pub sha1: Option<String>,
+    /// MAME `merge`: the name of the same ROM in the parent or BIOS set
+    pub merge: Option<String>,
 }
 
 /// Metadata emitted at the start of a DAT
@@ -53,6 +56,14 @@ pub struct DatHeader {
 #[derive(Debug, Clone)]
 pub struct DatSetInfo {
     pub name: String,
+    /// The set's `<description>`, only known by the time of `set_end`
+    pub description: Option<String>,
+    /// MAME `cloneof`: the parent set of a clone
+    pub cloneof: Option<String>,
+    /// MAME `romof`: the set ROMs are borrowed from (parent or BIOS)
+    pub romof: Option<String>,
+    /// MAME `isbios="yes"`
+    pub is_bios: bool,
 }
 
 /// Supported DAT formats (best-effort detection)
@@ -223,6 +234,9 @@ fn parse_logiqx<R: BufRead>(
                     "name" if in_header => current_text_target = Some("name"),
                     "description" if in_header => current_text_target = Some("description"),
                     "version" if in_header => current_text_target = Some("version"),
+                    "description" if current_set.is_some() => {
+                        current_text_target = Some("set_description")
+                    }
                     "game" | "machine" | "software" => {
                         emit_header(
                             &mut dat_started,
@@ -234,13 +248,23 @@ fn parse_logiqx<R: BufRead>(
                             path,
                         )?;
 
-                        let mut set_name = String::new();
+                        let mut set = DatSetInfo {
+                            name: String::new(),
+                            description: None,
+                            cloneof: None,
+                            romof: None,
+                            is_bios: false,
+                        };
                         for attr in e.attributes().flatten() {
-                            if attr.key.as_ref() == b"name" {
-                                set_name = String::from_utf8_lossy(&attr.value).to_string();
+                            let value = String::from_utf8_lossy(&attr.value).to_string();
+                            match attr.key.as_ref() {
+                                b"name" => set.name = value,
+                                b"cloneof" => set.cloneof = Some(value),
+                                b"romof" => set.romof = Some(value),
+                                b"isbios" => set.is_bios = value == "yes",
+                                _ => {}
                             }
                         }
-                        let set = DatSetInfo { name: set_name };
                         visitor.set_start(&set)?;
                         current_set = Some(set);
                     }
@@ -293,6 +317,11 @@ fn parse_logiqx<R: BufRead>(
                         "name" => dat_name = text,
                         "description" => header_description = Some(text),
                         "version" => dat_version = Some(text),
+                        "set_description" => {
+                            if let Some(set) = current_set.as_mut() {
+                                set.description = Some(text);
+                            }
+                        }
                         _ => {}
                     }
                 }
@@ -349,6 +378,7 @@ fn parse_rom_attributes(e: &quick_xml::events::BytesStart) -> DatEntry {
         crc32: None,
         md5: None,
         sha1: None,
+        merge: None,
     };
 
     for attr in e.attributes().flatten() {
@@ -358,9 +388,10 @@ fn parse_rom_attributes(e: &quick_xml::events::BytesStart) -> DatEntry {
         match key {
             b"name" => entry.name = value,
             b"size" => entry.size = value.parse().unwrap_or(0),
-            b"crc" => entry.crc32 = Some(value),
-            b"md5" => entry.md5 = Some(value),
-            b"sha1" => entry.sha1 = Some(value),
+            b"crc" => entry.crc32 = hash::normalise_crc32(&value),
+            b"md5" => entry.md5 = hash::normalise_md5(&value),
+            b"sha1" => entry.sha1 = hash::normalise_sha1(&value),
+            b"merge" => entry.merge = Some(value),
             _ => {}
         }
     }
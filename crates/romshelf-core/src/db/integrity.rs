@@ -0,0 +1,222 @@
+//! Integrity checks - orphaned rows and structural corruption
+//!
+//! Databases written before foreign keys were enforced can hold rows whose
+//! parent was deleted (entries of a removed DAT, matches of a pruned file).
+//! These are found with `PRAGMA foreign_key_check` and repaired using each
+//! foreign key's own ON DELETE action.
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Orphaned rows in one table that reference a missing parent
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct OrphanGroup {
+    pub table: String,
+    pub column: String,
+    pub parent: String,
+    pub count: i64,
+}
+
+/// Result of an integrity check
+#[derive(Debug, Serialize, Clone)]
+pub struct IntegrityReport {
+    /// Messages from `PRAGMA quick_check` (just "ok" when healthy)
+    pub structure: Vec<String>,
+    pub orphans: Vec<OrphanGroup>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.structure.iter().all(|m| m == "ok") && self.orphans.is_empty()
+    }
+
+    pub fn orphan_count(&self) -> i64 {
+        self.orphans.iter().map(|o| o.count).sum()
+    }
+}
+
+/// Outcome of repairing orphaned rows
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RepairSummary {
+    pub deleted: i64,
+    pub nulled: i64,
+}
+
+struct Violation {
+    table: String,
+    rowid: i64,
+    parent: String,
+    fkid: i64,
+}
+
+struct ForeignKey {
+    column: String,
+    on_delete: String,
+}
+
+/// Check structure and report orphaned rows without changing anything
+pub fn check(conn: &Connection) -> Result<IntegrityReport> {
+    let structure: Vec<String> = conn
+        .prepare("PRAGMA quick_check")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut groups: BTreeMap<(String, String, String), i64> = BTreeMap::new();
+    let mut keys: BTreeMap<(String, i64), ForeignKey> = BTreeMap::new();
+    for violation in foreign_key_violations(conn)? {
+        let fk = foreign_key(conn, &mut keys, &violation.table, violation.fkid)?;
+        *groups
+            .entry((violation.table, fk.column.clone(), violation.parent))
+            .or_default() += 1;
+    }
+
+    let orphans = groups
+        .into_iter()
+        .map(|((table, column, parent), count)| OrphanGroup {
+            table,
+            column,
+            parent,
+            count,
+        })
+        .collect();
+
+    Ok(IntegrityReport { structure, orphans })
+}
+
+/// Remove or detach orphaned rows
+///
+/// Rows whose foreign key is declared `ON DELETE SET NULL` have the reference
+/// cleared; every other orphan is deleted (cascading to its own children).
+pub fn repair(conn: &mut Connection) -> Result<RepairSummary> {
+    let tx = conn.transaction()?;
+    let mut summary = RepairSummary::default();
+    let mut keys: BTreeMap<(String, i64), ForeignKey> = BTreeMap::new();
+
+    // Deleting a row can orphan its children in tables without cascades,
+    // so keep going until the check comes back clean.
+    loop {
+        let violations = foreign_key_violations(&tx)?;
+        if violations.is_empty() {
+            break;
+        }
+        for violation in violations {
+            let fk = foreign_key(&tx, &mut keys, &violation.table, violation.fkid)?;
+            if fk.on_delete.eq_ignore_ascii_case("SET NULL") {
+                let sql = format!(
+                    "UPDATE {} SET {} = NULL WHERE rowid = ?1",
+                    violation.table, fk.column
+                );
+                summary.nulled += tx.execute(&sql, [violation.rowid])? as i64;
+            } else {
+                let sql = format!("DELETE FROM {} WHERE rowid = ?1", violation.table);
+                summary.deleted += tx.execute(&sql, [violation.rowid])? as i64;
+            }
+        }
+    }
+
+    tx.commit()?;
+    Ok(summary)
+}
+
+fn foreign_key_violations(conn: &Connection) -> Result<Vec<Violation>> {
+    let violations = conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| {
+            Ok(Violation {
+                table: row.get(0)?,
+                rowid: row.get(1)?,
+                parent: row.get(2)?,
+                fkid: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(violations)
+}
+
+fn foreign_key<'a>(
+    conn: &Connection,
+    cache: &'a mut BTreeMap<(String, i64), ForeignKey>,
+    table: &str,
+    fkid: i64,
+) -> Result<&'a ForeignKey> {
+    let key = (table.to_string(), fkid);
+    if !cache.contains_key(&key) {
+        let mut stmt = conn.prepare(&format!("PRAGMA foreign_key_list({})", table))?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                ForeignKey {
+                    column: row.get(3)?,
+                    on_delete: row.get(6)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (id, fk) = row?;
+            cache.insert((table.to_string(), id), fk);
+        }
+    }
+    cache
+        .get(&key)
+        .ok_or_else(|| anyhow::anyhow!("Unknown foreign key {} on {}", fkid, table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_clean_database_passes() {
+        let conn = setup();
+        let report = check(&conn).unwrap();
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn test_reports_and_repairs_orphans() {
+        let mut conn = setup();
+        // Simulate rows left behind by an older version
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO dat_entries (id, dat_version_id, name, size) VALUES (1, 99, 'a.rom', 1);
+             INSERT INTO dat_entries (id, dat_version_id, name, size) VALUES (2, 99, 'b.rom', 1);
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/x', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, directory_id)
+                 VALUES (1, 1, 'a.rom', 'a.rom', 1, 'now', 42);
+             PRAGMA foreign_keys = ON;",
+        )
+        .unwrap();
+
+        let report = check(&conn).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.orphan_count(), 3);
+        assert!(report.orphans.contains(&OrphanGroup {
+            table: "dat_entries".to_string(),
+            column: "dat_version_id".to_string(),
+            parent: "dat_versions".to_string(),
+            count: 2,
+        }));
+
+        let summary = repair(&mut conn).unwrap();
+        assert_eq!(summary.deleted, 2);
+        assert_eq!(summary.nulled, 1);
+        assert!(check(&conn).unwrap().is_ok());
+
+        // The file survives with its directory reference cleared
+        let dir: Option<i64> = conn
+            .query_row("SELECT directory_id FROM files WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(dir, None);
+    }
+}
@@ -0,0 +1,256 @@
+//! Library handle - shared connection manager for one database
+//!
+//! Every front end (CLI, GUI, a future server) opens a database through a
+//! [`Library`] rather than creating ad-hoc connections. The database runs in
+//! WAL mode so readers never block the writer, all connections wait on a busy
+//! timeout instead of failing with "database is locked", and writes within a
+//! process are serialised through a single connection.
+
+use anyhow::Result;
+use rusqlite::Connection;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::migrations;
+
+/// How long a connection waits for another process's lock before giving up
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of pooled reader connections
+pub const DEFAULT_MAX_READERS: usize = 4;
+
+/// An open ROM library: one writer connection and a small reader pool
+pub struct Library {
+    path: PathBuf,
+    writer: Mutex<Connection>,
+    readers: Mutex<ReaderPool>,
+    reader_returned: Condvar,
+    max_readers: usize,
+}
+
+struct ReaderPool {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+impl Library {
+    /// Open (creating if needed) the library at `path` and apply migrations
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::with_max_readers(path, DEFAULT_MAX_READERS)
+    }
+
+    /// Open the library with a custom reader pool size
+    pub fn with_max_readers(path: &Path, max_readers: usize) -> Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = Connection::open(path)?;
+        configure(&writer)?;
+        // WAL is persistent, so setting it once on the writer covers readers too
+        writer.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        migrations::run(&mut writer)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: Mutex::new(writer),
+            readers: Mutex::new(ReaderPool {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            reader_returned: Condvar::new(),
+            max_readers: max_readers.max(1),
+        })
+    }
+
+    /// Path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Borrow the writer connection, waiting for other writers in this process
+    pub fn writer(&self) -> WriteConnection<'_> {
+        WriteConnection {
+            conn: lock(&self.writer),
+        }
+    }
+
+    /// Borrow a read-only connection from the pool
+    ///
+    /// Connections are opened lazily up to the pool size; once all are in use
+    /// this waits for one to be returned.
+    pub fn reader(&self) -> Result<ReadConnection<'_>> {
+        let mut pool = lock(&self.readers);
+        loop {
+            if let Some(conn) = pool.idle.pop() {
+                return Ok(ReadConnection {
+                    library: self,
+                    conn: Some(conn),
+                });
+            }
+            if pool.open < self.max_readers {
+                pool.open += 1;
+                drop(pool);
+                return match open_reader(&self.path) {
+                    Ok(conn) => Ok(ReadConnection {
+                        library: self,
+                        conn: Some(conn),
+                    }),
+                    Err(e) => {
+                        lock(&self.readers).open -= 1;
+                        self.reader_returned.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            pool = self
+                .reader_returned
+                .wait(pool)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn release(&self, conn: Connection) {
+        lock(&self.readers).idle.push(conn);
+        self.reader_returned.notify_one();
+    }
+}
+
+/// Exclusive access to the library's writer connection
+pub struct WriteConnection<'a> {
+    conn: MutexGuard<'a, Connection>,
+}
+
+impl Deref for WriteConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl DerefMut for WriteConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
+
+/// A pooled read-only connection, returned to the pool on drop
+pub struct ReadConnection<'a> {
+    library: &'a Library,
+    conn: Option<Connection>,
+}
+
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection present until drop")
+    }
+}
+
+impl Drop for ReadConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.library.release(conn);
+        }
+    }
+}
+
+/// Settings every connection needs, reader or writer
+fn configure(conn: &Connection) -> Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Cascading deletes depend on this; never rely on the compile-time default.
+    // Recursive triggers make INSERT OR REPLACE fire delete triggers for the
+    // rows it replaces, which keeps the search indexes consistent.
+    conn.execute_batch(
+        "PRAGMA foreign_keys = ON;
+         PRAGMA recursive_triggers = ON;
+         PRAGMA synchronous = NORMAL;",
+    )?;
+    Ok(())
+}
+
+fn open_reader(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    configure(&conn)?;
+    conn.execute_batch("PRAGMA query_only = ON")?;
+    Ok(conn)
+}
+
+/// A panic while holding a connection doesn't leave it unusable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_open_uses_wal_and_migrates() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = Library::open(&dir.path().join("lib.db")).unwrap();
+
+        let mode: String = library
+            .reader()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        let version = migrations::current_version(&library.writer()).unwrap();
+        assert_eq!(version, migrations::latest_version());
+    }
+
+    #[test]
+    fn test_readers_see_writes_and_cannot_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = Library::open(&dir.path().join("lib.db")).unwrap();
+
+        library
+            .writer()
+            .execute(
+                "INSERT INTO dats (name, format, file_path, file_sha1) VALUES ('D', 'TOSEC', '/d.dat', 'x')",
+                [],
+            )
+            .unwrap();
+
+        let reader = library.reader().unwrap();
+        let count: i64 = reader
+            .query_row("SELECT COUNT(*) FROM dats", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(reader.execute("DELETE FROM dats", []).is_err());
+    }
+
+    #[test]
+    fn test_reader_pool_is_bounded_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = Arc::new(Library::with_max_readers(&dir.path().join("lib.db"), 2).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let library = Arc::clone(&library);
+                thread::spawn(move || {
+                    let conn = library.reader().unwrap();
+                    conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get::<_, i64>(0))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 0);
+        }
+
+        let pool = lock(&library.readers);
+        assert!(pool.open <= 2);
+        assert_eq!(pool.idle.len(), pool.open);
+    }
+}
@@ -0,0 +1,271 @@
+//! Rematching - recomputing the `matches` table for a scope in bulk
+//!
+//! The verifier keeps matches up to date incrementally and re-exports what
+//! is here; migrations rematch through this module directly, as the database
+//! layer doesn't depend on the services built on it.
+
+use crate::dat::DatEntry;
+use crate::db;
+use crate::hash;
+use crate::scan::ScannedFile;
+use crate::verify::{self, NamePolicy};
+use anyhow::Result;
+use chrono::Utc;
+use rusqlite::{Connection, params, params_from_iter};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The DATs and files a verification covers; the default covers everything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scope {
+    /// Only these DATs; `None` for every DAT
+    pub dat_ids: Option<Vec<i64>>,
+    /// Only files at or under this absolute path (archive members included)
+    pub path: Option<String>,
+}
+
+impl Scope {
+    /// One DAT
+    pub fn dat(dat_id: i64) -> Self {
+        Self {
+            dat_ids: Some(vec![dat_id]),
+            path: None,
+        }
+    }
+
+    /// The DATs in a category and its sub-categories, as in the DAT tree
+    pub fn category(conn: &Connection, category: &str) -> Result<Self> {
+        Ok(Self {
+            dat_ids: Some(db::dats_in_category(conn, category)?),
+            path: None,
+        })
+    }
+
+    /// Narrow to files at or under `path`
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        let path: String = path.into();
+        self.path = Some(path.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.dat_ids.is_none() && self.path.is_none()
+    }
+
+    /// Whether files matching nothing belong in results: not when the scope
+    /// is only a choice of DATs, as those files are about no DAT
+    pub fn includes_unmatched(&self) -> bool {
+        self.dat_ids.is_none() || self.path.is_some()
+    }
+
+    /// SQL condition on a DAT ID column
+    pub(crate) fn dat_sql(&self, column: &str) -> String {
+        match &self.dat_ids {
+            None => "1".to_string(),
+            Some(ids) if ids.is_empty() => "0".to_string(),
+            Some(ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                format!("{} IN ({})", column, ids.join(", "))
+            }
+        }
+    }
+
+    /// SQL condition on an absolute path column; the path is bound as `?1`
+    pub(crate) fn path_sql(&self, column: &str) -> String {
+        match &self.path {
+            None => "1".to_string(),
+            Some(_) => db::under_path_sql(column),
+        }
+    }
+
+    /// SQL condition on a file ID column; the path is bound as `?1`
+    pub(crate) fn file_sql(&self, column: &str) -> String {
+        match &self.path {
+            None => "1".to_string(),
+            Some(_) => format!(
+                "{} IN (SELECT file_id FROM file_paths WHERE {})",
+                column,
+                self.path_sql("path")
+            ),
+        }
+    }
+
+    /// Parameters for the conditions above
+    pub(crate) fn params(&self) -> impl Iterator<Item = &str> {
+        self.path.as_deref().into_iter()
+    }
+}
+
+/// Recompute the matches between the entries and files in a scope
+///
+/// Loads those entries and files once and matches them with the in-memory
+/// hash index, which is far quicker than one join per DAT version on
+/// large libraries. Matches outside the scope are left alone.
+pub fn rematch(conn: &Connection, scope: &Scope) -> Result<u64> {
+    if scope.is_everything() {
+        conn.execute("DELETE FROM matches", [])?;
+    } else {
+        conn.execute(
+            &format!(
+                "DELETE FROM matches
+                 WHERE dat_entry_id IN (SELECT e.id FROM dat_entries e
+                                        JOIN dat_versions v ON v.id = e.dat_version_id
+                                        WHERE {})
+                   AND {}",
+                scope.dat_sql("v.dat_id"),
+                scope.file_sql("file_id")
+            ),
+            params_from_iter(scope.params()),
+        )?;
+    }
+    let policies = NamePolicies::load(conn)?;
+    let (entry_ids, entry_dats, entries) = load_entries(conn, scope)?;
+    let (file_ids, files) = load_files(conn, scope)?;
+
+    let index = verify::MatchIndex::new(&entries);
+    let candidates = verify::match_files(&files, &index);
+
+    let now = Utc::now().to_rfc3339();
+    let mut insert = conn.prepare_cached(
+        "INSERT OR IGNORE INTO matches (file_id, dat_entry_id, name_correct, matched_at)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    let mut recorded = 0;
+    for ((file_id, file), found) in file_ids.iter().zip(&files).zip(candidates) {
+        for idx in found {
+            let name_correct = policies
+                .get(entry_dats[idx])
+                .is_name_correct(&file.path.to_string_lossy(), &entries[idx].name);
+            recorded += insert.execute(params![file_id, entry_ids[idx], name_correct, now])? as u64;
+        }
+    }
+    Ok(recorded)
+}
+
+/// The scope's DAT entries with row and DAT IDs, hashes as hex strings
+fn load_entries(conn: &Connection, scope: &Scope) -> Result<(Vec<i64>, Vec<i64>, Vec<DatEntry>)> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT e.id, e.name, e.size, e.crc32, e.md5, e.sha1, v.dat_id
+         FROM dat_entries e
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         JOIN dats d ON d.id = v.dat_id AND d.enabled = 1
+         WHERE {}",
+        scope.dat_sql("v.dat_id")
+    ))?;
+    let mut ids = Vec::new();
+    let mut dat_ids = Vec::new();
+    let mut entries = Vec::new();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        ids.push(row.get(0)?);
+        dat_ids.push(row.get(6)?);
+        entries.push(DatEntry {
+            name: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+            crc32: row.get::<_, Option<i64>>(3)?.map(hash::crc32_from_db),
+            md5: row
+                .get::<_, Option<Vec<u8>>>(4)?
+                .map(|b| hash::bytes_to_hex(&b)),
+            sha1: row
+                .get::<_, Option<Vec<u8>>>(5)?
+                .map(|b| hash::bytes_to_hex(&b)),
+            merge: None,
+        });
+    }
+    Ok((ids, dat_ids, entries))
+}
+
+/// The scope's files with row IDs and root-relative paths; missing hashes
+/// become empty strings
+fn load_files(conn: &Connection, scope: &Scope) -> Result<(Vec<i64>, Vec<ScannedFile>)> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, filename, size, crc32, md5, sha1, path FROM files WHERE {}",
+        scope.file_sql("id")
+    ))?;
+    let mut ids = Vec::new();
+    let mut files = Vec::new();
+    let mut rows = stmt.query(params_from_iter(scope.params()))?;
+    while let Some(row) = rows.next()? {
+        ids.push(row.get(0)?);
+        files.push(ScannedFile {
+            path: PathBuf::from(row.get::<_, String>(6)?),
+            filename: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+            mtime: None,
+            crc32: row
+                .get::<_, Option<i64>>(3)?
+                .map(hash::crc32_from_db)
+                .unwrap_or_default(),
+            md5: row
+                .get::<_, Option<Vec<u8>>>(4)?
+                .map(|b| hash::bytes_to_hex(&b))
+                .unwrap_or_default(),
+            sha1: row
+                .get::<_, Option<Vec<u8>>>(5)?
+                .map(|b| hash::bytes_to_hex(&b))
+                .unwrap_or_default(),
+        });
+    }
+    Ok((ids, files))
+}
+
+/// Recompute `name_correct` for a file's matches after it was renamed
+pub fn refresh_names(conn: &Connection, file_id: i64) -> Result<()> {
+    refresh_name_flags(conn, "m.file_id = ?1", file_id)
+}
+
+/// Recompute `name_correct` for the matches selected by `filter`
+pub(crate) fn refresh_name_flags(conn: &Connection, filter: &str, param: i64) -> Result<()> {
+    let policies = NamePolicies::load(conn)?;
+    let rows: Vec<(i64, String, String, i64)> = conn
+        .prepare(&format!(
+            "SELECT m.id, f.path, e.name, v.dat_id FROM matches m
+             JOIN files f ON f.id = m.file_id
+             JOIN dat_entries e ON e.id = m.dat_entry_id
+             JOIN dat_versions v ON v.id = e.dat_version_id
+             WHERE {}",
+            filter
+        ))?
+        .query_map([param], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = conn.prepare_cached("UPDATE matches SET name_correct = ?1 WHERE id = ?2")?;
+    for (match_id, path, entry_name, dat_id) in rows {
+        let name_correct = policies.get(dat_id).is_name_correct(&path, &entry_name);
+        update.execute(params![name_correct, match_id])?;
+    }
+    Ok(())
+}
+
+/// Every DAT's name policy, loaded once per operation
+pub(crate) struct NamePolicies {
+    by_dat: HashMap<i64, NamePolicy>,
+}
+
+impl NamePolicies {
+    pub(crate) fn load(conn: &Connection) -> Result<Self> {
+        let mut stmt =
+            conn.prepare("SELECT id, name_policy FROM dats WHERE name_policy IS NOT NULL")?;
+        let mut rows = stmt.query([])?;
+        let mut by_dat = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let json: String = row.get(1)?;
+            by_dat.insert(row.get(0)?, parse_policy(Some(&json))?);
+        }
+        Ok(Self { by_dat })
+    }
+
+    pub(crate) fn get(&self, dat_id: i64) -> NamePolicy {
+        self.by_dat.get(&dat_id).copied().unwrap_or_default()
+    }
+}
+
+pub(crate) fn parse_policy(json: Option<&str>) -> Result<NamePolicy> {
+    match json {
+        Some(json) => Ok(serde_json::from_str(json)?),
+        None => Ok(NamePolicy::default()),
+    }
+}
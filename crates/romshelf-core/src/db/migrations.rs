@@ -0,0 +1,824 @@
+//! Versioned schema migrations
+//!
+//! Every schema change is a numbered step in [`MIGRATIONS`]. The applied
+//! versions are recorded in the `schema_version` table, each step runs in its
+//! own transaction, and a database written by a newer build is refused rather
+//! than silently downgraded. Before any pending step runs against an existing
+//! database, a backup copy is written next to it.
+//!
+//! Steps never rematch themselves: one that needs every file matched
+//! again records a pending rematch, which [`run`] carries out once the schema
+//! is current.
+
+use super::matching::{self, Scope};
+use super::roots;
+use crate::hash;
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use rusqlite::{Connection, Transaction, params};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A single numbered schema migration
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    apply: fn(&Transaction) -> Result<()>,
+}
+
+/// All migrations, in the order they must be applied
+///
+/// Append new steps to the end. Never edit or renumber a step once released.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Baseline schema (adopts pre-versioned databases)",
+        apply: baseline_schema,
+    },
+    Migration {
+        version: 2,
+        description: "ON DELETE actions and foreign key indexes",
+        apply: foreign_key_actions,
+    },
+    Migration {
+        version: 3,
+        description: "Scan roots with root-relative file and directory paths",
+        apply: scan_roots,
+    },
+    Migration {
+        version: 4,
+        description: "Volumes and online state for scan roots",
+        apply: volumes,
+    },
+    Migration {
+        version: 5,
+        description: "Full-text search indexes",
+        apply: search,
+    },
+    Migration {
+        version: 6,
+        description: "Persist verification results in matches",
+        apply: populate_matches,
+    },
+    Migration {
+        version: 7,
+        description: "Set relationships and per-DAT merge modes",
+        apply: merge_modes,
+    },
+    Migration {
+        version: 8,
+        description: "Per-DAT name policies; conflicting hashes no longer match",
+        apply: name_policies,
+    },
+    Migration {
+        version: 9,
+        description: "Collection history snapshots",
+        apply: history,
+    },
+    Migration {
+        version: 10,
+        description: "DAT enabled flag and match priority",
+        apply: dat_priority,
+    },
+    Migration {
+        version: 11,
+        description: "Per-DAT rebuild output formats",
+        apply: output_formats,
+    },
+];
+
+/// The schema version this build creates and understands
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Get the schema version recorded in the database (0 if never migrated)
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    if !table_exists(conn, "schema_version")? {
+        return Ok(0);
+    }
+    let version: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Bring the database up to the latest schema version
+///
+/// Returns the path of the pre-migration backup, if one was written.
+pub fn run(conn: &mut Connection) -> Result<Option<PathBuf>> {
+    // The search indexes' delete triggers only fire for rows INSERT OR
+    // REPLACE removes with recursive triggers on; a migrated connection
+    // mustn't depend on its opener to set this.
+    conn.execute_batch("PRAGMA recursive_triggers = ON")?;
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        bail!(
+            "Database schema version {} is newer than this build supports ({}). \
+             Please upgrade romshelf.",
+            current,
+            latest
+        );
+    }
+    if current == latest {
+        rematch_if_pending(conn)?;
+        return Ok(None);
+    }
+
+    let backup = if has_user_tables(conn)? {
+        backup_database(conn, current)?
+    } else {
+        None
+    };
+
+    // Table rebuilds need foreign key enforcement off, and the pragma is a
+    // no-op inside a transaction, so it is toggled around the whole run.
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let result = apply_pending(conn, current, latest);
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    result?;
+    rematch_if_pending(conn)?;
+
+    Ok(backup)
+}
+
+/// Checkpoint job marking a rematch that a migration step asked for
+const REMATCH_JOB: &str = "rematch";
+const REMATCH_SOURCE: &str = "migrations";
+
+/// Ask for every file to be matched again once all pending steps have run
+fn request_rematch(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO checkpoints (job_type, source, last_token, updated_at)
+         VALUES (?1, ?2, '', ?3)",
+        params![REMATCH_JOB, REMATCH_SOURCE, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Run a rematch a migration step asked for, against the current schema
+///
+/// The request is cleared in the same transaction, so an interrupted rematch
+/// runs again on the next open.
+fn rematch_if_pending(conn: &mut Connection) -> Result<()> {
+    if !table_exists(conn, "checkpoints")? {
+        return Ok(());
+    }
+    let pending: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM checkpoints WHERE job_type = ?1 AND source = ?2",
+        params![REMATCH_JOB, REMATCH_SOURCE],
+        |row| row.get(0),
+    )?;
+    if pending == 0 {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    matching::rematch(&tx, &Scope::default())?;
+    tx.execute(
+        "DELETE FROM checkpoints WHERE job_type = ?1 AND source = ?2",
+        params![REMATCH_JOB, REMATCH_SOURCE],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn apply_pending(conn: &mut Connection, current: i64, target: i64) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )",
+    )?;
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+    {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx).map_err(|e| {
+            anyhow!(
+                "Migration {} ({}) failed: {}",
+                migration.version,
+                migration.description,
+                e
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at)
+             VALUES (?1, ?2, ?3)",
+            params![
+                migration.version,
+                migration.description,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Write a consistent copy of the database before migrating it
+fn backup_database(conn: &Connection, from_version: i64) -> Result<Option<PathBuf>> {
+    let Some(db_path) = conn.path().filter(|p| !p.is_empty()) else {
+        return Ok(None);
+    };
+    let backup_path = backup_path_for(Path::new(db_path), from_version);
+    conn.execute(
+        "VACUUM INTO ?1",
+        [backup_path.to_string_lossy().to_string()],
+    )?;
+    Ok(Some(backup_path))
+}
+
+fn backup_path_for(db_path: &Path, from_version: i64) -> PathBuf {
+    let stamp = Utc::now().format("%Y%m%d%H%M%S");
+    let name = db_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "romshelf.db".to_string());
+    db_path.with_file_name(format!("{}.v{}-{}.bak", name, from_version, stamp))
+}
+
+fn has_user_tables(conn: &Connection) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master
+         WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// ============================================================================
+// Migration steps
+// ============================================================================
+
+/// v1: create the baseline schema, or adopt a database created before
+/// versioning by adding the columns it may lack and converting hashes
+fn baseline_schema(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("schema.sql"))?;
+
+    // Add mtime column to files if not exists
+    if !column_exists(tx, "files", "mtime")? {
+        tx.execute("ALTER TABLE files ADD COLUMN mtime INTEGER", [])?;
+    }
+
+    // Add file_size column to dats if not exists
+    if !column_exists(tx, "dats", "file_size")? {
+        tx.execute("ALTER TABLE dats ADD COLUMN file_size INTEGER", [])?;
+    }
+
+    // Add file_mtime column to dats if not exists
+    if !column_exists(tx, "dats", "file_mtime")? {
+        tx.execute("ALTER TABLE dats ADD COLUMN file_mtime INTEGER", [])?;
+    }
+
+    // Add directory_id column to files if not exists
+    if !column_exists(tx, "files", "directory_id")? {
+        tx.execute(
+            "ALTER TABLE files ADD COLUMN directory_id INTEGER REFERENCES directories(id)",
+            [],
+        )?;
+    }
+
+    // Convert legacy hex TEXT hashes to INTEGER/BLOB columns
+    if column_type(tx, "dat_entries", "sha1")?.as_deref() == Some("TEXT") {
+        rebuild_with_binary_hashes(tx, "dat_entries", DAT_ENTRIES_BINARY_SQL)?;
+    }
+    if column_type(tx, "files", "sha1")?.as_deref() == Some("TEXT") {
+        rebuild_with_binary_hashes(tx, "files", FILES_BINARY_SQL)?;
+    }
+
+    Ok(())
+}
+
+/// v2: rebuild tables with ON DELETE CASCADE / SET NULL foreign keys
+fn foreign_key_actions(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/002_foreign_key_actions.sql"))?;
+    Ok(())
+}
+
+/// v3: register scan roots and store file/directory paths relative to them
+///
+/// Older databases don't record what was scanned, so each file is assigned
+/// to the outermost directory holding scanned files. The directory tree is
+/// rebuilt per root from the files.
+fn scan_roots(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/003_scan_roots.sql"))?;
+
+    let files: Vec<(i64, String)> = {
+        let mut stmt = tx.prepare("SELECT id, path FROM files")?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Outermost directories first, so nested ones find their ancestor
+    let mut dirs: Vec<PathBuf> = files
+        .iter()
+        .map(|(_, path)| legacy_file_directory(path))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    let mut roots: Vec<(PathBuf, i64)> = Vec::new();
+    let now = Utc::now().to_rfc3339();
+    for dir in dirs {
+        if roots.iter().any(|(root, _)| dir.starts_with(root)) {
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO scan_roots (path, added_at) VALUES (?1, ?2)",
+            params![dir.to_string_lossy().to_string(), now],
+        )?;
+        roots.push((dir, tx.last_insert_rowid()));
+    }
+
+    let mut directory_ids: HashMap<(i64, String), i64> = HashMap::new();
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO files_rooted (id, root_id, path, filename, size, mtime, crc32, md5, sha1,
+                                       scanned_at, directory_id)
+             SELECT id, ?2, ?3, filename, size, mtime, crc32, md5, sha1, scanned_at, ?4
+             FROM files WHERE id = ?1",
+        )?;
+        for (id, path) in &files {
+            let dir = legacy_file_directory(path);
+            let (root, root_id) = roots
+                .iter()
+                .find(|(root, _)| dir.starts_with(root))
+                .ok_or_else(|| anyhow!("No root found for {}", path))?;
+            let relative = roots::relative_path(root, Path::new(path))
+                .ok_or_else(|| anyhow!("{} is not under {}", path, root.display()))?;
+            let relative_dir = roots::relative_path(root, &dir).unwrap_or_default();
+            let directory_id =
+                legacy_directory(tx, &mut directory_ids, *root_id, root, &relative_dir)?;
+            insert.execute(params![id, root_id, relative, directory_id])?;
+        }
+    }
+
+    tx.execute_batch(include_str!("migrations/003_scan_roots_finish.sql"))?;
+    Ok(())
+}
+
+/// v4: track volumes so offline roots aren't mistaken for deleted files
+fn volumes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/004_volumes.sql"))?;
+    Ok(())
+}
+
+/// v5: FTS5 indexes over sets, DAT entries and file paths
+fn search(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/005_search.sql"))?;
+    Ok(())
+}
+
+/// v6: fill `matches` from existing files and DAT entries
+fn populate_matches(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/006_matches.sql"))?;
+    request_rematch(tx)?;
+    Ok(())
+}
+
+/// v7: parent/clone/BIOS columns and `dats.merge_mode`
+fn merge_modes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/007_merge_modes.sql"))?;
+    Ok(())
+}
+
+/// v8: `dats.name_policy`, then (re)match every file under the stricter
+/// hash rule and the DATs' name policies
+fn name_policies(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/008_name_policies.sql"))?;
+    request_rematch(tx)?;
+    Ok(())
+}
+
+/// v9: `snapshots` and `snapshot_dats`
+fn history(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/009_history.sql"))?;
+    Ok(())
+}
+
+/// v10: `dats.enabled`, `dats.priority` and the views that honour them
+fn dat_priority(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/010_dat_priority.sql"))?;
+    Ok(())
+}
+
+/// v11: `dats.output_format`
+fn output_formats(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(include_str!("migrations/011_output_formats.sql"))?;
+    Ok(())
+}
+
+/// Directory holding a legacy absolute file path (archive members use the archive's)
+fn legacy_file_directory(path: &str) -> PathBuf {
+    let container = path.split_once('#').map(|(c, _)| c).unwrap_or(path);
+    Path::new(container)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// Insert a root-relative directory and its ancestors into `directories_rooted`
+fn legacy_directory(
+    tx: &Transaction,
+    cache: &mut HashMap<(i64, String), i64>,
+    root_id: i64,
+    root: &Path,
+    path: &str,
+) -> Result<i64> {
+    if let Some(&id) = cache.get(&(root_id, path.to_string())) {
+        return Ok(id);
+    }
+    let (name, parent_id) = if path.is_empty() {
+        (roots::root_directory_name(&root.to_string_lossy()), None)
+    } else {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent_id = legacy_directory(tx, cache, root_id, root, parent)?;
+        (name.to_string(), Some(parent_id))
+    };
+    tx.execute(
+        "INSERT INTO directories_rooted (root_id, path, name, parent_id) VALUES (?1, ?2, ?3, ?4)",
+        params![root_id, path, name, parent_id],
+    )?;
+    let id = tx.last_insert_rowid();
+    cache.insert((root_id, path.to_string()), id);
+    Ok(id)
+}
+
+/// Table definitions used when rebuilding legacy tables with binary hash columns
+const DAT_ENTRIES_BINARY_SQL: &str = "CREATE TABLE dat_entries_binary (
+    id INTEGER PRIMARY KEY,
+    dat_version_id INTEGER NOT NULL REFERENCES dat_versions(id),
+    set_id INTEGER REFERENCES sets(id),
+    name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB
+)";
+
+const FILES_BINARY_SQL: &str = "CREATE TABLE files_binary (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    filename TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER,
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB,
+    scanned_at TEXT NOT NULL,
+    directory_id INTEGER REFERENCES directories(id)
+)";
+
+/// Rebuild a table whose crc32/md5/sha1 columns still hold hex TEXT
+///
+/// Row IDs are preserved so references from other tables stay valid. Hashes
+/// that fail to parse are stored as NULL rather than kept in the wrong form.
+fn rebuild_with_binary_hashes(conn: &Connection, table: &str, create_sql: &str) -> Result<()> {
+    let new_table = format!("{}_binary", table);
+    let columns: Vec<String> = {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        stmt.query_map([], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let hash_columns = ["crc32", "md5", "sha1"];
+    let other_columns: Vec<&str> = columns
+        .iter()
+        .map(|c| c.as_str())
+        .filter(|c| !hash_columns.contains(c))
+        .collect();
+    let select_sql = format!(
+        "SELECT crc32, md5, sha1, {} FROM {}",
+        other_columns.join(", "),
+        table
+    );
+    let insert_sql = format!(
+        "INSERT INTO {} (crc32, md5, sha1, {}) VALUES (?1, ?2, ?3, {})",
+        new_table,
+        other_columns.join(", "),
+        (4..4 + other_columns.len())
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    conn.execute(create_sql, [])?;
+    {
+        let mut select = conn.prepare(&select_sql)?;
+        let mut insert = conn.prepare(&insert_sql)?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let crc32: Option<String> = row.get(0)?;
+            let md5: Option<String> = row.get(1)?;
+            let sha1: Option<String> = row.get(2)?;
+            let mut values: Vec<rusqlite::types::Value> = vec![
+                crc32.as_deref().and_then(hash::crc32_to_db).into(),
+                md5.as_deref().and_then(hash::md5_to_db).into(),
+                sha1.as_deref().and_then(hash::sha1_to_db).into(),
+            ];
+            for i in 0..other_columns.len() {
+                values.push(row.get(3 + i)?);
+            }
+            insert.execute(rusqlite::params_from_iter(values))?;
+        }
+    }
+    conn.execute(&format!("DROP TABLE {}", table), [])?;
+    conn.execute(
+        &format!("ALTER TABLE {} RENAME TO {}", new_table, table),
+        [],
+    )?;
+    // Dropping the old table also dropped its indexes
+    conn.execute_batch(include_str!("schema.sql"))?;
+    Ok(())
+}
+
+/// Check if a column exists in a table
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(column_type(conn, table, column)?.is_some())
+}
+
+/// Get the declared type of a column, if the column exists
+fn column_type(conn: &Connection, table: &str, column: &str) -> Result<Option<String>> {
+    let sql = format!("PRAGMA table_info({})", table);
+    let mut stmt = conn.prepare(&sql)?;
+    let columns: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+    Ok(columns
+        .into_iter()
+        .find(|(name, _)| name == column)
+        .map(|(_, ty)| ty.to_uppercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fresh_database_reaches_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        run(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        // Running again is a no-op
+        assert!(run(&mut conn).unwrap().is_none());
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    /// Bring a fresh database up to `version` only
+    fn migrate_to(conn: &mut Connection, version: i64) {
+        conn.execute_batch("PRAGMA foreign_keys = OFF").unwrap();
+        apply_pending(conn, 0, version).unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+    }
+
+    fn match_count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM matches", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rematch_runs_once_after_migrating() {
+        for version in [5, 7] {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate_to(&mut conn, version);
+            conn.execute_batch(
+                "DELETE FROM checkpoints;
+                 INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'D', 'TOSEC', '/d.dat', '1');
+                 INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 1);
+                 INSERT INTO dat_entries (dat_version_id, name, size, sha1) VALUES (1, 'a.rom', 1, x'01');
+                 INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+                 INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                     VALUES (1, 'a.rom', 'a.rom', 1, 'now', x'01');",
+            )
+            .unwrap();
+
+            // v6 or v8 asks for a rematch, run after v11 against the current schema
+            run(&mut conn).unwrap();
+            assert_eq!(match_count(&conn), 1);
+            let pending: i64 = conn
+                .query_row("SELECT COUNT(*) FROM checkpoints", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(pending, 0);
+        }
+
+        // A database already past v8 is not matched again
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 9);
+        conn.execute("DELETE FROM checkpoints", []).unwrap();
+        conn.execute_batch(
+            "INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at)
+                 VALUES (1, 1, 'a.rom', 'a.rom', 1, 'now');
+             INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'D', 'TOSEC', '/d.dat', '1');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 1);
+             INSERT INTO dat_entries (id, dat_version_id, name, size) VALUES (1, 1, 'a.rom', 1);
+             INSERT INTO matches (file_id, dat_entry_id, name_correct, matched_at)
+                 VALUES (1, 1, 1, 'now');",
+        )
+        .unwrap();
+        run(&mut conn).unwrap();
+        assert_eq!(match_count(&conn), 1);
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at)
+             VALUES (?1, 'from the future', 'now')",
+            [latest_version() + 1],
+        )
+        .unwrap();
+
+        let err = run(&mut conn).unwrap_err();
+        assert!(err.to_string().contains("newer than this build supports"));
+    }
+
+    #[test]
+    fn test_backup_written_before_migrating_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("romshelf.db");
+        {
+            // A database created before versioning: tables but no schema_version
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(include_str!("schema.sql")).unwrap();
+        }
+
+        let mut conn = Connection::open(&path).unwrap();
+        let backup = run(&mut conn).unwrap().expect("backup should be written");
+        assert!(backup.exists());
+        assert!(
+            backup
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("romshelf.db.v0-")
+        );
+
+        // The backup holds the pre-migration schema
+        let old = Connection::open(&backup).unwrap();
+        assert_eq!(current_version(&old).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_migrate_legacy_text_hashes_to_binary() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE dat_entries (
+                id INTEGER PRIMARY KEY,
+                dat_version_id INTEGER NOT NULL,
+                set_id INTEGER,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                crc32 TEXT,
+                md5 TEXT,
+                sha1 TEXT
+            );
+            CREATE TABLE files (
+                id INTEGER PRIMARY KEY,
+                path TEXT NOT NULL UNIQUE,
+                filename TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                crc32 TEXT,
+                md5 TEXT,
+                sha1 TEXT,
+                scanned_at TEXT NOT NULL
+            );
+            INSERT INTO dat_entries (id, dat_version_id, name, size, crc32, md5, sha1)
+            VALUES (7, 1, 'game.rom', 12, '57F4675D', NULL,
+                    '1EEBDF4FDC9FC7BF283031B93F9AEF3338DE9052');
+            INSERT INTO files (id, path, filename, size, crc32, md5, sha1, scanned_at)
+            VALUES (3, '/roms/game.rom', 'game.rom', 12, '57f4675d',
+                    '9473fdd0d880a43c21b7778d34872157',
+                    '1eebdf4fdc9fc7bf283031b93f9aef3338de9052', 'now');",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+
+        assert_eq!(
+            column_type(&conn, "files", "crc32").unwrap().as_deref(),
+            Some("INTEGER")
+        );
+        assert_eq!(
+            column_type(&conn, "dat_entries", "sha1")
+                .unwrap()
+                .as_deref(),
+            Some("BLOB")
+        );
+        assert!(column_exists(&conn, "files", "directory_id").unwrap());
+
+        let (crc32, sha1): (i64, Vec<u8>) = conn
+            .query_row(
+                "SELECT crc32, sha1 FROM dat_entries WHERE id = 7",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(hash::crc32_from_db(crc32), "57f4675d");
+        assert_eq!(sha1.len(), hash::SHA1_LEN);
+
+        // Uppercase DAT hashes and lowercase scanned hashes now compare equal
+        let matched: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files f JOIN dat_entries e
+                 ON f.sha1 = e.sha1 AND f.crc32 = e.crc32",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(matched, 1);
+    }
+
+    #[test]
+    fn test_absolute_paths_become_root_relative() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply_pending(&mut conn, 0, 2).unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO files (id, path, filename, size, scanned_at)
+                 VALUES (1, '/mnt/roms/snes/usa/a.sfc', 'a.sfc', 1, 'now');
+             INSERT INTO files (id, path, filename, size, scanned_at)
+                 VALUES (2, '/mnt/roms/snes/b.zip#b.sfc', 'b.sfc', 1, 'now');
+             INSERT INTO files (id, path, filename, size, scanned_at)
+                 VALUES (3, '/media/usb/c.nes', 'c.nes', 1, 'now');
+             INSERT INTO matches (file_id, dat_entry_id, name_correct, matched_at)
+                 VALUES (2, 1, 1, 'now');",
+        )
+        .unwrap();
+
+        apply_pending(&mut conn, 2, 3).unwrap();
+
+        let roots: Vec<String> = conn
+            .prepare("SELECT path FROM scan_roots ORDER BY path")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(roots, vec!["/media/usb", "/mnt/roms/snes"]);
+
+        let files: Vec<(i64, String, String)> = conn
+            .prepare(
+                "SELECT f.id, f.path, p.path FROM files f
+                 JOIN file_paths p ON p.file_id = f.id ORDER BY f.id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(files[0].1, "usa/a.sfc");
+        assert_eq!(files[0].2, "/mnt/roms/snes/usa/a.sfc");
+        assert_eq!(files[1].1, "b.zip#b.sfc");
+        assert_eq!(files[2].2, "/media/usb/c.nes");
+
+        // Every file is linked into a rebuilt directory tree
+        let unlinked: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files WHERE directory_id IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unlinked, 0);
+        let usa: String = conn
+            .query_row(
+                "SELECT p.path FROM directories d
+                 JOIN directory_paths p ON p.directory_id = d.id
+                 WHERE d.name = 'usa'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(usa, "/mnt/roms/snes/usa");
+
+        // Row IDs survive, so matches still point at their files
+        let matched: i64 = conn
+            .query_row("SELECT file_id FROM matches", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(matched, 2);
+    }
+}
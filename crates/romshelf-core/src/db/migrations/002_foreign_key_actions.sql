@@ -0,0 +1,104 @@
+-- v2: declare ON DELETE actions so removing a DAT, file or directory
+-- cleans up dependent rows, and index every foreign key column.
+--
+-- SQLite cannot alter constraints in place, so each table is rebuilt with
+-- the same columns and row IDs. Foreign keys are disabled by the runner.
+
+CREATE TABLE dat_versions_new (
+    id INTEGER PRIMARY KEY,
+    dat_id INTEGER NOT NULL REFERENCES dats(id) ON DELETE CASCADE,
+    version TEXT,
+    date TEXT,
+    loaded_at TEXT NOT NULL,
+    entry_count INTEGER NOT NULL
+);
+INSERT INTO dat_versions_new (id, dat_id, version, date, loaded_at, entry_count)
+    SELECT id, dat_id, version, date, loaded_at, entry_count FROM dat_versions;
+DROP TABLE dat_versions;
+ALTER TABLE dat_versions_new RENAME TO dat_versions;
+
+CREATE TABLE sets_new (
+    id INTEGER PRIMARY KEY,
+    dat_version_id INTEGER NOT NULL REFERENCES dat_versions(id) ON DELETE CASCADE,
+    name TEXT NOT NULL
+);
+INSERT INTO sets_new (id, dat_version_id, name)
+    SELECT id, dat_version_id, name FROM sets;
+DROP TABLE sets;
+ALTER TABLE sets_new RENAME TO sets;
+
+CREATE TABLE dat_entries_new (
+    id INTEGER PRIMARY KEY,
+    dat_version_id INTEGER NOT NULL REFERENCES dat_versions(id) ON DELETE CASCADE,
+    set_id INTEGER REFERENCES sets(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB
+);
+INSERT INTO dat_entries_new (id, dat_version_id, set_id, name, size, crc32, md5, sha1)
+    SELECT id, dat_version_id, set_id, name, size, crc32, md5, sha1 FROM dat_entries;
+DROP TABLE dat_entries;
+ALTER TABLE dat_entries_new RENAME TO dat_entries;
+
+CREATE TABLE directories_new (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    parent_id INTEGER REFERENCES directories(id) ON DELETE CASCADE,
+    file_count INTEGER NOT NULL DEFAULT 0,
+    matched_count INTEGER NOT NULL DEFAULT 0,
+    total_size INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO directories_new (id, path, name, parent_id, file_count, matched_count, total_size)
+    SELECT id, path, name, parent_id, file_count, matched_count, total_size FROM directories;
+DROP TABLE directories;
+ALTER TABLE directories_new RENAME TO directories;
+
+CREATE TABLE files_new (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    filename TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER,
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB,
+    scanned_at TEXT NOT NULL,
+    directory_id INTEGER REFERENCES directories(id) ON DELETE SET NULL
+);
+INSERT INTO files_new (id, path, filename, size, mtime, crc32, md5, sha1, scanned_at, directory_id)
+    SELECT id, path, filename, size, mtime, crc32, md5, sha1, scanned_at, directory_id FROM files;
+DROP TABLE files;
+ALTER TABLE files_new RENAME TO files;
+
+CREATE TABLE matches_new (
+    id INTEGER PRIMARY KEY,
+    file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    dat_entry_id INTEGER NOT NULL REFERENCES dat_entries(id) ON DELETE CASCADE,
+    name_correct INTEGER NOT NULL,
+    matched_at TEXT NOT NULL
+);
+INSERT INTO matches_new (id, file_id, dat_entry_id, name_correct, matched_at)
+    SELECT id, file_id, dat_entry_id, name_correct, matched_at FROM matches;
+DROP TABLE matches;
+ALTER TABLE matches_new RENAME TO matches;
+
+-- Indexes dropped with the old tables
+CREATE INDEX idx_sets_dat_version ON sets(dat_version_id);
+CREATE INDEX idx_dat_entries_crc32_size ON dat_entries(crc32, size);
+CREATE INDEX idx_dat_entries_md5 ON dat_entries(md5);
+CREATE INDEX idx_dat_entries_sha1 ON dat_entries(sha1);
+CREATE INDEX idx_files_crc32_size ON files(crc32, size);
+CREATE INDEX idx_files_md5 ON files(md5);
+CREATE INDEX idx_files_sha1 ON files(sha1);
+CREATE INDEX idx_directories_parent ON directories(parent_id);
+
+-- Foreign key columns, so cascades don't scan whole tables
+CREATE INDEX idx_dat_versions_dat ON dat_versions(dat_id);
+CREATE INDEX idx_dat_entries_dat_version ON dat_entries(dat_version_id);
+CREATE INDEX idx_dat_entries_set ON dat_entries(set_id);
+CREATE INDEX idx_files_directory ON files(directory_id);
+CREATE INDEX idx_matches_file ON matches(file_id);
+CREATE INDEX idx_matches_dat_entry ON matches(dat_entry_id);
@@ -0,0 +1,37 @@
+-- v3 (part 1): scan roots, and new files/directories tables keyed by root.
+--
+-- Paths in files and directories become relative to their root with '/'
+-- separators; the runner fills the new tables from the old absolute paths.
+
+CREATE TABLE scan_roots (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    added_at TEXT NOT NULL
+);
+
+CREATE TABLE directories_rooted (
+    id INTEGER PRIMARY KEY,
+    root_id INTEGER NOT NULL REFERENCES scan_roots(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    name TEXT NOT NULL,
+    parent_id INTEGER REFERENCES directories(id) ON DELETE CASCADE,
+    file_count INTEGER NOT NULL DEFAULT 0,
+    matched_count INTEGER NOT NULL DEFAULT 0,
+    total_size INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (root_id, path)
+);
+
+CREATE TABLE files_rooted (
+    id INTEGER PRIMARY KEY,
+    root_id INTEGER NOT NULL REFERENCES scan_roots(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER,
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB,
+    scanned_at TEXT NOT NULL,
+    directory_id INTEGER REFERENCES directories(id) ON DELETE SET NULL,
+    UNIQUE (root_id, path)
+);
@@ -0,0 +1,26 @@
+-- v3 (part 2): swap in the root-relative tables and add path views.
+
+DROP TABLE files;
+ALTER TABLE files_rooted RENAME TO files;
+DROP TABLE directories;
+ALTER TABLE directories_rooted RENAME TO directories;
+
+-- Indexes dropped with the old tables
+CREATE INDEX idx_files_crc32_size ON files(crc32, size);
+CREATE INDEX idx_files_md5 ON files(md5);
+CREATE INDEX idx_files_sha1 ON files(sha1);
+CREATE INDEX idx_files_directory ON files(directory_id);
+CREATE INDEX idx_directories_parent ON directories(parent_id);
+
+-- Absolute paths, for callers that need to touch the filesystem
+CREATE VIEW file_paths AS
+    SELECT f.id AS file_id, rtrim(r.path, '/\') || '/' || f.path AS path
+    FROM files f
+    JOIN scan_roots r ON r.id = f.root_id;
+
+CREATE VIEW directory_paths AS
+    SELECT d.id AS directory_id,
+           CASE WHEN d.path = '' THEN r.path
+                ELSE rtrim(r.path, '/\') || '/' || d.path END AS path
+    FROM directories d
+    JOIN scan_roots r ON r.id = d.root_id;
@@ -0,0 +1,19 @@
+-- v4: volumes, so roots on unplugged drives go offline instead of being pruned.
+
+CREATE TABLE volumes (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,              -- 'marker' or 'fs-uuid'
+    identifier TEXT NOT NULL,
+    label TEXT NOT NULL,
+    last_mount TEXT,
+    last_seen_at TEXT,
+    online INTEGER NOT NULL DEFAULT 1,
+    UNIQUE (kind, identifier)
+);
+
+ALTER TABLE scan_roots ADD COLUMN volume_id INTEGER REFERENCES volumes(id) ON DELETE SET NULL;
+ALTER TABLE scan_roots ADD COLUMN online INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE scan_roots ADD COLUMN last_seen_at TEXT;
+
+CREATE INDEX idx_scan_roots_volume ON scan_roots(volume_id);
+CREATE INDEX idx_files_root ON files(root_id);
@@ -0,0 +1,65 @@
+-- v5: full-text search over set names and descriptions, ROM names and file paths.
+--
+-- Each index is an external-content FTS5 table keyed by the source row ID, so
+-- the text is not stored twice. Triggers keep them in step with every insert,
+-- update and delete (including cascades), whichever code path makes the change.
+
+ALTER TABLE sets ADD COLUMN description TEXT;
+
+CREATE VIRTUAL TABLE sets_fts USING fts5(
+    name, description,
+    content = 'sets', content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE VIRTUAL TABLE dat_entries_fts USING fts5(
+    name,
+    content = 'dat_entries', content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- File paths are root-relative, so the mount point doesn't pollute matches
+CREATE VIRTUAL TABLE files_fts USING fts5(
+    path,
+    content = 'files', content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER sets_fts_insert AFTER INSERT ON sets BEGIN
+    INSERT INTO sets_fts (rowid, name, description) VALUES (new.id, new.name, new.description);
+END;
+CREATE TRIGGER sets_fts_delete AFTER DELETE ON sets BEGIN
+    INSERT INTO sets_fts (sets_fts, rowid, name, description)
+        VALUES ('delete', old.id, old.name, old.description);
+END;
+CREATE TRIGGER sets_fts_update AFTER UPDATE OF name, description ON sets BEGIN
+    INSERT INTO sets_fts (sets_fts, rowid, name, description)
+        VALUES ('delete', old.id, old.name, old.description);
+    INSERT INTO sets_fts (rowid, name, description) VALUES (new.id, new.name, new.description);
+END;
+
+CREATE TRIGGER dat_entries_fts_insert AFTER INSERT ON dat_entries BEGIN
+    INSERT INTO dat_entries_fts (rowid, name) VALUES (new.id, new.name);
+END;
+CREATE TRIGGER dat_entries_fts_delete AFTER DELETE ON dat_entries BEGIN
+    INSERT INTO dat_entries_fts (dat_entries_fts, rowid, name) VALUES ('delete', old.id, old.name);
+END;
+CREATE TRIGGER dat_entries_fts_update AFTER UPDATE OF name ON dat_entries BEGIN
+    INSERT INTO dat_entries_fts (dat_entries_fts, rowid, name) VALUES ('delete', old.id, old.name);
+    INSERT INTO dat_entries_fts (rowid, name) VALUES (new.id, new.name);
+END;
+
+CREATE TRIGGER files_fts_insert AFTER INSERT ON files BEGIN
+    INSERT INTO files_fts (rowid, path) VALUES (new.id, new.path);
+END;
+CREATE TRIGGER files_fts_delete AFTER DELETE ON files BEGIN
+    INSERT INTO files_fts (files_fts, rowid, path) VALUES ('delete', old.id, old.path);
+END;
+CREATE TRIGGER files_fts_update AFTER UPDATE OF path ON files BEGIN
+    INSERT INTO files_fts (files_fts, rowid, path) VALUES ('delete', old.id, old.path);
+    INSERT INTO files_fts (rowid, path) VALUES (new.id, new.path);
+END;
+
+INSERT INTO sets_fts (sets_fts) VALUES ('rebuild');
+INSERT INTO dat_entries_fts (dat_entries_fts) VALUES ('rebuild');
+INSERT INTO files_fts (files_fts) VALUES ('rebuild');
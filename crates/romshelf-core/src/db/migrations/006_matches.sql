@@ -0,0 +1,8 @@
+-- v6: `matches` becomes the record of verification, with one row per
+-- file/entry pair. The rows themselves are computed by the migration step.
+
+DELETE FROM matches
+    WHERE id NOT IN (SELECT MIN(id) FROM matches GROUP BY file_id, dat_entry_id);
+
+DROP INDEX IF EXISTS idx_matches_file;
+CREATE UNIQUE INDEX idx_matches_file_entry ON matches(file_id, dat_entry_id);
@@ -0,0 +1,14 @@
+-- v7: MAME parent/clone/BIOS relationships and a merge mode per DAT.
+-- Relationships are stored by set name, as the DAT gives them; existing
+-- DATs only gain them when re-imported.
+
+ALTER TABLE sets ADD COLUMN cloneof TEXT;
+ALTER TABLE sets ADD COLUMN romof TEXT;
+ALTER TABLE sets ADD COLUMN is_bios INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE dat_entries ADD COLUMN merge_name TEXT;
+
+-- NULL: no merge mode, every set is audited on its own
+ALTER TABLE dats ADD COLUMN merge_mode TEXT
+    CHECK (merge_mode IN ('split', 'merged', 'non-merged'));
+
+CREATE INDEX idx_sets_version_name ON sets(dat_version_id, name);
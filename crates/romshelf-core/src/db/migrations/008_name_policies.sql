@@ -0,0 +1,3 @@
+-- v8: per-DAT filename rules, as JSON (NULL: the default policy)
+
+ALTER TABLE dats ADD COLUMN name_policy TEXT;
@@ -0,0 +1,25 @@
+-- v9: collection history. A snapshot is taken after each scan, verify and
+-- DAT import; DAT rows keep the name and no foreign key so history
+-- survives removing the DAT.
+
+CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY,
+    taken_at TEXT NOT NULL,
+    event TEXT NOT NULL CHECK (event IN ('scan', 'verify', 'import')),
+    file_count INTEGER NOT NULL,
+    total_bytes INTEGER NOT NULL
+);
+CREATE INDEX idx_snapshots_taken_at ON snapshots(taken_at);
+
+CREATE TABLE snapshot_dats (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+    dat_id INTEGER NOT NULL,
+    dat_name TEXT NOT NULL,
+    total INTEGER NOT NULL,
+    have INTEGER NOT NULL,
+    missing INTEGER NOT NULL,
+    sets_total INTEGER NOT NULL,
+    sets_complete INTEGER NOT NULL,
+    PRIMARY KEY (snapshot_id, dat_id)
+);
+CREATE INDEX idx_snapshot_dats_dat ON snapshot_dats(dat_id);
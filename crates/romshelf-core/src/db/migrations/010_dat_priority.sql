@@ -0,0 +1,27 @@
+-- v10: DATs can be disabled and ranked. A disabled DAT keeps its entries but
+-- has no matches and is left out of verification, stats and organise. When
+-- a file matches entries in several DATs, the highest priority DAT wins.
+
+ALTER TABLE dats ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE dats ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+
+-- Entries of enabled DATs, for counting what the collection should hold
+CREATE VIEW enabled_dat_entries AS
+    SELECT e.* FROM dat_entries e
+    JOIN dat_versions v ON v.id = e.dat_version_id
+    JOIN dats d ON d.id = v.dat_id
+    WHERE d.enabled = 1;
+
+-- One match per matched file: highest DAT priority first, then a correct
+-- name, then the oldest DAT
+CREATE VIEW primary_matches AS
+    SELECT m.* FROM matches m
+    WHERE m.id = (
+        SELECT m2.id FROM matches m2
+        JOIN dat_entries e ON e.id = m2.dat_entry_id
+        JOIN dat_versions v ON v.id = e.dat_version_id
+        JOIN dats d ON d.id = v.dat_id
+        WHERE m2.file_id = m.file_id
+        ORDER BY d.priority DESC, m2.name_correct DESC, d.id, m2.dat_entry_id
+        LIMIT 1
+    );
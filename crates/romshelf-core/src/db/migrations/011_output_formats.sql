@@ -0,0 +1,5 @@
+-- v11: the archive format organise writes a DAT's sets in.
+-- NULL: whatever format the rebuild was asked for.
+
+ALTER TABLE dats ADD COLUMN output_format TEXT
+    CHECK (output_format IN ('zip', '7z', 'zip-zstd'));
@@ -1,10 +1,21 @@
 //! Database module - SQLite connection, schema, queries
 
+use crate::hash;
 use anyhow::{Result, anyhow};
 use chrono::Utc;
 use rusqlite::{Connection, OptionalExtension};
 use serde::Serialize;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+
+pub mod integrity;
+pub mod library;
+pub mod matching;
+pub mod migrations;
+pub mod roots;
+pub mod search;
+pub mod volumes;
+
+pub use library::Library;
 
 /// Statistics about the collection
 #[derive(Debug, Serialize)]
@@ -25,6 +36,10 @@ pub struct DatSummary {
     pub version: Option<String>,
     pub entry_count: i64,
     pub set_count: i64,
+    /// Disabled DATs get no matches and are left out of verification
+    pub enabled: bool,
+    /// When a file matches several DATs, the highest priority one wins
+    pub priority: i64,
 }
 
 /// A node in the DAT tree hierarchy
@@ -70,6 +85,15 @@ pub struct DirectorySummary {
     pub child_count: i64,
 }
 
+/// Rows removed together with a DAT
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct DatRemoval {
+    pub versions: i64,
+    pub sets: i64,
+    pub entries: i64,
+    pub matches: i64,
+}
+
 /// Checkpoint information for resumable jobs
 #[derive(Debug, Clone)]
 pub struct Checkpoint {
@@ -81,88 +105,45 @@ pub struct Checkpoint {
 
 /// Get the default database path (~/.romshelf/romshelf.db)
 pub fn default_db_path() -> Result<PathBuf> {
-    let home = dirs::home_dir().ok_or_else(|| anyhow!("Cannot find home directory"))?;
-    let dir = home.join(".romshelf");
-    Ok(dir.join("romshelf.db"))
-}
-
-/// Open the database at the default location, creating if needed
-pub fn open_db() -> Result<Connection> {
-    let path = default_db_path()?;
-    if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
-    init_db(&path)
-}
-
-/// Initialize the database, creating tables if they don't exist
-pub fn init_db(path: &Path) -> Result<Connection> {
-    let conn = Connection::open(path)?;
-    create_schema(&conn)?;
-    migrate_schema(&conn)?;
-    Ok(conn)
+    Ok(config_dir()?.join("romshelf.db"))
 }
 
-/// Create the database schema
-fn create_schema(conn: &Connection) -> Result<()> {
-    conn.execute_batch(include_str!("schema.sql"))?;
-    Ok(())
+/// Directory holding romshelf's own files (~/.romshelf)
+pub fn config_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Cannot find home directory"))?;
+    Ok(home.join(".romshelf"))
 }
 
-/// Apply schema migrations for existing databases
-fn migrate_schema(conn: &Connection) -> Result<()> {
-    // Add mtime column to files if not exists
-    if !column_exists(conn, "files", "mtime")? {
-        conn.execute("ALTER TABLE files ADD COLUMN mtime INTEGER", [])?;
-    }
-
-    // Add file_size column to dats if not exists
-    if !column_exists(conn, "dats", "file_size")? {
-        conn.execute("ALTER TABLE dats ADD COLUMN file_size INTEGER", [])?;
-    }
-
-    // Add file_mtime column to dats if not exists
-    if !column_exists(conn, "dats", "file_mtime")? {
-        conn.execute("ALTER TABLE dats ADD COLUMN file_mtime INTEGER", [])?;
-    }
-
-    // Add directory_id column to files if not exists
-    if !column_exists(conn, "files", "directory_id")? {
-        conn.execute(
-            "ALTER TABLE files ADD COLUMN directory_id INTEGER REFERENCES directories(id)",
-            [],
-        )?;
-    }
-
-    Ok(())
+/// Get the schema version of an open database
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    migrations::current_version(conn)
 }
 
-/// Check if a column exists in a table
-fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
-    let sql = format!("PRAGMA table_info({})", table);
-    let mut stmt = conn.prepare(&sql)?;
-    let columns: Vec<String> = stmt
-        .query_map([], |row| row.get::<_, String>(1))?
-        .filter_map(|r| r.ok())
-        .collect();
-    Ok(columns.contains(&column.to_string()))
+/// Read an optional binary digest column as lowercase hex
+fn hex_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Option<String>> {
+    Ok(row
+        .get::<_, Option<Vec<u8>>>(idx)?
+        .map(|bytes| hash::bytes_to_hex(&bytes)))
 }
 
-/// Get collection statistics
+/// Get collection statistics; disabled DATs are not counted
 pub fn get_collection_stats(conn: &Connection) -> Result<CollectionStats> {
-    let dat_count: i64 = conn.query_row("SELECT COUNT(*) FROM dats", [], |row| row.get(0))?;
+    let dat_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM dats WHERE enabled = 1", [], |row| {
+            row.get(0)
+        })?;
 
     let entry_count: i64 =
-        conn.query_row("SELECT COUNT(*) FROM dat_entries", [], |row| row.get(0))?;
+        conn.query_row("SELECT COUNT(*) FROM enabled_dat_entries", [], |row| {
+            row.get(0)
+        })?;
 
     let scanned_files: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
 
-    let matched_files: i64 = conn.query_row(
-        "SELECT COUNT(DISTINCT f.id) FROM files f
-         INNER JOIN dat_entries e ON f.sha1 = e.sha1",
-        [],
-        |row| row.get(0),
-    )?;
+    let matched_files: i64 =
+        conn.query_row("SELECT COUNT(DISTINCT file_id) FROM matches", [], |row| {
+            row.get(0)
+        })?;
 
     let total_bytes_scanned: i64 =
         conn.query_row("SELECT COALESCE(SUM(size), 0) FROM files", [], |row| {
@@ -188,7 +169,8 @@ pub fn list_dats(conn: &Connection) -> Result<Vec<DatSummary>> {
                  WHERE dv.dat_id = d.id) as entry_count,
                 (SELECT COUNT(*) FROM sets s
                  INNER JOIN dat_versions dv ON s.dat_version_id = dv.id
-                 WHERE dv.dat_id = d.id) as set_count
+                 WHERE dv.dat_id = d.id) as set_count,
+                d.enabled, d.priority
          FROM dats d
          ORDER BY d.category, d.name",
     )?;
@@ -202,6 +184,8 @@ pub fn list_dats(conn: &Connection) -> Result<Vec<DatSummary>> {
                 version: row.get(3)?,
                 entry_count: row.get(4)?,
                 set_count: row.get(5)?,
+                enabled: row.get(6)?,
+                priority: row.get(7)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
@@ -266,13 +250,62 @@ fn sort_tree(node: &mut DatTreeNode) {
     }
 }
 
+impl DatTreeNode {
+    /// IDs of the DATs in this node and all nodes below it
+    pub fn dat_ids(&self) -> Vec<i64> {
+        let mut ids: Vec<i64> = self.dats.iter().map(|dat| dat.id).collect();
+        for child in &self.children {
+            ids.extend(child.dat_ids());
+        }
+        ids
+    }
+}
+
+/// IDs of the DATs filed under a category path such as `TOSEC/Commodore`,
+/// sub-categories included
+///
+/// Uses the [`get_dat_tree`] hierarchy; segments compare case-insensitively.
+/// Returns an empty list when no category matches.
+pub fn dats_in_category(conn: &Connection, category: &str) -> Result<Vec<i64>> {
+    let tree = get_dat_tree(conn)?;
+    let mut nodes = vec![&tree];
+    for segment in category.split('/').filter(|s| !s.is_empty()) {
+        nodes = nodes
+            .into_iter()
+            .flat_map(|node| node.children.iter())
+            .filter(|child| child.name.eq_ignore_ascii_case(segment))
+            .collect();
+    }
+    Ok(nodes.into_iter().flat_map(|node| node.dat_ids()).collect())
+}
+
+/// SQL condition on an absolute path column: at or under the path in `?1`,
+/// archive members included
+pub fn under_path_sql(column: &str) -> String {
+    format!("({column} = ?1 OR substr({column}, 1, length(?1) + 1) IN (?1 || '/', ?1 || '#'))")
+}
+
+/// IDs of the files at or under an absolute path
+pub fn files_under(conn: &Connection, path: &str) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT file_id FROM file_paths WHERE {}",
+        under_path_sql("path")
+    ))?;
+    let ids = stmt
+        .query_map([path.trim_end_matches('/')], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ids)
+}
+
 /// List scanned files with match status
 pub fn list_files(conn: &Connection, limit: i64, offset: i64) -> Result<Vec<FileSummary>> {
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.path, f.filename, f.size, f.sha1,
-                EXISTS(SELECT 1 FROM dat_entries e WHERE e.sha1 = f.sha1) as matched,
-                (SELECT e.name FROM dat_entries e WHERE e.sha1 = f.sha1 LIMIT 1) as match_name
+        "SELECT f.id, p.path, f.filename, f.size, f.sha1,
+                EXISTS(SELECT 1 FROM matches m WHERE m.file_id = f.id) as matched,
+                (SELECT e.name FROM primary_matches m JOIN dat_entries e ON e.id = m.dat_entry_id
+                 WHERE m.file_id = f.id) as match_name
          FROM files f
+         JOIN file_paths p ON p.file_id = f.id
          ORDER BY f.filename
          LIMIT ?1 OFFSET ?2",
     )?;
@@ -284,7 +317,7 @@ pub fn list_files(conn: &Connection, limit: i64, offset: i64) -> Result<Vec<File
                 path: row.get(1)?,
                 filename: row.get(2)?,
                 size: row.get(3)?,
-                sha1: row.get(4)?,
+                sha1: hex_column(row, 4)?,
                 matched: row.get(5)?,
                 match_name: row.get(6)?,
             })
@@ -297,11 +330,13 @@ pub fn list_files(conn: &Connection, limit: i64, offset: i64) -> Result<Vec<File
 /// Get all files as a tree structure based on filesystem paths
 pub fn get_file_tree(conn: &Connection) -> Result<FileTreeNode> {
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.path, f.filename, f.size, f.sha1,
-                EXISTS(SELECT 1 FROM dat_entries e WHERE e.sha1 = f.sha1) as matched,
-                (SELECT e.name FROM dat_entries e WHERE e.sha1 = f.sha1 LIMIT 1) as match_name
+        "SELECT f.id, p.path, f.filename, f.size, f.sha1,
+                EXISTS(SELECT 1 FROM matches m WHERE m.file_id = f.id) as matched,
+                (SELECT e.name FROM primary_matches m JOIN dat_entries e ON e.id = m.dat_entry_id
+                 WHERE m.file_id = f.id) as match_name
          FROM files f
-         ORDER BY f.path",
+         JOIN file_paths p ON p.file_id = f.id
+         ORDER BY p.path",
     )?;
 
     let files: Vec<FileSummary> = stmt
@@ -311,7 +346,7 @@ pub fn get_file_tree(conn: &Connection) -> Result<FileTreeNode> {
                 path: row.get(1)?,
                 filename: row.get(2)?,
                 size: row.get(3)?,
-                sha1: row.get(4)?,
+                sha1: hex_column(row, 4)?,
                 matched: row.get(5)?,
                 match_name: row.get(6)?,
             })
@@ -393,44 +428,41 @@ fn compute_file_tree_stats(node: &mut FileTreeNode) {
 // Directory-based lazy loading (scales to millions of files)
 // ============================================================================
 
-/// Get or create a directory entry, returning its ID
-pub fn get_or_create_directory(conn: &Connection, path: &str) -> Result<i64> {
-    // Check if directory exists
+/// Get or create a directory (and its ancestors) under a scan root
+///
+/// `path` is relative to the root with `/` separators; the empty path is the
+/// root's own directory.
+pub fn get_or_create_directory(conn: &Connection, root_id: i64, path: &str) -> Result<i64> {
     let existing: Option<i64> = conn
         .query_row(
-            "SELECT id FROM directories WHERE path = ?1",
-            [path],
+            "SELECT id FROM directories WHERE root_id = ?1 AND path = ?2",
+            rusqlite::params![root_id, path],
             |row| row.get(0),
         )
-        .ok();
+        .optional()?;
 
     if let Some(id) = existing {
         return Ok(id);
     }
 
-    // Parse path components
-    let path_obj = Path::new(path);
-    let name = path_obj
-        .file_name()
-        .map(|n| n.to_string_lossy().to_string())
-        .unwrap_or_else(|| path.to_string());
-
-    // Get or create parent
-    let parent_id = if let Some(parent) = path_obj.parent() {
-        let parent_str = parent.to_string_lossy().to_string();
-        if parent_str.is_empty() || parent_str == "/" {
-            None
-        } else {
-            Some(get_or_create_directory(conn, &parent_str)?)
-        }
+    let (name, parent_id) = if path.is_empty() {
+        let root_path: String = conn.query_row(
+            "SELECT path FROM scan_roots WHERE id = ?1",
+            [root_id],
+            |row| row.get(0),
+        )?;
+        (roots::root_directory_name(&root_path), None)
     } else {
-        None
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        (
+            name.to_string(),
+            Some(get_or_create_directory(conn, root_id, parent)?),
+        )
     };
 
-    // Insert the directory
     conn.execute(
-        "INSERT INTO directories (path, name, parent_id) VALUES (?1, ?2, ?3)",
-        rusqlite::params![path, name, parent_id],
+        "INSERT INTO directories (root_id, path, name, parent_id) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![root_id, path, name, parent_id],
     )?;
 
     Ok(conn.last_insert_rowid())
@@ -469,6 +501,69 @@ pub fn update_directory_stats(
     Ok(())
 }
 
+/// Count the rows that removing a DAT would delete
+pub fn dat_removal_counts(conn: &Connection, dat_id: i64) -> Result<DatRemoval> {
+    let (versions, sets, entries, matches) = conn.query_row(
+        "SELECT
+            (SELECT COUNT(*) FROM dat_versions WHERE dat_id = ?1),
+            (SELECT COUNT(*) FROM sets s
+             JOIN dat_versions dv ON s.dat_version_id = dv.id WHERE dv.dat_id = ?1),
+            (SELECT COUNT(*) FROM dat_entries de
+             JOIN dat_versions dv ON de.dat_version_id = dv.id WHERE dv.dat_id = ?1),
+            (SELECT COUNT(*) FROM matches m
+             JOIN dat_entries de ON m.dat_entry_id = de.id
+             JOIN dat_versions dv ON de.dat_version_id = dv.id WHERE dv.dat_id = ?1)",
+        [dat_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    Ok(DatRemoval {
+        versions,
+        sets,
+        entries,
+        matches,
+    })
+}
+
+/// Remove a DAT; its versions, sets, entries and matches cascade with it
+pub fn remove_dat(conn: &Connection, dat_id: i64) -> Result<DatRemoval> {
+    let removal = dat_removal_counts(conn, dat_id)?;
+    conn.execute("DELETE FROM dats WHERE id = ?1", [dat_id])?;
+    Ok(removal)
+}
+
+/// Remove a scanned file record by ID; its matches cascade with it
+pub fn remove_file(conn: &Connection, file_id: i64) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM files WHERE id = ?1", [file_id])? > 0)
+}
+
+/// Remove a scanned file record by absolute path; its matches cascade with it
+pub fn remove_file_by_path(conn: &Connection, path: &str) -> Result<bool> {
+    Ok(conn.execute(
+        "DELETE FROM files WHERE id IN (SELECT file_id FROM file_paths WHERE path = ?1)",
+        [path],
+    )? > 0)
+}
+
+/// Record that a file was renamed in place (same directory, new filename)
+///
+/// The `name_correct` flags on the file's matches are refreshed too.
+pub fn rename_file(conn: &Connection, file_id: i64, filename: &str) -> Result<()> {
+    let path: String =
+        conn.query_row("SELECT path FROM files WHERE id = ?1", [file_id], |row| {
+            row.get(0)
+        })?;
+    let new_path = match path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, filename),
+        None => filename.to_string(),
+    };
+    conn.execute(
+        "UPDATE files SET path = ?1, filename = ?2 WHERE id = ?3",
+        rusqlite::params![new_path, filename, file_id],
+    )?;
+    matching::refresh_names(conn, file_id)?;
+    Ok(())
+}
+
 /// Update or insert a checkpoint for resumable operations
 pub fn upsert_checkpoint(
     conn: &Connection,
@@ -524,9 +619,10 @@ pub fn get_checkpoint(
 /// Get root directories (top-level scan roots)
 pub fn get_root_directories(conn: &Connection) -> Result<Vec<DirectorySummary>> {
     let mut stmt = conn.prepare(
-        "SELECT d.id, d.path, d.name, d.parent_id, d.file_count, d.matched_count, d.total_size,
+        "SELECT d.id, p.path, d.name, d.parent_id, d.file_count, d.matched_count, d.total_size,
                 (SELECT COUNT(*) FROM directories c WHERE c.parent_id = d.id) as child_count
          FROM directories d
+         JOIN directory_paths p ON p.directory_id = d.id
          WHERE d.parent_id IS NULL
          ORDER BY d.name",
     )?;
@@ -552,9 +648,10 @@ pub fn get_root_directories(conn: &Connection) -> Result<Vec<DirectorySummary>>
 /// Get child directories of a parent
 pub fn get_child_directories(conn: &Connection, parent_id: i64) -> Result<Vec<DirectorySummary>> {
     let mut stmt = conn.prepare(
-        "SELECT d.id, d.path, d.name, d.parent_id, d.file_count, d.matched_count, d.total_size,
+        "SELECT d.id, p.path, d.name, d.parent_id, d.file_count, d.matched_count, d.total_size,
                 (SELECT COUNT(*) FROM directories c WHERE c.parent_id = d.id) as child_count
          FROM directories d
+         JOIN directory_paths p ON p.directory_id = d.id
          WHERE d.parent_id = ?1
          ORDER BY d.name",
     )?;
@@ -580,10 +677,12 @@ pub fn get_child_directories(conn: &Connection, parent_id: i64) -> Result<Vec<Di
 /// Get files directly in a directory (not recursive)
 pub fn get_files_in_directory(conn: &Connection, dir_id: i64) -> Result<Vec<FileSummary>> {
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.path, f.filename, f.size, f.sha1,
-                EXISTS(SELECT 1 FROM dat_entries e WHERE e.sha1 = f.sha1) as matched,
-                (SELECT e.name FROM dat_entries e WHERE e.sha1 = f.sha1 LIMIT 1) as match_name
+        "SELECT f.id, p.path, f.filename, f.size, f.sha1,
+                EXISTS(SELECT 1 FROM matches m WHERE m.file_id = f.id) as matched,
+                (SELECT e.name FROM primary_matches m JOIN dat_entries e ON e.id = m.dat_entry_id
+                 WHERE m.file_id = f.id) as match_name
          FROM files f
+         JOIN file_paths p ON p.file_id = f.id
          WHERE f.directory_id = ?1
          ORDER BY f.filename",
     )?;
@@ -595,7 +694,7 @@ pub fn get_files_in_directory(conn: &Connection, dir_id: i64) -> Result<Vec<File
                 path: row.get(1)?,
                 filename: row.get(2)?,
                 size: row.get(3)?,
-                sha1: row.get(4)?,
+                sha1: hex_column(row, 4)?,
                 matched: row.get(5)?,
                 match_name: row.get(6)?,
             })
@@ -622,7 +721,7 @@ pub fn recompute_directory_stats(conn: &Connection) -> Result<()> {
         "UPDATE directories SET
             file_count = (SELECT COUNT(*) FROM files f WHERE f.directory_id = directories.id),
             matched_count = (SELECT COUNT(*) FROM files f WHERE f.directory_id = directories.id
-                            AND EXISTS(SELECT 1 FROM dat_entries e WHERE e.sha1 = f.sha1)),
+                            AND EXISTS(SELECT 1 FROM matches m WHERE m.file_id = f.id)),
             total_size = (SELECT COALESCE(SUM(size), 0) FROM files f WHERE f.directory_id = directories.id)",
         [],
     )?;
@@ -668,8 +767,8 @@ mod tests {
 
     #[test]
     fn test_init_db_creates_tables() {
-        let conn = Connection::open_in_memory().unwrap();
-        create_schema(&conn).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
 
         // Verify tables exist
         let tables: Vec<String> = conn
@@ -687,4 +786,41 @@ mod tests {
         assert!(tables.contains(&"files".to_string()));
         assert!(tables.contains(&"matches".to_string()));
     }
+
+    #[test]
+    fn test_remove_dat_cascades() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'D', 'TOSEC', '/d.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 2);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'Game');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size) VALUES (1, 1, 1, 'a.rom', 1);
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size) VALUES (2, 1, 1, 'b.rom', 1);
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at) VALUES (1, 1, 'a.rom', 'a.rom', 1, 'now');
+             INSERT INTO matches (file_id, dat_entry_id, name_correct, matched_at) VALUES (1, 1, 1, 'now');",
+        )
+        .unwrap();
+
+        let removal = remove_dat(&conn, 1).unwrap();
+        assert_eq!(removal.versions, 1);
+        assert_eq!(removal.sets, 1);
+        assert_eq!(removal.entries, 2);
+        assert_eq!(removal.matches, 1);
+
+        for table in ["dat_versions", "sets", "dat_entries", "matches"] {
+            let count: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 0, "{} should be empty", table);
+        }
+
+        // Files are independent of DATs
+        assert!(remove_file_by_path(&conn, "/roms/a.rom").unwrap());
+        assert!(!remove_file(&conn, 1).unwrap());
+    }
 }
@@ -0,0 +1,332 @@
+//! Scan roots - registered top-level directories that file paths hang off
+//!
+//! Files and directories store paths relative to their scan root, using `/`
+//! as the separator (archive members keep the `archive#entry` form). Only
+//! the root row holds an absolute path, so moving a collection to a new mount
+//! point is a single update instead of a rescan. The `file_paths` and
+//! `directory_paths` views join the two back into absolute paths.
+
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::path::{Component, Path, PathBuf};
+
+/// A registered scan root
+#[derive(Debug, Serialize, Clone)]
+pub struct ScanRoot {
+    pub id: i64,
+    pub path: String,
+    pub added_at: String,
+    pub file_count: i64,
+}
+
+/// A root whose path was rewritten by [`relocate_roots`]
+#[derive(Debug, Serialize, Clone)]
+pub struct RelocatedRoot {
+    pub id: i64,
+    pub old_path: String,
+    pub new_path: String,
+    pub file_count: i64,
+}
+
+/// Make a root path absolute and canonical where possible
+///
+/// Paths that don't exist (an unmounted drive) are made absolute and
+/// cleaned up lexically instead of failing.
+pub fn normalise_root(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+    let mut cleaned = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            other => cleaned.push(other),
+        }
+    }
+    cleaned
+}
+
+/// Path of `path` relative to `root`, with `/` separators
+///
+/// Returns `None` if `path` is not under `root`, and an empty string if
+/// it is the root itself.
+pub fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let rest = path.strip_prefix(root).ok()?;
+    Some(
+        rest.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
+/// Absolute path of a root-relative path
+pub fn absolute_path(root: &str, relative: &str) -> PathBuf {
+    let mut path = PathBuf::from(root);
+    for part in relative.split('/').filter(|p| !p.is_empty()) {
+        path.push(part);
+    }
+    path
+}
+
+/// List registered roots with their file counts
+pub fn list_roots(conn: &Connection) -> Result<Vec<ScanRoot>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.id, r.path, r.added_at,
+                (SELECT COUNT(*) FROM files f WHERE f.root_id = r.id)
+         FROM scan_roots r
+         ORDER BY r.path",
+    )?;
+    let roots = stmt
+        .query_map([], |row| {
+            Ok(ScanRoot {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                added_at: row.get(2)?,
+                file_count: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(roots)
+}
+
+/// Find the root containing `path`, with the path relative to it
+pub fn find_root(conn: &Connection, path: &Path) -> Result<Option<(ScanRoot, String)>> {
+    for root in list_roots(conn)? {
+        if let Some(relative) = relative_path(Path::new(&root.path), path) {
+            return Ok(Some((root, relative)));
+        }
+    }
+    Ok(None)
+}
+
+/// Register `path` as a scan root, or return the root that already covers it
+///
+/// Registering a parent of existing roots folds them into the new root so
+/// roots never nest.
+pub fn register_root(conn: &Connection, path: &Path) -> Result<ScanRoot> {
+    let path = normalise_root(path);
+    if let Some((root, _)) = find_root(conn, &path)? {
+        return Ok(root);
+    }
+
+    let path_str = path.to_string_lossy().to_string();
+    conn.execute(
+        "INSERT INTO scan_roots (path, added_at) VALUES (?1, ?2)",
+        params![path_str, Utc::now().to_rfc3339()],
+    )?;
+    let root_id = conn.last_insert_rowid();
+
+    for nested in list_roots(conn)? {
+        if nested.id == root_id {
+            continue;
+        }
+        if let Some(prefix) = relative_path(&path, Path::new(&nested.path)) {
+            absorb_root(conn, root_id, &nested, &prefix)?;
+        }
+    }
+
+    list_roots(conn)?
+        .into_iter()
+        .find(|r| r.id == root_id)
+        .ok_or_else(|| anyhow!("Root {} vanished after insert", path_str))
+}
+
+/// Move a nested root's files and directories under `root_id`
+fn absorb_root(conn: &Connection, root_id: i64, nested: &ScanRoot, prefix: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET root_id = ?1, path = ?2 || '/' || path WHERE root_id = ?3",
+        params![root_id, prefix, nested.id],
+    )?;
+    conn.execute(
+        "UPDATE directories SET root_id = ?1,
+             path = CASE WHEN path = '' THEN ?2 ELSE ?2 || '/' || path END
+         WHERE root_id = ?3",
+        params![root_id, prefix, nested.id],
+    )?;
+
+    // The nested root's own directory now hangs off the new tree
+    let parent = prefix.rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+    let parent_id = super::get_or_create_directory(conn, root_id, parent)?;
+    conn.execute(
+        "UPDATE directories SET parent_id = ?1 WHERE root_id = ?2 AND path = ?3",
+        params![parent_id, root_id, prefix],
+    )?;
+
+    conn.execute("DELETE FROM scan_roots WHERE id = ?1", [nested.id])?;
+    Ok(())
+}
+
+/// Rewrite every root at or below `old` to live under `new` instead
+///
+/// Nothing is rehashed: only the root rows change, since file and directory
+/// paths are stored relative to them.
+pub fn relocate_roots(conn: &Connection, old: &Path, new: &Path) -> Result<Vec<RelocatedRoot>> {
+    let old = normalise_root(old);
+    let new = normalise_root(new);
+
+    let mut moves = Vec::new();
+    for root in list_roots(conn)? {
+        if let Some(rest) = relative_path(&old, Path::new(&root.path)) {
+            let new_path = absolute_path(&new.to_string_lossy(), &rest);
+            moves.push(RelocatedRoot {
+                id: root.id,
+                old_path: root.path,
+                new_path: new_path.to_string_lossy().to_string(),
+                file_count: root.file_count,
+            });
+        }
+    }
+    if moves.is_empty() {
+        bail!("No scan root at or below {}", old.display());
+    }
+
+    for relocated in &moves {
+        let clash: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM scan_roots WHERE path = ?1 AND id != ?2",
+                params![relocated.new_path, relocated.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if clash.is_some() {
+            bail!("{} is already a scan root", relocated.new_path);
+        }
+    }
+
+    for relocated in &moves {
+        // The new location may be on another volume; refresh re-identifies it
+        conn.execute(
+            "UPDATE scan_roots SET path = ?1, volume_id = NULL WHERE id = ?2",
+            params![relocated.new_path, relocated.id],
+        )?;
+        let name = root_directory_name(&relocated.new_path);
+        conn.execute(
+            "UPDATE directories SET name = ?1 WHERE root_id = ?2 AND path = ''",
+            params![name, relocated.id],
+        )?;
+    }
+
+    Ok(moves)
+}
+
+/// Display name for a root's own directory row
+pub(crate) fn root_directory_name(root_path: &str) -> String {
+    Path::new(root_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| root_path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, migrations};
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn
+    }
+
+    fn add_file(conn: &Connection, abs: &str) {
+        let (root, rel) = find_root(conn, Path::new(abs)).unwrap().unwrap();
+        let dir = rel.rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+        let dir_id = db::get_or_create_directory(conn, root.id, dir).unwrap();
+        let filename = rel.rsplit('/').next().unwrap();
+        conn.execute(
+            "INSERT INTO files (root_id, path, filename, size, scanned_at, directory_id)
+             VALUES (?1, ?2, ?3, 1, 'now', ?4)",
+            params![root.id, rel, filename, dir_id],
+        )
+        .unwrap();
+    }
+
+    fn file_paths(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT path FROM file_paths ORDER BY path")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let root = Path::new("/mnt/roms");
+        assert_eq!(
+            relative_path(root, Path::new("/mnt/roms/snes/game.zip#a.sfc")).as_deref(),
+            Some("snes/game.zip#a.sfc")
+        );
+        assert_eq!(relative_path(root, root).as_deref(), Some(""));
+        assert_eq!(relative_path(root, Path::new("/mnt/romsx/a")), None);
+        assert_eq!(
+            absolute_path("/mnt/roms", "snes/a.sfc"),
+            PathBuf::from("/mnt/roms/snes/a.sfc")
+        );
+    }
+
+    #[test]
+    fn test_register_reuses_and_absorbs_roots() {
+        let conn = setup();
+        let snes = register_root(&conn, Path::new("/mnt/roms/snes")).unwrap();
+        add_file(&conn, "/mnt/roms/snes/usa/game.sfc");
+
+        // A subdirectory of a root is covered by it
+        let again = register_root(&conn, Path::new("/mnt/roms/snes/usa")).unwrap();
+        assert_eq!(again.id, snes.id);
+
+        // A parent absorbs the nested root
+        let all = register_root(&conn, Path::new("/mnt/roms")).unwrap();
+        let roots = list_roots(&conn).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].id, all.id);
+        assert_eq!(file_paths(&conn), vec!["/mnt/roms/snes/usa/game.sfc"]);
+
+        let dirs: Vec<(String, Option<i64>)> = conn
+            .prepare("SELECT path, parent_id FROM directories ORDER BY path")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(dirs[0], ("".to_string(), None));
+        assert!(dirs[1..].iter().all(|(_, parent)| parent.is_some()));
+    }
+
+    #[test]
+    fn test_relocate_rewrites_only_roots() {
+        let conn = setup();
+        register_root(&conn, Path::new("/mnt/old/arcade")).unwrap();
+        register_root(&conn, Path::new("/mnt/old/console")).unwrap();
+        register_root(&conn, Path::new("/other")).unwrap();
+        add_file(&conn, "/mnt/old/arcade/pacman.zip#pacman.6e");
+        add_file(&conn, "/mnt/old/console/nes/smb.nes");
+
+        let moved = relocate_roots(&conn, Path::new("/mnt/old"), Path::new("/nas/roms")).unwrap();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(
+            file_paths(&conn),
+            vec![
+                "/nas/roms/arcade/pacman.zip#pacman.6e",
+                "/nas/roms/console/nes/smb.nes"
+            ]
+        );
+
+        assert!(relocate_roots(&conn, Path::new("/missing"), Path::new("/x")).is_err());
+        assert!(relocate_roots(&conn, Path::new("/other"), Path::new("/nas/roms/arcade")).is_err());
+    }
+}
@@ -1,4 +1,7 @@
 -- Romshelf database schema (Milestone 1 - simplified)
+--
+-- Baseline applied by migration v1. Later changes are numbered steps in
+-- migrations.rs; do not edit this file to change an existing schema.
 
 -- DATs
 CREATE TABLE IF NOT EXISTS dats (
@@ -41,9 +44,9 @@ CREATE TABLE IF NOT EXISTS dat_entries (
     set_id INTEGER REFERENCES sets(id),
     name TEXT NOT NULL,
     size INTEGER NOT NULL,
-    crc32 TEXT,
-    md5 TEXT,
-    sha1 TEXT
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB
 );
 
 -- Files
@@ -53,10 +56,11 @@ CREATE TABLE IF NOT EXISTS files (
     filename TEXT NOT NULL,
     size INTEGER NOT NULL,
     mtime INTEGER,
-    crc32 TEXT,
-    md5 TEXT,
-    sha1 TEXT,
-    scanned_at TEXT NOT NULL
+    crc32 INTEGER,
+    md5 BLOB,
+    sha1 BLOB,
+    scanned_at TEXT NOT NULL,
+    directory_id INTEGER REFERENCES directories(id)
 );
 
 -- Matches
@@ -69,9 +73,13 @@ CREATE TABLE IF NOT EXISTS matches (
 );
 
 -- Indexes for hash lookups
-CREATE INDEX IF NOT EXISTS idx_dat_entries_crc32 ON dat_entries(crc32);
+-- Hashes are stored normalised: CRC32 as INTEGER, MD5 (16 bytes) and SHA1 (20 bytes) as BLOB.
+-- CRC32 is only meaningful together with size, so it is indexed as a pair.
+CREATE INDEX IF NOT EXISTS idx_dat_entries_crc32_size ON dat_entries(crc32, size);
+CREATE INDEX IF NOT EXISTS idx_dat_entries_md5 ON dat_entries(md5);
 CREATE INDEX IF NOT EXISTS idx_dat_entries_sha1 ON dat_entries(sha1);
-CREATE INDEX IF NOT EXISTS idx_files_crc32 ON files(crc32);
+CREATE INDEX IF NOT EXISTS idx_files_crc32_size ON files(crc32, size);
+CREATE INDEX IF NOT EXISTS idx_files_md5 ON files(md5);
 CREATE INDEX IF NOT EXISTS idx_files_sha1 ON files(sha1);
 
 -- Index for rescan optimization (lookup by path)
@@ -92,7 +100,7 @@ CREATE TABLE IF NOT EXISTS directories (
 CREATE INDEX IF NOT EXISTS idx_directories_parent ON directories(parent_id);
 
 -- Foreign key from files to directories
--- Note: directory_id is added via migration, not here
+-- Note: older databases gain directory_id via migration
 
 -- Checkpoints for resumable operations
 CREATE TABLE IF NOT EXISTS checkpoints (
@@ -105,7 +113,3 @@ CREATE TABLE IF NOT EXISTS checkpoints (
 
 CREATE UNIQUE INDEX IF NOT EXISTS idx_checkpoints_job_source
     ON checkpoints(job_type, source);
-
--- Schema migrations for existing databases
--- Add mtime column to files if not exists
--- SQLite doesn't have IF NOT EXISTS for columns, but we handle this in code
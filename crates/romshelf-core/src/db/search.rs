@@ -0,0 +1,316 @@
+//! Full-text search across sets, DAT entries and scanned files
+//!
+//! Backed by the FTS5 indexes created in migration v5. Each kind of row has
+//! its own index; hits from all three are merged by BM25 rank.
+
+use anyhow::Result;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+/// What a search hit refers to
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchHitKind {
+    /// A set (game, application, ...) from a DAT
+    Set,
+    /// A single ROM entry within a set
+    Rom,
+    /// A scanned file on disk
+    File,
+}
+
+/// Whether the collection has what a hit refers to
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchStatus {
+    /// Every ROM is matched by a file (for files: matches a DAT entry)
+    Have,
+    /// Some but not all of a set's ROMs are matched
+    Partial,
+    /// Nothing is matched
+    Missing,
+    /// A file that matches no DAT entry
+    Unmatched,
+}
+
+/// A ranked search result
+#[derive(Debug, Serialize, Clone)]
+pub struct SearchHit {
+    pub kind: SearchHitKind,
+    /// Row ID in `sets`, `dat_entries` or `files` depending on `kind`
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub dat_id: Option<i64>,
+    pub dat_name: Option<String>,
+    pub set_name: Option<String>,
+    /// Absolute path, for file hits
+    pub path: Option<String>,
+    pub status: SearchStatus,
+    /// BM25 rank; lower is a better match
+    pub rank: f64,
+}
+
+/// Search sets, ROMs and files for `query`, best matches first
+///
+/// Each whitespace-separated word must match (as a prefix) somewhere in the
+/// row; FTS query syntax in the input is treated as plain text.
+pub fn search(conn: &Connection, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+    let Some(fts_query) = fts_query(query) else {
+        return Ok(Vec::new());
+    };
+    let limit = limit as i64;
+
+    let mut hits = search_sets(conn, &fts_query, limit)?;
+    hits.extend(search_roms(conn, &fts_query, limit)?);
+    hits.extend(search_files(conn, &fts_query, limit)?);
+    hits.sort_by(|a, b| a.rank.total_cmp(&b.rank));
+    hits.truncate(limit as usize);
+    Ok(hits)
+}
+
+/// Turn user input into an FTS5 query of quoted prefix terms
+fn fts_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+fn search_sets(conn: &Connection, query: &str, limit: i64) -> Result<Vec<SearchHit>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.name, s.description, d.id, d.name,
+                (SELECT COUNT(*) FROM dat_entries e WHERE e.set_id = s.id),
+                (SELECT COUNT(*) FROM dat_entries e
+                 WHERE e.set_id = s.id
+                   AND EXISTS (SELECT 1 FROM matches m WHERE m.dat_entry_id = e.id)),
+                sets_fts.rank
+         FROM sets_fts
+         JOIN sets s ON s.id = sets_fts.rowid
+         JOIN dat_versions v ON v.id = s.dat_version_id
+         JOIN dats d ON d.id = v.dat_id
+         WHERE sets_fts MATCH ?1
+         ORDER BY sets_fts.rank
+         LIMIT ?2",
+    )?;
+    let hits = stmt
+        .query_map(params![query, limit], |row| {
+            let total: i64 = row.get(5)?;
+            let have: i64 = row.get(6)?;
+            let status = if total > 0 && have == total {
+                SearchStatus::Have
+            } else if have > 0 {
+                SearchStatus::Partial
+            } else {
+                SearchStatus::Missing
+            };
+            let name: String = row.get(1)?;
+            Ok(SearchHit {
+                kind: SearchHitKind::Set,
+                id: row.get(0)?,
+                set_name: Some(name.clone()),
+                name,
+                description: row.get(2)?,
+                dat_id: row.get(3)?,
+                dat_name: row.get(4)?,
+                path: None,
+                status,
+                rank: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(hits)
+}
+
+fn search_roms(conn: &Connection, query: &str, limit: i64) -> Result<Vec<SearchHit>> {
+    let mut stmt = conn.prepare(
+        "SELECT e.id, e.name, d.id, d.name, s.name,
+                EXISTS (SELECT 1 FROM matches m WHERE m.dat_entry_id = e.id),
+                dat_entries_fts.rank
+         FROM dat_entries_fts
+         JOIN dat_entries e ON e.id = dat_entries_fts.rowid
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         JOIN dats d ON d.id = v.dat_id
+         LEFT JOIN sets s ON s.id = e.set_id
+         WHERE dat_entries_fts MATCH ?1
+         ORDER BY dat_entries_fts.rank
+         LIMIT ?2",
+    )?;
+    let hits = stmt
+        .query_map(params![query, limit], |row| {
+            let have: bool = row.get(5)?;
+            Ok(SearchHit {
+                kind: SearchHitKind::Rom,
+                id: row.get(0)?,
+                name: row.get(1)?,
+                description: None,
+                dat_id: row.get(2)?,
+                dat_name: row.get(3)?,
+                set_name: row.get(4)?,
+                path: None,
+                status: if have {
+                    SearchStatus::Have
+                } else {
+                    SearchStatus::Missing
+                },
+                rank: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(hits)
+}
+
+fn search_files(conn: &Connection, query: &str, limit: i64) -> Result<Vec<SearchHit>> {
+    // A file can match entries in several DATs; report the first
+    let mut stmt = conn.prepare(
+        "SELECT f.id, f.filename, fp.path, d.id, d.name, s.name, m.id IS NOT NULL,
+                files_fts.rank
+         FROM files_fts
+         JOIN files f ON f.id = files_fts.rowid
+         JOIN file_paths fp ON fp.file_id = f.id
+         LEFT JOIN primary_matches m ON m.file_id = f.id
+         LEFT JOIN dat_entries e ON e.id = m.dat_entry_id
+         LEFT JOIN dat_versions v ON v.id = e.dat_version_id
+         LEFT JOIN dats d ON d.id = v.dat_id
+         LEFT JOIN sets s ON s.id = e.set_id
+         WHERE files_fts MATCH ?1
+         ORDER BY files_fts.rank
+         LIMIT ?2",
+    )?;
+    let hits = stmt
+        .query_map(params![query, limit], |row| {
+            let matched: bool = row.get(6)?;
+            Ok(SearchHit {
+                kind: SearchHitKind::File,
+                id: row.get(0)?,
+                name: row.get(1)?,
+                description: None,
+                dat_id: row.get(3)?,
+                dat_name: row.get(4)?,
+                set_name: row.get(5)?,
+                path: row.get(2)?,
+                status: if matched {
+                    SearchStatus::Have
+                } else {
+                    SearchStatus::Unmatched
+                },
+                rank: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use crate::services::verifier;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'Nintendo - SNES', 'No-Intro', '/d.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 3);
+             INSERT INTO sets (id, dat_version_id, name, description)
+                 VALUES (1, 1, 'Super Metroid (USA)', 'Super Metroid');
+             INSERT INTO sets (id, dat_version_id, name) VALUES (2, 1, 'Super Mario World (USA)');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, sha1)
+                 VALUES (1, 1, 1, 'Super Metroid (USA).sfc', 1, x'01');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size)
+                 VALUES (2, 1, 2, 'Super Mario World (USA).sfc', 1);
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 1, 'snes/Super Metroid (USA).sfc', 'Super Metroid (USA).sfc', 1, 'now', x'01');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+        conn
+    }
+
+    fn kinds(hits: &[SearchHit]) -> Vec<(SearchHitKind, i64)> {
+        let mut kinds: Vec<_> = hits.iter().map(|h| (h.kind, h.id)).collect();
+        kinds.sort_by_key(|(kind, id)| (*kind as u8, *id));
+        kinds
+    }
+
+    #[test]
+    fn test_search_finds_sets_roms_and_files() {
+        let conn = setup();
+
+        let hits = search(&conn, "metro", 50).unwrap();
+        assert_eq!(
+            kinds(&hits),
+            vec![
+                (SearchHitKind::Set, 1),
+                (SearchHitKind::Rom, 1),
+                (SearchHitKind::File, 1)
+            ]
+        );
+        assert!(hits.iter().all(|h| h.status == SearchStatus::Have));
+        let file = hits.iter().find(|h| h.kind == SearchHitKind::File).unwrap();
+        assert_eq!(
+            file.path.as_deref(),
+            Some("/roms/snes/Super Metroid (USA).sfc")
+        );
+        assert_eq!(file.set_name.as_deref(), Some("Super Metroid (USA)"));
+
+        let hits = search(&conn, "mario world", 50).unwrap();
+        assert_eq!(
+            kinds(&hits),
+            vec![(SearchHitKind::Set, 2), (SearchHitKind::Rom, 2)]
+        );
+        assert!(hits.iter().all(|h| h.status == SearchStatus::Missing));
+
+        // Query syntax is treated as text, and blank queries find nothing
+        assert!(search(&conn, "\"NEAR(", 50).unwrap().is_empty());
+        assert!(search(&conn, "   ", 50).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_index_follows_changes() {
+        let conn = setup();
+
+        conn.execute(
+            "UPDATE files SET path = 'snes/Metroid 3.sfc' WHERE id = 1",
+            [],
+        )
+        .unwrap();
+        let hits = search(&conn, "metroid 3", 50).unwrap();
+        assert_eq!(kinds(&hits), vec![(SearchHitKind::File, 1)]);
+
+        // Replacing a row drops the old index entry
+        conn.execute(
+            "INSERT OR REPLACE INTO files (id, root_id, path, filename, size, scanned_at)
+             VALUES (2, 1, 'snes/Metroid 3.sfc', 'Metroid 3.sfc', 2, 'now')",
+            [],
+        )
+        .unwrap();
+        let hits = search(&conn, "metroid 3", 50).unwrap();
+        assert_eq!(kinds(&hits), vec![(SearchHitKind::File, 2)]);
+        let indexed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files_fts WHERE files_fts MATCH 'metroid'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, 1);
+
+        // Cascading deletes remove sets and entries from the index
+        conn.execute("DELETE FROM dats WHERE id = 1", []).unwrap();
+        assert!(search(&conn, "mario", 50).unwrap().is_empty());
+        conn.execute(
+            "INSERT INTO sets_fts (sets_fts) VALUES ('integrity-check')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO files_fts (files_fts) VALUES ('integrity-check')",
+            [],
+        )
+        .unwrap();
+    }
+}
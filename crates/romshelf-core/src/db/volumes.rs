@@ -0,0 +1,370 @@
+//! Volumes - which scan roots are currently reachable
+//!
+//! Each scan root may belong to an identified volume (see [`crate::volume`]).
+//! [`refresh`] checks every root against the filesystem and records whether
+//! it is online; files under offline roots are kept as "have (offline)"
+//! rather than treated as deleted.
+
+use anyhow::{Result, bail};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::path::Path;
+
+use super::roots::ScanRoot;
+use crate::volume::{self, VolumeId};
+
+/// A known volume and its last-seen state
+#[derive(Debug, Serialize, Clone)]
+pub struct Volume {
+    pub id: i64,
+    pub kind: String,
+    pub identifier: String,
+    pub label: String,
+    pub last_mount: Option<String>,
+    pub last_seen_at: Option<String>,
+    pub online: bool,
+    pub root_count: i64,
+    pub file_count: i64,
+}
+
+/// Online state of one scan root
+#[derive(Debug, Serialize, Clone)]
+pub struct RootStatus {
+    pub id: i64,
+    pub path: String,
+    pub volume_id: Option<i64>,
+    pub online: bool,
+    pub last_seen_at: Option<String>,
+    pub file_count: i64,
+}
+
+/// Files and DAT entries only available on offline volumes
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct OfflineSummary {
+    pub files: i64,
+    /// Entries matched by an offline file and by no online one
+    pub entries: i64,
+}
+
+/// Link a root to the volume it lives on, refusing a mismatched volume
+///
+/// Called before scanning: if the root was recorded on one volume and a
+/// different one (or none) is now mounted at its path, scanning would
+/// wrongly treat every file as deleted.
+pub fn attach_root(conn: &Connection, root: &ScanRoot) -> Result<Option<i64>> {
+    let found = volume::identify(Path::new(&root.path));
+    let recorded: Option<i64> = conn.query_row(
+        "SELECT volume_id FROM scan_roots WHERE id = ?1",
+        [root.id],
+        |row| row.get(0),
+    )?;
+
+    if let Some(volume_id) = recorded {
+        let (kind, identifier, label): (String, String, String) = conn.query_row(
+            "SELECT kind, identifier, label FROM volumes WHERE id = ?1",
+            [volume_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        if !matches_volume(found.as_ref(), &kind, &identifier) {
+            bail!(
+                "{} is not on volume '{}' (is the right drive mounted?)",
+                root.path,
+                label
+            );
+        }
+    }
+
+    let Some(found) = found else {
+        return Ok(recorded);
+    };
+    let volume_id = get_or_create_volume(conn, &found)?;
+    conn.execute(
+        "UPDATE scan_roots SET volume_id = ?1 WHERE id = ?2",
+        params![volume_id, root.id],
+    )?;
+    Ok(Some(volume_id))
+}
+
+/// Write a volume marker into `dir` and re-identify the roots beneath it
+///
+/// Roots under `dir` drop any volume they were recorded on (typically a
+/// filesystem UUID) so they pick up the marker instead.
+pub fn mark_volume(conn: &Connection, dir: &Path) -> Result<String> {
+    let id = volume::write_marker(dir)?;
+    let dir = super::roots::normalise_root(dir);
+    for root in super::roots::list_roots(conn)? {
+        if Path::new(&root.path).starts_with(&dir) {
+            conn.execute(
+                "UPDATE scan_roots SET volume_id = NULL WHERE id = ?1",
+                [root.id],
+            )?;
+        }
+    }
+    refresh(conn)?;
+    Ok(id)
+}
+
+/// Re-check every root and volume against the filesystem
+///
+/// Roots without a recorded volume are attached to one if it can now be
+/// identified.
+pub fn refresh(conn: &Connection) -> Result<()> {
+    let now = Utc::now().to_rfc3339();
+    let roots: Vec<(i64, String, Option<i64>)> = {
+        let mut stmt = conn.prepare("SELECT id, path, volume_id FROM scan_roots")?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    for (root_id, path, volume_id) in roots {
+        let found = volume::identify(Path::new(&path));
+        let online = match volume_id {
+            Some(volume_id) => {
+                let (kind, identifier): (String, String) = conn.query_row(
+                    "SELECT kind, identifier FROM volumes WHERE id = ?1",
+                    [volume_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                matches_volume(found.as_ref(), &kind, &identifier)
+            }
+            None => {
+                if let Some(found) = &found {
+                    let volume_id = get_or_create_volume(conn, found)?;
+                    conn.execute(
+                        "UPDATE scan_roots SET volume_id = ?1 WHERE id = ?2",
+                        params![volume_id, root_id],
+                    )?;
+                }
+                Path::new(&path).is_dir()
+            }
+        };
+
+        if online {
+            conn.execute(
+                "UPDATE scan_roots SET online = 1, last_seen_at = ?1 WHERE id = ?2",
+                params![now, root_id],
+            )?;
+            if let Some(found) = &found {
+                conn.execute(
+                    "UPDATE volumes SET last_mount = ?1, last_seen_at = ?2
+                     WHERE kind = ?3 AND identifier = ?4",
+                    params![
+                        found.mount().to_string_lossy().to_string(),
+                        now,
+                        found.kind(),
+                        found.identifier()
+                    ],
+                )?;
+            }
+        } else {
+            conn.execute("UPDATE scan_roots SET online = 0 WHERE id = ?1", [root_id])?;
+        }
+    }
+
+    conn.execute(
+        "UPDATE volumes SET online = EXISTS(
+             SELECT 1 FROM scan_roots r WHERE r.volume_id = volumes.id AND r.online = 1
+         )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// List known volumes
+pub fn list_volumes(conn: &Connection) -> Result<Vec<Volume>> {
+    let mut stmt = conn.prepare(
+        "SELECT v.id, v.kind, v.identifier, v.label, v.last_mount, v.last_seen_at, v.online,
+                (SELECT COUNT(*) FROM scan_roots r WHERE r.volume_id = v.id),
+                (SELECT COUNT(*) FROM files f JOIN scan_roots r ON r.id = f.root_id
+                 WHERE r.volume_id = v.id)
+         FROM volumes v
+         ORDER BY v.label",
+    )?;
+    let volumes = stmt
+        .query_map([], |row| {
+            Ok(Volume {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                identifier: row.get(2)?,
+                label: row.get(3)?,
+                last_mount: row.get(4)?,
+                last_seen_at: row.get(5)?,
+                online: row.get(6)?,
+                root_count: row.get(7)?,
+                file_count: row.get(8)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(volumes)
+}
+
+/// Online state of every scan root
+pub fn list_root_status(conn: &Connection) -> Result<Vec<RootStatus>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.id, r.path, r.volume_id, r.online, r.last_seen_at,
+                (SELECT COUNT(*) FROM files f WHERE f.root_id = r.id)
+         FROM scan_roots r
+         ORDER BY r.path",
+    )?;
+    let roots = stmt
+        .query_map([], |row| {
+            Ok(RootStatus {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                volume_id: row.get(2)?,
+                online: row.get(3)?,
+                last_seen_at: row.get(4)?,
+                file_count: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(roots)
+}
+
+/// Whether a root was reachable at the last [`refresh`]
+pub fn root_online(conn: &Connection, root_id: i64) -> Result<bool> {
+    let online: Option<bool> = conn
+        .query_row(
+            "SELECT online FROM scan_roots WHERE id = ?1",
+            [root_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(online.unwrap_or(false))
+}
+
+/// Count files and DAT entries held only on offline volumes
+pub fn offline_summary(conn: &Connection) -> Result<OfflineSummary> {
+    let files: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM files f
+         JOIN scan_roots r ON r.id = f.root_id
+         WHERE r.online = 0",
+        [],
+        |row| row.get(0),
+    )?;
+    if files == 0 {
+        return Ok(OfflineSummary::default());
+    }
+
+    let entries: i64 = conn.query_row(
+        "SELECT COUNT(DISTINCT m.dat_entry_id) FROM matches m
+         JOIN files f ON f.id = m.file_id
+         JOIN scan_roots r ON r.id = f.root_id
+         WHERE r.online = 0
+           AND NOT EXISTS (
+               SELECT 1 FROM matches m2
+               JOIN files f2 ON f2.id = m2.file_id
+               JOIN scan_roots r2 ON r2.id = f2.root_id
+               WHERE m2.dat_entry_id = m.dat_entry_id AND r2.online = 1
+           )",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(OfflineSummary { files, entries })
+}
+
+fn get_or_create_volume(conn: &Connection, found: &VolumeId) -> Result<i64> {
+    let existing: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM volumes WHERE kind = ?1 AND identifier = ?2",
+            params![found.kind(), found.identifier()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    conn.execute(
+        "INSERT INTO volumes (kind, identifier, label, last_mount, last_seen_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            found.kind(),
+            found.identifier(),
+            found.label(),
+            found.mount().to_string_lossy().to_string(),
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn matches_volume(found: Option<&VolumeId>, kind: &str, identifier: &str) -> bool {
+    found.is_some_and(|f| f.kind() == kind && f.identifier() == identifier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{migrations, roots};
+    use crate::services::verifier;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_unplugged_volume_goes_offline() {
+        let conn = setup();
+        let dir = tempfile::tempdir().unwrap();
+        let drive = dir.path().join("usb");
+        std::fs::create_dir_all(drive.join("roms")).unwrap();
+        volume::write_marker(&drive).unwrap();
+
+        let root = roots::register_root(&conn, &drive.join("roms")).unwrap();
+        let volume_id = attach_root(&conn, &root).unwrap();
+        assert!(volume_id.is_some());
+        conn.execute(
+            "INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+             VALUES (?1, 'a.rom', 'a.rom', 1, 'now', x'01')",
+            [root.id],
+        )
+        .unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'D', 'TOSEC', '/d', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 1);
+             INSERT INTO dat_entries (dat_version_id, name, size, sha1) VALUES (1, 'a.rom', 1, x'01');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+
+        refresh(&conn).unwrap();
+        assert!(root_online(&conn, root.id).unwrap());
+        assert_eq!(offline_summary(&conn).unwrap().files, 0);
+
+        // Unplug: the drive's directory disappears
+        std::fs::rename(&drive, dir.path().join("elsewhere")).unwrap();
+        refresh(&conn).unwrap();
+        assert!(!root_online(&conn, root.id).unwrap());
+        let offline = offline_summary(&conn).unwrap();
+        assert_eq!(offline.files, 1);
+        assert_eq!(offline.entries, 1);
+
+        let volumes = list_volumes(&conn).unwrap();
+        assert_eq!(volumes.len(), 1);
+        assert!(!volumes[0].online);
+        assert!(volumes[0].last_seen_at.is_some());
+    }
+
+    #[test]
+    fn test_different_volume_at_same_path_is_refused() {
+        let conn = setup();
+        let dir = tempfile::tempdir().unwrap();
+        let mount = dir.path().join("mnt");
+        std::fs::create_dir_all(&mount).unwrap();
+        volume::write_marker(&mount).unwrap();
+
+        let root = roots::register_root(&conn, &mount).unwrap();
+        attach_root(&conn, &root).unwrap();
+
+        // Another drive mounted at the same place
+        std::fs::remove_file(mount.join(volume::MARKER_FILE)).unwrap();
+        volume::write_marker(&mount).unwrap();
+
+        assert!(attach_root(&conn, &root).is_err());
+        refresh(&conn).unwrap();
+        assert!(!root_online(&conn, root.id).unwrap());
+    }
+}
@@ -0,0 +1,137 @@
+//! Hash normalisation and conversion between hex strings and stored binary forms
+//!
+//! Hashes travel through the code as lowercase hex strings. In the database,
+//! CRC32 is stored as an INTEGER and MD5/SHA1 as fixed-width BLOBs so that
+//! comparisons are exact and indexes stay compact.
+
+/// Width in bytes of an MD5 digest
+pub const MD5_LEN: usize = 16;
+
+/// Width in bytes of a SHA1 digest
+pub const SHA1_LEN: usize = 20;
+
+/// Normalise a CRC32 hex string to 8 lowercase digits
+///
+/// Accepts upper or lower case and an optional `0x` prefix. Short values are
+/// zero-padded (some DATs drop leading zeros). Returns `None` if the value is
+/// not valid hex or is too long.
+pub fn normalise_crc32(value: &str) -> Option<String> {
+    let trimmed = strip_hex_prefix(value.trim());
+    if trimmed.is_empty() || trimmed.len() > 8 || !is_hex(trimmed) {
+        return None;
+    }
+    Some(format!("{:0>8}", trimmed.to_ascii_lowercase()))
+}
+
+/// Normalise an MD5 hex string to 32 lowercase digits
+pub fn normalise_md5(value: &str) -> Option<String> {
+    normalise_fixed(value, MD5_LEN)
+}
+
+/// Normalise a SHA1 hex string to 40 lowercase digits
+pub fn normalise_sha1(value: &str) -> Option<String> {
+    normalise_fixed(value, SHA1_LEN)
+}
+
+/// Convert a CRC32 hex string into its stored integer form
+pub fn crc32_to_db(value: &str) -> Option<i64> {
+    let normalised = normalise_crc32(value)?;
+    u32::from_str_radix(&normalised, 16).ok().map(i64::from)
+}
+
+/// Convert a stored CRC32 integer back into hex
+pub fn crc32_from_db(value: i64) -> String {
+    format!("{:08x}", value as u32)
+}
+
+/// Convert an MD5 hex string into its stored 16-byte form
+pub fn md5_to_db(value: &str) -> Option<Vec<u8>> {
+    normalise_md5(value).and_then(|hex| hex_to_bytes(&hex))
+}
+
+/// Convert a SHA1 hex string into its stored 20-byte form
+pub fn sha1_to_db(value: &str) -> Option<Vec<u8>> {
+    normalise_sha1(value).and_then(|hex| hex_to_bytes(&hex))
+}
+
+/// Convert stored digest bytes back into lowercase hex
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a hex string into bytes
+pub fn hex_to_bytes(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !is_hex(value) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn normalise_fixed(value: &str, byte_len: usize) -> Option<String> {
+    let trimmed = strip_hex_prefix(value.trim());
+    if trimmed.len() != byte_len * 2 || !is_hex(trimmed) {
+        return None;
+    }
+    Some(trimmed.to_ascii_lowercase())
+}
+
+fn strip_hex_prefix(value: &str) -> &str {
+    value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value)
+}
+
+fn is_hex(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalise_crc32_case_and_padding() {
+        assert_eq!(normalise_crc32("ABCD1234").as_deref(), Some("abcd1234"));
+        assert_eq!(normalise_crc32("0x1f").as_deref(), Some("0000001f"));
+        assert_eq!(normalise_crc32(" 57F4675D ").as_deref(), Some("57f4675d"));
+        assert_eq!(normalise_crc32("xyz"), None);
+        assert_eq!(normalise_crc32("123456789"), None);
+        assert_eq!(normalise_crc32(""), None);
+    }
+
+    #[test]
+    fn test_normalise_sha1_requires_full_width() {
+        let sha1 = "1EEBDF4FDC9FC7BF283031B93F9AEF3338DE9052";
+        assert_eq!(
+            normalise_sha1(sha1).as_deref(),
+            Some("1eebdf4fdc9fc7bf283031b93f9aef3338de9052")
+        );
+        assert_eq!(normalise_sha1("1eebdf"), None);
+        assert_eq!(normalise_md5(sha1), None);
+    }
+
+    #[test]
+    fn test_crc32_db_round_trip() {
+        let stored = crc32_to_db("FFFFFFFF").unwrap();
+        assert_eq!(stored, 0xffff_ffff);
+        assert_eq!(crc32_from_db(stored), "ffffffff");
+        assert_eq!(crc32_from_db(crc32_to_db("0000001f").unwrap()), "0000001f");
+    }
+
+    #[test]
+    fn test_digest_db_round_trip() {
+        let md5 = "9473FDD0D880A43C21B7778D34872157";
+        let bytes = md5_to_db(md5).unwrap();
+        assert_eq!(bytes.len(), MD5_LEN);
+        assert_eq!(bytes_to_hex(&bytes), md5.to_ascii_lowercase());
+
+        let sha1 = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        let bytes = sha1_to_db(sha1).unwrap();
+        assert_eq!(bytes.len(), SHA1_LEN);
+        assert_eq!(bytes_to_hex(&bytes), sha1);
+    }
+}
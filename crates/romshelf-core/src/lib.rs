@@ -4,7 +4,12 @@
 
 pub mod dat;
 pub mod db;
+pub mod hash;
+pub mod profile;
+pub mod report;
 pub mod scan;
 pub mod services;
+pub mod torrentzip;
 pub mod tosec;
 pub mod verify;
+pub mod volume;
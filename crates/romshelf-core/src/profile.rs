@@ -0,0 +1,240 @@
+//! Profiles - named libraries, each with its own database
+//!
+//! The registry lives in `~/.romshelf/profiles.json` and maps profile names
+//! to database paths. The `default` profile always exists and points at
+//! `~/.romshelf/romshelf.db` unless the registry overrides it.
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+
+/// Name of the built-in profile
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A profile as stored in the registry
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub db_path: PathBuf,
+}
+
+/// A profile as listed to users
+#[derive(Debug, Serialize, Clone)]
+pub struct ProfileSummary {
+    pub name: String,
+    pub db_path: PathBuf,
+    pub active: bool,
+}
+
+/// The set of known profiles and which one is active
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProfileRegistry {
+    #[serde(default)]
+    active: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    #[serde(skip)]
+    location: PathBuf,
+    #[serde(skip)]
+    default_db: PathBuf,
+}
+
+impl ProfileRegistry {
+    /// Load the registry from `~/.romshelf/profiles.json`
+    pub fn load() -> Result<Self> {
+        let dir = db::config_dir()?;
+        Self::load_from(&dir.join("profiles.json"), &db::default_db_path()?)
+    }
+
+    /// Load a registry file, treating a missing file as empty
+    ///
+    /// `default_db` is the database used by the `default` profile when the
+    /// registry doesn't override it.
+    pub fn load_from(location: &Path, default_db: &Path) -> Result<Self> {
+        let mut registry: Self = match std::fs::read_to_string(location) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| anyhow!("Invalid profile registry {}: {}", location.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        registry.location = location.to_path_buf();
+        registry.default_db = default_db.to_path_buf();
+        Ok(registry)
+    }
+
+    /// Write the registry back to where it was loaded from
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(&self.location, text)?;
+        Ok(())
+    }
+
+    /// Name of the active profile
+    pub fn active(&self) -> &str {
+        self.active.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// All profiles, including the built-in default
+    pub fn list(&self) -> Vec<ProfileSummary> {
+        let mut summaries = Vec::new();
+        if !self.profiles.contains_key(DEFAULT_PROFILE) {
+            summaries.push(ProfileSummary {
+                name: DEFAULT_PROFILE.to_string(),
+                db_path: self.default_db.clone(),
+                active: self.active() == DEFAULT_PROFILE,
+            });
+        }
+        for (name, profile) in &self.profiles {
+            summaries.push(ProfileSummary {
+                name: name.clone(),
+                db_path: profile.db_path.clone(),
+                active: self.active() == name,
+            });
+        }
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    /// Database path for a named profile
+    pub fn db_path(&self, name: &str) -> Result<PathBuf> {
+        if let Some(profile) = self.profiles.get(name) {
+            return Ok(profile.db_path.clone());
+        }
+        if name == DEFAULT_PROFILE {
+            return Ok(self.default_db.clone());
+        }
+        Err(anyhow!("Unknown profile: {}", name))
+    }
+
+    /// Database path for the named profile, or the active one if `None`
+    pub fn resolve(&self, name: Option<&str>) -> Result<PathBuf> {
+        self.db_path(name.unwrap_or(self.active()))
+    }
+
+    /// Register a new profile
+    ///
+    /// Without an explicit path the database goes in
+    /// `~/.romshelf/profiles/<name>.db` (next to the registry file).
+    pub fn add(&mut self, name: &str, db_path: Option<&Path>) -> Result<PathBuf> {
+        validate_name(name)?;
+        if self.profiles.contains_key(name) || name == DEFAULT_PROFILE {
+            bail!("Profile already exists: {}", name);
+        }
+        let db_path = match db_path {
+            Some(path) => path.to_path_buf(),
+            None => self
+                .location
+                .parent()
+                .unwrap_or(Path::new("."))
+                .join("profiles")
+                .join(format!("{}.db", name)),
+        };
+        self.profiles.insert(
+            name.to_string(),
+            Profile {
+                db_path: db_path.clone(),
+            },
+        );
+        Ok(db_path)
+    }
+
+    /// Forget a profile (its database file is left in place)
+    pub fn remove(&mut self, name: &str) -> Result<Profile> {
+        if name == DEFAULT_PROFILE {
+            bail!("The default profile cannot be removed");
+        }
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| anyhow!("Unknown profile: {}", name))?;
+        if self.active.as_deref() == Some(name) {
+            self.active = None;
+        }
+        Ok(profile)
+    }
+
+    /// Make a profile the one used when none is named
+    pub fn set_active(&mut self, name: &str) -> Result<()> {
+        self.db_path(name)?;
+        self.active = if name == DEFAULT_PROFILE {
+            None
+        } else {
+            Some(name.to_string())
+        };
+        Ok(())
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!(
+            "Invalid profile name '{}': use letters, digits, '-' and '_'",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("profiles.json");
+        let default_db = dir.path().join("romshelf.db");
+
+        let mut registry = ProfileRegistry::load_from(&location, &default_db).unwrap();
+        assert_eq!(registry.active(), DEFAULT_PROFILE);
+        assert_eq!(registry.resolve(None).unwrap(), default_db);
+
+        let arcade = registry.add("arcade", None).unwrap();
+        assert_eq!(arcade, dir.path().join("profiles").join("arcade.db"));
+        registry
+            .add("console", Some(Path::new("/nas/console.db")))
+            .unwrap();
+        registry.set_active("console").unwrap();
+        registry.save().unwrap();
+
+        let registry = ProfileRegistry::load_from(&location, &default_db).unwrap();
+        assert_eq!(registry.active(), "console");
+        assert_eq!(
+            registry.resolve(None).unwrap(),
+            PathBuf::from("/nas/console.db")
+        );
+        assert_eq!(registry.resolve(Some("arcade")).unwrap(), arcade);
+        let names: Vec<_> = registry.list().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["arcade", "console", "default"]);
+    }
+
+    #[test]
+    fn test_registry_rejects_bad_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = ProfileRegistry::load_from(
+            &dir.path().join("profiles.json"),
+            &dir.path().join("romshelf.db"),
+        )
+        .unwrap();
+
+        assert!(registry.add("default", None).is_err());
+        assert!(registry.add("../escape", None).is_err());
+        assert!(registry.set_active("missing").is_err());
+        assert!(registry.remove(DEFAULT_PROFILE).is_err());
+
+        registry.add("test", None).unwrap();
+        assert!(registry.add("test", None).is_err());
+        registry.set_active("test").unwrap();
+        registry.remove("test").unwrap();
+        assert_eq!(registry.active(), DEFAULT_PROFILE);
+    }
+}
@@ -0,0 +1,66 @@
+//! CSV export: one row per ROM, then one per unmatched file
+
+use super::VerifyReport;
+use anyhow::Result;
+use std::io::Write;
+
+const HEADER: &str = "dat,set,rom,size,crc32,md5,sha1,status,files,note";
+
+pub(super) fn write(report: &VerifyReport, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "{}", HEADER)?;
+    for dat in &report.dats {
+        for set in &dat.sets {
+            for rom in &set.roms {
+                write_row(
+                    out,
+                    &[
+                        &dat.name,
+                        &set.name,
+                        &rom.name,
+                        &rom.size.to_string(),
+                        rom.crc32.as_deref().unwrap_or_default(),
+                        rom.md5.as_deref().unwrap_or_default(),
+                        rom.sha1.as_deref().unwrap_or_default(),
+                        rom.status.as_str(),
+                        &rom.files.join(";"),
+                        "",
+                    ],
+                )?;
+            }
+        }
+    }
+    for file in &report.unmatched {
+        let note = file
+            .near_miss
+            .as_ref()
+            .map(|n| {
+                format!(
+                    "likely {} in {}: {}",
+                    n.entry_name,
+                    n.dat_name,
+                    n.describe()
+                )
+            })
+            .unwrap_or_default();
+        write_row(
+            out,
+            &["", "", "", "", "", "", "", "unmatched", &file.path, &note],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_row(out: &mut impl Write, fields: &[&str]) -> Result<()> {
+    let row: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+    writeln!(out, "{}", row.join(","))?;
+    Ok(())
+}
+
+/// Quote a field if it holds a separator, quote or line break (RFC 4180)
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,106 @@
+//! HTML export: one self-contained page, no scripts or external assets
+
+use super::{VerifyReport, percent};
+use anyhow::Result;
+use std::io::Write;
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}
+table{border-collapse:collapse;margin:1em 0}
+th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left}
+td.n{text-align:right}
+.complete{color:#2a7a2a}.partial{color:#b07000}.missing,.misnamed{color:#b02020}
+details{margin:.3em 0}summary{cursor:pointer}
+ul{margin:.3em 0}";
+
+pub(super) fn write(report: &VerifyReport, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\"><head><meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Verification report</title>")?;
+    writeln!(out, "<style>{}</style></head><body>", STYLE)?;
+    writeln!(out, "<h1>Verification report</h1>")?;
+    writeln!(out, "<p>Generated {}</p>", escape(&report.generated_at))?;
+
+    writeln!(
+        out,
+        "<table><tr><th>DAT</th><th>ROMs</th><th>Verified</th><th>Misnamed</th>\
+         <th>Missing</th><th>Complete sets</th></tr>"
+    )?;
+    for dat in &report.dats {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{} ({:.1}%)</td>\
+             <td class=\"n\">{}</td><td class=\"n\">{}</td><td class=\"n\">{} / {}</td></tr>",
+            escape(&dat.name),
+            dat.total,
+            dat.verified,
+            percent(dat.verified, dat.total),
+            dat.misnamed,
+            dat.missing,
+            dat.complete_sets(),
+            dat.sets.len()
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    for dat in &report.dats {
+        writeln!(out, "<h2>{}</h2>", escape(&dat.name))?;
+        for set in dat.sets_with_issues() {
+            writeln!(
+                out,
+                "<details><summary>{} <span class=\"{status}\">{status}</span> {}/{}</summary><ul>",
+                escape(&set.name),
+                set.have,
+                set.total,
+                status = set.status.as_str()
+            )?;
+            for rom in set.issues() {
+                write!(
+                    out,
+                    "<li>{} <span class=\"{status}\">{status}</span>",
+                    escape(&rom.name),
+                    status = rom.status.as_str()
+                )?;
+                for file in &rom.files {
+                    write!(out, " <code>{}</code>", escape(file))?;
+                }
+                writeln!(out, "</li>")?;
+            }
+            writeln!(out, "</ul></details>")?;
+        }
+    }
+
+    if !report.unmatched.is_empty() {
+        writeln!(out, "<h2>Unmatched files</h2><ul>")?;
+        for file in &report.unmatched {
+            write!(out, "<li><code>{}</code>", escape(&file.path))?;
+            if let Some(n) = &file.near_miss {
+                write!(
+                    out,
+                    " likely {} in {}: {}",
+                    escape(&n.entry_name),
+                    escape(&n.dat_name),
+                    escape(&n.describe())
+                )?;
+            }
+            writeln!(out, "</li>")?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+    writeln!(out, "</body></html>")?;
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
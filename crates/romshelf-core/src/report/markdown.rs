@@ -0,0 +1,93 @@
+//! Markdown export: a summary table, then the sets that need attention
+
+use super::{VerifyReport, percent};
+use anyhow::Result;
+use std::io::Write;
+
+pub(super) fn write(report: &VerifyReport, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "# Verification report")?;
+    writeln!(out)?;
+    writeln!(out, "Generated {}", report.generated_at)?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "| DAT | ROMs | Verified | Misnamed | Missing | Complete sets |"
+    )?;
+    writeln!(out, "| --- | ---: | ---: | ---: | ---: | ---: |")?;
+    for dat in &report.dats {
+        writeln!(
+            out,
+            "| {} | {} | {} | {} | {} | {} / {} |",
+            cell(&dat.name),
+            dat.total,
+            dat.verified,
+            dat.misnamed,
+            dat.missing,
+            dat.complete_sets(),
+            dat.sets.len()
+        )?;
+    }
+
+    for dat in &report.dats {
+        writeln!(out)?;
+        writeln!(out, "## {}", dat.name)?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:.1}% verified, {} of {} sets complete.",
+            percent(dat.verified, dat.total),
+            dat.complete_sets(),
+            dat.sets.len()
+        )?;
+        for set in dat.sets_with_issues() {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "### {} ({}, {}/{})",
+                set.name,
+                set.status.as_str(),
+                set.have,
+                set.total
+            )?;
+            writeln!(out)?;
+            for rom in set.issues() {
+                if rom.files.is_empty() {
+                    writeln!(out, "- {}: {}", rom.name, rom.status.as_str())?;
+                } else {
+                    writeln!(
+                        out,
+                        "- {}: {} ({})",
+                        rom.name,
+                        rom.status.as_str(),
+                        rom.files.join(", ")
+                    )?;
+                }
+            }
+        }
+    }
+
+    if !report.unmatched.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "## Unmatched files")?;
+        writeln!(out)?;
+        for file in &report.unmatched {
+            match &file.near_miss {
+                Some(n) => writeln!(
+                    out,
+                    "- {} (likely {} in {}: {})",
+                    file.path,
+                    n.entry_name,
+                    n.dat_name,
+                    n.describe()
+                )?,
+                None => writeln!(out, "- {}", file.path)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Table cell text; pipes would end the cell
+fn cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
@@ -0,0 +1,409 @@
+//! Verification reports - the `verify` results as data, and exporters
+//!
+//! [`build`] collects per-DAT totals, per-set status and per-ROM detail from
+//! the `matches` table, plus the files that match nothing, for a verifier
+//! [`Scope`]. The report serialises as-is to JSON; [`write`] also renders it
+//! as CSV (one row per ROM or unmatched file), Markdown or a self-contained
+//! HTML page. Markdown and HTML list only the sets and ROMs that need
+//! attention.
+
+mod csv;
+mod html;
+mod markdown;
+
+use crate::hash;
+use crate::services::audit::{self, SetStatus};
+use crate::services::diagnostics::{self, NearMiss};
+use crate::services::verifier::{self, Scope};
+use anyhow::{Result, bail};
+use chrono::Utc;
+use rusqlite::{Connection, params_from_iter};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Everything `verify` knows, for one library
+#[derive(Debug, Serialize, Clone)]
+pub struct VerifyReport {
+    /// RFC 3339 timestamp
+    pub generated_at: String,
+    pub dats: Vec<DatReport>,
+    /// Files that match no DAT entry
+    pub unmatched: Vec<UnmatchedReport>,
+}
+
+/// Totals and sets of one DAT
+#[derive(Debug, Serialize, Clone)]
+pub struct DatReport {
+    pub dat_id: i64,
+    pub name: String,
+    pub total: i64,
+    pub verified: i64,
+    pub misnamed: i64,
+    pub missing: i64,
+    /// ROMs only present on unmounted volumes
+    pub offline: i64,
+    pub sets: Vec<SetReport>,
+}
+
+/// One set and its ROMs; ROMs outside any set are grouped under no ID
+#[derive(Debug, Serialize, Clone)]
+pub struct SetReport {
+    pub set_id: Option<i64>,
+    pub name: String,
+    pub status: SetStatus,
+    pub have: i64,
+    pub total: i64,
+    pub roms: Vec<RomReport>,
+}
+
+/// Verification state of one ROM
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RomStatus {
+    /// Matched by a correctly named file
+    Verified,
+    /// Matched, but only by wrongly named files
+    Misnamed,
+    /// No file matches
+    Missing,
+}
+
+impl RomStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RomStatus::Verified => "verified",
+            RomStatus::Misnamed => "misnamed",
+            RomStatus::Missing => "missing",
+        }
+    }
+}
+
+/// One ROM with the files that match it
+#[derive(Debug, Serialize, Clone)]
+pub struct RomReport {
+    pub entry_id: i64,
+    pub name: String,
+    pub size: i64,
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub status: RomStatus,
+    /// Absolute paths of matching files
+    pub files: Vec<String>,
+}
+
+/// A file that matches nothing, with its likely DAT entry if there is one
+#[derive(Debug, Serialize, Clone)]
+pub struct UnmatchedReport {
+    pub file_id: i64,
+    pub path: String,
+    pub near_miss: Option<NearMiss>,
+}
+
+/// Output formats of [`write`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Markdown,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            other => bail!(
+                "Unknown report format '{}' (expected json, csv, markdown or html)",
+                other
+            ),
+        }
+    }
+}
+
+/// Build the report for a scope, ordered by DAT, set and ROM name
+///
+/// Set status follows `verify --sets` (merge modes included) unless the
+/// scope has a path; then it counts only the files under that path.
+pub fn build(conn: &Connection, scope: &Scope) -> Result<VerifyReport> {
+    let set_status: HashMap<i64, (SetStatus, i64)> = if scope.path.is_none() {
+        audit::audit_sets(conn, None)?
+            .into_iter()
+            .map(|set| (set.set_id, (set.status, set.have)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let mut dats: Vec<DatReport> = verifier::summarise_in(conn, scope)?
+        .into_iter()
+        .map(|dat| DatReport {
+            dat_id: dat.dat_id,
+            name: dat.name,
+            total: dat.total,
+            verified: dat.verified,
+            misnamed: dat.misnamed,
+            missing: dat.missing,
+            offline: dat.offline,
+            sets: Vec::new(),
+        })
+        .collect();
+    let positions: HashMap<i64, usize> = dats
+        .iter()
+        .enumerate()
+        .map(|(idx, dat)| (dat.dat_id, idx))
+        .collect();
+
+    // DATs outside the scope are skipped below, as they aren't in `positions`
+    let mut stmt = conn.prepare(&format!(
+        "SELECT v.dat_id, e.set_id, COALESCE(s.name, ''), e.id, e.name, e.size,
+                e.crc32, e.md5, e.sha1, CASE WHEN p.path IS NOT NULL THEN m.name_correct END, p.path
+         FROM dat_entries e
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         LEFT JOIN sets s ON s.id = e.set_id
+         LEFT JOIN matches m ON m.dat_entry_id = e.id
+         LEFT JOIN file_paths p ON p.file_id = m.file_id AND {}
+         ORDER BY v.dat_id, s.name, e.set_id, e.name, e.id, p.path",
+        scope.path_sql("p.path")
+    ))?;
+    let mut rows = stmt.query(params_from_iter(scope.params()))?;
+    while let Some(row) = rows.next()? {
+        let Some(&dat_idx) = positions.get(&row.get::<_, i64>(0)?) else {
+            continue;
+        };
+        let sets = &mut dats[dat_idx].sets;
+        let set_id: Option<i64> = row.get(1)?;
+        if sets.last().is_none_or(|set| set.set_id != set_id) {
+            sets.push(SetReport {
+                set_id,
+                name: row.get(2)?,
+                status: SetStatus::Missing,
+                have: 0,
+                total: 0,
+                roms: Vec::new(),
+            });
+        }
+        let set = sets.last_mut().expect("set pushed above");
+
+        let entry_id: i64 = row.get(3)?;
+        if set.roms.last().is_none_or(|rom| rom.entry_id != entry_id) {
+            set.roms.push(RomReport {
+                entry_id,
+                name: row.get(4)?,
+                size: row.get(5)?,
+                crc32: row.get::<_, Option<i64>>(6)?.map(hash::crc32_from_db),
+                md5: row
+                    .get::<_, Option<Vec<u8>>>(7)?
+                    .map(|b| hash::bytes_to_hex(&b)),
+                sha1: row
+                    .get::<_, Option<Vec<u8>>>(8)?
+                    .map(|b| hash::bytes_to_hex(&b)),
+                status: RomStatus::Missing,
+                files: Vec::new(),
+            });
+        }
+        let rom = set.roms.last_mut().expect("ROM pushed above");
+        if let Some(name_correct) = row.get::<_, Option<bool>>(9)? {
+            if name_correct {
+                rom.status = RomStatus::Verified;
+            } else if rom.status == RomStatus::Missing {
+                rom.status = RomStatus::Misnamed;
+            }
+        }
+        if let Some(path) = row.get::<_, Option<String>>(10)? {
+            rom.files.push(path);
+        }
+    }
+
+    for set in dats.iter_mut().flat_map(|dat| dat.sets.iter_mut()) {
+        set.total = set.roms.len() as i64;
+        (set.status, set.have) = match set.set_id.and_then(|id| set_status.get(&id)) {
+            Some(&(status, have)) => (status, have),
+            None => {
+                let have = set
+                    .roms
+                    .iter()
+                    .filter(|rom| rom.status != RomStatus::Missing)
+                    .count() as i64;
+                let status = match have {
+                    0 => SetStatus::Missing,
+                    h if h == set.total => SetStatus::Complete,
+                    _ => SetStatus::Partial,
+                };
+                (status, have)
+            }
+        };
+    }
+
+    let unmatched = if scope.includes_unmatched() {
+        verifier::unmatched_files_in(conn, scope)?
+    } else {
+        Vec::new()
+    };
+    let mut near_misses: HashMap<i64, NearMiss> = if unmatched.is_empty() {
+        HashMap::new()
+    } else {
+        diagnostics::diagnose_unmatched(conn)?
+            .into_iter()
+            .map(|near_miss| (near_miss.file_id, near_miss))
+            .collect()
+    };
+    let unmatched = unmatched
+        .into_iter()
+        .map(|file| UnmatchedReport {
+            near_miss: near_misses.remove(&file.file_id),
+            file_id: file.file_id,
+            path: file.path,
+        })
+        .collect();
+
+    Ok(VerifyReport {
+        generated_at: Utc::now().to_rfc3339(),
+        dats,
+        unmatched,
+    })
+}
+
+/// Render a report in the given format
+pub fn write(report: &VerifyReport, format: Format, out: &mut impl Write) -> Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)?;
+        }
+        Format::Csv => csv::write(report, out)?,
+        Format::Markdown => markdown::write(report, out)?,
+        Format::Html => html::write(report, out)?,
+    }
+    Ok(())
+}
+
+impl SetReport {
+    /// ROMs that are missing or misnamed
+    fn issues(&self) -> impl Iterator<Item = &RomReport> {
+        self.roms
+            .iter()
+            .filter(|rom| rom.status != RomStatus::Verified)
+    }
+}
+
+impl DatReport {
+    /// Sets with at least one missing or misnamed ROM
+    fn sets_with_issues(&self) -> impl Iterator<Item = &SetReport> {
+        self.sets.iter().filter(|set| set.issues().next().is_some())
+    }
+
+    fn complete_sets(&self) -> usize {
+        self.sets
+            .iter()
+            .filter(|set| set.status == SetStatus::Complete)
+            .count()
+    }
+}
+
+/// Percentage for display, 0 when there is nothing to count
+fn percent(part: i64, total: i64) -> f64 {
+    if total > 0 {
+        part as f64 / total as f64 * 100.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'Test <DAT>', 'TOSEC', '/t.dat', 't');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 3);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'Game, The'), (2, 1, 'Other');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, crc32, sha1) VALUES
+                 (1, 1, 1, 'a.rom', 1, 1, x'01'),
+                 (2, 1, 1, 'b.rom', 2, 2, x'02'),
+                 (3, 1, 2, 'c.rom', 3, 3, x'03');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, crc32, sha1) VALUES
+                 (1, 1, 'a.rom', 'a.rom', 1, 'now', 1, x'01'),
+                 (2, 1, 'wrong.rom', 'wrong.rom', 2, 'now', 2, x'02'),
+                 (3, 1, 'junk.bin', 'junk.bin', 9, 'now', 9, x'09');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_build_report() {
+        let conn = setup();
+        let report = build(&conn, &Scope::default()).unwrap();
+        assert_eq!(report.dats.len(), 1);
+        let dat = &report.dats[0];
+        assert_eq!(
+            (dat.total, dat.verified, dat.misnamed, dat.missing),
+            (3, 1, 1, 1)
+        );
+        let sets: Vec<_> = dat
+            .sets
+            .iter()
+            .map(|s| (s.name.as_str(), s.status, s.have))
+            .collect();
+        assert_eq!(
+            sets,
+            vec![
+                ("Game, The", SetStatus::Complete, 2),
+                ("Other", SetStatus::Missing, 0)
+            ]
+        );
+        let roms: Vec<_> = dat.sets[0].roms.iter().map(|r| r.status).collect();
+        assert_eq!(roms, vec![RomStatus::Verified, RomStatus::Misnamed]);
+        assert_eq!(dat.sets[0].roms[0].files, vec!["/roms/a.rom"]);
+        assert_eq!(dat.sets[0].roms[0].crc32.as_deref(), Some("00000001"));
+        assert_eq!(report.unmatched.len(), 1);
+        assert_eq!(report.unmatched[0].path, "/roms/junk.bin");
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let conn = setup();
+        let report = build(&conn, &Scope::default()).unwrap();
+        let render = |format| {
+            let mut out = Vec::new();
+            write(&report, format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&render(Format::Json)).unwrap();
+        assert_eq!(json["dats"][0]["sets"][1]["status"], "missing");
+
+        let csv = render(Format::Csv);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("dat,set,rom,"));
+        assert!(lines[1].starts_with("Test <DAT>,\"Game, The\",a.rom,1,00000001,"));
+        assert!(lines[4].contains("unmatched,/roms/junk.bin"));
+
+        let markdown = render(Format::Markdown);
+        assert!(markdown.contains("| Test <DAT> | 3 | 1 | 1 | 1 |"));
+        assert!(markdown.contains("- b.rom: misnamed"));
+        assert!(!markdown.contains("a.rom"));
+
+        let html = render(Format::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("Test &lt;DAT&gt;"));
+        assert!(!html.contains("<script"));
+        assert_eq!("md".parse::<Format>().unwrap(), Format::Markdown);
+        assert!("pdf".parse::<Format>().is_err());
+    }
+}
@@ -53,7 +53,8 @@ pub enum ScanOutput {
     Skipped { path: PathBuf },
 }
 
-type SkipPredicate = dyn Fn(&Path, u64, Option<i64>) -> bool + Send + Sync;
+/// Predicate deciding whether a file can be skipped, given its path, size and mtime
+pub type SkipPredicate = dyn Fn(&Path, u64, Option<i64>) -> bool + Send + Sync;
 
 /// Additional configuration for scanning
 #[derive(Clone, Default)]
@@ -185,7 +186,7 @@ impl ScanProgress {
     pub fn get_active_files(&self) -> Vec<FileProgress> {
         if let Ok(active) = self.active_files.lock() {
             let mut files: Vec<_> = active.values().cloned().collect();
-            files.sort_by(|a, b| b.size.cmp(&a.size));
+            files.sort_by_key(|f| std::cmp::Reverse(f.size));
             files
         } else {
             Vec::new()
@@ -370,10 +371,10 @@ fn discover_files(
         if entry.file_type().is_file() {
             let file_path = entry.path().to_path_buf();
             let metadata = if skip_predicate.is_some() {
-                match entry.metadata().or_else(|_| std::fs::metadata(&file_path)) {
-                    Ok(m) => Some(m),
-                    Err(_) => None,
-                }
+                entry
+                    .metadata()
+                    .or_else(|_| std::fs::metadata(&file_path))
+                    .ok()
             } else {
                 None
             };
@@ -521,19 +522,27 @@ pub fn scan_directory(path: &Path) -> Result<Vec<ScannedFile>> {
 }
 
 /// Check if a file is a ZIP archive based on extension
-fn is_zip_file(path: &Path) -> bool {
+pub(crate) fn is_zip_file(path: &Path) -> bool {
     path.extension()
         .map(|ext| ext.eq_ignore_ascii_case("zip"))
         .unwrap_or(false)
 }
 
 /// Check if a file is a 7z archive based on extension
-fn is_7z_file(path: &Path) -> bool {
+pub(crate) fn is_7z_file(path: &Path) -> bool {
     path.extension()
         .map(|ext| ext.eq_ignore_ascii_case("7z"))
         .unwrap_or(false)
 }
 
+/// Split an `archive.zip#member` (or `.7z`) path into the archive and the
+/// member; a `#` anywhere else is part of a name
+pub(crate) fn split_archive_member(path: &str) -> Option<(&str, &str)> {
+    path.match_indices('#')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(archive, _)| is_zip_file(Path::new(archive)) || is_7z_file(Path::new(archive)))
+}
+
 /// Scan contents of a ZIP archive with progress tracking
 fn scan_zip_archive_with_progress(
     archive_path: &Path,
@@ -783,6 +792,20 @@ mod tests {
         assert!(!is_7z_file(Path::new("game")));
     }
 
+    #[test]
+    fn test_split_archive_member() {
+        assert_eq!(
+            split_archive_member("set.zip#a.bin"),
+            Some(("set.zip", "a.bin"))
+        );
+        assert_eq!(
+            split_archive_member("Disk #2/Set #1.7z#dir/a #1.bin"),
+            Some(("Disk #2/Set #1.7z", "dir/a #1.bin"))
+        );
+        assert_eq!(split_archive_member("Game #1.bin"), None);
+        assert_eq!(split_archive_member("game.bin"), None);
+    }
+
     #[test]
     fn test_scan_respects_skip_predicate() {
         let dir = tempfile::tempdir().unwrap();
@@ -794,7 +817,7 @@ mod tests {
         let progress = Arc::new(ScanProgress::new());
         let (tx, rx) = crossbeam_channel::unbounded();
         let skip_target = file_b.clone();
-        let skip_predicate: Arc<dyn Fn(&Path, u64, Option<i64>) -> bool + Send + Sync> =
+        let skip_predicate: Arc<SkipPredicate> =
             Arc::new(move |path, _size, _mtime| path == skip_target.as_path());
 
         let result = scan_directory_parallel_with_options(
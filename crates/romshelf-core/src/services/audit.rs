@@ -0,0 +1,623 @@
+//! Set auditing - collection completeness per set rather than per ROM
+//!
+//! A set (game, application, disk) is complete when every one of its ROMs is
+//! matched by a scanned file, partial when some are, and missing when none
+//! are. Built on the `matches` table, so it agrees with `verify`.
+//!
+//! The container audit checks set archives (`set.zip#rom.bin` paths): each
+//! archive should be named after the set its ROMs belong to and hold nothing
+//! else. Both audits honour a DAT's merge mode (see [`merge`]).
+
+use crate::scan::split_archive_member;
+use crate::services::merge::{self, MergeMode};
+use crate::services::verifier::Scope;
+use crate::verify;
+use anyhow::Result;
+use rusqlite::{Connection, params_from_iter};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+/// Completeness of one set
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SetStatus {
+    /// Every ROM is matched by a file
+    Complete,
+    /// Some ROMs are matched
+    Partial,
+    /// No ROM is matched
+    Missing,
+}
+
+impl SetStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SetStatus::Complete => "complete",
+            SetStatus::Partial => "partial",
+            SetStatus::Missing => "missing",
+        }
+    }
+}
+
+/// Audit result for one set
+#[derive(Debug, Serialize, Clone)]
+pub struct SetAudit {
+    pub set_id: i64,
+    pub name: String,
+    pub dat_id: i64,
+    pub dat_name: String,
+    /// ROMs in the set
+    pub total: i64,
+    /// ROMs matched by at least one file
+    pub have: i64,
+    pub status: SetStatus,
+    /// Names of the ROMs with no matching file
+    pub missing_roms: Vec<String>,
+}
+
+/// Set totals for one DAT
+#[derive(Debug, Serialize, Clone)]
+pub struct DatSetSummary {
+    pub dat_id: i64,
+    pub name: String,
+    pub total: i64,
+    pub complete: i64,
+    pub partial: i64,
+    pub missing: i64,
+}
+
+/// Audit every set, or only those of one DAT, ordered by DAT then set name
+pub fn audit_sets(conn: &Connection, dat_id: Option<i64>) -> Result<Vec<SetAudit>> {
+    audit_sets_in(conn, &dat_id.map(Scope::dat).unwrap_or_default())
+}
+
+/// Audit the sets of the scope's DATs, counting only files within it
+///
+/// A ROM counts as present when any file matches it, except in DATs with a
+/// merge mode, where the file must also sit in the archive (or directory)
+/// the mode expects it in. Sets without any ROMs are skipped, as are DATs
+/// with nothing under the path when the scope is only a path.
+pub fn audit_sets_in(conn: &Connection, scope: &Scope) -> Result<Vec<SetAudit>> {
+    let located = located_by_mode(conn, scope)?;
+
+    let touched = if scope.dat_ids.is_none() && scope.path.is_some() {
+        format!(
+            "d.id IN (SELECT mv.dat_id FROM matches mm
+                      JOIN dat_entries me ON me.id = mm.dat_entry_id
+                      JOIN dat_versions mv ON mv.id = me.dat_version_id
+                      WHERE {})",
+            scope.file_sql("mm.file_id")
+        )
+    } else {
+        "1".to_string()
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT s.id, s.name, d.id, d.name, e.id, e.name,
+                EXISTS (SELECT 1 FROM matches m WHERE m.dat_entry_id = e.id AND {})
+         FROM sets s
+         JOIN dat_versions v ON v.id = s.dat_version_id
+         JOIN dats d ON d.id = v.dat_id AND d.enabled = 1
+         JOIN dat_entries e ON e.set_id = s.id
+         WHERE {} AND {}
+         ORDER BY d.name, d.id, s.name, s.id, e.name",
+        scope.file_sql("m.file_id"),
+        scope.dat_sql("d.id"),
+        touched
+    ))?;
+    let mut rows = stmt.query(params_from_iter(scope.params()))?;
+
+    let mut sets: Vec<SetAudit> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let set_id: i64 = row.get(0)?;
+        let dat_id: i64 = row.get(2)?;
+        if sets.last().is_none_or(|set| set.set_id != set_id) {
+            sets.push(SetAudit {
+                set_id,
+                name: row.get(1)?,
+                dat_id,
+                dat_name: row.get(3)?,
+                total: 0,
+                have: 0,
+                status: SetStatus::Missing,
+                missing_roms: Vec::new(),
+            });
+        }
+        let set = sets.last_mut().expect("pushed above");
+        set.total += 1;
+        let have = match located.get(&dat_id) {
+            Some(located) => located.contains(&row.get(4)?),
+            None => row.get(6)?,
+        };
+        if have {
+            set.have += 1;
+        } else {
+            set.missing_roms.push(row.get(5)?);
+        }
+    }
+
+    for set in &mut sets {
+        set.status = if set.have == set.total {
+            SetStatus::Complete
+        } else if set.have > 0 {
+            SetStatus::Partial
+        } else {
+            SetStatus::Missing
+        };
+    }
+    Ok(sets)
+}
+
+/// Complete, partial and missing set counts per DAT of audited sets, in
+/// their order
+pub fn summarise_sets(sets: &[SetAudit]) -> Vec<DatSetSummary> {
+    let mut summaries: Vec<DatSetSummary> = Vec::new();
+    for set in sets {
+        if summaries.last().is_none_or(|dat| dat.dat_id != set.dat_id) {
+            summaries.push(DatSetSummary {
+                dat_id: set.dat_id,
+                name: set.dat_name.clone(),
+                total: 0,
+                complete: 0,
+                partial: 0,
+                missing: 0,
+            });
+        }
+        let dat = summaries.last_mut().expect("pushed above");
+        dat.total += 1;
+        match set.status {
+            SetStatus::Complete => dat.complete += 1,
+            SetStatus::Partial => dat.partial += 1,
+            SetStatus::Missing => dat.missing += 1,
+        }
+    }
+    summaries
+}
+
+/// For each DAT with a merge mode, the entries stored (within the scope)
+/// where the mode expects
+fn located_by_mode(conn: &Connection, scope: &Scope) -> Result<HashMap<i64, HashSet<i64>>> {
+    let mut located = HashMap::new();
+    for (dat_id, mode) in dat_merge_modes(conn, scope)? {
+        if let Some(mode) = mode {
+            let placements = merge::placements(conn, dat_id, Some(mode))?;
+            located.insert(
+                dat_id,
+                merge::located_entries(conn, dat_id, &placements, scope)?,
+            );
+        }
+    }
+    Ok(located)
+}
+
+/// Every enabled DAT in the scope with its merge mode
+fn dat_merge_modes(conn: &Connection, scope: &Scope) -> Result<Vec<(i64, Option<MergeMode>)>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, merge_mode FROM dats WHERE enabled = 1 AND {}",
+        scope.dat_sql("id")
+    ))?;
+    let mut rows = stmt.query([])?;
+    let mut modes = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mode: Option<String> = row.get(1)?;
+        modes.push((row.get(0)?, mode.map(|m| m.parse()).transpose()?));
+    }
+    Ok(modes)
+}
+
+/// Problems with set archives
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct ContainerAudit {
+    pub misnamed_archives: Vec<MisnamedArchive>,
+    pub misplaced_roms: Vec<MisplacedRom>,
+    pub unneeded_files: Vec<UnneededFile>,
+}
+
+/// An archive holding a set's ROMs but not named after it
+#[derive(Debug, Serialize, Clone)]
+pub struct MisnamedArchive {
+    /// Absolute path of the archive
+    pub archive: String,
+    /// The set its contents belong to
+    pub set_name: String,
+    /// The file name it should have
+    pub expected: String,
+}
+
+/// A ROM inside an archive it doesn't belong in
+#[derive(Debug, Serialize, Clone)]
+pub struct MisplacedRom {
+    pub file_id: i64,
+    pub archive: String,
+    /// Path of the ROM inside the archive
+    pub entry: String,
+    /// The set the archive holds
+    pub archive_set: String,
+    /// Archives (by set name) the ROM does belong in
+    pub belongs_to: Vec<String>,
+}
+
+/// A file inside a set archive that matches no DAT entry
+#[derive(Debug, Serialize, Clone)]
+pub struct UnneededFile {
+    pub file_id: i64,
+    pub archive: String,
+    pub entry: String,
+    pub archive_set: String,
+}
+
+/// One file inside an archive and the set archives its ROMs belong in
+struct ArchiveMember {
+    file_id: i64,
+    entry: String,
+    sets: Vec<String>,
+    /// Whether it matches a ROM of a DAT outside the scope
+    elsewhere: bool,
+}
+
+/// Check every scanned archive against the sets its contents match
+pub fn audit_containers(conn: &Connection) -> Result<ContainerAudit> {
+    audit_containers_in(conn, &Scope::default())
+}
+
+/// Check the archives within the scope against the sets of its DATs
+///
+/// Files matching only other DATs' ROMs are left out rather than reported
+/// as unneeded.
+///
+/// Where a ROM belongs follows each DAT's merge mode: in a split DAT a
+/// parent's ROM belongs in the parent's archive only, in a merged DAT a
+/// clone's ROMs belong in the parent's archive, and so on.
+///
+/// An archive's set is the one named like the archive if any of its files
+/// belong there, otherwise the set most of its files belong in. Archives
+/// whose files match nothing are not set archives and are skipped.
+pub fn audit_containers_in(conn: &Connection, scope: &Scope) -> Result<ContainerAudit> {
+    let mut homes: HashMap<i64, String> = HashMap::new();
+    for (dat_id, mode) in dat_merge_modes(conn, scope)? {
+        for placement in merge::placements(conn, dat_id, mode)? {
+            if placement.own {
+                homes.insert(placement.entry_id, placement.archive);
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT f.id, f.path, p.path, m.dat_entry_id, v.dat_id
+         FROM files f
+         JOIN file_paths p ON p.file_id = f.id
+         LEFT JOIN matches m ON m.file_id = f.id
+         LEFT JOIN dat_entries e ON e.id = m.dat_entry_id
+         LEFT JOIN dat_versions v ON v.id = e.dat_version_id
+         WHERE instr(f.path, '#') > 0 AND {}
+         ORDER BY p.path, f.id",
+        scope.path_sql("p.path")
+    ))?;
+    let mut rows = stmt.query(params_from_iter(scope.params()))?;
+
+    // Split on the root-relative path so a '#' in the root itself is harmless
+    let mut archives: BTreeMap<String, Vec<ArchiveMember>> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let file_id: i64 = row.get(0)?;
+        let relative: String = row.get(1)?;
+        let absolute: String = row.get(2)?;
+        let entry_id: Option<i64> = row.get(3)?;
+        let dat_id: Option<i64> = row.get(4)?;
+
+        let Some((container, entry)) = split_archive_member(&relative) else {
+            continue;
+        };
+        let archive = format!(
+            "{}{}",
+            &absolute[..absolute.len() - relative.len()],
+            container
+        );
+        let members = archives.entry(archive).or_default();
+        if members.last().is_none_or(|m| m.file_id != file_id) {
+            members.push(ArchiveMember {
+                file_id,
+                entry: entry.to_string(),
+                sets: Vec::new(),
+                elsewhere: false,
+            });
+        }
+        let member = members.last_mut().expect("pushed above");
+        member.elsewhere |=
+            dat_id.is_some_and(|id| scope.dat_ids.as_ref().is_some_and(|ids| !ids.contains(&id)));
+        if let Some(home) = entry_id.and_then(|id| homes.get(&id))
+            && !member.sets.contains(home)
+        {
+            member.sets.push(home.clone());
+        }
+    }
+
+    let mut audit = ContainerAudit::default();
+    for (archive, members) in archives {
+        let path = Path::new(&archive);
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let Some(archive_set) = archive_set(&stem, &members) else {
+            continue;
+        };
+
+        if !verify::is_name_correct(&stem, &archive_set) {
+            let expected = match path.extension() {
+                Some(ext) => format!("{}.{}", archive_set, ext.to_string_lossy()),
+                None => archive_set.clone(),
+            };
+            audit.misnamed_archives.push(MisnamedArchive {
+                archive: archive.clone(),
+                set_name: archive_set.clone(),
+                expected,
+            });
+        }
+
+        for member in members {
+            if member.sets.is_empty() && member.elsewhere {
+                continue;
+            } else if member.sets.is_empty() {
+                audit.unneeded_files.push(UnneededFile {
+                    file_id: member.file_id,
+                    archive: archive.clone(),
+                    entry: member.entry,
+                    archive_set: archive_set.clone(),
+                });
+            } else if !member.sets.iter().any(|set| set == &archive_set) {
+                audit.misplaced_roms.push(MisplacedRom {
+                    file_id: member.file_id,
+                    archive: archive.clone(),
+                    entry: member.entry,
+                    archive_set: archive_set.clone(),
+                    belongs_to: member.sets,
+                });
+            }
+        }
+    }
+    Ok(audit)
+}
+
+/// The set an archive holds, judged by its name and contents
+fn archive_set(stem: &str, members: &[ArchiveMember]) -> Option<String> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for set in members.iter().flat_map(|m| &m.sets) {
+        *counts.entry(set).or_default() += 1;
+    }
+    if let Some(named) = counts.keys().find(|set| verify::is_name_correct(stem, set)) {
+        return Some(named.to_string());
+    }
+    // max_by_key keeps the last maximum; iterate in reverse so ties go to
+    // the alphabetically first set
+    counts
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(set, _)| set.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use crate::services::verifier;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'Arcade', 'MAME', '/a.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 5);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'complete');
+             INSERT INTO sets (id, dat_version_id, name) VALUES (2, 1, 'partial');
+             INSERT INTO sets (id, dat_version_id, name) VALUES (3, 1, 'missing');
+             INSERT INTO sets (id, dat_version_id, name) VALUES (4, 1, 'empty');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 1, 'a.bin', 1, x'01');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 2, 'b1.bin', 1, x'02');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 2, 'b2.bin', 1, x'03');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 2, 'b3.bin', 1, x'04');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 3, 'c.bin', 1, x'05');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'a.bin', 'a.bin', 1, 'now', x'01');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'renamed.bin', 'renamed.bin', 1, 'now', x'03');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_audit_sets() {
+        let conn = setup();
+
+        let sets = audit_sets(&conn, None).unwrap();
+        let statuses: Vec<_> = sets.iter().map(|s| (s.name.as_str(), s.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("complete", SetStatus::Complete),
+                ("missing", SetStatus::Missing),
+                ("partial", SetStatus::Partial),
+            ]
+        );
+        let partial = &sets[2];
+        assert_eq!((partial.have, partial.total), (1, 3));
+        assert_eq!(partial.missing_roms, vec!["b1.bin", "b3.bin"]);
+
+        assert_eq!(audit_sets(&conn, Some(1)).unwrap().len(), 3);
+        assert!(audit_sets(&conn, Some(2)).unwrap().is_empty());
+
+        // Only files under the path count towards a set
+        let scope = Scope::default().with_path("/roms/renamed.bin");
+        let statuses: Vec<_> = audit_sets_in(&conn, &scope)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.name, s.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("complete".to_string(), SetStatus::Missing),
+                ("missing".to_string(), SetStatus::Missing),
+                ("partial".to_string(), SetStatus::Partial),
+            ]
+        );
+        let scope = Scope::default().with_path("/elsewhere");
+        assert!(audit_sets_in(&conn, &scope).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_summarise_sets() {
+        let conn = setup();
+
+        let summary = summarise_sets(&audit_sets(&conn, None).unwrap());
+        assert_eq!(summary.len(), 1);
+        let dat = &summary[0];
+        assert_eq!(
+            (dat.total, dat.complete, dat.partial, dat.missing),
+            (3, 1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_audit_containers() {
+        let conn = setup();
+        conn.execute_batch(
+            "INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'partial.zip#b1.bin', 'b1.bin', 1, 'now', x'02');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'partial.zip#a.bin', 'a.bin', 1, 'now', x'01');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'partial.zip#readme.txt', 'readme.txt', 9, 'now', x'99');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'sub/Missing (v2).zip#c.bin', 'c.bin', 1, 'now', x'05');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'junk.zip#junk.txt', 'junk.txt', 9, 'now', x'98');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'Game #1.bin', 'Game #1.bin', 1, 'now', x'01');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'Set #1/complete.zip#a.bin', 'a.bin', 1, 'now', x'01');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+
+        let audit = audit_containers(&conn).unwrap();
+
+        assert_eq!(audit.misnamed_archives.len(), 1);
+        let misnamed = &audit.misnamed_archives[0];
+        assert_eq!(misnamed.archive, "/roms/sub/Missing (v2).zip");
+        assert_eq!(misnamed.expected, "missing.zip");
+
+        assert_eq!(audit.misplaced_roms.len(), 1);
+        let misplaced = &audit.misplaced_roms[0];
+        assert_eq!(misplaced.archive, "/roms/partial.zip");
+        assert_eq!(misplaced.entry, "a.bin");
+        assert_eq!(misplaced.archive_set, "partial");
+        assert_eq!(misplaced.belongs_to, vec!["complete"]);
+
+        // A '#' outside an archive path is part of a name: the loose file is
+        // no archive's member, and Set #1/complete.zip is correctly named
+
+        // junk.zip matches nothing, so it isn't a set archive
+        assert_eq!(audit.unneeded_files.len(), 1);
+        assert_eq!(audit.unneeded_files[0].entry, "readme.txt");
+
+        let audit = audit_containers_in(&conn, &Scope::default().with_path("/roms/sub")).unwrap();
+        assert_eq!(audit.misnamed_archives.len(), 1);
+        assert!(audit.misplaced_roms.is_empty());
+        assert!(audit.unneeded_files.is_empty());
+
+        // Another DAT's scope has no sets for these archives to hold
+        let audit = audit_containers_in(&conn, &Scope::dat(2)).unwrap();
+        assert!(audit.misnamed_archives.is_empty());
+        assert!(audit.misplaced_roms.is_empty());
+        assert!(audit.unneeded_files.is_empty());
+    }
+
+    #[test]
+    fn test_audits_follow_merge_mode() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        // A BIOS, a parent using it and a clone, stored as split sets
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'MAME', 'MAME', '/m.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 6);
+             INSERT INTO sets (id, dat_version_id, name, is_bios) VALUES (1, 1, 'bios', 1);
+             INSERT INTO sets (id, dat_version_id, name, romof) VALUES (2, 1, 'parent', 'bios');
+             INSERT INTO sets (id, dat_version_id, name, cloneof, romof) VALUES (3, 1, 'clone', 'parent', 'parent');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 1, 'b.rom', 1, x'01');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 2, 'p.rom', 1, x'02');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1, merge_name) VALUES (1, 2, 'b.rom', 1, x'01', 'b.rom');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES (1, 3, 'c.rom', 1, x'03');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1, merge_name) VALUES (1, 3, 'p.rom', 1, x'02', 'p.rom');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'bios.zip#b.rom', 'b.rom', 1, 'now', x'01');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'parent.zip#p.rom', 'p.rom', 1, 'now', x'02');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 'clone.zip#c.rom', 'c.rom', 1, 'now', x'03');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+
+        let statuses = |conn: &Connection| -> Vec<(String, i64, i64)> {
+            audit_sets(conn, None)
+                .unwrap()
+                .into_iter()
+                .map(|s| (s.name, s.have, s.total))
+                .collect()
+        };
+        let status = |name: &str, have: i64, total: i64| (name.to_string(), have, total);
+
+        // Without a mode every ROM found anywhere counts
+        assert_eq!(
+            statuses(&conn),
+            vec![
+                status("bios", 1, 1),
+                status("clone", 2, 2),
+                status("parent", 2, 2)
+            ]
+        );
+
+        merge::set_merge_mode(&conn, 1, Some(MergeMode::Split)).unwrap();
+        assert_eq!(
+            statuses(&conn),
+            vec![
+                status("bios", 1, 1),
+                status("clone", 2, 2),
+                status("parent", 2, 2)
+            ]
+        );
+        let containers = audit_containers(&conn).unwrap();
+        assert!(containers.misnamed_archives.is_empty());
+        assert!(containers.misplaced_roms.is_empty());
+
+        // Non-merged sets must carry their parent's and BIOS's ROMs
+        merge::set_merge_mode(&conn, 1, Some(MergeMode::NonMerged)).unwrap();
+        assert_eq!(
+            statuses(&conn),
+            vec![
+                status("bios", 1, 1),
+                status("clone", 1, 2),
+                status("parent", 1, 2)
+            ]
+        );
+
+        // Merged: the clone's own ROM belongs in the parent's archive
+        merge::set_merge_mode(&conn, 1, Some(MergeMode::Merged)).unwrap();
+        assert_eq!(
+            statuses(&conn),
+            vec![
+                status("bios", 1, 1),
+                status("clone", 1, 2),
+                status("parent", 2, 2)
+            ]
+        );
+        let containers = audit_containers(&conn).unwrap();
+        assert_eq!(containers.misnamed_archives.len(), 1);
+        assert_eq!(containers.misnamed_archives[0].expected, "parent.zip");
+    }
+}
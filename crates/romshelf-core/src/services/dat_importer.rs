@@ -1,5 +1,7 @@
 use crate::dat::{self, DatEntry, DatHeader, DatSetInfo, DatVisitor};
+use crate::hash;
 use crate::services::progress::{DatImportEvent, ProgressSink};
+use crate::services::verifier;
 use crate::tosec;
 use anyhow::{Context, Result, anyhow};
 use chrono::Utc;
@@ -249,6 +251,7 @@ impl<'conn, 'cb, S: ProgressSink<DatImportEvent>, F: FnMut(DatImportEvent)>
             "UPDATE dat_versions SET entry_count = ?1 WHERE id = ?2",
             params![self.total_entries as i64, dat_version_id],
         )?;
+        verifier::match_dat_version(&self.tx, dat_version_id)?;
 
         let dat_id = self.dat_id.ok_or_else(|| anyhow!("DAT not created"))?;
         let name = self.dat_name.unwrap_or_else(|| "Unknown".to_string());
@@ -290,13 +293,20 @@ impl<'conn, 'cb, S: ProgressSink<DatImportEvent>, F: FnMut(DatImportEvent)>
         Ok(())
     }
 
-    fn insert_set(&mut self, name: &str) -> Result<()> {
+    fn insert_set(&mut self, set: &DatSetInfo) -> Result<()> {
         let dat_version_id = self
             .dat_version_id
             .ok_or_else(|| anyhow!("DAT version not initialised before set"))?;
         self.tx.execute(
-            "INSERT INTO sets (dat_version_id, name) VALUES (?1, ?2)",
-            params![dat_version_id, name],
+            "INSERT INTO sets (dat_version_id, name, cloneof, romof, is_bios)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                dat_version_id,
+                set.name,
+                set.cloneof,
+                set.romof,
+                set.is_bios
+            ],
         )?;
         self.current_set_id = Some(self.tx.last_insert_rowid());
         self.total_sets += 1;
@@ -308,16 +318,17 @@ impl<'conn, 'cb, S: ProgressSink<DatImportEvent>, F: FnMut(DatImportEvent)>
             .dat_version_id
             .ok_or_else(|| anyhow!("DAT version not initialised before ROM"))?;
         self.tx.execute(
-            "INSERT INTO dat_entries (dat_version_id, set_id, name, size, crc32, md5, sha1)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO dat_entries (dat_version_id, set_id, name, size, crc32, md5, sha1, merge_name)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
                 dat_version_id,
                 self.current_set_id,
                 entry.name,
                 entry.size as i64,
-                entry.crc32,
-                entry.md5,
-                entry.sha1,
+                entry.crc32.as_deref().and_then(hash::crc32_to_db),
+                entry.md5.as_deref().and_then(hash::md5_to_db),
+                entry.sha1.as_deref().and_then(hash::sha1_to_db),
+                entry.merge,
             ],
         )?;
         self.total_entries += 1;
@@ -343,7 +354,7 @@ impl<'conn, 'cb, S: ProgressSink<DatImportEvent>, F: FnMut(DatImportEvent)> DatV
     }
 
     fn set_start(&mut self, set: &DatSetInfo) -> Result<()> {
-        self.insert_set(&set.name)?;
+        self.insert_set(set)?;
         let event = DatImportEvent::SetStarted {
             name: set.name.clone(),
             index: self.total_sets,
@@ -353,7 +364,13 @@ impl<'conn, 'cb, S: ProgressSink<DatImportEvent>, F: FnMut(DatImportEvent)> DatV
         Ok(())
     }
 
-    fn set_end(&mut self, _set: &DatSetInfo) -> Result<()> {
+    fn set_end(&mut self, set: &DatSetInfo) -> Result<()> {
+        if let (Some(set_id), Some(description)) = (self.current_set_id, &set.description) {
+            self.tx.execute(
+                "UPDATE sets SET description = ?1 WHERE id = ?2",
+                params![description, set_id],
+            )?;
+        }
         self.current_set_id = None;
         Ok(())
     }
@@ -0,0 +1,285 @@
+//! Near-miss diagnostics - why an unmatched file almost matched
+//!
+//! A file with no match is often close to a DAT entry: same CRC32 and size
+//! but a different SHA1 (a collision or a bad DAT), or the same name with a
+//! size that is off by a copier header or power-of-two padding. Each
+//! unmatched file is classified against the closest such entry.
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Size of the header some cartridge copiers prepend to dumps
+pub const COPIER_HEADER: i64 = 512;
+
+/// How an unmatched file relates to its likely DAT entry, most telling first
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum NearMissKind {
+    /// CRC32 and size agree but another hash differs
+    CrcCollision,
+    /// Same name and size, different content: a bad dump or another revision
+    NameAndSize,
+    /// Same name, exactly one copier header larger
+    CopierHeader,
+    /// Same name, padded up to a power of two
+    Overdump,
+    /// Same name, cut short of a power-of-two size
+    Trimmed,
+}
+
+impl NearMissKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NearMissKind::CrcCollision => "CRC32 collision",
+            NearMissKind::NameAndSize => "bad dump or other revision",
+            NearMissKind::CopierHeader => "copier header",
+            NearMissKind::Overdump => "overdump",
+            NearMissKind::Trimmed => "trimmed",
+        }
+    }
+}
+
+/// An unmatched file and the DAT entry it most likely is
+#[derive(Debug, Serialize, Clone)]
+pub struct NearMiss {
+    pub file_id: i64,
+    pub path: String,
+    pub filename: String,
+    pub size: i64,
+    pub kind: NearMissKind,
+    pub entry_id: i64,
+    pub entry_name: String,
+    pub entry_size: i64,
+    pub set_name: Option<String>,
+    pub dat_name: String,
+}
+
+impl NearMiss {
+    /// Short explanation, e.g. "copier header, 512 bytes larger"
+    pub fn describe(&self) -> String {
+        let diff = self.size - self.entry_size;
+        match diff {
+            0 => self.kind.as_str().to_string(),
+            d if d > 0 => format!("{}, {} bytes larger", self.kind.as_str(), d),
+            d => format!("{}, {} bytes smaller", self.kind.as_str(), -d),
+        }
+    }
+}
+
+/// Hashes and size as stored, for comparing without hex conversion
+struct Content {
+    size: i64,
+    crc32: Option<i64>,
+    md5: Option<Vec<u8>>,
+    sha1: Option<Vec<u8>>,
+}
+
+struct Entry {
+    id: i64,
+    name: String,
+    content: Content,
+    set_name: Option<String>,
+    dat_name: String,
+}
+
+/// Classify every unmatched file that has a likely DAT entry, ordered by path
+///
+/// Files with nothing close are left out.
+pub fn diagnose_unmatched(conn: &Connection) -> Result<Vec<NearMiss>> {
+    let mut stmt = conn.prepare(
+        "SELECT f.id, p.path, f.filename, f.size, f.crc32, f.md5, f.sha1
+         FROM files f
+         JOIN file_paths p ON p.file_id = f.id
+         WHERE NOT EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id)
+         ORDER BY p.path",
+    )?;
+    let files = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                Content {
+                    size: row.get(3)?,
+                    crc32: row.get(4)?,
+                    md5: row.get(5)?,
+                    sha1: row.get(6)?,
+                },
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let entries = load_entries(conn)?;
+    let mut by_crc: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    let mut by_stem: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, entry) in entries.iter().enumerate() {
+        if let Some(crc) = entry.content.crc32 {
+            by_crc
+                .entry((crc, entry.content.size))
+                .or_default()
+                .push(idx);
+        }
+        by_stem.entry(stem(&entry.name)).or_default().push(idx);
+    }
+
+    let mut near_misses = Vec::new();
+    for (file_id, path, filename, content) in files {
+        let collisions = content
+            .crc32
+            .and_then(|crc| by_crc.get(&(crc, content.size)))
+            .into_iter()
+            .flatten()
+            .map(|&idx| (NearMissKind::CrcCollision, idx));
+        let same_name = by_stem
+            .get(&stem(&filename))
+            .into_iter()
+            .flatten()
+            .filter_map(|&idx| classify(&content, &entries[idx].content).map(|kind| (kind, idx)));
+        let Some((kind, idx)) = collisions.chain(same_name).min() else {
+            continue;
+        };
+        let entry = &entries[idx];
+        near_misses.push(NearMiss {
+            file_id,
+            path,
+            filename,
+            size: content.size,
+            kind,
+            entry_id: entry.id,
+            entry_name: entry.name.clone(),
+            entry_size: entry.content.size,
+            set_name: entry.set_name.clone(),
+            dat_name: entry.dat_name.clone(),
+        });
+    }
+    Ok(near_misses)
+}
+
+fn load_entries(conn: &Connection) -> Result<Vec<Entry>> {
+    let mut stmt = conn.prepare(
+        "SELECT e.id, e.name, e.size, e.crc32, e.md5, e.sha1, s.name, d.name
+         FROM dat_entries e
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         JOIN dats d ON d.id = v.dat_id AND d.enabled = 1
+         LEFT JOIN sets s ON s.id = e.set_id
+         ORDER BY e.id",
+    )?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok(Entry {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                content: Content {
+                    size: row.get(2)?,
+                    crc32: row.get(3)?,
+                    md5: row.get(4)?,
+                    sha1: row.get(5)?,
+                },
+                set_name: row.get(6)?,
+                dat_name: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+/// How a file relates to an entry with the same name, judged on size
+fn classify(file: &Content, entry: &Content) -> Option<NearMissKind> {
+    if file.size == entry.size {
+        // Equal hashes would have matched; only report real differences
+        return differs(file, entry).then_some(NearMissKind::NameAndSize);
+    }
+    if file.size == entry.size + COPIER_HEADER {
+        Some(NearMissKind::CopierHeader)
+    } else if file.size > entry.size && is_power_of_two(file.size) {
+        Some(NearMissKind::Overdump)
+    } else if file.size < entry.size && is_power_of_two(entry.size) {
+        Some(NearMissKind::Trimmed)
+    } else {
+        None
+    }
+}
+
+/// Whether any hash present on both sides differs
+fn differs(a: &Content, b: &Content) -> bool {
+    fn ne<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+        matches!((a, b), (Some(a), Some(b)) if a != b)
+    }
+    ne(&a.crc32, &b.crc32) || ne(&a.md5, &b.md5) || ne(&a.sha1, &b.sha1)
+}
+
+fn is_power_of_two(size: i64) -> bool {
+    size > 0 && (size as u64).is_power_of_two()
+}
+
+/// Lowercased name without directories or extension
+fn stem(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    Path::new(base)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use crate::services::verifier;
+
+    #[test]
+    fn test_diagnose_unmatched() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'SNES', 'No-Intro', '/s.dat', 's');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 7);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'Game');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, crc32, sha1) VALUES
+                 (1, 1, 1, 'collide.sfc', 100, 7, x'01'),
+                 (2, 1, 1, 'headered.sfc', 1024, 8, x'02'),
+                 (3, 1, 1, 'over.sfc', 1500, 9, x'03'),
+                 (4, 1, 1, 'trim.sfc', 2048, 10, x'04'),
+                 (5, 1, 1, 'baddump.sfc', 64, 11, x'05'),
+                 (6, 1, 1, 'Other #2.sfc', 64, 12, x'06'),
+                 (7, 1, 1, 'Disc #3.sfc', 64, 13, x'07');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, crc32, sha1) VALUES
+                 (1, 1, 'other.bin', 'other.bin', 100, 'now', 7, x'ff'),
+                 (2, 1, 'Headered.smc', 'Headered.smc', 1536, 'now', 20, x'f2'),
+                 (3, 1, 'over.sfc', 'over.sfc', 2048, 'now', 21, x'f3'),
+                 (4, 1, 'g.zip#trim.sfc', 'trim.sfc', 2000, 'now', 22, x'f4'),
+                 (5, 1, 'baddump.sfc', 'baddump.sfc', 64, 'now', 23, x'f5'),
+                 (6, 1, 'unrelated.txt', 'unrelated.txt', 10, 'now', 24, x'f6'),
+                 (7, 1, 'Disc #2.sfc', 'Disc #2.sfc', 64, 'now', 25, x'f7'),
+                 (8, 1, 'Disc #3.sfc', 'Disc #3.sfc', 64, 'now', 26, x'f8');",
+        )
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+
+        let near_misses = diagnose_unmatched(&conn).unwrap();
+        let found: Vec<_> = near_misses
+            .iter()
+            .map(|n| (n.file_id, n.kind, n.entry_id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (8, NearMissKind::NameAndSize, 7),
+                (2, NearMissKind::CopierHeader, 2),
+                (5, NearMissKind::NameAndSize, 5),
+                (4, NearMissKind::Trimmed, 4),
+                (1, NearMissKind::CrcCollision, 1),
+                (3, NearMissKind::Overdump, 3),
+            ]
+        );
+        assert_eq!(near_misses[1].describe(), "copier header, 512 bytes larger");
+        assert_eq!(near_misses[1].set_name.as_deref(), Some("Game"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Collection history - snapshots of per-DAT completeness over time
+//!
+//! A snapshot is recorded after each scan, verify and batch of DAT imports. It holds
+//! the file count and size of the collection and, per DAT, the ROMs had and
+//! missing and the sets complete, as `verify` and `verify --sets` report them.
+
+use crate::services::{audit, verifier};
+use anyhow::{Result, bail};
+use chrono::Utc;
+use rusqlite::{Connection, Transaction, params};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// What triggered a snapshot
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotEvent {
+    Scan,
+    Verify,
+    Import,
+}
+
+impl SnapshotEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotEvent::Scan => "scan",
+            SnapshotEvent::Verify => "verify",
+            SnapshotEvent::Import => "import",
+        }
+    }
+
+    fn from_db(value: &str) -> Result<Self> {
+        match value {
+            "scan" => Ok(SnapshotEvent::Scan),
+            "verify" => Ok(SnapshotEvent::Verify),
+            "import" => Ok(SnapshotEvent::Import),
+            other => bail!("Unknown snapshot event '{}'", other),
+        }
+    }
+}
+
+/// The collection at one point in time
+#[derive(Debug, Serialize, Clone)]
+pub struct Snapshot {
+    pub id: i64,
+    /// RFC 3339 timestamp
+    pub taken_at: String,
+    pub event: SnapshotEvent,
+    pub file_count: i64,
+    pub total_bytes: i64,
+    pub dats: Vec<DatSnapshot>,
+}
+
+/// One DAT's completeness in a snapshot
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct DatSnapshot {
+    pub dat_id: i64,
+    pub name: String,
+    pub total: i64,
+    pub have: i64,
+    pub missing: i64,
+    pub sets_total: i64,
+    pub sets_complete: i64,
+}
+
+/// Record the current state of the collection; returns the snapshot ID
+///
+/// The snapshot and its per-DAT rows are written in the caller's transaction.
+pub fn record_snapshot(tx: &Transaction, event: SnapshotEvent) -> Result<i64> {
+    let (file_count, total_bytes): (i64, i64) = tx.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM files",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    tx.execute(
+        "INSERT INTO snapshots (taken_at, event, file_count, total_bytes)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+            Utc::now().to_rfc3339(),
+            event.as_str(),
+            file_count,
+            total_bytes
+        ],
+    )?;
+    let snapshot_id = tx.last_insert_rowid();
+
+    let sets: HashMap<i64, (i64, i64)> = audit::summarise_sets(&audit::audit_sets(tx, None)?)
+        .into_iter()
+        .map(|dat| (dat.dat_id, (dat.total, dat.complete)))
+        .collect();
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO snapshot_dats
+             (snapshot_id, dat_id, dat_name, total, have, missing, sets_total, sets_complete)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for dat in verifier::summarise(tx)? {
+        let (sets_total, sets_complete) = sets.get(&dat.dat_id).copied().unwrap_or((0, 0));
+        insert.execute(params![
+            snapshot_id,
+            dat.dat_id,
+            dat.name,
+            dat.total,
+            dat.verified + dat.misnamed,
+            dat.missing,
+            sets_total,
+            sets_complete
+        ])?;
+    }
+    Ok(snapshot_id)
+}
+
+/// Snapshots in time order, optionally for one DAT and from a date on
+///
+/// `since` is compared with the RFC 3339 timestamps, so `2024-05` and
+/// `2024-05-01` both work. With a DAT, snapshots taken before it was
+/// imported are left out.
+pub fn history(
+    conn: &Connection,
+    dat_id: Option<i64>,
+    since: Option<&str>,
+) -> Result<Vec<Snapshot>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.taken_at, s.event, s.file_count, s.total_bytes,
+                d.dat_id, d.dat_name, d.total, d.have, d.missing, d.sets_total, d.sets_complete
+         FROM snapshots s
+         LEFT JOIN snapshot_dats d ON d.snapshot_id = s.id
+         WHERE (?1 IS NULL OR s.taken_at >= ?1)
+           AND (?2 IS NULL OR d.dat_id = ?2)
+         ORDER BY s.taken_at, s.id, d.dat_name, d.dat_id",
+    )?;
+    let mut rows = stmt.query(params![since, dat_id])?;
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        if snapshots.last().is_none_or(|s| s.id != id) {
+            snapshots.push(Snapshot {
+                id,
+                taken_at: row.get(1)?,
+                event: SnapshotEvent::from_db(&row.get::<_, String>(2)?)?,
+                file_count: row.get(3)?,
+                total_bytes: row.get(4)?,
+                dats: Vec::new(),
+            });
+        }
+        if let Some(dat_id) = row.get::<_, Option<i64>>(5)? {
+            let snapshot = snapshots.last_mut().expect("pushed above");
+            snapshot.dats.push(DatSnapshot {
+                dat_id,
+                name: row.get(6)?,
+                total: row.get(7)?,
+                have: row.get(8)?,
+                missing: row.get(9)?,
+                sets_total: row.get(10)?,
+                sets_complete: row.get(11)?,
+            });
+        }
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, migrations};
+
+    fn snapshot(conn: &mut Connection, event: SnapshotEvent) {
+        let tx = conn.transaction().unwrap();
+        record_snapshot(&tx, event).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_snapshots_track_changes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'Test', 'TOSEC', '/t.dat', 't');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 2);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'A'), (2, 1, 'B');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, sha1) VALUES
+                 (1, 1, 1, 'a.rom', 10, x'01'), (2, 1, 2, 'b.rom', 20, x'02');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');",
+        )
+        .unwrap();
+        snapshot(&mut conn, SnapshotEvent::Import);
+
+        conn.execute(
+            "INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+             VALUES (1, 1, 'a.rom', 'a.rom', 10, 'now', x'01')",
+            [],
+        )
+        .unwrap();
+        verifier::match_files(&conn, &[1]).unwrap();
+        snapshot(&mut conn, SnapshotEvent::Scan);
+
+        let snapshots = history(&conn, None, None).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].event, SnapshotEvent::Import);
+        assert_eq!((snapshots[0].file_count, snapshots[0].total_bytes), (0, 0));
+        assert_eq!((snapshots[1].file_count, snapshots[1].total_bytes), (1, 10));
+        assert_eq!(
+            snapshots[1].dats,
+            vec![DatSnapshot {
+                dat_id: 1,
+                name: "Test".to_string(),
+                total: 2,
+                have: 1,
+                missing: 1,
+                sets_total: 2,
+                sets_complete: 1,
+            }]
+        );
+
+        // History outlives the DAT; filters narrow it down
+        db::remove_dat(&conn, 1).unwrap();
+        snapshot(&mut conn, SnapshotEvent::Verify);
+        assert_eq!(history(&conn, Some(1), None).unwrap().len(), 2);
+        assert_eq!(history(&conn, None, None).unwrap().len(), 3);
+        assert!(history(&conn, None, Some("9999")).unwrap().is_empty());
+    }
+}
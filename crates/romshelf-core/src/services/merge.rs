@@ -0,0 +1,388 @@
+//! MAME merge modes - where each ROM of a parent/clone/BIOS family lives
+//!
+//! MAME DATs link sets with `cloneof`/`romof` and mark shared ROMs with
+//! `merge`. How a collection stores those shared ROMs is its merge mode:
+//!
+//! - **split**: each set holds only its own ROMs; ROMs shared with the
+//!   parent or BIOS are found in the parent's or BIOS's archive.
+//! - **merged**: clones have no archive of their own; their ROMs live in
+//!   the parent's archive. BIOS ROMs stay in the BIOS archive.
+//! - **non-merged**: every set holds every ROM it needs, parent and BIOS
+//!   ROMs included.
+//!
+//! A DAT without a merge mode is audited set by set with no borrowing.
+
+use crate::scan::split_archive_member;
+use crate::services::verifier::Scope;
+use anyhow::{Result, bail};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Longest `romof`/`cloneof` chain followed before assuming a cycle
+const MAX_CHAIN: usize = 8;
+
+/// How a collection stores ROMs shared between related sets
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeMode {
+    Split,
+    Merged,
+    NonMerged,
+}
+
+impl MergeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MergeMode::Split => "split",
+            MergeMode::Merged => "merged",
+            MergeMode::NonMerged => "non-merged",
+        }
+    }
+}
+
+impl Display for MergeMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MergeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "split" => Ok(MergeMode::Split),
+            "merged" => Ok(MergeMode::Merged),
+            "non-merged" | "nonmerged" => Ok(MergeMode::NonMerged),
+            other => bail!(
+                "Unknown merge mode '{}' (expected split, merged or non-merged)",
+                other
+            ),
+        }
+    }
+}
+
+/// Where one DAT entry is expected to be stored
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub entry_id: i64,
+    /// Name of the archive (or directory) that should hold the ROM
+    pub archive: String,
+    /// False when the ROM is borrowed: the archive counts it under the
+    /// parent's or BIOS's own entry instead
+    pub own: bool,
+}
+
+/// The merge mode configured for a DAT, if any
+pub fn merge_mode(conn: &Connection, dat_id: i64) -> Result<Option<MergeMode>> {
+    let mode: Option<Option<String>> = conn
+        .query_row(
+            "SELECT merge_mode FROM dats WHERE id = ?1",
+            [dat_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match mode {
+        None => bail!("DAT {} not found", dat_id),
+        Some(mode) => mode.map(|m| m.parse()).transpose(),
+    }
+}
+
+/// Set or clear the merge mode of a DAT
+pub fn set_merge_mode(conn: &Connection, dat_id: i64, mode: Option<MergeMode>) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE dats SET merge_mode = ?1 WHERE id = ?2",
+        params![mode.map(|m| m.as_str()), dat_id],
+    )?;
+    if updated == 0 {
+        bail!("DAT {} not found", dat_id);
+    }
+    Ok(())
+}
+
+struct SetRow {
+    version_id: i64,
+    name: String,
+    cloneof: Option<String>,
+    romof: Option<String>,
+    is_bios: bool,
+}
+
+struct EntryRow {
+    id: i64,
+    set_id: i64,
+    merge_name: Option<String>,
+}
+
+/// Where every entry of a DAT belongs under `mode`
+pub fn placements(
+    conn: &Connection,
+    dat_id: i64,
+    mode: Option<MergeMode>,
+) -> Result<Vec<Placement>> {
+    let mut sets: HashMap<i64, SetRow> = HashMap::new();
+    let mut set_ids: HashMap<(i64, String), i64> = HashMap::new();
+    {
+        let mut stmt = conn.prepare(
+            "SELECT s.id, s.dat_version_id, s.name, s.cloneof, s.romof, s.is_bios
+             FROM sets s
+             JOIN dat_versions v ON v.id = s.dat_version_id
+             WHERE v.dat_id = ?1",
+        )?;
+        let mut rows = stmt.query([dat_id])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let set = SetRow {
+                version_id: row.get(1)?,
+                name: row.get(2)?,
+                cloneof: row.get(3)?,
+                romof: row.get(4)?,
+                is_bios: row.get(5)?,
+            };
+            set_ids.insert((set.version_id, set.name.clone()), id);
+            sets.insert(id, set);
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut entry_merges: HashMap<(i64, String), Option<String>> = HashMap::new();
+    {
+        let mut stmt = conn.prepare(
+            "SELECT e.id, e.set_id, e.name, e.merge_name
+             FROM dat_entries e
+             JOIN dat_versions v ON v.id = e.dat_version_id
+             WHERE v.dat_id = ?1 AND e.set_id IS NOT NULL
+             ORDER BY e.id",
+        )?;
+        let mut rows = stmt.query([dat_id])?;
+        while let Some(row) = rows.next()? {
+            let entry = EntryRow {
+                id: row.get(0)?,
+                set_id: row.get(1)?,
+                merge_name: row.get(3)?,
+            };
+            entry_merges.insert((entry.set_id, row.get(2)?), entry.merge_name.clone());
+            entries.push(entry);
+        }
+    }
+
+    let lookup = |version_id: i64, name: &Option<String>| {
+        name.as_ref()
+            .and_then(|name| set_ids.get(&(version_id, name.clone())).copied())
+    };
+
+    // The set whose own entry this ROM is, following `merge` up the `romof` chain
+    let source_set = |entry: &EntryRow| {
+        let mut set_id = entry.set_id;
+        let mut merge = entry.merge_name.clone();
+        for _ in 0..MAX_CHAIN {
+            let Some(name) = merge else { break };
+            let set = &sets[&set_id];
+            let Some(parent) = lookup(set.version_id, &set.romof) else {
+                break;
+            };
+            let Some(parent_merge) = entry_merges.get(&(parent, name)) else {
+                break;
+            };
+            set_id = parent;
+            merge = parent_merge.clone();
+        }
+        set_id
+    };
+
+    // The top of a set's clone chain
+    let top_parent = |mut set_id: i64| {
+        for _ in 0..MAX_CHAIN {
+            let set = &sets[&set_id];
+            match lookup(set.version_id, &set.cloneof) {
+                Some(parent) if parent != set_id => set_id = parent,
+                _ => break,
+            }
+        }
+        set_id
+    };
+
+    let placements = entries
+        .iter()
+        .map(|entry| {
+            let (home, own) = match mode {
+                None | Some(MergeMode::NonMerged) => (entry.set_id, true),
+                Some(MergeMode::Split) => {
+                    let source = source_set(entry);
+                    (source, source == entry.set_id)
+                }
+                Some(MergeMode::Merged) => {
+                    let source = source_set(entry);
+                    let home = if sets[&source].is_bios {
+                        source
+                    } else {
+                        top_parent(source)
+                    };
+                    (home, source == entry.set_id)
+                }
+            };
+            Placement {
+                entry_id: entry.id,
+                archive: sets[&home].name.clone(),
+                own,
+            }
+        })
+        .collect();
+    Ok(placements)
+}
+
+/// Entries of a DAT matched by a file stored where `placements` expects it
+pub fn located_entries(
+    conn: &Connection,
+    dat_id: i64,
+    placements: &[Placement],
+    scope: &Scope,
+) -> Result<HashSet<i64>> {
+    let homes: HashMap<i64, String> = placements
+        .iter()
+        .map(|p| (p.entry_id, p.archive.to_lowercase()))
+        .collect();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.dat_entry_id, f.path
+         FROM matches m
+         JOIN files f ON f.id = m.file_id
+         JOIN dat_entries e ON e.id = m.dat_entry_id
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         WHERE v.dat_id = ?2 AND {}",
+        scope.file_sql("m.file_id")
+    ))?;
+    let mut rows = stmt.query(params![scope.path, dat_id])?;
+    let mut located = HashSet::new();
+    while let Some(row) = rows.next()? {
+        let entry_id: i64 = row.get(0)?;
+        let path: String = row.get(1)?;
+        if let (Some(home), Some(container)) = (homes.get(&entry_id), container_name(&path))
+            && container.to_lowercase() == *home
+        {
+            located.insert(entry_id);
+        }
+    }
+    Ok(located)
+}
+
+/// Name of the set container holding a file: the archive's stem for
+/// `archive.zip#rom` paths, otherwise the parent directory's name
+pub fn container_name(path: &str) -> Option<String> {
+    match split_archive_member(path) {
+        Some((archive, _)) => Path::new(archive).file_stem(),
+        None => Path::new(path).parent().and_then(Path::file_name),
+    }
+    .map(|name| name.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        // A BIOS, a parent that uses it, and a clone of the parent
+        conn.execute_batch(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'MAME', 'MAME', '/m.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 6);
+             INSERT INTO sets (id, dat_version_id, name, is_bios) VALUES (1, 1, 'neogeo', 1);
+             INSERT INTO sets (id, dat_version_id, name, romof) VALUES (2, 1, 'parent', 'neogeo');
+             INSERT INTO sets (id, dat_version_id, name, cloneof, romof) VALUES (3, 1, 'clone', 'parent', 'parent');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size) VALUES (1, 1, 1, 'bios.rom', 1);
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size) VALUES (2, 1, 2, 'p.rom', 1);
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, merge_name) VALUES (3, 1, 2, 'bios.rom', 1, 'bios.rom');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size) VALUES (4, 1, 3, 'c.rom', 1);
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, merge_name) VALUES (5, 1, 3, 'p.rom', 1, 'p.rom');
+             INSERT INTO dat_entries (id, dat_version_id, set_id, name, size, merge_name) VALUES (6, 1, 3, 'bios.rom', 1, 'bios.rom');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn homes(conn: &Connection, mode: Option<MergeMode>) -> Vec<(i64, String, bool)> {
+        placements(conn, 1, mode)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.entry_id, p.archive, p.own))
+            .collect()
+    }
+
+    fn owned(id: i64, archive: &str) -> (i64, String, bool) {
+        (id, archive.to_string(), true)
+    }
+
+    fn borrowed(id: i64, archive: &str) -> (i64, String, bool) {
+        (id, archive.to_string(), false)
+    }
+
+    #[test]
+    fn test_placements_per_mode() {
+        let conn = setup();
+
+        let own_sets = vec![
+            owned(1, "neogeo"),
+            owned(2, "parent"),
+            owned(3, "parent"),
+            owned(4, "clone"),
+            owned(5, "clone"),
+            owned(6, "clone"),
+        ];
+        assert_eq!(homes(&conn, None), own_sets);
+        assert_eq!(homes(&conn, Some(MergeMode::NonMerged)), own_sets);
+
+        assert_eq!(
+            homes(&conn, Some(MergeMode::Split)),
+            vec![
+                owned(1, "neogeo"),
+                owned(2, "parent"),
+                borrowed(3, "neogeo"),
+                owned(4, "clone"),
+                borrowed(5, "parent"),
+                borrowed(6, "neogeo"),
+            ]
+        );
+        assert_eq!(
+            homes(&conn, Some(MergeMode::Merged)),
+            vec![
+                owned(1, "neogeo"),
+                owned(2, "parent"),
+                borrowed(3, "neogeo"),
+                owned(4, "parent"),
+                borrowed(5, "parent"),
+                borrowed(6, "neogeo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_mode_setting() {
+        let conn = setup();
+        assert_eq!(merge_mode(&conn, 1).unwrap(), None);
+        set_merge_mode(&conn, 1, Some(MergeMode::NonMerged)).unwrap();
+        assert_eq!(merge_mode(&conn, 1).unwrap(), Some(MergeMode::NonMerged));
+        assert!(set_merge_mode(&conn, 9, None).is_err());
+        assert!("fullmerged".parse::<MergeMode>().is_err());
+    }
+
+    #[test]
+    fn test_container_name() {
+        assert_eq!(
+            container_name("mame/clone.zip#c.rom").as_deref(),
+            Some("clone")
+        );
+        assert_eq!(container_name("mame/clone/c.rom").as_deref(), Some("clone"));
+        assert_eq!(container_name("c.rom"), None);
+        assert_eq!(
+            container_name("Set #1/clone.zip#c #1.rom").as_deref(),
+            Some("clone")
+        );
+        assert_eq!(container_name("clone/c #1.rom").as_deref(), Some("clone"));
+    }
+}
@@ -1,2 +1,10 @@
+pub mod audit;
 pub mod dat_importer;
+pub mod diagnostics;
+pub mod history;
+pub mod merge;
+pub mod path_template;
 pub mod progress;
+pub mod rebuild;
+pub mod tzip;
+pub mod verifier;
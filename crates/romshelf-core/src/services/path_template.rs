@@ -0,0 +1,470 @@
+//! Path templates - where organise puts each set
+//!
+//! A template is a path relative to the rebuild target with `{variable}`
+//! placeholders, such as `{system}/{title} ({region})` or
+//! `{dat}/{first_letter}/{set}`. `{{` and `}}` stand for literal braces.
+//!
+//! Values are made safe for file names before they are substituted; only
+//! `{category}` keeps its `/` separators. After substitution, empty `()` and
+//! `[]` left by missing values are dropped, spaces are collapsed, and empty
+//! path segments disappear, so `{category}/{set}` is just `{set}` for a DAT
+//! without a category.
+//!
+//! The extension is added by the rebuild, for the format it writes; a
+//! template may end in `.zip` or `.7z` all the same.
+//!
+//! `{title}`, `{region}` and `{tags}` come from the set name as No-Intro and
+//! TOSEC write it: `Title (Region) (Other tags) [Flags]`.
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Region names as No-Intro and Redump write them
+const REGIONS: &[&str] = &[
+    "World",
+    "USA",
+    "Europe",
+    "Japan",
+    "Asia",
+    "Australia",
+    "Brazil",
+    "Canada",
+    "China",
+    "Denmark",
+    "Finland",
+    "France",
+    "Germany",
+    "Greece",
+    "Hong Kong",
+    "India",
+    "Ireland",
+    "Italy",
+    "Korea",
+    "Mexico",
+    "Netherlands",
+    "New Zealand",
+    "Norway",
+    "Poland",
+    "Portugal",
+    "Russia",
+    "Scandinavia",
+    "Spain",
+    "Sweden",
+    "Switzerland",
+    "Taiwan",
+    "UK",
+    "Unknown",
+];
+
+/// A value a template can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    /// DAT name
+    Dat,
+    /// Category path, e.g. `TOSEC/Commodore/C64`
+    Category,
+    /// Last part of the category, or the DAT name without one
+    System,
+    /// Set name
+    Set,
+    /// Set description, or the set name without one
+    Description,
+    /// Parent set of a clone, or the set itself
+    Parent,
+    /// Set name up to its first tag
+    Title,
+    /// First tag of the set name that names regions
+    Region,
+    /// Every tag of the set name
+    Tags,
+    /// First letter of the title, upper-case; `#` for anything else
+    FirstLetter,
+}
+
+impl Variable {
+    pub const ALL: &[Variable] = &[
+        Variable::Dat,
+        Variable::Category,
+        Variable::System,
+        Variable::Set,
+        Variable::Description,
+        Variable::Parent,
+        Variable::Title,
+        Variable::Region,
+        Variable::Tags,
+        Variable::FirstLetter,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variable::Dat => "dat",
+            Variable::Category => "category",
+            Variable::System => "system",
+            Variable::Set => "set",
+            Variable::Description => "description",
+            Variable::Parent => "parent",
+            Variable::Title => "title",
+            Variable::Region => "region",
+            Variable::Tags => "tags",
+            Variable::FirstLetter => "first_letter",
+        }
+    }
+
+    /// Whether the value differs between sets of one DAT
+    pub fn is_per_set(&self) -> bool {
+        !matches!(self, Variable::Dat | Variable::Category | Variable::System)
+    }
+}
+
+/// The DAT, set and category a path is rendered for
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TemplateValues<'a> {
+    pub dat: &'a str,
+    pub category: Option<&'a str>,
+    pub set: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub parent: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// A parsed and validated path template
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl PathTemplate {
+    /// The variables the template uses, in order
+    pub fn variables(&self) -> impl Iterator<Item = Variable> + '_ {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Variable(variable) => Some(*variable),
+            Part::Literal(_) => None,
+        })
+    }
+
+    /// Whether sets of one DAT can render to different paths
+    pub fn is_per_set(&self) -> bool {
+        self.variables().any(|v| v.is_per_set())
+    }
+
+    /// Render the template into a path relative to the rebuild target
+    ///
+    /// May be empty, when every segment rendered empty.
+    pub fn render(&self, values: &TemplateValues) -> PathBuf {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => rendered.push_str(text),
+                Part::Variable(variable) => rendered.push_str(&value(*variable, values)),
+            }
+        }
+        rendered
+            .split('/')
+            .map(tidy_segment)
+            .filter(|segment| !segment.is_empty())
+            .collect()
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unclosed '{{' in template '{}'", source),
+                        }
+                    }
+                    let Some(variable) = Variable::ALL.iter().find(|v| v.as_str() == name.trim())
+                    else {
+                        bail!(
+                            "Unknown template variable {{{}}} (expected one of {})",
+                            name,
+                            Variable::ALL
+                                .iter()
+                                .map(|v| v.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        );
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Variable(*variable));
+                }
+                '}' => bail!("Unmatched '}}' in template '{}' (write '}}}}')", source),
+                '\\' => bail!("Use '/' to separate directories in template '{}'", source),
+                c => literal.push(c),
+            }
+        }
+        for extension in [".zip", ".7z"] {
+            if literal.to_ascii_lowercase().ends_with(extension) {
+                literal.truncate(literal.len() - extension.len());
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        if source.starts_with('/') {
+            bail!("Template '{}' must be relative to the target", source);
+        }
+        let literals: String = parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.as_str(),
+                Part::Variable(_) => "\0",
+            })
+            .collect();
+        if literals.split('/').any(|s| s.trim() == "..") {
+            bail!("Template '{}' must not leave the target ('..')", source);
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Variable(_))) {
+            bail!("Template '{}' uses no variables", source);
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        source.parse()
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> String {
+        template.source
+    }
+}
+
+impl Display for PathTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The safe-for-paths value of a variable; empty when unknown
+fn value(variable: Variable, values: &TemplateValues) -> String {
+    let set = values.set.unwrap_or_default();
+    let raw = match variable {
+        Variable::Dat => values.dat.to_string(),
+        Variable::Category => {
+            return values
+                .category
+                .unwrap_or_default()
+                .split('/')
+                .map(sanitise_path)
+                .collect::<Vec<_>>()
+                .join("/");
+        }
+        Variable::System => values
+            .category
+            .and_then(|c| c.rsplit('/').find(|s| !s.is_empty()))
+            .unwrap_or(values.dat)
+            .to_string(),
+        Variable::Set => set.to_string(),
+        Variable::Description => values.description.unwrap_or(set).to_string(),
+        Variable::Parent => values.parent.unwrap_or(set).to_string(),
+        Variable::Title => title(set).to_string(),
+        Variable::Region => tags(set)
+            .into_iter()
+            .find(|tag| is_region(tag))
+            .map(|tag| tag[1..tag.len() - 1].to_string())
+            .unwrap_or_default(),
+        Variable::Tags => tags(set).join(" "),
+        Variable::FirstLetter => match title(set).chars().next() {
+            Some(c) if c.is_alphabetic() => c.to_uppercase().collect(),
+            Some(_) => "#".to_string(),
+            None => String::new(),
+        },
+    };
+    sanitise_path(&raw)
+}
+
+/// Sanitise a string for use as a directory/file name
+pub fn sanitise_path(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            _ => c,
+        })
+        .collect()
+}
+
+/// A set name up to its first `(` or `[` tag
+fn title(set: &str) -> &str {
+    match set.find(['(', '[']) {
+        Some(i) => set[..i].trim(),
+        None => set.trim(),
+    }
+}
+
+/// The `(...)` and `[...]` tags of a set name, brackets included
+fn tags(set: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = set;
+    while let Some(start) = rest.find(['(', '[']) {
+        let close = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        let Some(len) = rest[start..].find(close) else {
+            break;
+        };
+        tags.push(&rest[start..=start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    tags
+}
+
+/// `(USA)`, `(USA, Europe)` or TOSEC's `(US)` and `(DE-GB)`
+fn is_region(tag: &str) -> bool {
+    if !tag.starts_with('(') {
+        return false;
+    }
+    let inner = &tag[1..tag.len() - 1];
+    let names = inner.split(", ").all(|name| REGIONS.contains(&name));
+    let codes = inner
+        .split('-')
+        .all(|code| code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase()));
+    names || codes
+}
+
+/// Drop brackets left empty by missing values and collapse whitespace
+fn tidy_segment(segment: &str) -> String {
+    let mut tidy = segment.to_string();
+    for empty in ["()", "[]"] {
+        while tidy.contains(empty) {
+            tidy = tidy.replace(empty, "");
+        }
+    }
+    let tidy = tidy.split_whitespace().collect::<Vec<_>>().join(" ");
+    match tidy.as_str() {
+        "." | ".." => "_".to_string(),
+        _ => tidy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn values<'a>(set: &'a str) -> TemplateValues<'a> {
+        TemplateValues {
+            dat: "Nintendo - Game Boy",
+            category: Some("No-Intro/Nintendo - Game Boy"),
+            set: Some(set),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(sanitise_path("a/b:c?"), "a_b_c_");
+        let template: PathTemplate = "{system}/{title} ({region}).zip".parse().unwrap();
+        assert!(template.is_per_set());
+        assert_eq!(
+            template.render(&values("Tetris (Japan) (En) (Rev 1)")),
+            Path::new("Nintendo - Game Boy/Tetris (Japan)")
+        );
+        // Missing values leave no empty brackets or segments behind
+        assert_eq!(
+            template.render(&values("Homebrew Game")),
+            Path::new("Nintendo - Game Boy/Homebrew Game")
+        );
+
+        let letters: PathTemplate = "{dat}/{first_letter}/{set}".parse().unwrap();
+        assert_eq!(
+            letters.render(&values("4-in-1 (USA, Europe)")),
+            Path::new("Nintendo - Game Boy/#/4-in-1 (USA, Europe)")
+        );
+        assert_eq!(
+            letters.render(&values("alleyway (World)")),
+            Path::new("Nintendo - Game Boy/A/alleyway (World)")
+        );
+
+        let tosec: PathTemplate = "{category}/{region}/{tags}".parse().unwrap();
+        let values = TemplateValues {
+            dat: "C64",
+            category: Some("TOSEC/Commodore"),
+            set: Some("Game: The Sequel (1986)(Publisher)(DE-GB)[cr]"),
+            ..Default::default()
+        };
+        assert_eq!(
+            tosec.render(&values),
+            Path::new("TOSEC/Commodore/DE-GB/(1986) (Publisher) (DE-GB) [cr]")
+        );
+        let title: PathTemplate = "{title}".parse().unwrap();
+        assert_eq!(title.render(&values), Path::new("Game_ The Sequel"));
+
+        let flat: PathTemplate = "{{flat}}/{parent}".parse().unwrap();
+        let clone = TemplateValues {
+            parent: Some("pacman"),
+            ..values
+        };
+        assert_eq!(flat.render(&clone), Path::new("{flat}/pacman"));
+        assert_eq!(flat.to_string(), "{{flat}}/{parent}");
+        assert!(
+            !"{category}/{dat}"
+                .parse::<PathTemplate>()
+                .unwrap()
+                .is_per_set()
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        for bad in [
+            "{sytem}/{set}",
+            "{set",
+            "set}",
+            "/abs/{set}",
+            "../{set}",
+            "{dat}/../x",
+            "{dat}\\{set}",
+            "fixed/name",
+            "",
+        ] {
+            assert!(bad.parse::<PathTemplate>().is_err(), "{} should fail", bad);
+        }
+        let json = serde_json::to_string(&"{dat}/{set}".parse::<PathTemplate>().unwrap()).unwrap();
+        assert_eq!(json, "\"{dat}/{set}\"");
+        assert!(serde_json::from_str::<PathTemplate>("\"{nope}\"").is_err());
+    }
+}
@@ -60,6 +60,95 @@ pub enum ScanEvent {
     },
 }
 
+/// Events emitted while rebuilding the collection into a target directory
+///
+/// In a dry run the same events describe what would be done.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum RebuildEvent {
+    Started {
+        total_files: u64,
+        dry_run: bool,
+    },
+    FilePlaced {
+        source: PathBuf,
+        target: PathBuf,
+        copied: bool,
+    },
+    ArchiveWritten {
+        path: PathBuf,
+        files: u64,
+    },
+    ArchiveMerged {
+        path: PathBuf,
+        kept: u64,
+        added: u64,
+        dropped: u64,
+    },
+    SourceMissing {
+        path: PathBuf,
+    },
+    SourceRemoved {
+        path: PathBuf,
+    },
+    SourceRewritten {
+        path: PathBuf,
+        removed: u64,
+    },
+    TargetExists {
+        path: PathBuf,
+    },
+    Failed {
+        path: PathBuf,
+        error: String,
+    },
+    Completed {
+        placed: u64,
+        archives: u64,
+        merged: u64,
+        skipped: u64,
+        errors: u64,
+        duration_ms: u128,
+    },
+}
+
+/// Events emitted while converting archives to TorrentZip
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum TzipEvent {
+    ArchiveStarted {
+        path: PathBuf,
+    },
+    ArchiveConverted {
+        path: PathBuf,
+        new_path: PathBuf,
+        entries: u64,
+    },
+    AlreadyTorrentzipped {
+        path: PathBuf,
+    },
+    /// Dry run: the archive would be rewritten
+    WouldConvert {
+        path: PathBuf,
+    },
+    ArchiveFailed {
+        path: PathBuf,
+        error: String,
+    },
+    /// A path couldn't be walked while looking for archives
+    DiscoveryFailed {
+        path: PathBuf,
+        error: String,
+    },
+    Summary {
+        converted: u64,
+        would_convert: u64,
+        unchanged: u64,
+        failed: u64,
+        duration_ms: u128,
+    },
+}
+
 pub trait ProgressSink<E>: Send + Sync + 'static {
     fn emit(&self, event: E);
 }
@@ -0,0 +1,2433 @@
+//! Rebuild - organise matched files into a target directory
+//!
+//! Every matched file is placed under the target, named after the ROM it
+//! matched (its highest priority match). Files can be placed loose in a
+//! directory per set, or packed into an archive per set or per DAT. Where
+//! those directories and archives go is a [`PathTemplate`], by default
+//! `{category}/{set}` (or `{category}/{dat}` for an archive per DAT).
+//! Archive members are read out of their archive; in loose mode the whole
+//! archive is placed instead.
+//!
+//! Archives are TorrentZip unless another [`ArchiveFormat`] is asked for,
+//! either for the whole rebuild or for one DAT (`dats.output_format`).
+//! Every archive is read back after writing and its entries checked against
+//! the CRCs of what went in, and those against the CRCs the sources were
+//! matched with: a source changed since it was scanned fails its archive.
+//!
+//! Sources are streamed, never held in memory whole. Zip members are read
+//! straight from their compressed data. A 7z is decompressed once, in a
+//! single pass, and the members still to be packed are spooled to a
+//! temporary directory inside the target until the last archive needing
+//! them is written.
+//!
+//! Unless copying, sources are moved: each is deleted once every archive
+//! taking it has been written and checked, and a source archive is rewritten
+//! without the members taken from it (or deleted, once all are taken).
+//! [`record_moves`] brings the library's `files` rows in line, pointing them
+//! at where their sources went so their matches survive without a rescan.
+//!
+//! An archive that already exists is left alone, unless the rebuild merges:
+//! then its correct entries are kept, newly found ROMs added, and entries
+//! that are wrong or belong to no set of its own are moved to a backup
+//! directory. The merged archive is written beside the old one and renamed
+//! over it once checked.
+
+use crate::db::{self, roots};
+use crate::scan::{is_7z_file, is_zip_file, split_archive_member};
+use crate::services::path_template::{PathTemplate, TemplateValues, sanitise_path};
+use crate::services::progress::{ProgressSink, RebuildEvent};
+use crate::services::verifier;
+use crate::torrentzip::{self, TorrentZipWriter};
+use anyhow::{Result, anyhow, bail};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use sevenz_rust::{Password, SeqReader, SevenZArchiveEntry, SevenZReader, SevenZWriter};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tempfile::{TempDir, TempPath};
+use zip::{CompressionMethod, ZipArchive};
+
+/// How rebuilt files are laid out in the target
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebuildMode {
+    /// Loose files in a directory per set
+    Loose,
+    /// One archive per set
+    #[default]
+    ZipPerSet,
+    /// One archive per DAT, with a folder per set inside
+    ZipPerDat,
+}
+
+impl RebuildMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RebuildMode::Loose => "loose",
+            RebuildMode::ZipPerSet => "zip-per-set",
+            RebuildMode::ZipPerDat => "zip-per-dat",
+        }
+    }
+
+    /// The layout used when no template is given
+    pub fn default_template(&self) -> PathTemplate {
+        let template = match self {
+            RebuildMode::Loose | RebuildMode::ZipPerSet => "{category}/{set}",
+            RebuildMode::ZipPerDat => "{category}/{dat}",
+        };
+        template.parse().expect("default templates are valid")
+    }
+}
+
+/// The container rebuilt archives are written in
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+pub enum ArchiveFormat {
+    /// TorrentZip: deflate, sorted entries, fixed timestamps
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    /// Solid LZMA2 7z with sorted entries and no timestamps, as torrent7z
+    #[serde(rename = "7z")]
+    SevenZip,
+    /// ZIP with zstd compressed entries, sorted and with fixed timestamps
+    #[serde(rename = "zip-zstd")]
+    ZipZstd,
+}
+
+impl ArchiveFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::SevenZip => "7z",
+            ArchiveFormat::ZipZstd => "zip-zstd",
+        }
+    }
+
+    /// File extension of archives in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip | ArchiveFormat::ZipZstd => "zip",
+            ArchiveFormat::SevenZip => "7z",
+        }
+    }
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "zip" | "torrentzip" => Ok(ArchiveFormat::Zip),
+            "7z" | "torrent7z" => Ok(ArchiveFormat::SevenZip),
+            "zip-zstd" | "zstd" => Ok(ArchiveFormat::ZipZstd),
+            other => bail!(
+                "Unknown archive format '{}' (expected zip, 7z or zip-zstd)",
+                other
+            ),
+        }
+    }
+}
+
+/// Options controlling a rebuild
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RebuildOptions {
+    /// Directory the collection is rebuilt into
+    pub target: PathBuf,
+    #[serde(default)]
+    pub mode: RebuildMode,
+    /// Report what would be done without touching any file
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Leave sources in place instead of moving them
+    #[serde(default)]
+    pub copy: bool,
+    /// Archive format for DATs that don't set their own
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Where each archive (or directory of loose files) goes, relative to
+    /// the target; without one, the mode's default
+    #[serde(default)]
+    pub template: Option<PathTemplate>,
+    /// Bring existing archives up to date instead of skipping them
+    #[serde(default)]
+    pub merge: bool,
+    /// Where entries dropped from merged archives go; without one,
+    /// `.romshelf-backup` in the target
+    #[serde(default)]
+    pub backup: Option<PathBuf>,
+}
+
+/// A matched file and where it belongs
+#[derive(Debug, Clone)]
+pub struct RebuildSource {
+    /// Absolute path; archive members are `archive.zip#member`
+    pub path: PathBuf,
+    pub rom_name: String,
+    pub dat_name: String,
+    pub set_name: Option<String>,
+    pub set_description: Option<String>,
+    /// Parent set of a clone
+    pub parent: Option<String>,
+    pub category: Option<String>,
+    /// The DAT's own output format, if it has one
+    pub format: Option<ArchiveFormat>,
+    /// CRC32 of the file as matched, where known; an archive whose source
+    /// no longer has it fails
+    pub crc32: Option<u32>,
+}
+
+impl RebuildSource {
+    fn template_values(&self) -> TemplateValues<'_> {
+        TemplateValues {
+            dat: &self.dat_name,
+            category: self.category.as_deref(),
+            set: self.set_name.as_deref(),
+            description: self.set_description.as_deref(),
+            parent: self.parent.as_deref(),
+        }
+    }
+}
+
+/// Where a rebuild puts the files of one or more sets
+#[derive(Debug, Serialize, Clone)]
+pub struct PlannedTarget {
+    /// An archive, or a directory of loose files
+    pub path: PathBuf,
+    /// `DAT: set` of every set placed there (just the DAT for an archive
+    /// per DAT)
+    pub sets: Vec<String>,
+    pub files: u64,
+    /// Several sets (or DATs) would share one archive
+    pub conflict: bool,
+}
+
+/// Totals of a rebuild (or of what a dry run would do)
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RebuildResult {
+    /// Files placed loose or packed into archives
+    pub placed: u64,
+    /// Archives written
+    pub archives: u64,
+    /// Existing archives brought up to date
+    pub merged: u64,
+    /// Sources that are gone and targets that already exist
+    pub skipped: u64,
+    pub errors: u64,
+    pub duration: Duration,
+    /// Sources a move took away: files, whole archives and members of
+    /// archives (`archive.zip#rom`)
+    pub removed: Vec<PathBuf>,
+    /// Source archives rewritten without the members taken from them
+    pub rewritten: Vec<PathBuf>,
+    /// Where each removed source went: a member of an archive written
+    /// (`archive.zip#rom`), or the file placed loose; a source several
+    /// archives took appears once per archive
+    pub moved: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Every matched file with the ROM, set, DAT and category it belongs to
+pub fn load_sources(conn: &Connection) -> Result<Vec<RebuildSource>> {
+    let mut stmt = conn.prepare(
+        "SELECT p.path, de.name, d.name, s.name, s.description, s.cloneof,
+                d.category, d.output_format, coalesce(f.crc32, de.crc32)
+         FROM files f
+         JOIN file_paths p ON p.file_id = f.id
+         JOIN primary_matches m ON m.file_id = f.id
+         JOIN dat_entries de ON de.id = m.dat_entry_id
+         JOIN dat_versions dv ON de.dat_version_id = dv.id
+         JOIN dats d ON dv.dat_id = d.id
+         LEFT JOIN sets s ON de.set_id = s.id
+         ORDER BY p.path",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                RebuildSource {
+                    path: PathBuf::from(row.get::<_, String>(0)?),
+                    rom_name: row.get(1)?,
+                    dat_name: row.get(2)?,
+                    set_name: row.get(3)?,
+                    set_description: row.get(4)?,
+                    parent: row.get(5)?,
+                    category: row.get(6)?,
+                    format: None,
+                    crc32: row.get::<_, Option<i64>>(8)?.map(|crc| crc as u32),
+                },
+                row.get::<_, Option<String>>(7)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.into_iter()
+        .map(|(mut source, format)| {
+            source.format = format.map(|f| f.parse()).transpose()?;
+            Ok(source)
+        })
+        .collect()
+}
+
+/// Bring the library in line with a move: point the rows of sources taken
+/// away at where they went, and refresh the mtime of source archives
+/// rewritten
+///
+/// A row follows its source to the first destination inside a scan root and
+/// is copied to any others, hashes and all, so it stays matched. Rows of
+/// sources that went nowhere the library covers are dropped; scan the target
+/// to pick those up. Returns the rows re-pointed and the rows dropped.
+pub fn record_moves(conn: &Connection, result: &RebuildResult) -> Result<(usize, usize)> {
+    let mut went: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for (source, destination) in &result.moved {
+        went.entry(source).or_default().push(destination);
+    }
+
+    let (mut repointed, mut dropped) = (0, 0);
+    for path in &result.removed {
+        let Some((root, relative)) = roots::find_root(conn, path)? else {
+            continue;
+        };
+        let rows: Vec<(i64, String)> = conn
+            .prepare(
+                "SELECT id, path FROM files
+                 WHERE root_id = ?1
+                   AND (path = ?2 OR substr(path, 1, length(?2) + 1) = ?2 || '#')",
+            )?
+            .query_map(params![root.id, relative], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        for (file_id, row_path) in rows {
+            // The row's own source, or a whole archive placed loose taking
+            // its members along under the same names
+            let member = &row_path[relative.len()..];
+            let source = PathBuf::from(format!("{}{}", path.display(), member));
+            let destinations: Vec<PathBuf> = match went.get(source.as_path()) {
+                Some(destinations) => destinations.iter().map(|d| d.to_path_buf()).collect(),
+                None => went
+                    .get(path.as_path())
+                    .into_iter()
+                    .flatten()
+                    .map(|d| PathBuf::from(format!("{}{}", d.display(), member)))
+                    .collect(),
+            };
+            if follow_move(conn, file_id, &destinations)? {
+                repointed += 1;
+            } else {
+                conn.execute("DELETE FROM files WHERE id = ?1", [file_id])?;
+                dropped += 1;
+            }
+        }
+    }
+    for archive in &result.rewritten {
+        let Some((root, relative)) = roots::find_root(conn, archive)? else {
+            continue;
+        };
+        let mtime = file_mtime(archive);
+        conn.execute(
+            "UPDATE files SET mtime = ?3
+             WHERE root_id = ?1 AND substr(path, 1, length(?2) + 1) = ?2 || '#'",
+            params![root.id, relative, mtime],
+        )?;
+    }
+    Ok((repointed, dropped))
+}
+
+/// Point a file's row at the first of its destinations inside a scan root
+/// and copy it to the rest; false when none is inside one
+fn follow_move(conn: &Connection, file_id: i64, destinations: &[PathBuf]) -> Result<bool> {
+    let mut copies = Vec::new();
+    let mut followed = false;
+    for destination in destinations {
+        let Some((root, relative)) = roots::find_root(conn, destination)? else {
+            continue;
+        };
+        let (file, name) = match split_member(destination) {
+            Some((archive, member)) => (archive, PathBuf::from(member)),
+            None => (destination.clone(), destination.clone()),
+        };
+        let filename = name.file_name().map(|n| n.to_string_lossy().to_string());
+        // Directories as a scan records them: a member's is its archive's
+        let directory = destination
+            .parent()
+            .and_then(|parent| roots::relative_path(Path::new(&root.path), parent))
+            .unwrap_or_default();
+        let directory_id = db::get_or_create_directory(conn, root.id, &directory)?;
+
+        // Whatever the library had there before has been replaced
+        conn.execute(
+            "DELETE FROM files WHERE root_id = ?1 AND path = ?2 AND id != ?3",
+            params![root.id, relative, file_id],
+        )?;
+        if followed {
+            conn.execute(
+                "INSERT INTO files (root_id, path, filename, size, mtime, crc32, md5, sha1,
+                                    scanned_at, directory_id)
+                 SELECT ?1, ?2, coalesce(?3, filename), size, ?4, crc32, md5, sha1,
+                        scanned_at, ?5
+                 FROM files WHERE id = ?6",
+                params![
+                    root.id,
+                    relative,
+                    filename,
+                    file_mtime(&file),
+                    directory_id,
+                    file_id
+                ],
+            )?;
+            copies.push(conn.last_insert_rowid());
+        } else {
+            conn.execute(
+                "UPDATE files SET root_id = ?1, path = ?2, filename = coalesce(?3, filename),
+                                  mtime = ?4, directory_id = ?5
+                 WHERE id = ?6",
+                params![
+                    root.id,
+                    relative,
+                    filename,
+                    file_mtime(&file),
+                    directory_id,
+                    file_id
+                ],
+            )?;
+            verifier::refresh_names(conn, file_id)?;
+            followed = true;
+        }
+    }
+    verifier::match_files(conn, &copies)?;
+    Ok(followed)
+}
+
+/// A file's mtime as a scan records it
+fn file_mtime(path: &Path) -> Option<i64> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+/// The ROMs of each set, by DAT and set name, with their CRCs where known
+pub type SetRoms = HashMap<(String, String), Vec<(String, Option<u32>)>>;
+
+/// The ROMs of every set of the enabled DATs, which a merge checks existing
+/// archives against
+pub fn load_set_roms(conn: &Connection) -> Result<SetRoms> {
+    let mut stmt = conn.prepare(
+        "SELECT d.name, s.name, e.name, e.crc32
+         FROM enabled_dat_entries e
+         JOIN sets s ON s.id = e.set_id
+         JOIN dat_versions dv ON e.dat_version_id = dv.id
+         JOIN dats d ON dv.dat_id = d.id",
+    )?;
+    let mut roms = SetRoms::new();
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<i64>>(3)?,
+        ))
+    })?;
+    for row in rows {
+        let (dat, set, rom, crc) = row?;
+        roms.entry((dat, set))
+            .or_default()
+            .push((rom, crc.map(|crc| crc as u32)));
+    }
+    Ok(roms)
+}
+
+/// The archive format configured for a DAT, if any
+pub fn output_format(conn: &Connection, dat_id: i64) -> Result<Option<ArchiveFormat>> {
+    let format: Option<Option<String>> = conn
+        .query_row(
+            "SELECT output_format FROM dats WHERE id = ?1",
+            [dat_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match format {
+        None => bail!("DAT {} not found", dat_id),
+        Some(format) => format.map(|f| f.parse()).transpose(),
+    }
+}
+
+/// Set or clear the archive format of a DAT
+pub fn set_output_format(
+    conn: &Connection,
+    dat_id: i64,
+    format: Option<ArchiveFormat>,
+) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE dats SET output_format = ?1 WHERE id = ?2",
+        params![format.map(|f| f.as_str()), dat_id],
+    )?;
+    if updated == 0 {
+        bail!("DAT {} not found", dat_id);
+    }
+    Ok(())
+}
+
+/// Files to pack into one archive, and the sets they come from
+#[derive(Default)]
+struct ArchiveGroup {
+    /// DAT and set of each owner; no set for an archive of a whole DAT
+    owners: BTreeSet<(String, Option<String>)>,
+    files: Vec<(PathBuf, String)>,
+    /// CRC32 of each source as matched, where known
+    crcs: HashMap<PathBuf, u32>,
+}
+
+impl ArchiveGroup {
+    /// `DAT: set` of each owner, or just the DAT
+    fn labels(&self) -> Vec<String> {
+        self.owners
+            .iter()
+            .map(|(dat, set)| match set {
+                Some(set) => format!("{}: {}", dat, set),
+                None => dat.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Archives to write, keyed by path and format
+type ArchiveGroups = BTreeMap<(PathBuf, ArchiveFormat), ArchiveGroup>;
+
+/// What a merge keeps, adds and drops
+struct Merge {
+    kept: u64,
+    /// Sources of the entries added
+    added: Vec<PathBuf>,
+    dropped: u64,
+}
+
+/// Directory in the target that dropped entries go to by default
+const BACKUP_DIR: &str = ".romshelf-backup";
+
+pub struct Rebuilder<S: ProgressSink<RebuildEvent> = ()> {
+    options: RebuildOptions,
+    sink: S,
+    set_roms: SetRoms,
+    /// The sets in `set_roms` of each DAT
+    dat_sets: HashMap<String, Vec<String>>,
+}
+
+impl<S: ProgressSink<RebuildEvent>> Rebuilder<S> {
+    pub fn new(options: RebuildOptions, sink: S) -> Self {
+        Self {
+            options,
+            sink,
+            set_roms: SetRoms::new(),
+            dat_sets: HashMap::new(),
+        }
+    }
+
+    /// The ROMs each set should hold, which a merge keeps existing entries
+    /// of; without them, only entries matching a source are kept
+    pub fn with_set_roms(mut self, set_roms: SetRoms) -> Self {
+        self.set_set_roms(set_roms);
+        self
+    }
+
+    fn set_set_roms(&mut self, set_roms: SetRoms) {
+        self.dat_sets.clear();
+        for (dat, set) in set_roms.keys() {
+            self.dat_sets
+                .entry(dat.clone())
+                .or_default()
+                .push(set.clone());
+        }
+        self.set_roms = set_roms;
+    }
+
+    /// Rebuild every matched file in the library
+    pub fn run(mut self, conn: &Connection) -> Result<RebuildResult> {
+        if self.options.merge {
+            self.set_set_roms(load_set_roms(conn)?);
+        }
+        self.rebuild(&load_sources(conn)?)
+    }
+
+    /// Rebuild the given sources
+    ///
+    /// Problems with single files are counted and reported as events; the
+    /// rebuild carries on with the rest.
+    pub fn rebuild(&self, sources: &[RebuildSource]) -> Result<RebuildResult> {
+        self.check_template()?;
+        let started = Instant::now();
+        self.sink.emit(RebuildEvent::Started {
+            total_files: sources.len() as u64,
+            dry_run: self.options.dry_run,
+        });
+
+        let mut result = RebuildResult::default();
+        match self.options.mode {
+            RebuildMode::Loose => self.rebuild_loose(sources, &mut result),
+            RebuildMode::ZipPerSet | RebuildMode::ZipPerDat => {
+                let archives = self.group_archives(sources);
+                self.rebuild_archives(archives, &mut result);
+            }
+        }
+
+        result.duration = started.elapsed();
+        self.sink.emit(RebuildEvent::Completed {
+            placed: result.placed,
+            archives: result.archives,
+            merged: result.merged,
+            skipped: result.skipped,
+            errors: result.errors,
+            duration_ms: result.duration.as_millis(),
+        });
+        Ok(result)
+    }
+
+    /// Where every archive or directory of loose files would go, without
+    /// reading or writing any file
+    pub fn preview(&self, sources: &[RebuildSource]) -> Result<Vec<PlannedTarget>> {
+        self.check_template()?;
+        let groups = match self.options.mode {
+            RebuildMode::Loose => {
+                let mut dirs: BTreeMap<PathBuf, ArchiveGroup> = BTreeMap::new();
+                for source in sources {
+                    let dir = dirs.entry(self.loose_dir(source)).or_default();
+                    dir.owners.insert(set_owner(source));
+                    dir.files
+                        .push((source.path.clone(), source.rom_name.clone()));
+                }
+                dirs
+            }
+            RebuildMode::ZipPerSet | RebuildMode::ZipPerDat => self
+                .group_archives(sources)
+                .into_iter()
+                .map(|((path, _), group)| (path, group))
+                .collect(),
+        };
+        Ok(groups
+            .into_iter()
+            .map(|(path, group)| PlannedTarget {
+                conflict: self.options.mode != RebuildMode::Loose && group.owners.len() > 1,
+                sets: group.labels(),
+                path,
+                files: group.files.len() as u64,
+            })
+            .collect())
+    }
+
+    fn template(&self) -> PathTemplate {
+        self.options
+            .template
+            .clone()
+            .unwrap_or_else(|| self.options.mode.default_template())
+    }
+
+    /// An archive per set needs a template that tells sets apart
+    fn check_template(&self) -> Result<()> {
+        let template = self.template();
+        if self.options.mode == RebuildMode::ZipPerSet && !template.is_per_set() {
+            bail!(
+                "Template '{}' would put every set of a DAT in one archive; \
+                 add a set variable such as {{set}}",
+                template
+            );
+        }
+        Ok(())
+    }
+
+    fn loose_dir(&self, source: &RebuildSource) -> PathBuf {
+        self.options
+            .target
+            .join(self.template().render(&source.template_values()))
+    }
+
+    /// Group sources into archives by rendered path and format
+    fn group_archives(&self, sources: &[RebuildSource]) -> ArchiveGroups {
+        let template = self.template();
+        let mut archives = ArchiveGroups::new();
+        for source in sources {
+            let mut values = source.template_values();
+            values.set = Some(values.set.unwrap_or("unknown"));
+            let mut name = template.render(&values).into_os_string();
+            if name.is_empty() {
+                name.push("unknown");
+            }
+            let format = self.format_for(source);
+            name.push(".");
+            name.push(format.extension());
+
+            let (owner, inner_name) = match self.options.mode {
+                // Sets become folders inside the DAT's archive
+                RebuildMode::ZipPerDat => (
+                    (source.dat_name.clone(), None),
+                    match &source.set_name {
+                        Some(set) => per_dat_name(set, &source.rom_name),
+                        None => source.rom_name.clone(),
+                    },
+                ),
+                _ => (set_owner(source), source.rom_name.clone()),
+            };
+            let archive = archives
+                .entry((self.options.target.join(name), format))
+                .or_default();
+            archive.owners.insert(owner);
+            archive.files.push((source.path.clone(), inner_name));
+            if let Some(crc) = source.crc32 {
+                archive.crcs.insert(source.path.clone(), crc);
+            }
+        }
+        archives
+    }
+
+    /// Place files loose as `target/<template>/rom`
+    fn rebuild_loose(&self, sources: &[RebuildSource], result: &mut RebuildResult) {
+        let mut seen_archives: HashSet<PathBuf> = HashSet::new();
+
+        for source in sources {
+            let (actual_source, target_name) = match split_member(&source.path) {
+                // A member of an archive: place the archive itself, once
+                Some((archive, _)) => {
+                    if !seen_archives.insert(archive.clone()) {
+                        continue;
+                    }
+                    let name = archive
+                        .file_name()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_else(|| "unknown.zip".to_string());
+                    (archive, name)
+                }
+                None => (source.path.clone(), source.rom_name.clone()),
+            };
+
+            let target_dir = self.loose_dir(source);
+            let target_path = target_dir.join(&target_name);
+
+            if !actual_source.exists() {
+                self.skip(RebuildEvent::SourceMissing {
+                    path: actual_source,
+                });
+                result.skipped += 1;
+                continue;
+            }
+            if target_path.exists() {
+                self.skip(RebuildEvent::TargetExists { path: target_path });
+                result.skipped += 1;
+                continue;
+            }
+
+            if !self.options.dry_run {
+                if let Err(e) = fs::create_dir_all(&target_dir) {
+                    self.fail(&target_dir, e.into(), result);
+                    continue;
+                }
+                let placed = if self.options.copy {
+                    fs::copy(&actual_source, &target_path).map(|_| ())
+                } else {
+                    fs::rename(&actual_source, &target_path)
+                };
+                if let Err(e) = placed {
+                    self.fail(&actual_source, e.into(), result);
+                    continue;
+                }
+                if !self.options.copy {
+                    result.removed.push(actual_source.clone());
+                    result
+                        .moved
+                        .push((actual_source.clone(), target_path.clone()));
+                }
+            }
+            result.placed += 1;
+            self.sink.emit(RebuildEvent::FilePlaced {
+                source: actual_source,
+                target: target_path,
+                copied: self.options.copy,
+            });
+        }
+    }
+
+    /// Write one archive per group, taking away moved sources as soon as
+    /// every archive needing them is written
+    fn rebuild_archives(&self, archives: ArchiveGroups, result: &mut RebuildResult) {
+        let mut members = MemberSources::new(&archives, &self.options.target);
+        let mut moved =
+            (!self.options.copy && !self.options.dry_run).then(|| MovedSources::new(&archives));
+        for ((archive_path, format), group) in archives {
+            let written = archive_path.clone();
+            let taken = self.rebuild_archive(&mut members, archive_path, format, &group, result);
+            let released = members.release(&group.files);
+            let Some(moved) = &mut moved else {
+                continue;
+            };
+            for path in moved.finish(&written, &group.files, &taken) {
+                match fs::remove_file(&path) {
+                    Ok(()) => {
+                        result.moved.extend(moved.went(&path));
+                        self.removed(path, result)
+                    }
+                    Err(e) => self.fail(&path, e.into(), result),
+                }
+            }
+            for archive in released {
+                let Some(taken) = moved.taken.remove(&archive) else {
+                    continue;
+                };
+                if self.take_members(&members, &archive, &taken, result) {
+                    for member in &taken {
+                        result
+                            .moved
+                            .extend(moved.went(&member_path(&archive, member)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Delete a source archive whose members have all been taken, or
+    /// rewrite it without those taken; false if that failed
+    fn take_members(
+        &self,
+        members: &MemberSources,
+        archive: &Path,
+        taken: &HashSet<String>,
+        result: &mut RebuildResult,
+    ) -> bool {
+        match remove_members(members, archive, taken) {
+            Ok(true) => self.removed(archive.to_path_buf(), result),
+            Ok(false) => {
+                result
+                    .removed
+                    .extend(taken.iter().map(|member| member_path(archive, member)));
+                self.sink.emit(RebuildEvent::SourceRewritten {
+                    path: archive.to_path_buf(),
+                    removed: taken.len() as u64,
+                });
+                result.rewritten.push(archive.to_path_buf());
+            }
+            Err(e) => {
+                self.fail(archive, e, result);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn removed(&self, path: PathBuf, result: &mut RebuildResult) {
+        self.sink
+            .emit(RebuildEvent::SourceRemoved { path: path.clone() });
+        result.removed.push(path);
+    }
+
+    /// Write (or merge) one archive; returns the sources it took
+    fn rebuild_archive(
+        &self,
+        members: &mut MemberSources,
+        archive_path: PathBuf,
+        format: ArchiveFormat,
+        group: &ArchiveGroup,
+        result: &mut RebuildResult,
+    ) -> Vec<PathBuf> {
+        let target_dir = archive_path
+            .parent()
+            .unwrap_or(&self.options.target)
+            .to_path_buf();
+        let files = &group.files;
+
+        if group.owners.len() > 1 {
+            let error = anyhow!(
+                "{} would share this archive; change the template to tell them apart",
+                group.labels().join(", ")
+            );
+            self.fail(&archive_path, error, result);
+            return Vec::new();
+        }
+
+        if archive_path.exists() {
+            if self.options.merge {
+                return self.merge_archive(members, archive_path, format, group, result);
+            }
+            self.skip(RebuildEvent::TargetExists { path: archive_path });
+            result.skipped += 1;
+            return Vec::new();
+        }
+        if self.options.dry_run {
+            result.archives += 1;
+            result.placed += files.len() as u64;
+            self.sink.emit(RebuildEvent::ArchiveWritten {
+                path: archive_path,
+                files: files.len() as u64,
+            });
+            return Vec::new();
+        }
+
+        if let Err(e) = fs::create_dir_all(&target_dir) {
+            self.fail(&target_dir, e.into(), result);
+            return Vec::new();
+        }
+        let written = files
+            .iter()
+            .map(|(path, name)| {
+                let crc = group.crcs.get(path).copied();
+                Ok((members.locate(path)?, name.clone(), crc))
+            })
+            .collect::<Result<Vec<_>>>()
+            .and_then(|sources| {
+                let (written, count) = write_archive(&archive_path, format, sources)?;
+                written.persist(&archive_path)?;
+                Ok(count)
+            });
+        match written {
+            Ok(count) => {
+                result.archives += 1;
+                result.placed += count;
+                self.sink.emit(RebuildEvent::ArchiveWritten {
+                    path: archive_path,
+                    files: count,
+                });
+                files.iter().map(|(path, _)| path.clone()).collect()
+            }
+            Err(e) => {
+                self.fail(&archive_path, e, result);
+                Vec::new()
+            }
+        }
+    }
+
+    fn merge_archive(
+        &self,
+        members: &mut MemberSources,
+        archive_path: PathBuf,
+        format: ArchiveFormat,
+        group: &ArchiveGroup,
+        result: &mut RebuildResult,
+    ) -> Vec<PathBuf> {
+        let merged = self.try_merge(members, &archive_path, format, group);
+        // Its members have moved; later reads must open it afresh
+        members.forget(&archive_path);
+        match merged {
+            Ok(Some(merge)) => {
+                result.merged += 1;
+                result.placed += merge.added.len() as u64;
+                self.sink.emit(RebuildEvent::ArchiveMerged {
+                    path: archive_path,
+                    kept: merge.kept,
+                    added: merge.added.len() as u64,
+                    dropped: merge.dropped,
+                });
+                merge.added
+            }
+            Ok(None) => {
+                self.skip(RebuildEvent::TargetExists { path: archive_path });
+                result.skipped += 1;
+                Vec::new()
+            }
+            Err(e) => {
+                self.fail(&archive_path, e, result);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Keep an existing archive's correct entries, add the files it lacks
+    /// and back up the rest, then swap the new archive in for the old
+    ///
+    /// `None` when the archive is already as it should be.
+    fn try_merge(
+        &self,
+        members: &mut MemberSources,
+        archive_path: &Path,
+        format: ArchiveFormat,
+        group: &ArchiveGroup,
+    ) -> Result<Option<Merge>> {
+        let expected = self.expected_entries(group);
+        let mut sources: BTreeMap<&str, &Path> = BTreeMap::new();
+        for (path, name) in &group.files {
+            sources.entry(name.as_str()).or_insert(path);
+        }
+
+        let mut kept = BTreeSet::new();
+        let mut dropped = Vec::new();
+        for (name, crc) in archive_entries(archive_path, format)? {
+            let crcs = expected.get(&name);
+            let correct = crcs.is_some_and(|crcs| crcs.contains(&Some(crc)));
+            // With no CRC to go by, keep it unless a matched file replaces it
+            let unchecked = crcs.is_some_and(|crcs| crcs.contains(&None))
+                && sources.get(name.as_str()).is_none_or(|path| {
+                    split_member(path) == Some((archive_path.to_path_buf(), name.clone()))
+                });
+            if (correct || unchecked) && !kept.contains(&name) {
+                kept.insert(name);
+            } else {
+                dropped.push(name);
+            }
+        }
+        let added: Vec<_> = sources
+            .into_iter()
+            .filter(|(name, _)| !kept.contains(*name))
+            .collect();
+
+        let tidy = format != ArchiveFormat::Zip || torrentzip::is_torrentzipped(archive_path)?;
+        if added.is_empty() && dropped.is_empty() && tidy {
+            return Ok(None);
+        }
+        let merge = Merge {
+            kept: kept.len() as u64,
+            added: added.iter().map(|(_, path)| path.to_path_buf()).collect(),
+            dropped: dropped.len() as u64,
+        };
+        if self.options.dry_run {
+            return Ok(Some(merge));
+        }
+
+        let needed = kept.iter().chain(&dropped).cloned().collect();
+        let old = members.load(archive_path, &needed)?;
+        let mut files = Vec::new();
+        for name in &kept {
+            files.push((old.locate(archive_path, name)?, name.clone(), None));
+        }
+        for (name, path) in added {
+            let crc = group.crcs.get(path).copied();
+            files.push((members.locate(path)?, name.to_string(), crc));
+        }
+
+        let (merged, _) = write_archive(archive_path, format, files)?;
+        self.back_up(archive_path, &old, &dropped)?;
+        merged.persist(archive_path)?;
+        Ok(Some(merge))
+    }
+
+    /// Every entry an archive should hold, with the CRCs it may have; `None`
+    /// where the DAT gives no CRC
+    fn expected_entries(&self, group: &ArchiveGroup) -> HashMap<String, HashSet<Option<u32>>> {
+        let mut expected: HashMap<String, HashSet<Option<u32>>> = HashMap::new();
+        for (dat, set) in &group.owners {
+            let sets = match set {
+                Some(set) => vec![set],
+                None => self.dat_sets.get(dat).into_iter().flatten().collect(),
+            };
+            for set_name in sets {
+                let Some(roms) = self.set_roms.get(&(dat.clone(), set_name.clone())) else {
+                    continue;
+                };
+                for (rom, crc) in roms {
+                    let name = match set {
+                        Some(_) => rom.clone(),
+                        None => per_dat_name(set_name, rom),
+                    };
+                    expected.entry(name).or_default().insert(*crc);
+                }
+            }
+        }
+        for (_, name) in &group.files {
+            expected.entry(name.clone()).or_default().insert(None);
+        }
+        expected
+    }
+
+    /// Copy entries dropped from an archive into the backup directory, under
+    /// the archive's path in the target
+    fn back_up(&self, archive_path: &Path, old: &LoadedArchive, names: &[String]) -> Result<()> {
+        let backup = match &self.options.backup {
+            Some(backup) => backup.clone(),
+            None => self.options.target.join(BACKUP_DIR),
+        };
+        let dir = backup.join(archive_path.strip_prefix(&self.options.target)?);
+        for name in names {
+            let path = unused_path(dir.join(backup_name(name)));
+            fs::create_dir_all(path.parent().unwrap_or(&dir))?;
+            let mut out = BufWriter::new(fs::File::create(&path)?);
+            io::copy(
+                &mut LazySource::new(old.locate(archive_path, name)?),
+                &mut out,
+            )?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn format_for(&self, source: &RebuildSource) -> ArchiveFormat {
+        source.format.unwrap_or(self.options.format)
+    }
+
+    fn skip(&self, event: RebuildEvent) {
+        self.sink.emit(event);
+    }
+
+    fn fail(&self, path: &Path, error: anyhow::Error, result: &mut RebuildResult) {
+        result.errors += 1;
+        self.sink.emit(RebuildEvent::Failed {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        });
+    }
+}
+
+/// The DAT and set a source belongs to
+fn set_owner(source: &RebuildSource) -> (String, Option<String>) {
+    (
+        source.dat_name.clone(),
+        Some(source.set_name.as_deref().unwrap_or("unknown").to_string()),
+    )
+}
+
+/// A dropped entry's path in the backup directory
+fn backup_name(name: &str) -> PathBuf {
+    let path: PathBuf = name
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(|part| match part {
+            "." | ".." => "_".to_string(),
+            part => sanitise_path(part),
+        })
+        .collect();
+    if path.as_os_str().is_empty() {
+        PathBuf::from("_")
+    } else {
+        path
+    }
+}
+
+/// `path`, or `path.1`, `path.2` and so on if it is taken
+fn unused_path(path: PathBuf) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut n = 0;
+    while candidate.exists() {
+        n += 1;
+        let mut name = path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        candidate = PathBuf::from(name);
+    }
+    candidate
+}
+
+/// A ROM's name inside its DAT's archive
+fn per_dat_name(set: &str, rom: &str) -> String {
+    format!("{}/{}", sanitise_path(set), rom)
+}
+
+/// The `archive.zip#member` path of an archive's member
+fn member_path(archive: &Path, member: &str) -> PathBuf {
+    PathBuf::from(format!("{}#{}", archive.display(), member))
+}
+
+/// Split `archive.zip#member` into the archive path and member name
+///
+/// Only a `#` following a zip or 7z path splits, so one in a directory's
+/// name (a scan root's included) or a loose file's is part of that name.
+fn split_member(path: &Path) -> Option<(PathBuf, String)> {
+    let path = path.to_string_lossy();
+    let (archive, member) = split_archive_member(&path)?;
+    Some((PathBuf::from(archive), member.to_string()))
+}
+
+/// Write an archive of matched files beside `archive_path`, then read it
+/// back and check it
+///
+/// A file given with the CRC32 it was matched with must still have it.
+///
+/// Returns the checked archive, for the caller to persist over
+/// `archive_path`, and its entry count. It is deleted if dropped, so a
+/// failed write leaves nothing behind.
+fn write_archive(
+    archive_path: &Path,
+    format: ArchiveFormat,
+    mut files: Vec<(SourceLocation, String, Option<u32>)>,
+) -> Result<(TempPath, u64)> {
+    files.sort_by(|a, b| torrentzip::compare_names(&a.1, &b.1));
+    let (sources, matched): (Vec<(String, LazySource)>, Vec<Option<u32>>) = files
+        .into_iter()
+        .map(|(location, name, crc)| ((name, LazySource::new(location)), crc))
+        .unzip();
+    let crcs: Vec<_> = sources
+        .iter()
+        .map(|(name, source)| (name.clone(), source.crc.clone()))
+        .collect();
+
+    let written = temp_path_beside(archive_path)?;
+    let out = BufWriter::new(fs::File::create(&written)?);
+    let out = match format {
+        ArchiveFormat::Zip => write_torrentzip(out, sources)?,
+        ArchiveFormat::SevenZip => write_7z(out, sources)?,
+        ArchiveFormat::ZipZstd => write_zstd_zip(out, sources)?,
+    };
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    let expected: Vec<(String, u32)> = crcs
+        .into_iter()
+        .map(|(name, crc)| (name, crc.borrow().clone().finalize()))
+        .collect();
+    if let Some(((name, _), _)) = expected
+        .iter()
+        .zip(&matched)
+        .find(|((_, crc), matched)| matched.is_some_and(|matched| matched != *crc))
+    {
+        bail!("The source of '{}' has changed since it was matched", name);
+    }
+    check_archive(&written, format, &expected)?;
+    Ok((written, expected.len() as u64))
+}
+
+/// A new temporary file in the same directory as `path`, so it can be
+/// renamed over it
+fn temp_path_beside(path: &Path) -> Result<TempPath> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(tempfile::Builder::new()
+        .prefix(".romshelf-")
+        .suffix(".tmp")
+        .tempfile_in(dir)?
+        .into_temp_path())
+}
+
+fn write_torrentzip<W: Write + Seek>(out: W, sources: Vec<(String, LazySource)>) -> Result<W> {
+    let mut zip = TorrentZipWriter::new(out);
+    for (name, source) in sources {
+        zip.add_file(&name, source)?;
+    }
+    zip.finish()
+}
+
+/// Write every file into one solid LZMA2 block
+fn write_7z<W: Write + Seek>(out: W, sources: Vec<(String, LazySource)>) -> Result<W> {
+    let mut archive = SevenZWriter::new(out)?;
+    let (entries, readers): (Vec<_>, Vec<_>) = sources
+        .into_iter()
+        .map(|(name, source)| {
+            let mut entry = SevenZArchiveEntry::new();
+            entry.name = name;
+            entry.has_stream = true;
+            (entry, sevenz_rust::SourceReader::new(source))
+        })
+        .unzip();
+    if !entries.is_empty() {
+        archive.push_archive_entries(entries, SeqReader::new(readers))?;
+    }
+    Ok(archive.finish()?)
+}
+
+fn write_zstd_zip<W: Write + Seek>(out: W, sources: Vec<(String, LazySource)>) -> Result<W> {
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Zstd)
+        .last_modified_time(zip::DateTime::default());
+    let mut zip = zip::ZipWriter::new(out);
+    for (name, mut source) in sources {
+        zip.start_file(name, options)?;
+        io::copy(&mut source, &mut zip)?;
+    }
+    Ok(zip.finish()?)
+}
+
+/// Read a written archive back and check it holds exactly the expected entries
+fn check_archive(path: &Path, format: ArchiveFormat, expected: &[(String, u32)]) -> Result<()> {
+    if format == ArchiveFormat::Zip && !torrentzip::is_torrentzipped(path)? {
+        bail!("Not a valid TorrentZip archive");
+    }
+    compare_entries(expected.to_vec(), archive_entries(path, format)?)
+}
+
+/// Check the entries read back from an archive are exactly those expected
+fn compare_entries(mut expected: Vec<(String, u32)>, mut found: Vec<(String, u32)>) -> Result<()> {
+    expected.sort();
+    found.sort();
+    if found.len() != expected.len() {
+        bail!("Expected {} entries, found {}", expected.len(), found.len());
+    }
+    if let Some((name, _)) = expected
+        .iter()
+        .zip(&found)
+        .find(|(e, f)| e != f)
+        .map(|(e, _)| e)
+    {
+        bail!("Entry '{}' failed its CRC check", name);
+    }
+    Ok(())
+}
+
+/// Name and CRC of each file in an archive, read from its data
+fn archive_entries(path: &Path, format: ArchiveFormat) -> Result<Vec<(String, u32)>> {
+    let mut found = Vec::new();
+    match format {
+        ArchiveFormat::Zip | ArchiveFormat::ZipZstd => {
+            let mut archive = zip::ZipArchive::new(BufReader::new(fs::File::open(path)?))?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = entry.name().to_string();
+                found.push((name, crc_of(entry)?));
+            }
+        }
+        ArchiveFormat::SevenZip => {
+            SevenZReader::open(path, Password::empty())?.for_each_entries(|entry, reader| {
+                let crc = crc_of(reader).map_err(sevenz_rust::Error::io)?;
+                if !entry.is_directory() {
+                    found.push((entry.name().replace('\\', "/"), crc));
+                }
+                Ok(true)
+            })?;
+        }
+    }
+    Ok(found)
+}
+
+fn crc_of(mut reader: impl Read) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Where a source's data is read from
+#[derive(Debug, Clone)]
+enum SourceLocation {
+    /// A plain file, or an archive member spooled to one
+    File(PathBuf),
+    /// A stored or deflated zip member, read straight from the zip
+    ZipMember { archive: PathBuf, member: ZipMember },
+}
+
+#[derive(Debug, Clone)]
+struct ZipMember {
+    data_start: u64,
+    compressed_size: u64,
+    crc32: u32,
+    deflated: bool,
+}
+
+/// Members of one source archive, ready to be read
+#[derive(Default)]
+struct LoadedArchive {
+    zip_members: HashMap<String, ZipMember>,
+    spooled: HashMap<String, PathBuf>,
+    _spool: Option<TempDir>,
+}
+
+impl LoadedArchive {
+    fn locate(&self, archive: &Path, member: &str) -> Result<SourceLocation> {
+        if let Some(zip_member) = self.zip_members.get(member) {
+            Ok(SourceLocation::ZipMember {
+                archive: archive.to_path_buf(),
+                member: zip_member.clone(),
+            })
+        } else if let Some(spooled) = self.spooled.get(member) {
+            Ok(SourceLocation::File(spooled.clone()))
+        } else {
+            bail!("'{}' not found in {}", member, archive.display())
+        }
+    }
+}
+
+/// The members rebuilt archives take from source archives
+///
+/// Each source archive is opened once, when the first archive needing it is
+/// written: a zip is indexed, a 7z decompressed in one pass with the needed
+/// members spooled to disk. It is let go when the last archive needing it
+/// is done.
+struct MemberSources {
+    /// Members needed from each source archive
+    needed: HashMap<PathBuf, HashSet<String>>,
+    /// Archives still to be written that read from each source archive
+    users: HashMap<PathBuf, usize>,
+    loaded: HashMap<PathBuf, LoadedArchive>,
+    /// Where spool directories are made; on the target's disk rather than
+    /// a possibly memory-backed temp directory
+    spool_root: PathBuf,
+}
+
+impl MemberSources {
+    fn new(archives: &ArchiveGroups, spool_root: &Path) -> Self {
+        let mut needed: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+        let mut users: HashMap<PathBuf, usize> = HashMap::new();
+        for group in archives.values() {
+            for archive in source_archives(&group.files) {
+                *users.entry(archive).or_default() += 1;
+            }
+            for (path, _) in &group.files {
+                if let Some((archive, member)) = split_member(path) {
+                    needed.entry(archive).or_default().insert(member);
+                }
+            }
+        }
+        Self {
+            needed,
+            users,
+            loaded: HashMap::new(),
+            spool_root: spool_root.to_path_buf(),
+        }
+    }
+
+    fn locate(&mut self, path: &Path) -> Result<SourceLocation> {
+        let Some((archive, member)) = split_member(path) else {
+            return Ok(SourceLocation::File(path.to_path_buf()));
+        };
+        if !self.loaded.contains_key(&archive) {
+            let needed = self.needed.get(&archive).cloned().unwrap_or_default();
+            let loaded = self.load(&archive, &needed)?;
+            self.loaded.insert(archive.clone(), loaded);
+        }
+        self.loaded[&archive].locate(&archive, &member)
+    }
+
+    /// Drop what was read of an archive that has been rewritten
+    fn forget(&mut self, archive: &Path) {
+        self.loaded.remove(archive);
+    }
+
+    /// Let go of source archives no archive left to write needs; returns
+    /// them
+    fn release(&mut self, files: &[(PathBuf, String)]) -> Vec<PathBuf> {
+        let mut released = Vec::new();
+        for archive in source_archives(files) {
+            if let Some(users) = self.users.get_mut(&archive) {
+                *users -= 1;
+                if *users == 0 {
+                    self.users.remove(&archive);
+                    self.needed.remove(&archive);
+                    self.loaded.remove(&archive);
+                    released.push(archive);
+                }
+            }
+        }
+        released.sort();
+        released
+    }
+
+    fn load(&self, archive: &Path, needed: &HashSet<String>) -> Result<LoadedArchive> {
+        if is_zip_file(archive) {
+            self.load_zip(archive, needed)
+        } else if is_7z_file(archive) {
+            self.load_7z(archive, needed)
+        } else {
+            Err(anyhow!("Unknown archive format"))
+        }
+    }
+
+    /// Index a zip's stored and deflated members; spool any others
+    fn load_zip(&self, path: &Path, needed: &HashSet<String>) -> Result<LoadedArchive> {
+        let mut zip = ZipArchive::new(BufReader::new(fs::File::open(path)?))?;
+        let mut loaded = LoadedArchive::default();
+        let mut others = Vec::new();
+        for member in needed {
+            let Some(index) = zip.index_for_name(member) else {
+                continue;
+            };
+            let entry = zip.by_index_raw(index)?;
+            let deflated = entry.compression() == CompressionMethod::Deflated;
+            if entry.encrypted() || !(deflated || entry.compression() == CompressionMethod::Stored)
+            {
+                others.push(member);
+                continue;
+            }
+            loaded.zip_members.insert(
+                member.clone(),
+                ZipMember {
+                    data_start: entry.data_start(),
+                    compressed_size: entry.compressed_size(),
+                    crc32: entry.crc32(),
+                    deflated,
+                },
+            );
+        }
+
+        if !others.is_empty() {
+            let spool = self.spool_dir()?;
+            for member in others {
+                let spooled = spool.path().join(loaded.spooled.len().to_string());
+                let mut out = BufWriter::new(fs::File::create(&spooled)?);
+                io::copy(&mut zip.by_name(member)?, &mut out)?;
+                out.flush()?;
+                loaded.spooled.insert(member.clone(), spooled);
+            }
+            loaded._spool = Some(spool);
+        }
+        Ok(loaded)
+    }
+
+    /// Decompress a 7z in one pass, spooling the needed members
+    fn load_7z(&self, path: &Path, needed: &HashSet<String>) -> Result<LoadedArchive> {
+        let spool = self.spool_dir()?;
+        let spooled = spool_7z(path, spool.path(), Some(needed))?;
+        Ok(LoadedArchive {
+            zip_members: HashMap::new(),
+            spooled,
+            _spool: Some(spool),
+        })
+    }
+
+    fn spool_dir(&self) -> Result<TempDir> {
+        fs::create_dir_all(&self.spool_root)?;
+        Ok(tempfile::Builder::new()
+            .prefix(".romshelf-spool-")
+            .tempdir_in(&self.spool_root)?)
+    }
+}
+
+/// Decompress a 7z in one pass, spooling its files (or just the `needed`
+/// ones) into the `spool` directory; returns each member's spooled file
+pub(crate) fn spool_7z(
+    path: &Path,
+    spool: &Path,
+    needed: Option<&HashSet<String>>,
+) -> Result<HashMap<String, PathBuf>> {
+    let mut spooled = HashMap::new();
+    SevenZReader::open(path, Password::empty())?.for_each_entries(|entry, reader| {
+        let name = entry.name().replace('\\', "/");
+        let wanted = needed.is_none_or(|needed| needed.contains(&name));
+        if entry.is_directory() || !wanted || spooled.contains_key(&name) {
+            // Solid blocks decode in order: skip through unneeded data
+            io::copy(reader, &mut io::sink()).map_err(sevenz_rust::Error::io)?;
+        } else {
+            let file = spool.join(spooled.len().to_string());
+            let mut out = BufWriter::new(fs::File::create(&file).map_err(sevenz_rust::Error::io)?);
+            io::copy(reader, &mut out).map_err(sevenz_rust::Error::io)?;
+            out.flush().map_err(sevenz_rust::Error::io)?;
+            spooled.insert(name, file);
+        }
+        Ok(needed.is_none_or(|needed| spooled.len() < needed.len()))
+    })?;
+    Ok(spooled)
+}
+
+/// Sources a move takes away once every archive taking them is written
+///
+/// Sources inside the archives being written (met when merging) are never
+/// taken away.
+struct MovedSources {
+    /// Archives still to write that take each source
+    pending: HashMap<PathBuf, usize>,
+    /// Sources an archive failed to take; they stay
+    kept: HashSet<PathBuf>,
+    /// Members taken from each source archive
+    taken: HashMap<PathBuf, HashSet<String>>,
+    /// Where the archives written so far put each source
+    destinations: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl MovedSources {
+    fn new(archives: &ArchiveGroups) -> Self {
+        let targets: HashSet<&PathBuf> = archives.keys().map(|(path, _)| path).collect();
+        let mut pending: HashMap<PathBuf, usize> = HashMap::new();
+        for group in archives.values() {
+            for (path, _) in &group.files {
+                let file = split_member(path).map_or_else(|| path.clone(), |(archive, _)| archive);
+                if !targets.contains(&file) {
+                    *pending.entry(path.clone()).or_default() += 1;
+                }
+            }
+        }
+        Self {
+            pending,
+            kept: HashSet::new(),
+            taken: HashMap::new(),
+            destinations: HashMap::new(),
+        }
+    }
+
+    /// Note which of a group's sources its archive took; returns the plain
+    /// files no archive still needs
+    fn finish(
+        &mut self,
+        archive: &Path,
+        files: &[(PathBuf, String)],
+        taken: &[PathBuf],
+    ) -> Vec<PathBuf> {
+        let taken: HashSet<&PathBuf> = taken.iter().collect();
+        let mut done = Vec::new();
+        for (path, name) in files {
+            let Some(pending) = self.pending.get_mut(path) else {
+                continue;
+            };
+            if taken.contains(path) {
+                self.destinations
+                    .entry(path.clone())
+                    .or_default()
+                    .push(member_path(archive, name));
+            } else {
+                self.kept.insert(path.clone());
+            }
+            *pending -= 1;
+            if *pending > 0 {
+                continue;
+            }
+            self.pending.remove(path);
+            if self.kept.remove(path) {
+                continue;
+            }
+            match split_member(path) {
+                Some((archive, member)) => {
+                    self.taken.entry(archive).or_default().insert(member);
+                }
+                None => done.push(path.clone()),
+            }
+        }
+        done
+    }
+
+    /// A removed source paired with each archive member it went to
+    fn went(&mut self, source: &Path) -> Vec<(PathBuf, PathBuf)> {
+        self.destinations
+            .remove(source)
+            .into_iter()
+            .flatten()
+            .map(|destination| (source.to_path_buf(), destination))
+            .collect()
+    }
+}
+
+/// Take members out of a source archive: delete it if none are left
+/// (returning true), or rewrite it beside itself with the rest and swap it in
+fn remove_members(
+    members: &MemberSources,
+    archive: &Path,
+    taken: &HashSet<String>,
+) -> Result<bool> {
+    let rest: HashSet<String> = member_names(archive)?
+        .into_iter()
+        .filter(|name| !taken.contains(name))
+        .collect();
+    if rest.is_empty() {
+        fs::remove_file(archive)?;
+        return Ok(true);
+    }
+
+    let rewritten = if is_zip_file(archive) {
+        let rewritten = temp_path_beside(archive)?;
+        copy_zip_without(archive, &rewritten, taken)?;
+        rewritten
+    } else {
+        let loaded = members.load(archive, &rest)?;
+        let files = rest
+            .iter()
+            .map(|name| Ok((loaded.locate(archive, name)?, name.clone(), None)))
+            .collect::<Result<Vec<_>>>()?;
+        write_archive(archive, ArchiveFormat::SevenZip, files)?.0
+    };
+    rewritten.persist(archive)?;
+    Ok(false)
+}
+
+/// Names of the files in a zip or 7z, without reading their data
+fn member_names(archive: &Path) -> Result<Vec<String>> {
+    if is_zip_file(archive) {
+        let zip = ZipArchive::new(BufReader::new(fs::File::open(archive)?))?;
+        Ok(zip
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .map(str::to_string)
+            .collect())
+    } else if is_7z_file(archive) {
+        let reader = SevenZReader::open(archive, Password::empty())?;
+        Ok(reader
+            .archive()
+            .files
+            .iter()
+            .filter(|entry| !entry.is_directory())
+            .map(|entry| entry.name().replace('\\', "/"))
+            .collect())
+    } else {
+        Err(anyhow!("Unknown archive format"))
+    }
+}
+
+/// Copy a zip's entries, still compressed, leaving some out; then check the
+/// copy's data against the CRCs of the entries kept
+fn copy_zip_without(source: &Path, dest: &Path, left_out: &HashSet<String>) -> Result<()> {
+    let mut zip = ZipArchive::new(BufReader::new(fs::File::open(source)?))?;
+    let mut out = zip::ZipWriter::new(BufWriter::new(fs::File::create(dest)?));
+    let mut expected = Vec::new();
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i)?;
+        if left_out.contains(entry.name()) {
+            continue;
+        }
+        if !entry.is_dir() {
+            expected.push((entry.name().to_string(), entry.crc32()));
+        }
+        out.raw_copy_file(entry)?;
+    }
+    out.finish()?
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    compare_entries(expected, archive_entries(dest, ArchiveFormat::Zip)?)
+}
+
+/// The archives a group of files takes members from
+fn source_archives(files: &[(PathBuf, String)]) -> HashSet<PathBuf> {
+    files
+        .iter()
+        .filter_map(|(path, _)| split_member(path).map(|(archive, _)| archive))
+        .collect()
+}
+
+/// A source to pack, opened on first read; keeps a running CRC of what
+/// was read, and checks zip members against theirs
+struct LazySource {
+    location: SourceLocation,
+    reader: Option<Box<dyn Read>>,
+    crc: Rc<RefCell<crc32fast::Hasher>>,
+}
+
+impl LazySource {
+    fn new(location: SourceLocation) -> Self {
+        Self {
+            location,
+            reader: None,
+            crc: Rc::default(),
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Read>> {
+        Ok(match &self.location {
+            SourceLocation::File(path) => Box::new(BufReader::new(fs::File::open(path)?)),
+            SourceLocation::ZipMember { archive, member } => {
+                let mut file = fs::File::open(archive)?;
+                file.seek(SeekFrom::Start(member.data_start))?;
+                let data = BufReader::new(file).take(member.compressed_size);
+                if member.deflated {
+                    Box::new(flate2::read::DeflateDecoder::new(data))
+                } else {
+                    Box::new(data)
+                }
+            }
+        })
+    }
+}
+
+impl Read for LazySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => {
+                let reader = self.open()?;
+                self.reader.insert(reader)
+            }
+        };
+        let n = reader.read(buf)?;
+        self.crc.borrow_mut().update(&buf[..n]);
+        if n == 0
+            && !buf.is_empty()
+            && let SourceLocation::ZipMember { archive, member } = &self.location
+            && self.crc.borrow().clone().finalize() != member.crc32
+        {
+            return Err(io::Error::other(format!(
+                "CRC mismatch reading a member of {}",
+                archive.display()
+            )));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use crate::services::verifier;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    fn rebuild_options(target: &Path, mode: RebuildMode) -> RebuildOptions {
+        RebuildOptions {
+            target: target.to_path_buf(),
+            mode,
+            dry_run: false,
+            copy: true,
+            format: ArchiveFormat::Zip,
+            template: None,
+            merge: false,
+            backup: None,
+        }
+    }
+
+    fn source(path: &Path, rom: &str, set: &str) -> RebuildSource {
+        RebuildSource {
+            path: path.to_path_buf(),
+            rom_name: rom.to_string(),
+            dat_name: "Test DAT".to_string(),
+            set_name: Some(set.to_string()),
+            set_description: None,
+            parent: None,
+            category: Some("Sys".to_string()),
+            format: None,
+            crc32: None,
+        }
+    }
+
+    fn zip_members(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index(i).unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (entry.name().to_string(), content)
+            })
+            .collect()
+    }
+
+    fn write_source_zip(path: &Path, members: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, content) in members {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_loose() {
+        let dir = tempfile::tempdir().unwrap();
+        let roms = dir.path().join("roms");
+        fs::create_dir(&roms).unwrap();
+        fs::write(roms.join("wrong name.bin"), b"aaa").unwrap();
+        write_source_zip(&roms.join("pack.zip"), &[("b.rom", b"b"), ("c.rom", b"c")]);
+        let target = dir.path().join("out");
+        let sources = vec![
+            source(&roms.join("wrong name.bin"), "a.rom", "Game: A"),
+            source(&roms.join("pack.zip#b.rom"), "b.rom", "Game B"),
+            source(&roms.join("pack.zip#c.rom"), "c.rom", "Game B"),
+            source(&roms.join("gone.bin"), "d.rom", "Game D"),
+        ];
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let options = RebuildOptions {
+            dry_run: true,
+            ..rebuild_options(&target, RebuildMode::Loose)
+        };
+        let result = Rebuilder::new(options.clone(), move |e| seen.lock().unwrap().push(e))
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.placed, result.skipped, result.errors), (2, 1, 0));
+        assert!(!target.exists());
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            events[0],
+            RebuildEvent::Started {
+                total_files: 4,
+                dry_run: true
+            }
+        ));
+        assert!(matches!(
+            events.last(),
+            Some(RebuildEvent::Completed { placed: 2, .. })
+        ));
+
+        let options = RebuildOptions {
+            dry_run: false,
+            ..options
+        };
+        let result = Rebuilder::new(options.clone(), ())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.placed, result.skipped, result.errors), (2, 1, 0));
+        assert_eq!(fs::read(target.join("Sys/Game_ A/a.rom")).unwrap(), b"aaa");
+        assert!(target.join("Sys/Game B/pack.zip").exists());
+        assert!(roms.join("wrong name.bin").exists());
+
+        // Existing targets are skipped; a move takes the source away
+        let result = Rebuilder::new(options, ()).rebuild(&sources).unwrap();
+        assert_eq!((result.placed, result.skipped), (0, 3));
+        let moved = RebuildOptions {
+            copy: false,
+            ..rebuild_options(&dir.path().join("moved"), RebuildMode::Loose)
+        };
+        Rebuilder::new(moved, ()).rebuild(&sources[..1]).unwrap();
+        assert!(!roms.join("wrong name.bin").exists());
+        assert!(dir.path().join("moved/Sys/Game_ A/a.rom").exists());
+    }
+
+    #[test]
+    fn test_rebuild_zips() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("x.bin"), b"xx").unwrap();
+        write_source_zip(&dir.path().join("pack.zip"), &[("inner.bin", b"yy")]);
+        let sources = vec![
+            source(&dir.path().join("x.bin"), "b.rom", "Game"),
+            source(&dir.path().join("pack.zip#inner.bin"), "A.rom", "Game"),
+            source(&dir.path().join("x.bin"), "z.rom", "Other"),
+        ];
+
+        let per_set = rebuild_options(&dir.path().join("sets"), RebuildMode::ZipPerSet);
+        let result = Rebuilder::new(per_set.clone(), ())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.archives, result.placed), (2, 3));
+        assert_eq!(
+            zip_members(&dir.path().join("sets/Sys/Game.zip")),
+            vec![
+                ("A.rom".to_string(), b"yy".to_vec()),
+                ("b.rom".to_string(), b"xx".to_vec())
+            ]
+        );
+        assert!(torrentzip::is_torrentzipped(&dir.path().join("sets/Sys/Game.zip")).unwrap());
+        let again = Rebuilder::new(per_set, ()).rebuild(&sources).unwrap();
+        assert_eq!((again.archives, again.skipped), (0, 2));
+
+        let per_dat = rebuild_options(&dir.path().join("dats"), RebuildMode::ZipPerDat);
+        Rebuilder::new(per_dat, ()).rebuild(&sources).unwrap();
+        let names: Vec<String> = zip_members(&dir.path().join("dats/Sys/Test DAT.zip"))
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["Game/A.rom", "Game/b.rom", "Other/z.rom"]);
+
+        // A source that can't be read fails its archive and leaves nothing behind
+        let broken = vec![source(&dir.path().join("gone.bin"), "g.rom", "Broken")];
+        let failed = rebuild_options(&dir.path().join("broken"), RebuildMode::ZipPerSet);
+        let result = Rebuilder::new(failed, ()).rebuild(&broken).unwrap();
+        assert_eq!((result.archives, result.errors), (0, 1));
+        assert!(!dir.path().join("broken/Sys/Broken.zip").exists());
+
+        // A '#' in a directory's or a loose file's name is part of the name
+        let hashed = dir.path().join("Disk #2");
+        fs::create_dir(&hashed).unwrap();
+        fs::write(hashed.join("Disc #3.bin"), b"dd").unwrap();
+        write_source_zip(&hashed.join("Set #1.zip"), &[("e #1.bin", b"ee")]);
+        let sources = vec![
+            source(&hashed.join("Disc #3.bin"), "d.rom", "Hashed"),
+            source(&hashed.join("Set #1.zip#e #1.bin"), "e.rom", "Hashed"),
+        ];
+        let options = rebuild_options(&dir.path().join("hashed"), RebuildMode::ZipPerSet);
+        let result = Rebuilder::new(options, ()).rebuild(&sources).unwrap();
+        assert_eq!((result.archives, result.errors), (1, 0));
+        assert_eq!(
+            zip_members(&dir.path().join("hashed/Sys/Hashed.zip")),
+            vec![
+                ("d.rom".to_string(), b"dd".to_vec()),
+                ("e.rom".to_string(), b"ee".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("x.bin"), b"xx").unwrap();
+        fs::write(dir.path().join("empty.bin"), b"").unwrap();
+        write_source_zip(&dir.path().join("pack.zip"), &[("inner.bin", b"yy")]);
+        let mut sources = vec![
+            source(&dir.path().join("x.bin"), "b.rom", "Game"),
+            source(&dir.path().join("empty.bin"), "c.rom", "Game"),
+            source(&dir.path().join("pack.zip#inner.bin"), "A.rom", "Game"),
+            source(&dir.path().join("x.bin"), "z.rom", "Other"),
+        ];
+        // A DAT's own format wins over the rebuild's
+        sources[3].format = Some(ArchiveFormat::ZipZstd);
+
+        let options = RebuildOptions {
+            format: ArchiveFormat::SevenZip,
+            ..rebuild_options(&dir.path().join("out"), RebuildMode::ZipPerSet)
+        };
+        let result = Rebuilder::new(options, ()).rebuild(&sources).unwrap();
+        assert_eq!((result.archives, result.placed, result.errors), (2, 4, 0));
+        assert!(dir.path().join("out/Sys/Game.7z").exists());
+        assert_eq!(
+            zip_members(&dir.path().join("out/Sys/Other.zip")),
+            vec![("z.rom".to_string(), b"xx".to_vec())]
+        );
+
+        // Both read back through the scanner, as verification sees them
+        let mut scanned: Vec<(String, String)> =
+            crate::scan::scan_directory(&dir.path().join("out"))
+                .unwrap()
+                .into_iter()
+                .map(|file| (file.filename, file.crc32))
+                .collect();
+        scanned.sort();
+        let crc = |data: &[u8]| format!("{:08x}", crc32fast::hash(data));
+        assert_eq!(
+            scanned,
+            vec![
+                ("A.rom".to_string(), crc(b"yy")),
+                ("b.rom".to_string(), crc(b"xx")),
+                ("c.rom".to_string(), crc(b"")),
+                ("z.rom".to_string(), crc(b"xx")),
+            ]
+        );
+
+        assert_eq!(
+            "torrent7z".parse::<ArchiveFormat>().unwrap(),
+            ArchiveFormat::SevenZip
+        );
+        assert!("rar".parse::<ArchiveFormat>().is_err());
+    }
+
+    #[test]
+    fn test_rebuild_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("x.bin"), b"xx").unwrap();
+        let sources = vec![
+            source(&dir.path().join("x.bin"), "a.rom", "Alpha (USA)"),
+            source(&dir.path().join("x.bin"), "a.rom", "Alpha (USA) (Rev 1)"),
+            source(&dir.path().join("x.bin"), "b.rom", "beta (Europe)"),
+        ];
+        let options = RebuildOptions {
+            template: Some(
+                "{system}/{first_letter}/{title} ({region}).zip"
+                    .parse()
+                    .unwrap(),
+            ),
+            ..rebuild_options(&dir.path().join("out"), RebuildMode::ZipPerSet)
+        };
+
+        // Both Alpha sets render to the same archive
+        let planned = Rebuilder::new(options.clone(), ())
+            .preview(&sources)
+            .unwrap();
+        let summary: Vec<_> = planned
+            .iter()
+            .map(|p| {
+                (
+                    p.path.strip_prefix(dir.path()).unwrap(),
+                    p.files,
+                    p.conflict,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Path::new("out/Sys/A/Alpha (USA).zip"), 2, true),
+                (Path::new("out/Sys/B/beta (Europe).zip"), 1, false),
+            ]
+        );
+        assert_eq!(
+            planned[0].sets,
+            vec!["Test DAT: Alpha (USA)", "Test DAT: Alpha (USA) (Rev 1)"]
+        );
+
+        let result = Rebuilder::new(options.clone(), ())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.archives, result.errors), (1, 1));
+        assert!(dir.path().join("out/Sys/B/beta (Europe).zip").exists());
+        assert!(!dir.path().join("out/Sys/A").exists());
+
+        // An archive per set needs a set variable
+        let per_dat_template = RebuildOptions {
+            template: Some("{category}/{dat}".parse().unwrap()),
+            ..options.clone()
+        };
+        assert!(
+            Rebuilder::new(per_dat_template.clone(), ())
+                .rebuild(&sources)
+                .is_err()
+        );
+        let per_dat = RebuildOptions {
+            mode: RebuildMode::ZipPerDat,
+            ..per_dat_template
+        };
+        let planned = Rebuilder::new(per_dat, ()).preview(&sources).unwrap();
+        assert_eq!(planned.len(), 1);
+        assert_eq!((planned[0].files, planned[0].conflict), (3, false));
+
+        // Loose files go in the rendered directory, flat here
+        let loose = RebuildOptions {
+            mode: RebuildMode::Loose,
+            template: Some("flat/{system}".parse().unwrap()),
+            ..options
+        };
+        let result = Rebuilder::new(loose, ()).rebuild(&sources[2..]).unwrap();
+        assert_eq!(result.placed, 1);
+        assert!(dir.path().join("out/flat/Sys/b.rom").exists());
+    }
+
+    #[test]
+    fn test_rebuild_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        let archive = target.join("Sys/Game.zip");
+        fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        write_source_zip(
+            &archive,
+            &[("a.rom", b"aa"), ("b.rom", b"bad"), ("junk.txt", b"junk")],
+        );
+        fs::write(dir.path().join("c.bin"), b"cc").unwrap();
+        let sources = vec![source(&dir.path().join("c.bin"), "c.rom", "Game")];
+        let set_roms = SetRoms::from([(
+            ("Test DAT".to_string(), "Game".to_string()),
+            vec![
+                ("a.rom".to_string(), Some(crc32fast::hash(b"aa"))),
+                ("b.rom".to_string(), Some(crc32fast::hash(b"bb"))),
+                ("c.rom".to_string(), Some(crc32fast::hash(b"cc"))),
+            ],
+        )]);
+        let options = RebuildOptions {
+            dry_run: true,
+            merge: true,
+            ..rebuild_options(&target, RebuildMode::ZipPerSet)
+        };
+
+        let result = Rebuilder::new(options.clone(), ())
+            .with_set_roms(set_roms.clone())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.merged, result.placed), (1, 1));
+        assert_eq!(zip_members(&archive).len(), 3);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let options = RebuildOptions {
+            dry_run: false,
+            ..options
+        };
+        let result = Rebuilder::new(options.clone(), move |e| seen.lock().unwrap().push(e))
+            .with_set_roms(set_roms.clone())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((result.merged, result.placed, result.errors), (1, 1, 0));
+        assert!(events.lock().unwrap().iter().any(|e| matches!(
+            e,
+            RebuildEvent::ArchiveMerged {
+                kept: 1,
+                added: 1,
+                dropped: 2,
+                ..
+            }
+        )));
+        assert_eq!(
+            zip_members(&archive),
+            vec![
+                ("a.rom".to_string(), b"aa".to_vec()),
+                ("c.rom".to_string(), b"cc".to_vec())
+            ]
+        );
+        assert!(torrentzip::is_torrentzipped(&archive).unwrap());
+        let backup = target.join(".romshelf-backup/Sys/Game.zip");
+        assert_eq!(fs::read(backup.join("b.rom")).unwrap(), b"bad");
+        assert_eq!(fs::read(backup.join("junk.txt")).unwrap(), b"junk");
+        assert_eq!(fs::read_dir(archive.parent().unwrap()).unwrap().count(), 1);
+
+        // Nothing left to change
+        let again = Rebuilder::new(options.clone(), ())
+            .with_set_roms(set_roms.clone())
+            .rebuild(&sources)
+            .unwrap();
+        assert_eq!((again.merged, again.skipped), (0, 1));
+
+        // Per DAT, only the sets of the archive's own DAT are kept
+        let per_dat = target.join("Sys/Test DAT.zip");
+        write_source_zip(&per_dat, &[("Game/a.rom", b"aa"), ("Other/x.rom", b"xx")]);
+        let mut set_roms = set_roms;
+        set_roms.insert(
+            ("Other DAT".to_string(), "Other".to_string()),
+            vec![("x.rom".to_string(), Some(crc32fast::hash(b"xx")))],
+        );
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let options = RebuildOptions {
+            mode: RebuildMode::ZipPerDat,
+            dry_run: true,
+            ..options
+        };
+        Rebuilder::new(options, move |e| seen.lock().unwrap().push(e))
+            .with_set_roms(set_roms)
+            .rebuild(&sources)
+            .unwrap();
+        assert!(events.lock().unwrap().iter().any(|e| matches!(
+            e,
+            RebuildEvent::ArchiveMerged {
+                kept: 1,
+                added: 1,
+                dropped: 1,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn test_rebuild_streams_archive_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let packed = dir.path().join("packed");
+        fs::create_dir(&packed).unwrap();
+        fs::write(packed.join("a.bin"), b"aaa").unwrap();
+        fs::write(packed.join("b.bin"), b"bbb").unwrap();
+        fs::write(packed.join("c.bin"), b"ccc").unwrap();
+        sevenz_rust::compress_to_path(&packed, dir.path().join("src.7z")).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(dir.path().join("src.zip")).unwrap());
+        for (name, method) in [
+            ("stored.bin", zip::CompressionMethod::Stored),
+            ("bzip.bin", zip::CompressionMethod::Bzip2),
+        ] {
+            let options = zip::write::SimpleFileOptions::default().compression_method(method);
+            zip.start_file(name, options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let sources = vec![
+            source(&dir.path().join("src.7z#a.bin"), "a.rom", "One"),
+            source(&dir.path().join("src.7z#b.bin"), "b.rom", "Two"),
+            source(&dir.path().join("src.7z#c.bin"), "c.rom", "Two"),
+            source(&dir.path().join("src.zip#stored.bin"), "s.rom", "Two"),
+            source(&dir.path().join("src.zip#bzip.bin"), "z.rom", "Two"),
+        ];
+        let options = rebuild_options(&dir.path().join("out"), RebuildMode::ZipPerSet);
+        let rebuilder = Rebuilder::new(options, ());
+
+        // The 7z is loaded once for both archives, then let go
+        let archives = rebuilder.group_archives(&sources);
+        let mut members = MemberSources::new(&archives, &dir.path().join("out"));
+        assert_eq!(members.users[&dir.path().join("src.7z")], 2);
+        let (_, first) = archives.iter().next().unwrap();
+        members.locate(&first.files[0].0).unwrap();
+        members.release(&first.files);
+        assert_eq!(members.loaded.len(), 1);
+        drop(members);
+
+        let result = rebuilder.rebuild(&sources).unwrap();
+        assert_eq!((result.archives, result.placed, result.errors), (2, 5, 0));
+        assert_eq!(
+            zip_members(&dir.path().join("out/Sys/Two.zip")),
+            vec![
+                ("b.rom".to_string(), b"bbb".to_vec()),
+                ("c.rom".to_string(), b"ccc".to_vec()),
+                ("s.rom".to_string(), b"stored.bin".to_vec()),
+                ("z.rom".to_string(), b"bzip.bin".to_vec()),
+            ]
+        );
+        // No spooled members are left behind
+        let leftovers: Vec<_> = fs::read_dir(dir.path().join("out"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers, vec!["Sys"]);
+
+        // A member that doesn't match its CRC fails its archive
+        let mut data = fs::read(dir.path().join("src.zip")).unwrap();
+        let at = data
+            .windows(10)
+            .position(|w| w == b"stored.bin")
+            .map(|i| i + 10)
+            .unwrap();
+        data[at] ^= 0xff;
+        fs::write(dir.path().join("src.zip"), data).unwrap();
+        let options = rebuild_options(&dir.path().join("corrupt"), RebuildMode::ZipPerSet);
+        let result = Rebuilder::new(options, ()).rebuild(&sources[3..4]).unwrap();
+        assert_eq!((result.archives, result.errors), (0, 1));
+    }
+
+    #[test]
+    fn test_rebuild_keeps_changed_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        fs::write(dir.path().join("a.bin"), b"aa").unwrap();
+        fs::write(dir.path().join("b.bin"), b"bb").unwrap();
+        let mut sources = vec![
+            source(&dir.path().join("a.bin"), "a.rom", "Game"),
+            source(&dir.path().join("b.bin"), "b.rom", "Game"),
+        ];
+        sources[0].crc32 = Some(crc32fast::hash(b"aa"));
+        sources[1].crc32 = Some(crc32fast::hash(b"bb"));
+        // Changed after it was matched
+        fs::write(dir.path().join("b.bin"), b"bc").unwrap();
+
+        let options = RebuildOptions {
+            copy: false,
+            ..rebuild_options(&target, RebuildMode::ZipPerSet)
+        };
+        let result = Rebuilder::new(options, ()).rebuild(&sources).unwrap();
+        assert_eq!((result.archives, result.errors), (0, 1));
+        assert!(result.removed.is_empty());
+        assert!(dir.path().join("a.bin").exists());
+        assert_eq!(fs::read(dir.path().join("b.bin")).unwrap(), b"bc");
+        assert_eq!(fs::read_dir(target.join("Sys")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_rebuild_moves_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("packed")).unwrap();
+        fs::write(src.join("x.bin"), b"xx").unwrap();
+        fs::write(src.join("y.bin"), b"yy").unwrap();
+        write_source_zip(
+            &src.join("pack.zip"),
+            &[("b.bin", b"bb"), ("c.bin", b"cc"), ("readme.txt", b"hi")],
+        );
+        write_source_zip(&src.join("all.zip"), &[("d.bin", b"dd")]);
+        fs::write(src.join("packed/e.bin"), b"ee").unwrap();
+        fs::write(src.join("packed/f.bin"), b"ff").unwrap();
+        sevenz_rust::compress_to_path(src.join("packed"), src.join("src.7z")).unwrap();
+        let target = dir.path().join("out");
+        fs::create_dir_all(target.join("Sys")).unwrap();
+        fs::write(target.join("Sys/Three.zip"), b"").unwrap();
+        let sources = vec![
+            source(&src.join("x.bin"), "a.rom", "One"),
+            source(&src.join("x.bin"), "a.rom", "Two"),
+            source(&src.join("pack.zip#b.bin"), "b.rom", "One"),
+            source(&src.join("pack.zip#c.bin"), "c.rom", "Two"),
+            source(&src.join("all.zip#d.bin"), "d.rom", "Two"),
+            source(&src.join("src.7z#e.bin"), "e.rom", "Two"),
+            source(&src.join("y.bin"), "y.rom", "Three"),
+        ];
+
+        let options = RebuildOptions {
+            copy: false,
+            ..rebuild_options(&target, RebuildMode::ZipPerSet)
+        };
+        let result = Rebuilder::new(options, ()).rebuild(&sources).unwrap();
+        assert_eq!((result.archives, result.skipped, result.errors), (2, 1, 0));
+        assert_eq!(zip_members(&target.join("Sys/Two.zip")).len(), 4);
+        assert!(!src.join("x.bin").exists());
+        assert!(src.join("y.bin").exists());
+        assert!(!src.join("all.zip").exists());
+        assert_eq!(
+            zip_members(&src.join("pack.zip")),
+            vec![("readme.txt".to_string(), b"hi".to_vec())]
+        );
+        assert_eq!(member_names(&src.join("src.7z")).unwrap(), vec!["f.bin"]);
+        assert_eq!(
+            result.rewritten,
+            vec![src.join("pack.zip"), src.join("src.7z")]
+        );
+        assert_eq!(result.removed.len(), 5);
+        assert_eq!(result.moved.len(), 6);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(&format!(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES (1, 'Sys', 'TOSEC', '/s.dat', 'x');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 2);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'One'), (2, 1, 'Two');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES
+                 (1, 1, 'a.rom', 2, x'01'), (1, 2, 'a.rom', 2, x'01'), (1, 2, 'd.rom', 2, x'04');
+             INSERT INTO scan_roots (id, path, added_at) VALUES
+                 (1, '{}', 'now'), (2, '{}', 'now');
+             INSERT INTO files (root_id, path, filename, size, scanned_at, sha1) VALUES
+                 (1, 'x.bin', 'x.bin', 2, 'now', x'01'),
+                 (2, 'x.bin', 'x.bin', 2, 'now', NULL),
+                 (1, 'y.bin', 'y.bin', 2, 'now', NULL),
+                 (1, 'pack.zip#b.bin', 'b.bin', 2, 'now', NULL),
+                 (1, 'pack.zip#c.bin', 'c.bin', 2, 'now', NULL),
+                 (1, 'pack.zip#readme.txt', 'readme.txt', 2, 'now', NULL),
+                 (1, 'all.zip#d.bin', 'd.bin', 2, 'now', x'04');",
+            src.display(),
+            target.display()
+        ))
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+        assert_eq!(record_moves(&conn, &result).unwrap(), (4, 0));
+        let left: Vec<(i64, String, String, Option<i64>)> = conn
+            .prepare("SELECT root_id, path, filename, mtime FROM files ORDER BY root_id, path")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let paths: Vec<(i64, &str, &str)> = left
+            .iter()
+            .map(|(root, path, filename, _)| (*root, path.as_str(), filename.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                (1, "pack.zip#readme.txt", "readme.txt"),
+                (1, "y.bin", "y.bin"),
+                (2, "Sys/One.zip#a.rom", "a.rom"),
+                (2, "Sys/One.zip#b.rom", "b.rom"),
+                (2, "Sys/Two.zip#a.rom", "a.rom"),
+                (2, "Sys/Two.zip#c.rom", "c.rom"),
+                (2, "Sys/Two.zip#d.rom", "d.rom"),
+                // Another root's file at the same relative path stays
+                (2, "x.bin", "x.bin"),
+            ]
+        );
+        assert!(left[0].3.is_some() && left[2].3.is_some());
+        assert_eq!(left[1].3, None);
+
+        // The moved ROMs are still matched, now by their right names
+        let matched: Vec<(String, bool)> = conn
+            .prepare(
+                "SELECT f.path, m.name_correct FROM matches m
+                 JOIN files f ON f.id = m.file_id ORDER BY f.path, m.dat_entry_id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            matched,
+            vec![
+                ("Sys/One.zip#a.rom".to_string(), true),
+                ("Sys/One.zip#a.rom".to_string(), true),
+                ("Sys/Two.zip#a.rom".to_string(), true),
+                ("Sys/Two.zip#a.rom".to_string(), true),
+                ("Sys/Two.zip#d.rom".to_string(), true),
+            ]
+        );
+
+        // Outside every scan root, the rows go
+        conn.execute("DELETE FROM scan_roots WHERE id = 2", [])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO files (root_id, path, filename, size, scanned_at)
+             VALUES (1, 'x.bin', 'x.bin', 2, 'now')",
+            [],
+        )
+        .unwrap();
+        let loose = RebuildResult {
+            removed: vec![src.join("x.bin")],
+            moved: vec![(src.join("x.bin"), target.join("Sys/One/a.rom"))],
+            ..RebuildResult::default()
+        };
+        assert_eq!(record_moves(&conn, &loose).unwrap(), (0, 1));
+    }
+
+    #[test]
+    fn test_load_sources_uses_primary_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(&format!(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1, category, priority) VALUES
+                 (1, 'Low', 'TOSEC', '/1.dat', '1', 'A', 0),
+                 (2, 'High', 'TOSEC', '/2.dat', '2', 'B', 5);
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES (1, 1, 'now', 1), (2, 2, 'now', 1);
+             INSERT INTO sets (id, dat_version_id, name) VALUES (1, 1, 'Low Set'), (2, 2, 'High Set');
+             INSERT INTO dat_entries (dat_version_id, set_id, name, size, sha1) VALUES
+                 (1, 1, 'low.rom', 1, x'01'), (2, 2, 'high.rom', 1, x'01');
+             INSERT INTO scan_roots (id, path, added_at) VALUES (1, '{}', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 1, 'x.bin', 'x.bin', 1, 'now', x'01');
+             UPDATE dats SET output_format = '7z' WHERE id = 2;",
+            dir.path().display()
+        ))
+        .unwrap();
+        verifier::rematch_all(&conn).unwrap();
+
+        let sources = load_sources(&conn).unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].path, dir.path().join("x.bin"));
+        assert_eq!(
+            (sources[0].rom_name.as_str(), sources[0].set_name.as_deref()),
+            ("high.rom", Some("High Set"))
+        );
+        assert_eq!(sources[0].format, Some(ArchiveFormat::SevenZip));
+        set_output_format(&conn, 2, None).unwrap();
+        assert_eq!(output_format(&conn, 2).unwrap(), None);
+        assert!(set_output_format(&conn, 9, Some(ArchiveFormat::Zip)).is_err());
+    }
+}
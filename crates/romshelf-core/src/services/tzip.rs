@@ -0,0 +1,520 @@
+//! TorrentZip conversion - rewrite existing zips and 7z archives in place
+//!
+//! Archives are found by walking the given paths and converted in parallel:
+//! a discovery thread feeds a worker pool, as in scanning. Each new archive
+//! is written to a temp file beside the old one and its entries are read
+//! back and checked against the CRCs of the data written; only then does it
+//! replace the old archive. A 7z archive becomes a zip of the same name and
+//! the 7z is removed.
+
+use crate::db::roots;
+use crate::scan::{is_7z_file, is_zip_file};
+use crate::services::progress::{ProgressSink, TzipEvent};
+use crate::services::rebuild::spool_7z;
+use crate::torrentzip::{self, TorrentZipWriter};
+use anyhow::{Context, Result, anyhow, bail};
+use crossbeam_channel::{Receiver, Sender, bounded};
+use rayon::prelude::*;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+/// Options controlling a conversion run
+#[derive(Debug, Clone)]
+pub struct TzipOptions {
+    /// Worker threads converting archives
+    pub threads: usize,
+    /// Only report which archives would be rewritten
+    pub dry_run: bool,
+}
+
+/// What happened to one archive
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum TzipOutcome {
+    /// Rewritten as TorrentZip; `new_path` differs from the path for a 7z
+    Converted {
+        new_path: PathBuf,
+        entries: u64,
+    },
+    AlreadyTorrentzipped,
+    /// Dry run: the archive would be rewritten
+    WouldConvert,
+    /// Left untouched
+    Failed {
+        error: String,
+    },
+}
+
+/// An archive found under the given paths and its outcome
+#[derive(Debug, Serialize, Clone)]
+pub struct TzipArchive {
+    pub path: PathBuf,
+    pub outcome: TzipOutcome,
+}
+
+/// Convert every zip and 7z archive under `paths` (files or directories)
+///
+/// Failures are reported per archive; the rest carry on. Archives come back
+/// ordered by path.
+pub fn convert_paths<S: ProgressSink<TzipEvent>>(
+    paths: &[PathBuf],
+    options: &TzipOptions,
+    sink: &S,
+) -> Result<Vec<TzipArchive>> {
+    let start_time = Instant::now();
+    let (sender, receiver) = bounded::<PathBuf>(1000);
+    let roots: Vec<PathBuf> = paths
+        .iter()
+        .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+        .collect();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads.max(1))
+        .build()?;
+    let dry_run = options.dry_run;
+    let mut archives: Vec<TzipArchive> = std::thread::scope(|scope| {
+        let discovery = scope.spawn(|| discover_archives(&roots, sender, sink));
+        let archives = pool.install(|| convert_queued(receiver, dry_run, sink));
+        discovery
+            .join()
+            .map_err(|_| anyhow!("Archive discovery panicked"))?;
+        Ok::<_, anyhow::Error>(archives)
+    })?;
+
+    archives.sort_by(|a, b| a.path.cmp(&b.path));
+    let count =
+        |f: fn(&TzipOutcome) -> bool| archives.iter().filter(|a| f(&a.outcome)).count() as u64;
+    sink.emit(TzipEvent::Summary {
+        converted: count(|o| matches!(o, TzipOutcome::Converted { .. })),
+        would_convert: count(|o| matches!(o, TzipOutcome::WouldConvert)),
+        unchanged: count(|o| matches!(o, TzipOutcome::AlreadyTorrentzipped)),
+        failed: count(|o| matches!(o, TzipOutcome::Failed { .. })),
+        duration_ms: start_time.elapsed().as_millis(),
+    });
+    Ok(archives)
+}
+
+/// Convert (or, on a dry run, check) each archive discovery queues
+fn convert_queued<S: ProgressSink<TzipEvent>>(
+    receiver: Receiver<PathBuf>,
+    dry_run: bool,
+    sink: &S,
+) -> Vec<TzipArchive> {
+    receiver
+        .into_iter()
+        .par_bridge()
+        .map(|path| {
+            sink.emit(TzipEvent::ArchiveStarted { path: path.clone() });
+            let outcome = if dry_run {
+                needs_conversion(&path)
+                    .map(|needed| match needed {
+                        true => TzipOutcome::WouldConvert,
+                        false => TzipOutcome::AlreadyTorrentzipped,
+                    })
+                    .unwrap_or_else(|e| TzipOutcome::Failed {
+                        error: format!("{:#}", e),
+                    })
+            } else {
+                convert_archive(&path).unwrap_or_else(|e| TzipOutcome::Failed {
+                    error: format!("{:#}", e),
+                })
+            };
+            sink.emit(match &outcome {
+                TzipOutcome::Converted { new_path, entries } => TzipEvent::ArchiveConverted {
+                    path: path.clone(),
+                    new_path: new_path.clone(),
+                    entries: *entries,
+                },
+                TzipOutcome::AlreadyTorrentzipped => {
+                    TzipEvent::AlreadyTorrentzipped { path: path.clone() }
+                }
+                TzipOutcome::WouldConvert => TzipEvent::WouldConvert { path: path.clone() },
+                TzipOutcome::Failed { error } => TzipEvent::ArchiveFailed {
+                    path: path.clone(),
+                    error: error.clone(),
+                },
+            });
+            TzipArchive { path, outcome }
+        })
+        .collect()
+}
+
+/// Walk the given paths and queue every zip and 7z archive
+///
+/// Anything that can't be walked is reported and skipped.
+fn discover_archives<S: ProgressSink<TzipEvent>>(
+    paths: &[PathBuf],
+    sender: Sender<PathBuf>,
+    sink: &S,
+) {
+    for path in paths {
+        for entry in WalkDir::new(path).follow_links(true) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    sink.emit(TzipEvent::DiscoveryFailed {
+                        path: e.path().unwrap_or(path).to_path_buf(),
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let file_path = entry.path();
+            if entry.file_type().is_file()
+                && (is_zip_file(file_path) || is_7z_file(file_path))
+                && sender.send(file_path.to_path_buf()).is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// Whether an archive isn't TorrentZip yet (every 7z needs converting)
+pub fn needs_conversion(path: &Path) -> Result<bool> {
+    if is_7z_file(path) {
+        return Ok(true);
+    }
+    Ok(!torrentzip::is_torrentzipped(path)?)
+}
+
+/// Rewrite one zip or 7z archive as TorrentZip, unless it already is one
+pub fn convert_archive(path: &Path) -> Result<TzipOutcome> {
+    if !needs_conversion(path)? {
+        return Ok(TzipOutcome::AlreadyTorrentzipped);
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp = tempfile::Builder::new()
+        .prefix(".romshelf-")
+        .suffix(".tzip.tmp")
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create a temp file in {}", dir.display()))?;
+
+    let (new_path, expected) = if is_7z_file(path) {
+        let new_path = path.with_extension("zip");
+        if new_path.exists() {
+            bail!("{} already exists", new_path.display());
+        }
+        (new_path, write_from_7z(path, temp.as_file_mut())?)
+    } else {
+        (
+            path.to_path_buf(),
+            write_from_zip(path, temp.as_file_mut())?,
+        )
+    };
+    temp.as_file().sync_all()?;
+    verify_entries(temp.path(), &expected)
+        .with_context(|| format!("Rewritten {} failed verification", path.display()))?;
+
+    fs::set_permissions(temp.path(), fs::metadata(path)?.permissions())?;
+    if new_path == path {
+        temp.persist(path)?;
+    } else {
+        temp.persist_noclobber(&new_path)?;
+        fs::remove_file(path)?;
+    }
+    Ok(TzipOutcome::Converted {
+        new_path,
+        entries: expected.len() as u64,
+    })
+}
+
+/// Copy a zip's entries into `out` in TorrentZip order; returns names and CRCs
+///
+/// Directory entries are kept only for empty directories.
+fn write_from_zip(path: &Path, out: &mut File) -> Result<Vec<(String, u32)>> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))
+        .with_context(|| format!("Failed to read ZIP archive: {}", path.display()))?;
+    let mut names = (0..archive.len())
+        .map(|i| Ok(archive.by_index_raw(i)?.name().to_string()))
+        .collect::<Result<Vec<String>>>()?;
+    // A directory's contents sort straight after it byte-wise
+    names.sort();
+    let mut names: Vec<String> = names
+        .iter()
+        .enumerate()
+        .filter(|(i, name)| {
+            !name.ends_with('/') || names.get(i + 1).is_none_or(|next| !next.starts_with(*name))
+        })
+        .map(|(_, name)| name.clone())
+        .collect();
+    names.sort_by(|a, b| torrentzip::compare_names(a, b));
+
+    let mut writer = TorrentZipWriter::new(BufWriter::new(out));
+    let mut written = Vec::with_capacity(names.len());
+    for name in names {
+        let entry = archive.by_name(&name)?;
+        let source_crc = entry.crc32();
+        let crc = writer.add_file(&name, entry)?;
+        if crc != source_crc {
+            bail!("'{}' does not match its CRC in {}", name, path.display());
+        }
+        written.push((name, crc));
+    }
+    writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(written)
+}
+
+/// Copy a 7z's files into `out` in TorrentZip order; returns names and CRCs
+///
+/// The 7z is decompressed in one pass, its files spooled beside it rather
+/// than in a possibly memory-backed temp directory.
+fn write_from_7z(path: &Path, out: &mut File) -> Result<Vec<(String, u32)>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let spool = tempfile::Builder::new()
+        .prefix(".romshelf-spool-")
+        .tempdir_in(dir)
+        .with_context(|| format!("Failed to create a spool directory in {}", dir.display()))?;
+    let mut files: Vec<(String, PathBuf)> = spool_7z(path, spool.path(), None)
+        .with_context(|| format!("Failed to extract 7z archive: {}", path.display()))?
+        .into_iter()
+        .collect();
+    files.sort_by(|a, b| torrentzip::compare_names(&a.0, &b.0));
+
+    let mut writer = TorrentZipWriter::new(BufWriter::new(out));
+    let mut written = Vec::with_capacity(files.len());
+    for (name, file) in files {
+        let crc = writer.add_file(&name, BufReader::new(File::open(&file)?))?;
+        written.push((name, crc));
+    }
+    writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(written)
+}
+
+/// Read back a written archive and check it holds exactly the expected entries
+fn verify_entries(path: &Path, expected: &[(String, u32)]) -> Result<()> {
+    if !torrentzip::is_torrentzipped(path)? {
+        bail!("Not a valid TorrentZip archive");
+    }
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    if archive.len() != expected.len() {
+        bail!(
+            "Expected {} entries, found {}",
+            expected.len(),
+            archive.len()
+        );
+    }
+    let mut buf = vec![0u8; 64 * 1024];
+    for (i, (name, crc)) in expected.iter().enumerate() {
+        let mut entry = archive.by_index(i)?;
+        let mut hasher = crc32fast::Hasher::new();
+        loop {
+            let n = entry.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        if entry.name() != name || hasher.finalize() != *crc {
+            bail!("Entry '{}' failed its CRC check", name);
+        }
+    }
+    Ok(())
+}
+
+/// Point the `files` rows of a converted archive's members at the new archive
+///
+/// Returns the number of rows updated; archives outside every scan root have
+/// none.
+pub fn record_conversion(conn: &Connection, old_path: &Path, new_path: &Path) -> Result<usize> {
+    let Some((root, old_relative)) = roots::find_root(conn, old_path)? else {
+        return Ok(0);
+    };
+    let Some(new_relative) = roots::relative_path(Path::new(&root.path), new_path) else {
+        bail!(
+            "{} is outside the scan root {}",
+            new_path.display(),
+            root.path
+        );
+    };
+    let mtime = fs::metadata(new_path)
+        .ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    let updated = conn.execute(
+        "UPDATE files SET path = ?1 || substr(path, length(?2) + 1), mtime = ?3
+         WHERE root_id = ?4 AND substr(path, 1, length(?2) + 1) = ?2 || '#'",
+        params![new_relative, old_relative, mtime, root.id],
+    )?;
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            if name.ends_with('/') {
+                zip.add_directory(*name, zip::write::SimpleFileOptions::default())
+                    .unwrap();
+            } else {
+                zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                    .unwrap();
+                zip.write_all(content).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+    }
+
+    fn zip_contents(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index(i).unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (entry.name().to_string(), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_convert_zip_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("set.zip");
+        write_zip(
+            &path,
+            &[
+                ("b.rom", b"bb"),
+                ("A.rom", b"a"),
+                ("empty/", b""),
+                ("sub/", b""),
+                ("sub/c.rom", b"ccc"),
+            ],
+        );
+
+        let outcome = convert_archive(&path).unwrap();
+        assert_eq!(
+            outcome,
+            TzipOutcome::Converted {
+                new_path: path.clone(),
+                entries: 4
+            }
+        );
+        assert!(torrentzip::is_torrentzipped(&path).unwrap());
+        assert_eq!(
+            zip_contents(&path),
+            vec![
+                ("A.rom".to_string(), b"a".to_vec()),
+                ("b.rom".to_string(), b"bb".to_vec()),
+                ("empty/".to_string(), Vec::new()),
+                ("sub/c.rom".to_string(), b"ccc".to_vec()),
+            ]
+        );
+        assert_eq!(
+            convert_archive(&path).unwrap(),
+            TzipOutcome::AlreadyTorrentzipped
+        );
+        // No temp files left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_convert_paths_and_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src/x.rom"), b"xx").unwrap();
+        fs::write(root.join("src/y.rom"), b"yyy").unwrap();
+        sevenz_rust::compress_to_path(root.join("src"), root.join("game.7z")).unwrap();
+        fs::remove_dir_all(root.join("src")).unwrap();
+        write_zip(&root.join("plain.zip"), &[("z.rom", b"z")]);
+        fs::write(root.join("broken.zip"), b"not a zip").unwrap();
+        fs::write(root.join("readme.txt"), b"ignored").unwrap();
+
+        let options = TzipOptions {
+            threads: 2,
+            dry_run: true,
+        };
+        let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let sink = move |e| seen.lock().unwrap().push(e);
+        let archives = convert_paths(std::slice::from_ref(&root), &options, &sink).unwrap();
+        let outcomes: Vec<_> = archives.iter().map(|a| &a.outcome).collect();
+        // A dry run only reads headers, so the broken zip is just not TorrentZip yet
+        assert!(outcomes.iter().all(|o| **o == TzipOutcome::WouldConvert));
+        assert!(root.join("game.7z").exists());
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(TzipEvent::Summary {
+                converted: 0,
+                would_convert: 3,
+                ..
+            })
+        ));
+        events.lock().unwrap().clear();
+
+        let options = TzipOptions {
+            threads: 2,
+            dry_run: false,
+        };
+        let archives = convert_paths(std::slice::from_ref(&root), &options, &sink).unwrap();
+        assert_eq!(archives.len(), 3);
+        assert_eq!(
+            archives[1].outcome,
+            TzipOutcome::Converted {
+                new_path: root.join("game.zip"),
+                entries: 2
+            }
+        );
+        assert!(!root.join("game.7z").exists());
+        assert!(torrentzip::is_torrentzipped(&root.join("game.zip")).unwrap());
+        assert!(torrentzip::is_torrentzipped(&root.join("plain.zip")).unwrap());
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(TzipEvent::Summary {
+                converted: 2,
+                would_convert: 0,
+                unchanged: 0,
+                failed: 1,
+                ..
+            })
+        ));
+
+        // The members' rows follow the 7z to its new zip
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO scan_roots (id, path, added_at) VALUES (1, ?1, 'now')",
+            [root.to_string_lossy()],
+        )
+        .unwrap();
+        conn.execute_batch(
+            "INSERT INTO files (id, root_id, path, filename, size, scanned_at) VALUES
+                 (1, 1, 'game.7z#x.rom', 'x.rom', 2, 'now'),
+                 (2, 1, 'game.7z#y.rom', 'y.rom', 3, 'now'),
+                 (3, 1, 'game.7z.bak', 'game.7z.bak', 1, 'now');",
+        )
+        .unwrap();
+        let updated =
+            record_conversion(&conn, &root.join("game.7z"), &root.join("game.zip")).unwrap();
+        assert_eq!(updated, 2);
+        let mut stmt = conn
+            .prepare("SELECT path FROM file_paths ORDER BY path")
+            .unwrap();
+        let paths: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                root.join("game.7z.bak").to_string_lossy().to_string(),
+                root.join("game.zip#x.rom").to_string_lossy().to_string(),
+                root.join("game.zip#y.rom").to_string_lossy().to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,571 @@
+//! Verification service - keeps the `matches` table up to date
+//!
+//! A match links a scanned file to a DAT entry with the same content (SHA1,
+//! CRC32 + size, or MD5) and records whether the file carries the entry's
+//! name. Matches are maintained incrementally: the scanner re-matches the
+//! files it adds or changes, the importer matches a new DAT's entries, and
+//! removing a file or DAT cascades to its matches. Reports read `matches`
+//! instead of joining on hashes themselves.
+//!
+//! Names are checked with the DAT's [`NamePolicy`], stored as JSON in
+//! `dats.name_policy` (NULL for the default).
+//!
+//! Rematching and the report queries take a [`Scope`] to cover only some
+//! DATs (one DAT or a category) and only files under a path.
+//!
+//! Disabled DATs get no matches and are left out of the totals. Where one
+//! match per file is needed, the highest priority DAT wins (the
+//! `primary_matches` view).
+
+use crate::db::matching::{NamePolicies, parse_policy, refresh_name_flags};
+pub use crate::db::matching::{Scope, refresh_names, rematch};
+use crate::verify::NamePolicy;
+use anyhow::{Result, bail};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
+use serde::Serialize;
+
+/// Hash comparison between `files f` and `dat_entries e`: one hash matches
+/// and none that both sides carry differs
+const SAME_CONTENT: &str =
+    "((e.sha1 = f.sha1 OR (e.crc32 = f.crc32 AND e.size = f.size) OR e.md5 = f.md5)
+     AND (e.sha1 IS NULL OR f.sha1 IS NULL OR e.sha1 = f.sha1)
+     AND (e.md5 IS NULL OR f.md5 IS NULL OR e.md5 = f.md5)
+     AND (e.crc32 IS NULL OR f.crc32 IS NULL OR e.crc32 = f.crc32))";
+
+/// Verification totals for one DAT, counted per entry
+#[derive(Debug, Serialize, Clone)]
+pub struct DatVerification {
+    pub dat_id: i64,
+    pub name: String,
+    pub total: i64,
+    /// Entries matched by a correctly named file
+    pub verified: i64,
+    /// Entries only matched by files with the wrong name
+    pub misnamed: i64,
+    /// Entries with no matching file
+    pub missing: i64,
+    /// Matched entries whose files are all on offline volumes
+    pub offline: i64,
+}
+
+/// A file that matches a DAT entry but doesn't carry its name
+#[derive(Debug, Serialize, Clone)]
+pub struct MisnamedFile {
+    pub file_id: i64,
+    pub path: String,
+    pub filename: String,
+    pub expected: String,
+}
+
+/// A file that matches no DAT entry
+#[derive(Debug, Serialize, Clone)]
+pub struct UnmatchedFile {
+    pub file_id: i64,
+    pub path: String,
+    pub filename: String,
+}
+
+/// (Re)match scanned files against every DAT entry
+///
+/// Existing matches for these files are replaced. Returns the number of
+/// matches recorded.
+pub fn match_files(conn: &Connection, file_ids: &[i64]) -> Result<u64> {
+    let mut delete = conn.prepare_cached("DELETE FROM matches WHERE file_id = ?1")?;
+    let sql = format!(
+        "SELECT f.id, f.path, e.id, e.name, v.dat_id
+         FROM files f
+         JOIN dat_entries e ON {}
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         JOIN dats d ON d.id = v.dat_id AND d.enabled = 1
+         WHERE f.id = ?1",
+        SAME_CONTENT
+    );
+    let policies = NamePolicies::load(conn)?;
+    let mut recorded = 0;
+    for &file_id in file_ids {
+        delete.execute([file_id])?;
+        recorded += record_matches(conn, &policies, &sql, file_id)?;
+    }
+    Ok(recorded)
+}
+
+/// Match the entries of a DAT version against every scanned file
+pub fn match_dat_version(conn: &Connection, dat_version_id: i64) -> Result<u64> {
+    conn.execute(
+        "DELETE FROM matches
+         WHERE dat_entry_id IN (SELECT id FROM dat_entries WHERE dat_version_id = ?1)",
+        [dat_version_id],
+    )?;
+    let sql = format!(
+        "SELECT f.id, f.path, e.id, e.name, v.dat_id
+         FROM dat_entries e
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         JOIN dats d ON d.id = v.dat_id AND d.enabled = 1
+         JOIN files f ON {}
+         WHERE e.dat_version_id = ?1",
+        SAME_CONTENT
+    );
+    let policies = NamePolicies::load(conn)?;
+    record_matches(conn, &policies, &sql, dat_version_id)
+}
+
+/// Throw away all matches and compute them again from scratch
+pub fn rematch_all(conn: &Connection) -> Result<u64> {
+    rematch(conn, &Scope::default())
+}
+
+/// Enable or disable a DAT
+///
+/// Disabling drops the DAT's matches; enabling matches its entries again.
+pub fn set_dat_enabled(conn: &Connection, dat_id: i64, enabled: bool) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE dats SET enabled = ?1 WHERE id = ?2",
+        params![enabled, dat_id],
+    )?;
+    if updated == 0 {
+        bail!("DAT {} not found", dat_id);
+    }
+    if enabled {
+        rematch(conn, &Scope::dat(dat_id))?;
+    } else {
+        conn.execute(
+            "DELETE FROM matches WHERE dat_entry_id IN (
+                 SELECT e.id FROM dat_entries e
+                 JOIN dat_versions v ON v.id = e.dat_version_id
+                 WHERE v.dat_id = ?1)",
+            [dat_id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Set a DAT's priority; when a file matches several DATs the highest wins
+pub fn set_dat_priority(conn: &Connection, dat_id: i64, priority: i64) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE dats SET priority = ?1 WHERE id = ?2",
+        params![priority, dat_id],
+    )?;
+    if updated == 0 {
+        bail!("DAT {} not found", dat_id);
+    }
+    Ok(())
+}
+
+/// The name policy a DAT's matches are checked with
+pub fn name_policy(conn: &Connection, dat_id: i64) -> Result<NamePolicy> {
+    let policy: Option<Option<String>> = conn
+        .query_row(
+            "SELECT name_policy FROM dats WHERE id = ?1",
+            [dat_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match policy {
+        None => bail!("DAT {} not found", dat_id),
+        Some(json) => parse_policy(json.as_deref()),
+    }
+}
+
+/// Change a DAT's name policy and recheck the names of its matches
+pub fn set_name_policy(conn: &Connection, dat_id: i64, policy: &NamePolicy) -> Result<()> {
+    let json = if *policy == NamePolicy::default() {
+        None
+    } else {
+        Some(serde_json::to_string(policy)?)
+    };
+    let updated = conn.execute(
+        "UPDATE dats SET name_policy = ?1 WHERE id = ?2",
+        params![json, dat_id],
+    )?;
+    if updated == 0 {
+        bail!("DAT {} not found", dat_id);
+    }
+    refresh_name_flags(
+        conn,
+        "e.dat_version_id IN (SELECT id FROM dat_versions WHERE dat_id = ?1)",
+        dat_id,
+    )
+}
+
+/// Per-DAT verification totals, ordered by DAT name
+pub fn summarise(conn: &Connection) -> Result<Vec<DatVerification>> {
+    summarise_in(conn, &Scope::default())
+}
+
+/// Per-DAT totals within a scope, ordered by DAT name
+///
+/// With a path, only files under it count as having an entry, and when no
+/// DATs are chosen only DATs with a match there are listed.
+pub fn summarise_in(conn: &Connection, scope: &Scope) -> Result<Vec<DatVerification>> {
+    let having = if scope.dat_ids.is_none() && scope.path.is_some() {
+        "HAVING SUM(s.matched > 0) > 0"
+    } else {
+        ""
+    };
+    let mut stmt = conn.prepare(&format!(
+        "WITH entry_status AS (
+             SELECT e.id, dv.dat_id,
+                    MAX(m.name_correct) AS correct,
+                    COUNT(m.id) AS matched,
+                    MAX(CASE WHEN r.online = 1 THEN 1 ELSE 0 END) AS online
+             FROM enabled_dat_entries e
+             JOIN dat_versions dv ON dv.id = e.dat_version_id
+             LEFT JOIN matches m ON m.dat_entry_id = e.id AND {}
+             LEFT JOIN files f ON f.id = m.file_id
+             LEFT JOIN scan_roots r ON r.id = f.root_id
+             WHERE {}
+             GROUP BY e.id
+         )
+         SELECT d.id, d.name,
+                COUNT(s.id),
+                COALESCE(SUM(s.matched > 0 AND s.correct = 1), 0),
+                COALESCE(SUM(s.matched > 0 AND s.correct = 0), 0),
+                COALESCE(SUM(s.matched = 0), 0),
+                COALESCE(SUM(s.matched > 0 AND s.online = 0), 0)
+         FROM dats d
+         JOIN entry_status s ON s.dat_id = d.id
+         GROUP BY d.id
+         {}
+         ORDER BY d.name",
+        scope.file_sql("m.file_id"),
+        scope.dat_sql("dv.dat_id"),
+        having
+    ))?;
+    let rows = stmt
+        .query_map(params_from_iter(scope.params()), |row| {
+            Ok(DatVerification {
+                dat_id: row.get(0)?,
+                name: row.get(1)?,
+                total: row.get(2)?,
+                verified: row.get(3)?,
+                misnamed: row.get(4)?,
+                missing: row.get(5)?,
+                offline: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Files that match DAT entries but carry none of their names
+pub fn misnamed_files(conn: &Connection) -> Result<Vec<MisnamedFile>> {
+    misnamed_files_in(conn, &Scope::default())
+}
+
+/// Misnamed files within a scope, judged on the scope's DATs only
+///
+/// The expected name comes from the highest priority DAT.
+pub fn misnamed_files_in(conn: &Connection, scope: &Scope) -> Result<Vec<MisnamedFile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT f.id, p.path, f.filename,
+                (SELECT e2.name FROM matches m2
+                 JOIN dat_entries e2 ON e2.id = m2.dat_entry_id
+                 JOIN dat_versions v2 ON v2.id = e2.dat_version_id
+                 JOIN dats d2 ON d2.id = v2.dat_id
+                 WHERE m2.file_id = f.id AND {}
+                 ORDER BY d2.priority DESC, d2.id, e2.name
+                 LIMIT 1)
+         FROM matches m
+         JOIN files f ON f.id = m.file_id
+         JOIN file_paths p ON p.file_id = f.id
+         JOIN dat_entries e ON e.id = m.dat_entry_id
+         JOIN dat_versions v ON v.id = e.dat_version_id
+         WHERE {} AND {}
+         GROUP BY f.id
+         HAVING MAX(m.name_correct) = 0
+         ORDER BY p.path",
+        scope.dat_sql("v2.dat_id"),
+        scope.dat_sql("v.dat_id"),
+        scope.path_sql("p.path")
+    ))?;
+    let files = stmt
+        .query_map(params_from_iter(scope.params()), |row| {
+            Ok(MisnamedFile {
+                file_id: row.get(0)?,
+                path: row.get(1)?,
+                filename: row.get(2)?,
+                expected: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(files)
+}
+
+/// Files that match no DAT entry
+pub fn unmatched_files(conn: &Connection) -> Result<Vec<UnmatchedFile>> {
+    unmatched_files_in(conn, &Scope::default())
+}
+
+/// Files under the scope's path that match no DAT entry at all
+pub fn unmatched_files_in(conn: &Connection, scope: &Scope) -> Result<Vec<UnmatchedFile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT f.id, p.path, f.filename
+         FROM files f
+         JOIN file_paths p ON p.file_id = f.id
+         WHERE NOT EXISTS (SELECT 1 FROM matches m WHERE m.file_id = f.id) AND {}
+         ORDER BY p.path",
+        scope.path_sql("p.path")
+    ))?;
+    let files = stmt
+        .query_map(params_from_iter(scope.params()), |row| {
+            Ok(UnmatchedFile {
+                file_id: row.get(0)?,
+                path: row.get(1)?,
+                filename: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(files)
+}
+
+/// Run a `(file_id, path, entry_id, entry_name, dat_id)` query and record each row
+fn record_matches(
+    conn: &Connection,
+    policies: &NamePolicies,
+    sql: &str,
+    param: i64,
+) -> Result<u64> {
+    let now = Utc::now().to_rfc3339();
+    let mut select = conn.prepare_cached(sql)?;
+    let mut insert = conn.prepare_cached(
+        "INSERT OR IGNORE INTO matches (file_id, dat_entry_id, name_correct, matched_at)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    let mut rows = select.query([param])?;
+    let mut recorded = 0;
+    while let Some(row) = rows.next()? {
+        let file_id: i64 = row.get(0)?;
+        let path: String = row.get(1)?;
+        let entry_id: i64 = row.get(2)?;
+        let entry_name: String = row.get(3)?;
+        let name_correct = policies
+            .get(row.get(4)?)
+            .is_name_correct(&path, &entry_name);
+        recorded += insert.execute(params![file_id, entry_id, name_correct, now])? as u64;
+    }
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, migrations};
+    use crate::verify::CaseMode;
+
+    fn setup() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO scan_roots (id, path, added_at) VALUES (1, '/roms', 'now');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (1, 1, 'a.rom', 'a.rom', 1, 'now', x'01');
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, crc32)
+                 VALUES (2, 1, 'wrong.rom', 'wrong.rom', 2, 'now', 2);
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (3, 1, 'junk.bin', 'junk.bin', 3, 'now', x'03');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn import_dat(conn: &Connection, id: i64) -> i64 {
+        conn.execute_batch(&format!(
+            "INSERT INTO dats (id, name, format, file_path, file_sha1) VALUES ({id}, 'DAT {id}', 'TOSEC', '/{id}.dat', '{id}');
+             INSERT INTO dat_versions (id, dat_id, loaded_at, entry_count) VALUES ({id}, {id}, 'now', 3);
+             INSERT INTO dat_entries (dat_version_id, name, size, sha1) VALUES ({id}, 'a.rom', 1, x'01');
+             INSERT INTO dat_entries (dat_version_id, name, size, crc32) VALUES ({id}, 'b.rom', 2, 2);
+             INSERT INTO dat_entries (dat_version_id, name, size, sha1) VALUES ({id}, 'c.rom', 4, x'04');"
+        ))
+        .unwrap();
+        id
+    }
+
+    fn match_count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM matches", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_matches_follow_imports_scans_and_removals() {
+        let conn = setup();
+        let version = import_dat(&conn, 1);
+        assert_eq!(match_dat_version(&conn, version).unwrap(), 2);
+
+        let summary = summarise(&conn).unwrap();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(
+            (summary[0].verified, summary[0].misnamed, summary[0].missing),
+            (1, 1, 1)
+        );
+        let misnamed = misnamed_files(&conn).unwrap();
+        assert_eq!(misnamed.len(), 1);
+        assert_eq!(misnamed[0].expected, "b.rom");
+        let unmatched = unmatched_files(&conn).unwrap();
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(unmatched[0].filename, "junk.bin");
+
+        // A second DAT with the same ROMs matches the same files
+        import_dat(&conn, 2);
+        match_dat_version(&conn, 2).unwrap();
+        assert_eq!(match_count(&conn), 4);
+
+        // A newly scanned file picks up matches in both DATs
+        conn.execute(
+            "INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+             VALUES (4, 1, 'c.rom', 'c.rom', 4, 'now', x'04')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(match_files(&conn, &[4]).unwrap(), 2);
+        // Re-matching is idempotent
+        assert_eq!(match_files(&conn, &[4]).unwrap(), 2);
+        assert_eq!(match_count(&conn), 6);
+
+        db::remove_dat(&conn, 2).unwrap();
+        assert_eq!(match_count(&conn), 3);
+        db::remove_file(&conn, 1).unwrap();
+        assert_eq!(match_count(&conn), 2);
+
+        assert_eq!(rematch_all(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rename_refreshes_name_flags() {
+        let conn = setup();
+        import_dat(&conn, 1);
+        rematch_all(&conn).unwrap();
+        assert_eq!(misnamed_files(&conn).unwrap().len(), 1);
+
+        db::rename_file(&conn, 2, "b.rom").unwrap();
+        assert!(misnamed_files(&conn).unwrap().is_empty());
+        assert_eq!(summarise(&conn).unwrap()[0].verified, 2);
+    }
+
+    #[test]
+    fn test_name_policy_rechecks_matches() {
+        let conn = setup();
+        import_dat(&conn, 1);
+        conn.execute(
+            "UPDATE files SET path = 'A.ROM', filename = 'A.ROM' WHERE id = 1",
+            [],
+        )
+        .unwrap();
+        rematch_all(&conn).unwrap();
+        assert_eq!(misnamed_files(&conn).unwrap().len(), 1);
+        assert_eq!(name_policy(&conn, 1).unwrap(), NamePolicy::default());
+
+        let sensitive = NamePolicy {
+            case: CaseMode::Sensitive,
+            ..NamePolicy::default()
+        };
+        set_name_policy(&conn, 1, &sensitive).unwrap();
+        assert_eq!(name_policy(&conn, 1).unwrap(), sensitive);
+        assert_eq!(misnamed_files(&conn).unwrap().len(), 2);
+
+        // Resetting stores NULL and restores the default comparison
+        set_name_policy(&conn, 1, &NamePolicy::default()).unwrap();
+        let stored: Option<String> = conn
+            .query_row("SELECT name_policy FROM dats WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(stored.is_none());
+        assert_eq!(misnamed_files(&conn).unwrap().len(), 1);
+        assert!(set_name_policy(&conn, 9, &sensitive).is_err());
+    }
+
+    #[test]
+    fn test_scoped_verification() {
+        let conn = setup();
+        import_dat(&conn, 1);
+        import_dat(&conn, 2);
+        conn.execute_batch(
+            "UPDATE dats SET category = 'TOSEC/Commodore/C64' WHERE id = 2;
+             INSERT INTO files (id, root_id, path, filename, size, scanned_at, sha1)
+                 VALUES (4, 1, 'sub/c.rom', 'c.rom', 4, 'now', x'04');",
+        )
+        .unwrap();
+
+        // A category covers its sub-categories; only its DATs are rematched
+        let commodore = Scope::category(&conn, "tosec/commodore").unwrap();
+        assert_eq!(commodore.dat_ids, Some(vec![2]));
+        assert_eq!(rematch(&conn, &commodore).unwrap(), 3);
+        assert_eq!(match_count(&conn), 3);
+        let summary = summarise_in(&conn, &commodore).unwrap();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].dat_id, 2);
+        assert_eq!(misnamed_files_in(&conn, &commodore).unwrap().len(), 1);
+
+        // A path counts only the files under it
+        rematch_all(&conn).unwrap();
+        assert_eq!(db::files_under(&conn, "/roms/sub/").unwrap(), vec![4]);
+        let sub = Scope::default().with_path("/roms/sub/");
+        let summary = summarise_in(&conn, &sub).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(
+            (summary[0].verified, summary[0].misnamed, summary[0].missing),
+            (1, 0, 2)
+        );
+        assert!(misnamed_files_in(&conn, &sub).unwrap().is_empty());
+        assert!(unmatched_files_in(&conn, &sub).unwrap().is_empty());
+        let roms = Scope::default().with_path("/roms");
+        assert_eq!(unmatched_files_in(&conn, &roms).unwrap().len(), 1);
+
+        // Rematching a path leaves other files' matches alone
+        assert_eq!(rematch(&conn, &sub).unwrap(), 2);
+        assert_eq!(match_count(&conn), 6);
+        assert_eq!(
+            Scope::category(&conn, "Nope").unwrap().dat_ids,
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn test_disabled_dats_and_priority() {
+        let conn = setup();
+        import_dat(&conn, 1);
+        import_dat(&conn, 2);
+        conn.execute(
+            "UPDATE dat_entries SET name = 'b2.rom' WHERE dat_version_id = 2 AND name = 'b.rom'",
+            [],
+        )
+        .unwrap();
+        rematch_all(&conn).unwrap();
+        let primary_dat = |file_id: i64| -> i64 {
+            conn.query_row(
+                "SELECT v.dat_id FROM primary_matches m
+                 JOIN dat_entries e ON e.id = m.dat_entry_id
+                 JOIN dat_versions v ON v.id = e.dat_version_id
+                 WHERE m.file_id = ?1",
+                [file_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        // Ties go to the oldest DAT; a higher priority takes over
+        assert_eq!(misnamed_files(&conn).unwrap()[0].expected, "b.rom");
+        assert_eq!(primary_dat(2), 1);
+        set_dat_priority(&conn, 2, 5).unwrap();
+        assert_eq!(misnamed_files(&conn).unwrap()[0].expected, "b2.rom");
+        assert_eq!(primary_dat(2), 2);
+
+        // A disabled DAT loses its matches and stays out of the totals
+        set_dat_enabled(&conn, 2, false).unwrap();
+        assert_eq!(match_count(&conn), 2);
+        assert_eq!(primary_dat(2), 1);
+        let summary = summarise(&conn).unwrap();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].dat_id, 1);
+        assert_eq!(db::get_collection_stats(&conn).unwrap().entry_count, 3);
+        rematch_all(&conn).unwrap();
+        match_files(&conn, &[1, 2]).unwrap();
+        assert_eq!(match_count(&conn), 2);
+
+        set_dat_enabled(&conn, 2, true).unwrap();
+        assert_eq!(match_count(&conn), 4);
+        assert!(set_dat_enabled(&conn, 99, false).is_err());
+        assert!(set_dat_priority(&conn, 99, 1).is_err());
+    }
+}
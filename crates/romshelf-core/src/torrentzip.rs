@@ -0,0 +1,512 @@
+//! TorrentZip - zips laid out so the same files always give the same archive
+//!
+//! A TorrentZip archive:
+//!
+//! - holds its entries sorted by lowercased name, then by name
+//! - deflates every entry with zlib at the maximum level, flagged as such
+//! - stamps every entry with the DOS time 1996-12-24 23:32:00
+//! - has no extra fields, no data descriptors and no file comments
+//! - ends with the comment `TORRENTZIPPED-XXXXXXXX`, the CRC32 of its
+//!   central directory in upper-case hex
+//!
+//! The deflate streams have to match zlib's byte for byte, which is why
+//! flate2 is built on zlib rather than its Rust backend, and on the bundled
+//! zlib rather than the system's (which may be zlib-ng). The checker
+//! verifies the rest, reading headers only.
+
+use anyhow::{Result, bail};
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Start of the archive comment; the CRC32 of the central directory follows
+pub const COMMENT_PREFIX: &str = "TORRENTZIPPED-";
+
+const COMMENT_LEN: usize = COMMENT_PREFIX.len() + 8;
+const DOS_TIME: u16 = 0xBC00;
+const DOS_DATE: u16 = 0x2198;
+const VERSION_NEEDED: u16 = 20;
+/// Bit 1: deflated at the maximum level
+const FLAG_MAX_COMPRESSION: u16 = 0x0002;
+/// Bit 11: the name is UTF-8
+const FLAG_UTF8: u16 = 0x0800;
+const METHOD_DEFLATE: u16 = 8;
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_SIG: u32 = 0x0605_4b50;
+const LOCAL_HEADER_LEN: u64 = 30;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_OF_CENTRAL_LEN: usize = 22;
+
+/// TorrentZip entry order: lowercased name first, then the name as is
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+    a.bytes()
+        .map(|c| c.to_ascii_lowercase())
+        .cmp(b.bytes().map(|c| c.to_ascii_lowercase()))
+        .then_with(|| a.cmp(b))
+}
+
+/// Writes a TorrentZip archive one entry at a time
+///
+/// Entries must be added in [`compare_names`] order. Each entry is
+/// compressed as it is read, so memory use doesn't grow with file size;
+/// its local header is patched afterwards, hence the `Seek` bound.
+pub struct TorrentZipWriter<W: Write + Seek> {
+    out: W,
+    central: Vec<u8>,
+    entries: usize,
+    last_name: Option<String>,
+}
+
+impl<W: Write + Seek> TorrentZipWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            central: Vec::new(),
+            entries: 0,
+            last_name: None,
+        }
+    }
+
+    /// Compress `data` into the archive as `name`; returns its CRC32
+    pub fn add_file<R: Read>(&mut self, name: &str, mut data: R) -> Result<u32> {
+        if let Some(last) = &self.last_name
+            && compare_names(last, name) != Ordering::Less
+        {
+            bail!("'{}' is out of TorrentZip order after '{}'", name, last);
+        }
+        let offset = self.out.stream_position()?;
+        let offset_32 = zip32(offset, "Archive")?;
+        let name_len = u16::try_from(name.len())?;
+        let flags = entry_flags(name);
+
+        // Local header with the CRC and sizes left blank until the data is written
+        let mut header = Vec::with_capacity(LOCAL_HEADER_LEN as usize + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIG);
+        put_u16(&mut header, VERSION_NEEDED);
+        put_u16(&mut header, flags);
+        put_u16(&mut header, METHOD_DEFLATE);
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        header.extend_from_slice(&[0; 12]);
+        put_u16(&mut header, name_len);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+        self.out.write_all(&header)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        // Raw deflate at level 9, 15 window bits, memLevel 8 and the default
+        // strategy: the stream trrntzip gets from zlib
+        let mut encoder = DeflateEncoder::new(
+            CountingWriter {
+                inner: &mut self.out,
+                count: 0,
+            },
+            Compression::best(),
+        );
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            encoder.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+        let compressed = encoder.finish()?.count;
+        let crc = hasher.finalize();
+        let size = zip32(size, name)?;
+        let compressed = zip32(compressed, name)?;
+
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(offset + 14))?;
+        let mut sizes = Vec::with_capacity(12);
+        put_u32(&mut sizes, crc);
+        put_u32(&mut sizes, compressed);
+        put_u32(&mut sizes, size);
+        self.out.write_all(&sizes)?;
+        self.out.seek(SeekFrom::Start(end))?;
+
+        let central = &mut self.central;
+        put_u32(central, CENTRAL_HEADER_SIG);
+        put_u16(central, 0);
+        put_u16(central, VERSION_NEEDED);
+        put_u16(central, flags);
+        put_u16(central, METHOD_DEFLATE);
+        put_u16(central, DOS_TIME);
+        put_u16(central, DOS_DATE);
+        put_u32(central, crc);
+        put_u32(central, compressed);
+        put_u32(central, size);
+        put_u16(central, name_len);
+        // Extra field, comment, disk number, internal and external attributes
+        central.extend_from_slice(&[0; 12]);
+        put_u32(central, offset_32);
+        central.extend_from_slice(name.as_bytes());
+
+        self.entries += 1;
+        self.last_name = Some(name.to_string());
+        Ok(crc)
+    }
+
+    /// Write the central directory and the TorrentZip comment
+    pub fn finish(mut self) -> Result<W> {
+        let entries = u16::try_from(self.entries)
+            .map_err(|_| anyhow::anyhow!("Too many entries for TorrentZip without ZIP64"))?;
+        let offset = zip32(self.out.stream_position()?, "Archive")?;
+        let size = zip32(self.central.len() as u64, "Central directory")?;
+        let comment = format!("{}{:08X}", COMMENT_PREFIX, crc32fast::hash(&self.central));
+
+        let mut end = Vec::with_capacity(END_OF_CENTRAL_LEN + COMMENT_LEN);
+        put_u32(&mut end, END_OF_CENTRAL_SIG);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, entries);
+        put_u16(&mut end, entries);
+        put_u32(&mut end, size);
+        put_u32(&mut end, offset);
+        put_u16(&mut end, COMMENT_LEN as u16);
+        end.extend_from_slice(comment.as_bytes());
+
+        self.out.write_all(&self.central)?;
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Whether the zip at `path` is already a valid TorrentZip archive
+pub fn is_torrentzipped(path: &Path) -> Result<bool> {
+    check(BufReader::new(File::open(path)?))
+}
+
+/// Whether a zip is a valid TorrentZip archive
+///
+/// Anything that breaks the layout gives `false`; only I/O errors are errors.
+pub fn check<R: Read + Seek>(mut reader: R) -> Result<bool> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let tail_len = (END_OF_CENTRAL_LEN + COMMENT_LEN) as u64;
+    if len < tail_len {
+        return Ok(false);
+    }
+    let central_end = len - tail_len;
+    reader.seek(SeekFrom::Start(central_end))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+
+    let mut end = Fields(&tail);
+    if end.u32() != END_OF_CENTRAL_SIG || end.u16() != 0 || end.u16() != 0 {
+        return Ok(false);
+    }
+    let entries = end.u16();
+    if end.u16() != entries {
+        return Ok(false);
+    }
+    let (size, offset) = (end.u32() as u64, end.u32() as u64);
+    if end.u16() as usize != COMMENT_LEN || offset + size != central_end {
+        return Ok(false);
+    }
+    let comment = end.bytes(COMMENT_LEN);
+    let Some(expected_crc) = comment
+        .strip_prefix(COMMENT_PREFIX.as_bytes())
+        .filter(|hex| hex.iter().all(|c| matches!(c, b'0'..=b'9' | b'A'..=b'F')))
+        .and_then(|hex| std::str::from_utf8(hex).ok())
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+    else {
+        return Ok(false);
+    };
+
+    let mut central = vec![0u8; size as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut central)?;
+    if crc32fast::hash(&central) != expected_crc {
+        return Ok(false);
+    }
+
+    let mut fields = Fields(&central);
+    let mut next_offset: u64 = 0;
+    let mut last_name: Option<&[u8]> = None;
+    for _ in 0..entries {
+        if fields.0.len() < CENTRAL_HEADER_LEN {
+            return Ok(false);
+        }
+        let fixed = (fields.u32(), fields.u16(), fields.u16());
+        if fixed != (CENTRAL_HEADER_SIG, 0, VERSION_NEEDED) {
+            return Ok(false);
+        }
+        let header = EntryHeader {
+            flags: fields.u16(),
+            method: fields.u16(),
+            time: fields.u16(),
+            date: fields.u16(),
+            crc: fields.u32(),
+            compressed: fields.u32(),
+            size: fields.u32(),
+            name_len: fields.u16(),
+        };
+        let rest = (
+            fields.u16(),
+            fields.u16(),
+            fields.u16(),
+            fields.u16(),
+            fields.u32(),
+        );
+        let local_offset = fields.u32() as u64;
+        if rest != (0, 0, 0, 0, 0) || local_offset != next_offset || !header.is_torrentzip() {
+            return Ok(false);
+        }
+        if fields.0.len() < header.name_len as usize {
+            return Ok(false);
+        }
+        let name = fields.bytes(header.name_len as usize);
+        let Ok(name_str) = std::str::from_utf8(name) else {
+            return Ok(false);
+        };
+        if header.flags != entry_flags(name_str) {
+            return Ok(false);
+        }
+        if let Some(last) = last_name
+            && compare_names(std::str::from_utf8(last)?, name_str) != Ordering::Less
+        {
+            return Ok(false);
+        }
+
+        // The local header must repeat the central one exactly
+        let mut local = vec![0u8; LOCAL_HEADER_LEN as usize + name.len()];
+        reader.seek(SeekFrom::Start(local_offset))?;
+        reader.read_exact(&mut local)?;
+        let mut local_fields = Fields(&local);
+        let sig = local_fields.u32();
+        let needed = local_fields.u16();
+        let local_header = EntryHeader {
+            flags: local_fields.u16(),
+            method: local_fields.u16(),
+            time: local_fields.u16(),
+            date: local_fields.u16(),
+            crc: local_fields.u32(),
+            compressed: local_fields.u32(),
+            size: local_fields.u32(),
+            name_len: local_fields.u16(),
+        };
+        if sig != LOCAL_HEADER_SIG
+            || needed != VERSION_NEEDED
+            || local_header != header
+            || local_fields.u16() != 0
+            || local_fields.bytes(name.len()) != name
+        {
+            return Ok(false);
+        }
+
+        next_offset =
+            local_offset + LOCAL_HEADER_LEN + name.len() as u64 + header.compressed as u64;
+        last_name = Some(name);
+    }
+    Ok(fields.0.is_empty() && next_offset == offset)
+}
+
+/// The fields local and central headers share
+#[derive(PartialEq, Eq)]
+struct EntryHeader {
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    name_len: u16,
+}
+
+impl EntryHeader {
+    fn is_torrentzip(&self) -> bool {
+        self.method == METHOD_DEFLATE && self.time == DOS_TIME && self.date == DOS_DATE
+    }
+}
+
+/// Flags TorrentZip sets for an entry name
+fn entry_flags(name: &str) -> u16 {
+    if name.is_ascii() {
+        FLAG_MAX_COMPRESSION
+    } else {
+        FLAG_MAX_COMPRESSION | FLAG_UTF8
+    }
+}
+
+/// Check a size or offset fits the 32-bit fields (no ZIP64)
+fn zip32(value: u64, what: &str) -> Result<u32> {
+    u32::try_from(value)
+        .map_err(|_| anyhow::anyhow!("{} is too large for TorrentZip without ZIP64", what))
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Little-endian fields read off the front of a byte slice
+///
+/// Callers check the length first; reading past the end panics.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        head
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+    }
+}
+
+/// Counts the bytes the encoder writes, i.e. the compressed size
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn torrentzip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut sorted = entries.to_vec();
+        sorted.sort_by(|a, b| compare_names(a.0, b.0));
+        let mut writer = TorrentZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in sorted {
+            writer.add_file(name, data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_written_archives_are_torrentzipped() {
+        let big = vec![7u8; 200_000];
+        let bytes = torrentzip(&[
+            ("b.rom", b"bbbb"),
+            ("A.rom", b"aaaa"),
+            ("sub/big.bin", &big),
+            ("empty.bin", b""),
+            ("Ünï.rom", b"u"),
+        ]);
+        assert!(check(Cursor::new(&bytes)).unwrap());
+
+        // Readable by an ordinary zip reader, in TorrentZip order
+        let mut archive = zip::ZipArchive::new(Cursor::new(&bytes)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.len(), 5);
+        let mut ordered = Vec::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            ordered.push((entry.name().to_string(), data.len()));
+        }
+        assert_eq!(
+            ordered,
+            vec![
+                ("A.rom".to_string(), 4),
+                ("b.rom".to_string(), 4),
+                ("empty.bin".to_string(), 0),
+                ("sub/big.bin".to_string(), 200_000),
+                ("Ünï.rom".to_string(), 1),
+            ]
+        );
+        let comment = std::str::from_utf8(archive.comment()).unwrap();
+        assert!(comment.starts_with(COMMENT_PREFIX));
+        assert_eq!(comment.len(), COMMENT_LEN);
+
+        // Same files, same bytes
+        assert_eq!(
+            bytes,
+            torrentzip(&[
+                ("empty.bin", b""),
+                ("Ünï.rom", b"u"),
+                ("sub/big.bin", &big),
+                ("A.rom", b"aaaa"),
+                ("b.rom", b"bbbb"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_matches_trrntzip() {
+        // testdata/torrentzip.zip holds these files as trrntzip lays them out
+        // (see testdata/README.md for how it was made)
+        let pattern: Vec<u8> = (0..150_000u64)
+            .map(|i| ((i * i / 7 + i) % 251) as u8)
+            .collect();
+        let text: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("ROMSHELF {:05}\n", i % 997).into_bytes())
+            .collect();
+        let bytes = torrentzip(&[
+            ("sub/pattern.bin", &pattern),
+            ("readme.TXT", &text),
+            ("Readme.txt", b"TorrentZip reference\n"),
+            ("empty.bin", b""),
+        ]);
+        let reference = include_bytes!("../testdata/torrentzip.zip");
+        assert!(bytes == reference, "differs from the reference archive");
+    }
+
+    #[test]
+    fn test_rejects_other_zips() {
+        let mut writer = TorrentZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_file("b.rom", &b"b"[..]).unwrap();
+        assert!(writer.add_file("a.rom", &b"a"[..]).is_err());
+        assert!(writer.add_file("B.rom", &b"b"[..]).is_err());
+
+        // An ordinary zip, even sorted and deflated at level 9
+        let mut plain = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(9));
+        plain.start_file("a.rom", options).unwrap();
+        plain.write_all(b"aaaa").unwrap();
+        let plain = plain.finish().unwrap().into_inner();
+        assert!(!check(Cursor::new(&plain)).unwrap());
+        assert!(!check(Cursor::new(b"not a zip")).unwrap());
+
+        // Any change to the central directory breaks the comment's CRC
+        let mut bytes = torrentzip(&[("a.rom", b"aaaa")]);
+        assert!(check(Cursor::new(&bytes)).unwrap());
+        let central_time = bytes.len() - END_OF_CENTRAL_LEN - COMMENT_LEN - 5 - 34;
+        bytes[central_time] ^= 1;
+        assert!(!check(Cursor::new(&bytes)).unwrap());
+    }
+
+    #[test]
+    fn test_compare_names() {
+        assert_eq!(compare_names("a.rom", "B.rom"), Ordering::Less);
+        assert_eq!(compare_names("B.rom", "b.rom"), Ordering::Less);
+        assert_eq!(compare_names("dir/x", "dir.bin"), Ordering::Greater);
+        assert_eq!(compare_names("a", "a"), Ordering::Equal);
+    }
+}
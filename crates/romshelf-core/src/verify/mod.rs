@@ -1,58 +1,196 @@
 //! Verification module - matching files to DAT entries
+//!
+//! Entries are indexed once by SHA1, CRC32 + size and MD5, so matching a
+//! file is a few hash lookups rather than a scan of every entry. Files are
+//! matched in parallel, and every entry a file satisfies is returned: the
+//! same ROM often appears in several sets or DATs. A hash both sides carry
+//! must agree, so a CRC32 collision with a different SHA1 is not a match.
+//!
+//! Whether a matched file is correctly named is decided by a [`NamePolicy`],
+//! which each DAT can configure.
 
 use crate::dat::DatEntry;
-use crate::scan::ScannedFile;
+use crate::scan::{ScannedFile, split_archive_member};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use unicode_normalization::UnicodeNormalization;
 
 /// Result of verification
 #[derive(Debug)]
-pub struct VerifyResult {
-    pub verified: Vec<Match>,
-    pub misnamed: Vec<Match>,
-    pub missing: Vec<DatEntry>,
-    pub unmatched: Vec<ScannedFile>,
+pub struct VerifyResult<'a> {
+    pub verified: Vec<Match<'a>>,
+    pub misnamed: Vec<Match<'a>>,
+    pub missing: Vec<&'a DatEntry>,
+    pub unmatched: Vec<&'a ScannedFile>,
 }
 
-/// A match between a file and a DAT entry
+/// A file and the DAT entries it matches
 #[derive(Debug, Clone)]
-pub struct Match {
-    pub file: ScannedFile,
-    pub entry: DatEntry,
+pub struct Match<'a> {
+    pub file: &'a ScannedFile,
+    /// The best candidate: the first correctly named one, else the first
+    pub entry: &'a DatEntry,
+    /// Every entry with the same content, in entry order
+    pub candidates: Vec<&'a DatEntry>,
+}
+
+/// Hash indexes over a slice of DAT entries
+pub struct MatchIndex<'a> {
+    entries: &'a [DatEntry],
+    sha1: KeyIndex<&'a str>,
+    crc32_size: KeyIndex<(&'a str, u64)>,
+    md5: KeyIndex<&'a str>,
+}
+
+impl<'a> MatchIndex<'a> {
+    /// Index `entries` by each hash they carry
+    pub fn new(entries: &'a [DatEntry]) -> Self {
+        let mut sha1 = KeyIndex::with_capacity(entries.len());
+        let mut crc32_size = KeyIndex::with_capacity(entries.len());
+        let mut md5 = KeyIndex::new(entries.len());
+        for (idx, entry) in entries.iter().enumerate() {
+            if let Some(hash) = entry.sha1.as_deref() {
+                sha1.insert(hash, idx);
+            }
+            if let Some(crc32) = entry.crc32.as_deref() {
+                crc32_size.insert((crc32, entry.size), idx);
+            }
+            if let Some(hash) = entry.md5.as_deref() {
+                md5.insert(hash, idx);
+            }
+        }
+        Self {
+            entries,
+            sha1,
+            crc32_size,
+            md5,
+        }
+    }
+
+    /// The indexed entries
+    pub fn entries(&self) -> &'a [DatEntry] {
+        self.entries
+    }
+
+    /// Indices of every entry `file` matches (SHA1, CRC32 + size, or MD5), ascending
+    pub fn candidates(&self, file: &ScannedFile) -> Vec<usize> {
+        let mut found = Vec::new();
+        if !file.sha1.is_empty() {
+            self.sha1.collect(&file.sha1.as_str(), &mut found);
+        }
+        if !file.crc32.is_empty() {
+            self.crc32_size
+                .collect(&(file.crc32.as_str(), file.size), &mut found);
+        }
+        if !file.md5.is_empty() {
+            self.md5.collect(&file.md5.as_str(), &mut found);
+        }
+        found.sort_unstable();
+        found.dedup();
+        found.retain(|&idx| hashes_agree(file, &self.entries[idx]));
+        found
+    }
+}
+
+/// No hash present on both sides differs
+fn hashes_agree(file: &ScannedFile, entry: &DatEntry) -> bool {
+    let agree = |file_hash: &str, entry_hash: &Option<String>| {
+        file_hash.is_empty() || entry_hash.as_deref().is_none_or(|h| h == file_hash)
+    };
+    agree(&file.sha1, &entry.sha1)
+        && agree(&file.md5, &entry.md5)
+        && agree(&file.crc32, &entry.crc32)
+}
+
+/// Entries sharing a key, as a head map plus a chain through `next`
+///
+/// Avoids a `Vec` per key, which matters with millions of entries.
+struct KeyIndex<K> {
+    heads: HashMap<K, usize>,
+    next: Vec<usize>,
+}
+
+impl<K: Hash + Eq + Copy> KeyIndex<K> {
+    fn with_capacity(entries: usize) -> Self {
+        Self {
+            heads: HashMap::with_capacity(entries),
+            next: vec![usize::MAX; entries],
+        }
+    }
+
+    /// An index expected to hold few keys (MD5 is rarely the only hash)
+    fn new(entries: usize) -> Self {
+        Self {
+            heads: HashMap::new(),
+            next: vec![usize::MAX; entries],
+        }
+    }
+
+    fn insert(&mut self, key: K, idx: usize) {
+        if let Some(head) = self.heads.insert(key, idx) {
+            self.next[idx] = head;
+        }
+    }
+
+    fn collect(&self, key: &K, out: &mut Vec<usize>) {
+        let mut idx = self.heads.get(key).copied().unwrap_or(usize::MAX);
+        while idx != usize::MAX {
+            out.push(idx);
+            idx = self.next[idx];
+        }
+    }
+}
+
+/// Candidate entry indices for every file, computed in parallel
+pub fn match_files(files: &[ScannedFile], index: &MatchIndex) -> Vec<Vec<usize>> {
+    files
+        .par_iter()
+        .map(|file| index.candidates(file))
+        .collect()
 }
 
 /// Verify scanned files against DAT entries
-pub fn verify(files: &[ScannedFile], entries: &[DatEntry]) -> VerifyResult {
+pub fn verify<'a>(files: &'a [ScannedFile], entries: &'a [DatEntry]) -> VerifyResult<'a> {
+    let index = MatchIndex::new(entries);
+    let candidates = match_files(files, &index);
+
     let mut verified = Vec::new();
     let mut misnamed = Vec::new();
     let mut unmatched = Vec::new();
-    let mut matched_entry_indices: Vec<bool> = vec![false; entries.len()];
-
-    // For each file, try to find a matching DAT entry
-    for file in files {
-        if let Some((idx, entry)) = find_match(file, entries) {
-            matched_entry_indices[idx] = true;
-
-            let name_correct = is_name_correct(&file.filename, &entry.name);
-            let m = Match {
-                file: file.clone(),
-                entry: entry.clone(),
-            };
-
-            if name_correct {
-                verified.push(m);
-            } else {
-                misnamed.push(m);
-            }
+    let mut matched_entries = vec![false; entries.len()];
+
+    for (file, found) in files.iter().zip(candidates) {
+        if found.is_empty() {
+            unmatched.push(file);
+            continue;
+        }
+        for &idx in &found {
+            matched_entries[idx] = true;
+        }
+
+        let correct = found
+            .iter()
+            .map(|&idx| &entries[idx])
+            .find(|entry| is_name_correct(&file.filename, &entry.name));
+        let m = Match {
+            file,
+            entry: correct.unwrap_or(&entries[found[0]]),
+            candidates: found.iter().map(|&idx| &entries[idx]).collect(),
+        };
+        if correct.is_some() {
+            verified.push(m);
         } else {
-            unmatched.push(file.clone());
+            misnamed.push(m);
         }
     }
 
-    // Find missing entries (entries without matching files)
-    let missing: Vec<DatEntry> = entries
+    let missing = entries
         .iter()
-        .enumerate()
-        .filter(|(idx, _)| !matched_entry_indices[*idx])
-        .map(|(_, e)| e.clone())
+        .zip(&matched_entries)
+        .filter(|(_, matched)| !**matched)
+        .map(|(entry, _)| entry)
         .collect();
 
     VerifyResult {
@@ -63,40 +201,111 @@ pub fn verify(files: &[ScannedFile], entries: &[DatEntry]) -> VerifyResult {
     }
 }
 
-/// Find a matching DAT entry for a file by hash
-fn find_match<'a>(file: &ScannedFile, entries: &'a [DatEntry]) -> Option<(usize, &'a DatEntry)> {
-    for (idx, entry) in entries.iter().enumerate() {
-        // First try SHA1 (most reliable)
-        if let Some(ref sha1) = entry.sha1
-            && sha1 == &file.sha1
-        {
-            return Some((idx, entry));
-        }
+/// Check if the filename matches the expected ROM name under the default policy
+pub fn is_name_correct(filename: &str, rom_name: &str) -> bool {
+    NamePolicy::default().is_name_correct(filename, rom_name)
+}
 
-        // Fall back to CRC32 + size
-        if let Some(ref crc32) = entry.crc32
-            && crc32 == &file.crc32
-            && entry.size == file.size
-        {
-            return Some((idx, entry));
-        }
+/// Rules for deciding whether a file carries a DAT entry's name
+///
+/// The default ignores case, treats NFC and NFD spellings as equal (macOS
+/// shares hand out NFD), compares extensions and ignores folders.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct NamePolicy {
+    pub case: CaseMode,
+    pub unicode: UnicodeMode,
+    pub extension: ExtensionMode,
+    pub archive_path: ArchivePathMode,
+}
+
+/// Whether letter case is significant
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseMode {
+    #[default]
+    Insensitive,
+    Sensitive,
+}
+
+/// How Unicode spellings of the same name are compared
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeMode {
+    /// Compare NFC-normalised names, so composed and decomposed forms match
+    #[default]
+    Nfc,
+    /// Compare code points as stored
+    Exact,
+}
+
+/// Whether the extension takes part in the comparison
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtensionMode {
+    #[default]
+    Compare,
+    /// `Game.smc` counts as correctly named for `Game.sfc`
+    Ignore,
+}
 
-        // Fall back to MD5
-        if let Some(ref md5) = entry.md5
-            && md5 == &file.md5
-        {
-            return Some((idx, entry));
+/// How folders in ROM names and inside archives are treated
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchivePathMode {
+    /// Compare only the final path component on both sides
+    #[default]
+    FileName,
+    /// An archive member's path inside the archive must equal the ROM name,
+    /// folders included
+    FullPath,
+}
+
+impl NamePolicy {
+    /// Whether the file at `path` (a file name, a path, or an
+    /// `archive#member` path) carries `rom_name`
+    pub fn is_name_correct(&self, path: &str, rom_name: &str) -> bool {
+        let rom_name = rom_name.replace('\\', "/");
+        let member = split_archive_member(path).map(|(_, member)| member);
+        let (name, rom_name) = match (self.archive_path, member) {
+            (ArchivePathMode::FullPath, Some(member)) => (member.replace('\\', "/"), rom_name),
+            (ArchivePathMode::FullPath, None) => (base_name(path).to_string(), rom_name),
+            (ArchivePathMode::FileName, member) => (
+                base_name(member.unwrap_or(path)).to_string(),
+                base_name(&rom_name).to_string(),
+            ),
+        };
+        self.normalise(&name) == self.normalise(&rom_name)
+    }
+
+    fn normalise(&self, name: &str) -> String {
+        let name = match self.extension {
+            ExtensionMode::Compare => name,
+            ExtensionMode::Ignore => strip_extension(name),
+        };
+        let name: String = match self.unicode {
+            UnicodeMode::Nfc => name.nfc().collect(),
+            UnicodeMode::Exact => name.to_string(),
+        };
+        match self.case {
+            CaseMode::Insensitive => name.to_lowercase(),
+            CaseMode::Sensitive => name,
         }
     }
+}
 
-    None
+/// The last component of a `/` or `\\` separated path
+fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
 }
 
-/// Check if the filename matches the expected ROM name
-fn is_name_correct(filename: &str, rom_name: &str) -> bool {
-    // Simple case-insensitive comparison
-    // Could be more sophisticated (ignore extension, etc.)
-    filename.to_lowercase() == rom_name.to_lowercase()
+/// A name without its final extension (dotfiles keep their name)
+fn strip_extension(name: &str) -> &str {
+    let start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[start..].rfind('.') {
+        Some(dot) if dot > 0 => &name[..start + dot],
+        _ => name,
+    }
 }
 
 #[cfg(test)]
@@ -122,6 +331,7 @@ mod tests {
             crc32: Some(crc32.to_string()),
             md5: None,
             sha1: Some(sha1.to_string()),
+            merge: None,
         }
     }
 
@@ -176,4 +386,97 @@ mod tests {
         assert_eq!(result.missing.len(), 1);
         assert_eq!(result.unmatched.len(), 1);
     }
+
+    #[test]
+    fn test_every_candidate_is_returned() {
+        // The same ROM in two sets, plus a CRC-only entry and an MD5-only entry
+        let mut crc_only = make_entry("crc.rom", "abcd1234", "");
+        crc_only.sha1 = None;
+        let md5_only = DatEntry {
+            name: "md5.rom".to_string(),
+            size: 1,
+            crc32: None,
+            md5: Some("md5hash".to_string()),
+            sha1: None,
+            merge: None,
+        };
+        let entries = vec![
+            make_entry("set1.rom", "abcd1234", "sha1hash"),
+            make_entry("other.rom", "00000000", "othersha"),
+            make_entry("game.rom", "abcd1234", "sha1hash"),
+            crc_only,
+            md5_only,
+        ];
+        let files = vec![make_file("game.rom", "abcd1234", "sha1hash")];
+
+        let index = MatchIndex::new(&entries);
+        assert_eq!(index.candidates(&files[0]), vec![0, 2, 3, 4]);
+
+        let result = verify(&files, &entries);
+        assert_eq!(result.verified.len(), 1);
+        assert_eq!(result.verified[0].entry.name, "game.rom");
+        assert_eq!(result.verified[0].candidates.len(), 4);
+        assert_eq!(result.missing.len(), 1);
+        assert_eq!(result.missing[0].name, "other.rom");
+    }
+
+    #[test]
+    fn test_crc_requires_matching_size() {
+        let entries = vec![make_entry("game.rom", "abcd1234", "a")];
+        let mut file = make_file("game.rom", "abcd1234", "");
+        file.md5 = String::new();
+        file.size = 2048;
+
+        let index = MatchIndex::new(&entries);
+        assert!(index.candidates(&file).is_empty());
+        file.size = 1024;
+        assert_eq!(index.candidates(&file), vec![0]);
+    }
+
+    #[test]
+    fn test_conflicting_hashes_do_not_match() {
+        let entries = vec![make_entry("game.rom", "abcd1234", "sha1hash")];
+        let file = make_file("game.rom", "abcd1234", "othersha");
+
+        let index = MatchIndex::new(&entries);
+        assert!(index.candidates(&file).is_empty());
+    }
+
+    #[test]
+    fn test_name_policy() {
+        let default = NamePolicy::default();
+        assert!(default.is_name_correct("GAME.sfc", "Game.sfc"));
+        // "é" composed (NFC) and decomposed (NFD)
+        assert!(default.is_name_correct("Pok\u{e9}mon.gb", "Poke\u{301}mon.gb"));
+        assert!(!default.is_name_correct("Game.smc", "Game.sfc"));
+        assert!(default.is_name_correct("/roms/set.zip#disk1/track.bin", "disk2\\track.bin"));
+        assert!(!default.is_name_correct("Foo #1.bin", "Bar #1.bin"));
+        assert!(default.is_name_correct("/roms/Disk #2/Game #1.bin", "Game #1.bin"));
+        assert!(default.is_name_correct("/roms/Set #1.7z#a #1.bin", "a #1.bin"));
+
+        let strict = NamePolicy {
+            case: CaseMode::Sensitive,
+            unicode: UnicodeMode::Exact,
+            extension: ExtensionMode::Compare,
+            archive_path: ArchivePathMode::FullPath,
+        };
+        assert!(!strict.is_name_correct("GAME.sfc", "Game.sfc"));
+        assert!(!strict.is_name_correct("Pok\u{e9}mon.gb", "Poke\u{301}mon.gb"));
+        assert!(strict.is_name_correct("/roms/set.zip#disk1/track.bin", "disk1\\track.bin"));
+        assert!(!strict.is_name_correct("/roms/set.zip#disk1/track.bin", "disk2\\track.bin"));
+        assert!(!strict.is_name_correct("/roms/set.zip#track.bin", "disk1/track.bin"));
+        assert!(strict.is_name_correct("/roms/Game #1.bin", "Game #1.bin"));
+        assert!(strict.is_name_correct("/roms/Set #1.zip#dir/a #1.bin", "dir/a #1.bin"));
+
+        let no_extension = NamePolicy {
+            extension: ExtensionMode::Ignore,
+            ..NamePolicy::default()
+        };
+        assert!(no_extension.is_name_correct("Game.smc", "Game.sfc"));
+        assert!(no_extension.is_name_correct("Game (v1.1).smc", "Game (v1.1).sfc"));
+        assert!(!no_extension.is_name_correct("Game (v1.1).smc", "Game (v1.0).sfc"));
+
+        let policy: NamePolicy = serde_json::from_str(r#"{"extension":"ignore"}"#).unwrap();
+        assert_eq!(policy, no_extension);
+    }
 }
@@ -0,0 +1,171 @@
+//! Volume identification - telling an unplugged drive from deleted files
+//!
+//! A volume is identified by a marker file (`.romshelf-volume`) at or above a
+//! scan root, or failing that by its filesystem UUID where the platform
+//! exposes one. When a root's path is missing, or the volume found there is
+//! not the one recorded, the root is offline and its files are kept.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the marker file written by `romshelf volumes mark`
+pub const MARKER_FILE: &str = ".romshelf-volume";
+
+/// How a volume was recognised
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VolumeId {
+    /// ID read from a marker file, with the directory holding it
+    Marker { id: String, dir: PathBuf },
+    /// Filesystem UUID, with the mount point
+    FsUuid { uuid: String, mount: PathBuf },
+}
+
+impl VolumeId {
+    /// Stored `kind` column value
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VolumeId::Marker { .. } => "marker",
+            VolumeId::FsUuid { .. } => "fs-uuid",
+        }
+    }
+
+    /// Stored `identifier` column value
+    pub fn identifier(&self) -> &str {
+        match self {
+            VolumeId::Marker { id, .. } => id,
+            VolumeId::FsUuid { uuid, .. } => uuid,
+        }
+    }
+
+    /// Where the volume is currently mounted (or marked)
+    pub fn mount(&self) -> &Path {
+        match self {
+            VolumeId::Marker { dir, .. } => dir,
+            VolumeId::FsUuid { mount, .. } => mount,
+        }
+    }
+
+    /// Default human-readable name
+    pub fn label(&self) -> String {
+        self.mount()
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.mount().to_string_lossy().to_string())
+    }
+}
+
+/// Identify the volume holding `path`, if it exists and can be recognised
+pub fn identify(path: &Path) -> Option<VolumeId> {
+    if !path.exists() {
+        return None;
+    }
+    find_marker(path).or_else(|| fs_uuid(path))
+}
+
+/// Write a marker file into `dir`, returning the new volume ID
+///
+/// An existing marker is kept so re-marking a volume never changes its ID.
+pub fn write_marker(dir: &Path) -> Result<String> {
+    let marker = dir.join(MARKER_FILE);
+    if let Some(id) = read_marker(&marker) {
+        return Ok(id);
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let id = format!("{:032x}{:08x}", nanos, std::process::id());
+    std::fs::write(&marker, format!("{}\n", id))?;
+    Ok(id)
+}
+
+fn find_marker(path: &Path) -> Option<VolumeId> {
+    path.ancestors().find_map(|dir| {
+        read_marker(&dir.join(MARKER_FILE)).map(|id| VolumeId::Marker {
+            id,
+            dir: dir.to_path_buf(),
+        })
+    })
+}
+
+fn read_marker(marker: &Path) -> Option<String> {
+    let text = std::fs::read_to_string(marker).ok()?;
+    let id = text.lines().next()?.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn fs_uuid(path: &Path) -> Option<VolumeId> {
+    use std::os::unix::fs::MetadataExt;
+
+    // The mount with the longest matching mount point holds the path
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let path = path.canonicalize().ok()?;
+    let (device, mount) = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let device = fields.get(2)?.to_string();
+            let mount = PathBuf::from(unescape_mount(fields.get(4)?));
+            path.starts_with(&mount).then_some((device, mount))
+        })
+        .max_by_key(|(_, mount)| mount.components().count())?;
+
+    for entry in std::fs::read_dir("/dev/disk/by-uuid").ok()?.flatten() {
+        let Ok(meta) = std::fs::metadata(entry.path()) else {
+            continue;
+        };
+        let rdev = meta.rdev();
+        let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+        let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+        if format!("{}:{}", major, minor) == device {
+            return Some(VolumeId::FsUuid {
+                uuid: entry.file_name().to_string_lossy().to_string(),
+                mount,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fs_uuid(_path: &Path) -> Option<VolumeId> {
+    None
+}
+
+/// Mount points in mountinfo escape spaces and tabs as octal
+#[cfg(target_os = "linux")]
+fn unescape_mount(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\134", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_found_from_nested_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("roms").join("snes");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        let id = write_marker(dir.path()).unwrap();
+        // Marking again keeps the ID
+        assert_eq!(write_marker(dir.path()).unwrap(), id);
+
+        let found = identify(&nested).unwrap();
+        assert_eq!(found.kind(), "marker");
+        assert_eq!(found.identifier(), id);
+        assert_eq!(found.mount(), dir.path());
+    }
+
+    #[test]
+    fn test_missing_path_has_no_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(identify(&dir.path().join("unplugged")), None);
+    }
+}
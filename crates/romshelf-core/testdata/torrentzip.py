@@ -0,0 +1,57 @@
+"""Write torrentzip.zip, the reference archive for test_matches_trrntzip.
+
+The layout follows trrntzip's rules: entries sorted by lowercased name,
+then name; deflated by zlib at level 9 with a raw stream, 32 KiB window,
+memLevel 8 and the default strategy; the DOS time 1996-12-24 23:32:00 and
+the "maximum compression" flag on every entry; no extra fields; and the
+TORRENTZIPPED-XXXXXXXX comment over the central directory's CRC32.
+
+Run from this directory: python3 torrentzip.py
+"""
+
+import struct
+import zlib
+
+DOS_TIME, DOS_DATE = 0xBC00, 0x2198
+FLAGS = 2  # maximum compression
+
+
+def contents():
+    pattern = bytes(((i * i) // 7 + i) % 251 for i in range(150_000))
+    text = b"".join(b"ROMSHELF %05d\n" % (i % 997) for i in range(20_000))
+    return [
+        ("sub/pattern.bin", pattern),
+        ("readme.TXT", text),
+        ("Readme.txt", b"TorrentZip reference\n"),
+        ("empty.bin", b""),
+    ]
+
+
+def main():
+    local, central = b"", b""
+    entries = sorted(contents(), key=lambda e: (e[0].lower(), e[0]))
+    for name, data in entries:
+        deflate = zlib.compressobj(9, zlib.DEFLATED, -15, 8, zlib.Z_DEFAULT_STRATEGY)
+        packed = deflate.compress(data) + deflate.flush()
+        crc = zlib.crc32(data)
+        encoded = name.encode()
+        offset = len(local)
+        local += struct.pack(
+            "<IHHHHHIIIHH", 0x04034B50, 20, FLAGS, 8, DOS_TIME, DOS_DATE,
+            crc, len(packed), len(data), len(encoded), 0,
+        ) + encoded + packed
+        central += struct.pack(
+            "<IHHHHHHIIIHHHHHII", 0x02014B50, 0, 20, FLAGS, 8, DOS_TIME, DOS_DATE,
+            crc, len(packed), len(data), len(encoded), 0, 0, 0, 0, 0, offset,
+        ) + encoded
+    comment = b"TORRENTZIPPED-%08X" % zlib.crc32(central)
+    end = struct.pack(
+        "<IHHHHIIH", 0x06054B50, 0, 0, len(entries), len(entries),
+        len(central), len(local), len(comment),
+    ) + comment
+    with open("torrentzip.zip", "wb") as out:
+        out.write(local + central + end)
+
+
+if __name__ == "__main__":
+    main()
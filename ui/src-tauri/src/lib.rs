@@ -3,49 +3,110 @@
 //! This module provides thin wrappers around romshelf-core functions.
 //! All business logic and database queries live in the core library.
 
+use romshelf_core::db::search::SearchHit;
 use romshelf_core::db::{
     self, CollectionStats, DatSummary, DatTreeNode, DirectorySummary, FileSummary, FileTreeNode,
+    Library,
 };
+use romshelf_core::profile::{ProfileRegistry, ProfileSummary};
+use romshelf_core::report::{self, VerifyReport};
 use romshelf_core::scan::{self, ScanProgress};
-use romshelf_core::services::dat_importer::{DatImportOptions, DatImporter};
-use romshelf_core::services::progress::{DatImportEvent, ProgressSink, ScanEvent};
+use romshelf_core::services::audit::{self, ContainerAudit, DatSetSummary, SetAudit};
+use romshelf_core::services::dat_importer::{DatImportOptions, DatImportOutcome, DatImporter};
+use romshelf_core::services::diagnostics::{self, NearMiss};
+use romshelf_core::services::history::{self, Snapshot, SnapshotEvent};
+use romshelf_core::services::merge::{self, MergeMode};
+use romshelf_core::services::progress::{DatImportEvent, ProgressSink, RebuildEvent, ScanEvent};
+use romshelf_core::services::rebuild::{
+    self, ArchiveFormat, PlannedTarget, RebuildOptions, RebuildResult, Rebuilder,
+};
+use romshelf_core::services::verifier::{self, Scope};
+use romshelf_core::verify::NamePolicy;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tauri::AppHandle;
+use std::sync::{Arc, RwLock};
 use tauri::Emitter;
+use tauri::{AppHandle, Manager, State};
+
+/// The library the window is currently showing
+struct AppState {
+    active: RwLock<ActiveLibrary>,
+}
+
+struct ActiveLibrary {
+    profile: String,
+    library: Arc<Library>,
+}
+
+impl AppState {
+    /// Open the registry's active profile
+    fn load() -> Result<Self, String> {
+        let registry = ProfileRegistry::load().map_err(|e| e.to_string())?;
+        let profile = registry.active().to_string();
+        let library = open_profile(&registry, &profile)?;
+        Ok(Self {
+            active: RwLock::new(ActiveLibrary { profile, library }),
+        })
+    }
+
+    fn library(&self) -> Arc<Library> {
+        let active = self.active.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(&active.library)
+    }
+
+    fn profile(&self) -> String {
+        let active = self.active.read().unwrap_or_else(|e| e.into_inner());
+        active.profile.clone()
+    }
+}
+
+fn open_profile(registry: &ProfileRegistry, name: &str) -> Result<Arc<Library>, String> {
+    let path = registry.db_path(name).map_err(|e| e.to_string())?;
+    Library::open(&path)
+        .map(Arc::new)
+        .map_err(|e| e.to_string())
+}
 
 /// Get collection statistics
 #[tauri::command]
-fn get_stats() -> Result<CollectionStats, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_stats(state: State<'_, AppState>) -> Result<CollectionStats, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_collection_stats(&conn).map_err(|e| e.to_string())
 }
 
 /// List all loaded DATs with summary info
 #[tauri::command]
-fn list_dats() -> Result<Vec<DatSummary>, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn list_dats(state: State<'_, AppState>) -> Result<Vec<DatSummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::list_dats(&conn).map_err(|e| e.to_string())
 }
 
 /// Get DATs as a tree structure based on category hierarchy
 #[tauri::command]
-fn get_dat_tree() -> Result<DatTreeNode, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_dat_tree(state: State<'_, AppState>) -> Result<DatTreeNode, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_dat_tree(&conn).map_err(|e| e.to_string())
 }
 
 /// List scanned files with match status
 #[tauri::command]
-fn list_files(limit: i64, offset: i64) -> Result<Vec<FileSummary>, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn list_files(
+    state: State<'_, AppState>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<FileSummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::list_files(&conn, limit, offset).map_err(|e| e.to_string())
 }
 
 /// Get files as a tree structure based on filesystem paths
 #[tauri::command]
-fn get_file_tree() -> Result<FileTreeNode, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_file_tree(state: State<'_, AppState>) -> Result<FileTreeNode, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_file_tree(&conn).map_err(|e| e.to_string())
 }
 
@@ -55,34 +116,198 @@ fn get_file_tree() -> Result<FileTreeNode, String> {
 
 /// Get root directories for lazy tree loading
 #[tauri::command]
-fn get_root_directories() -> Result<Vec<DirectorySummary>, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_root_directories(state: State<'_, AppState>) -> Result<Vec<DirectorySummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_root_directories(&conn).map_err(|e| e.to_string())
 }
 
 /// Get child directories of a parent directory
 #[tauri::command]
-fn get_child_directories(parent_id: i64) -> Result<Vec<DirectorySummary>, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_child_directories(
+    state: State<'_, AppState>,
+    parent_id: i64,
+) -> Result<Vec<DirectorySummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_child_directories(&conn, parent_id).map_err(|e| e.to_string())
 }
 
 /// Get files directly in a directory (not recursive)
 #[tauri::command]
-fn get_files_in_directory(dir_id: i64) -> Result<Vec<FileSummary>, String> {
-    let conn = db::open_db().map_err(|e| e.to_string())?;
+fn get_files_in_directory(
+    state: State<'_, AppState>,
+    dir_id: i64,
+) -> Result<Vec<FileSummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
     db::get_files_in_directory(&conn, dir_id).map_err(|e| e.to_string())
 }
 
+/// Search sets, ROMs and files for the global search box
+#[tauri::command]
+fn search(
+    state: State<'_, AppState>,
+    query: String,
+    limit: usize,
+) -> Result<Vec<SearchHit>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    db::search::search(&conn, &query, limit).map_err(|e| e.to_string())
+}
+
+/// Per-set completeness, optionally limited to one DAT
+#[tauri::command]
+fn audit_sets(state: State<'_, AppState>, dat_id: Option<i64>) -> Result<Vec<SetAudit>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    audit::audit_sets(&conn, dat_id).map_err(|e| e.to_string())
+}
+
+/// Complete, partial and missing set counts per DAT
+#[tauri::command]
+fn get_set_summary(state: State<'_, AppState>) -> Result<Vec<DatSetSummary>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    let sets = audit::audit_sets(&conn, None).map_err(|e| e.to_string())?;
+    Ok(audit::summarise_sets(&sets))
+}
+
+/// Misnamed set archives, misplaced ROMs and unneeded files in archives
+#[tauri::command]
+fn audit_containers(state: State<'_, AppState>) -> Result<ContainerAudit, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    audit::audit_containers(&conn).map_err(|e| e.to_string())
+}
+
+/// Set or clear a DAT's merge mode (split, merged, non-merged)
+#[tauri::command]
+fn set_merge_mode(
+    state: State<'_, AppState>,
+    dat_id: i64,
+    mode: Option<MergeMode>,
+) -> Result<(), String> {
+    let library = state.library();
+    let conn = library.writer();
+    merge::set_merge_mode(&conn, dat_id, mode).map_err(|e| e.to_string())
+}
+
+/// Snapshots over time for charts, optionally for one DAT and from a date on
+#[tauri::command]
+fn get_history(
+    state: State<'_, AppState>,
+    dat_id: Option<i64>,
+    since: Option<String>,
+) -> Result<Vec<Snapshot>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    history::history(&conn, dat_id, since.as_deref()).map_err(|e| e.to_string())
+}
+
+/// Per-DAT, per-set and per-ROM verification results, optionally scoped to
+/// some DATs and a path
+#[tauri::command]
+fn get_verify_report(
+    state: State<'_, AppState>,
+    scope: Option<Scope>,
+) -> Result<VerifyReport, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    report::build(&conn, &scope.unwrap_or_default()).map_err(|e| e.to_string())
+}
+
+/// Write the verification report to a file as json, csv, markdown or html
+#[tauri::command]
+fn export_verify_report(
+    state: State<'_, AppState>,
+    path: PathBuf,
+    format: String,
+    scope: Option<Scope>,
+) -> Result<(), String> {
+    let format = format
+        .parse::<report::Format>()
+        .map_err(|e| e.to_string())?;
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    let report = report::build(&conn, &scope.unwrap_or_default()).map_err(|e| e.to_string())?;
+    let file = std::fs::File::create(&path).map_err(|e| e.to_string())?;
+    let mut out = std::io::BufWriter::new(file);
+    report::write(&report, format, &mut out).map_err(|e| e.to_string())?;
+    std::io::Write::flush(&mut out).map_err(|e| e.to_string())
+}
+
+/// Unmatched files that nearly match a DAT entry, with the likely entry
+#[tauri::command]
+fn diagnose_unmatched(state: State<'_, AppState>) -> Result<Vec<NearMiss>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    diagnostics::diagnose_unmatched(&conn).map_err(|e| e.to_string())
+}
+
+/// How a DAT's filenames are checked against its ROM names
+#[tauri::command]
+fn get_name_policy(state: State<'_, AppState>, dat_id: i64) -> Result<NamePolicy, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    verifier::name_policy(&conn, dat_id).map_err(|e| e.to_string())
+}
+
+/// Change a DAT's name policy and recheck its matches
+#[tauri::command]
+fn set_name_policy(
+    state: State<'_, AppState>,
+    dat_id: i64,
+    policy: NamePolicy,
+) -> Result<(), String> {
+    let library = state.library();
+    let conn = library.writer();
+    verifier::set_name_policy(&conn, dat_id, &policy).map_err(|e| e.to_string())
+}
+
+/// Enable or disable a DAT, dropping or restoring its matches
+#[tauri::command]
+fn set_dat_enabled(state: State<'_, AppState>, dat_id: i64, enabled: bool) -> Result<(), String> {
+    let library = state.library();
+    let conn = library.writer();
+    verifier::set_dat_enabled(&conn, dat_id, enabled).map_err(|e| e.to_string())
+}
+
+/// Set a DAT's priority for files that match several DATs
+#[tauri::command]
+fn set_dat_priority(state: State<'_, AppState>, dat_id: i64, priority: i64) -> Result<(), String> {
+    let library = state.library();
+    let conn = library.writer();
+    verifier::set_dat_priority(&conn, dat_id, priority).map_err(|e| e.to_string())
+}
+
+/// Set or clear the archive format a DAT's sets are rebuilt in
+#[tauri::command]
+fn set_dat_output_format(
+    state: State<'_, AppState>,
+    dat_id: i64,
+    format: Option<ArchiveFormat>,
+) -> Result<(), String> {
+    let library = state.library();
+    let conn = library.writer();
+    rebuild::set_output_format(&conn, dat_id, format).map_err(|e| e.to_string())
+}
+
 /// Import a DAT file and stream progress events to the frontend
 #[tauri::command]
-async fn import_dat(app: AppHandle, path: String, category: Option<String>) -> Result<(), String> {
+async fn import_dat(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    path: String,
+    category: Option<String>,
+) -> Result<(), String> {
     let path = PathBuf::from(path);
+    let library = state.library();
     tauri::async_runtime::spawn_blocking(move || {
-        let mut conn = db::open_db().map_err(|e| e.to_string())?;
+        let mut conn = library.writer();
         let sink = AppProgressSink::new(app.clone());
         let mut importer = DatImporter::new(&mut conn, sink);
-        importer
+        let result = importer
             .import_path(
                 &path,
                 DatImportOptions {
@@ -92,6 +317,11 @@ async fn import_dat(app: AppHandle, path: String, category: Option<String>) -> R
                 |_event| {},
             )
             .map_err(|e| e.to_string())?;
+        if matches!(result.outcome, DatImportOutcome::Imported { .. }) {
+            let tx = conn.transaction().map_err(|e| e.to_string())?;
+            history::record_snapshot(&tx, SnapshotEvent::Import).map_err(|e| e.to_string())?;
+            tx.commit().map_err(|e| e.to_string())?;
+        }
         Ok(())
     })
     .await
@@ -119,6 +349,77 @@ async fn scan_directory(
     .map_err(|e| e.to_string())?
 }
 
+/// Rebuild matched files into a target directory, streaming rebuild events
+#[tauri::command]
+async fn rebuild_collection(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    options: RebuildOptions,
+) -> Result<RebuildResult, String> {
+    let library = state.library();
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = library.reader().map_err(|e| e.to_string())?;
+        let sink = AppProgressSink::new(app.clone());
+        let result = Rebuilder::new(options, sink)
+            .run(&conn)
+            .map_err(|e| e.to_string())?;
+        // Point the library's rows of moved sources at where they went
+        rebuild::record_moves(&library.writer(), &result).map_err(|e| e.to_string())?;
+        Ok(result)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Where a rebuild would put each set, without touching any file
+#[tauri::command]
+fn preview_rebuild(
+    state: State<'_, AppState>,
+    options: RebuildOptions,
+) -> Result<Vec<PlannedTarget>, String> {
+    let library = state.library();
+    let conn = library.reader().map_err(|e| e.to_string())?;
+    let sources = rebuild::load_sources(&conn).map_err(|e| e.to_string())?;
+    Rebuilder::new(options, ())
+        .preview(&sources)
+        .map_err(|e| e.to_string())
+}
+
+// ============================================================================
+// Profiles (separate libraries)
+// ============================================================================
+
+/// List profiles, marking the one this window has open
+#[tauri::command]
+fn list_profiles(state: State<'_, AppState>) -> Result<Vec<ProfileSummary>, String> {
+    let registry = ProfileRegistry::load().map_err(|e| e.to_string())?;
+    let current = state.profile();
+    Ok(registry
+        .list()
+        .into_iter()
+        .map(|mut profile| {
+            profile.active = profile.name == current;
+            profile
+        })
+        .collect())
+}
+
+/// Switch the window to another profile and make it the default
+#[tauri::command]
+fn switch_profile(state: State<'_, AppState>, name: String) -> Result<(), String> {
+    let mut registry = ProfileRegistry::load().map_err(|e| e.to_string())?;
+    let library = open_profile(&registry, &name)?;
+    registry.set_active(&name).map_err(|e| e.to_string())?;
+    registry.save().map_err(|e| e.to_string())?;
+
+    let mut active = state.active.write().unwrap_or_else(|e| e.into_inner());
+    *active = ActiveLibrary {
+        profile: name,
+        library,
+    };
+    Ok(())
+}
+
 #[derive(Clone)]
 struct AppProgressSink {
     app: AppHandle,
@@ -142,11 +443,21 @@ impl ProgressSink<ScanEvent> for AppProgressSink {
     }
 }
 
+impl ProgressSink<RebuildEvent> for AppProgressSink {
+    fn emit(&self, event: RebuildEvent) {
+        let _ = self.app.emit("rebuild", event);
+    }
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_opener::init())
+        .setup(|app| {
+            app.manage(AppState::load()?);
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_stats,
             list_dats,
@@ -156,8 +467,26 @@ pub fn run() {
             get_root_directories,
             get_child_directories,
             get_files_in_directory,
+            search,
+            audit_sets,
+            get_set_summary,
+            audit_containers,
+            set_merge_mode,
+            diagnose_unmatched,
+            get_verify_report,
+            get_history,
+            export_verify_report,
+            get_name_policy,
+            set_name_policy,
+            set_dat_enabled,
+            set_dat_priority,
+            set_dat_output_format,
             import_dat,
-            scan_directory
+            scan_directory,
+            rebuild_collection,
+            preview_rebuild,
+            list_profiles,
+            switch_profile
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");